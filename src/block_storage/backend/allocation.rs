@@ -1,48 +1,100 @@
-use super::frames::Frame;
-use super::header::Header;
+use super::frames::{Frame, FrameState};
 use super::Backend;
 use std::error::Error;
 
 impl Backend {
-    /// allocator with runtime O(1)
-    pub fn next_free_frame(&mut self) -> Result<usize, Box<dyn Error>> {
+    /// allocator with runtime O(1), for a frame of the given size class
+    pub fn next_free_frame(&mut self, class: u8) -> Result<usize, Box<dyn Error>> {
         // try to use an existing frame that got "deleted"
-        if self.header.first_free_frame != 0 {
-            let frame = self.read_frame(self.header.first_free_frame)?;
-            self.header.first_free_frame = frame.next;
-            self.header.update(&mut self.mapped_file)?;
-            Ok(frame.position)
+        let first_free_frame = self.first_free_frame(class);
+        if first_free_frame != 0 {
+            let mut frame = self.read_frame(first_free_frame)?;
+            let position = frame.position;
+            self.set_first_free_frame(class, frame.next);
+            self.write_header()?;
+            frame.state = FrameState::Tombstone;
+            frame.next = 0;
+            self.update_frame(frame)?;
+            Ok(position)
         // or allocate more memory
         } else {
-            let next_free_position = Header::size() + self.header.frame_count * Frame::total_size();
-            self.header.frame_count += 1;
-            self.header.update(&mut self.mapped_file)?;
-            if (next_free_position + Frame::total_size()) > self.size {
+            // grow first, so the header never counts frames beyond the file
+            let unit = self.layout.unit();
+            let next_free_position = self.offset() + self.header.frame_count * unit;
+            while (next_free_position + self.layout.frame_size(class)) > self.size {
                 self.resize_file()?;
             }
+            self.header.frame_count += self.layout.units(class);
+            self.write_header()?;
             Ok(next_free_position)
         }
     }
 
-    /// remove a frame from the list of deleted frames, making it an orphan.
+    /// the first frame on the free list of the size class, 0 if it is empty
+    pub fn first_free_frame(&self, class: u8) -> usize {
+        match class {
+            0 => self.header.first_free_frame,
+            class => self.header.first_free_class_frames[class as usize - 1],
+        }
+    }
+
+    pub fn set_first_free_frame(&mut self, class: u8, position: usize) {
+        match class {
+            0 => self.header.first_free_frame = position,
+            class => self.header.first_free_class_frames[class as usize - 1] = position,
+        }
+    }
+
+    /// make sure the frames of the given plan, see `Layout::plan`, can be
+    /// allocated without growing the file, taking frames on the free lists
+    /// into account. Writes reserve their frames upfront, so running out of
+    /// space never interrupts them halfway through a chain of frames.
+    pub fn reserve_frames(&mut self, plan: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut units = 0;
+        for class in 0..self.layout.class_count() as u8 {
+            let count = plan.iter().filter(|planned| **planned == class).count();
+            let mut available = 0;
+            let mut cursor = self.first_free_frame(class);
+            while cursor != 0 && available < count {
+                available += 1;
+                cursor = self.read_frame(cursor)?.next;
+            }
+            units += (count - available) * self.layout.units(class);
+        }
+        let frame_count = self.header.frame_count + units;
+        while self.offset() + frame_count * self.layout.unit() > self.size {
+            self.resize_file()?;
+        }
+        Ok(())
+    }
+
+    /// remove a frame from the list of deleted frames of its size class,
+    /// making it an orphan.
     ///
     /// this should be used with great care, since this memory frame will never
-    /// be reclaimed again if not used immediately.
-    pub fn unlink_free_frame(&mut self, position: usize) -> Result<usize, Box<dyn Error>> {
-        let mut cursor: usize = self.header.first_free_frame;
+    /// be reclaimed again if not used immediately. The orphan is marked as a
+    /// tombstone, so it can never be mistaken for a frame on the free list.
+    pub fn unlink_free_frame(
+        &mut self,
+        position: usize,
+        class: u8,
+    ) -> Result<usize, Box<dyn Error>> {
+        let mut cursor: usize = self.first_free_frame(class);
         let mut prev: Option<Frame> = None;
         while cursor != 0 {
-            let frame = self.read_frame(cursor)?;
+            let mut frame = self.read_frame(cursor)?;
             if frame.position == position {
                 if let Some(mut prev) = prev {
                     prev.next = frame.next;
                     self.update_frame(prev)?;
-                    break;
                 } else {
-                    self.header.first_free_frame = frame.next;
-                    self.header.update(&mut self.mapped_file)?;
-                    break;
+                    self.set_first_free_frame(class, frame.next);
+                    self.write_header()?;
                 }
+                frame.state = FrameState::Tombstone;
+                frame.next = 0;
+                self.update_frame(frame)?;
+                break;
             } else {
                 cursor = frame.next;
                 prev = Some(frame);
@@ -50,4 +102,235 @@ impl Backend {
         }
         Ok(position)
     }
+
+    /// write a record into consecutive frames, starting at the frame with the
+    /// given number, which must not be counted by the header yet. Neither the
+    /// header nor the free list get touched and nothing is flushed, so a bulk
+    /// load appends many records cheaply and calls `finish_appending` once.
+    /// An interrupted bulk load leaves the file as empty as it was.
+    ///
+    /// runtime: O(n) in the number of frames of the record
+    pub fn append_at(&mut self, frame: usize, bytes: &[u8]) -> Result<usize, Box<dyn Error>> {
+        let layout = self.layout;
+        let plan = layout.plan(bytes.len());
+        self.grow_to_frames(frame + layout.units_needed(bytes.len()))?;
+        let start = self.offset() + frame * layout.unit();
+        let mut position = start;
+        // an empty record still occupies its frame
+        let mut chunks = layout.chunks(&plan, bytes).peekable();
+        while let Some((class, chunk)) = chunks.next() {
+            let next = match chunks.peek() {
+                Some(_) => position + layout.frame_size(class),
+                None => 0,
+            };
+            self.update_frame(Frame {
+                position,
+                body_size: chunk.len(),
+                state: FrameState::Live,
+                next,
+                class,
+            })?;
+            let body = position + Frame::header_size();
+            self.mapped_file[body..body + chunk.len()].copy_from_slice(chunk);
+            self.dirty.add(body..body + chunk.len());
+            position += layout.frame_size(class);
+        }
+        Ok(start)
+    }
+
+    /// count the frames written by `append_at` in the header, with nothing
+    /// on the free lists, and flush everything at once
+    pub fn finish_appending(&mut self, frame_count: usize) -> Result<(), Box<dyn Error>> {
+        self.header.frame_count = frame_count;
+        self.header.first_free_frame = 0;
+        self.header.first_free_class_frames = [0; 2];
+        self.write_header()?;
+        self.touch()?;
+        self.flush()
+    }
+
+    /// grow the file in a single step, so it holds at least the given number
+    /// of frames in total
+    pub fn grow_to_frames(&mut self, count: usize) -> Result<(), Box<dyn Error>> {
+        let needed = self.offset() + count * self.layout.unit();
+        if needed > self.size {
+            self.resize_file_to(needed.max(self.size * 2))?;
+        }
+        Ok(())
+    }
+
+    /// forget every frame, including the metadata record, and shrink the
+    /// file back to the header region. Positions of records are invalid
+    /// afterwards, and the next frame gets allocated at the very start.
+    pub fn clear(&mut self) -> Result<(), Box<dyn Error>> {
+        self.header.frame_count = 0;
+        self.header.first_free_frame = 0;
+        self.header.first_free_class_frames = [0; 2];
+        self.header.meta_position = 0;
+        self.write_header()?;
+        self.touch()?;
+        self.resize_file_to(self.offset())
+    }
+
+    /// write a copy of a record into the lowest free frames of the file, if
+    /// that brings its last frame closer to the front. The original stays
+    /// as it is. Returns the position of the copy, `None` if there are not
+    /// enough free frames below the last frame of the record.
+    ///
+    /// runtime: O(n) in the number of free frames
+    pub fn copy_to_front(&mut self, position: usize) -> Result<Option<usize>, Box<dyn Error>> {
+        let end = match self.record_frames(position)?.into_iter().max() {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let bytes = self.read(position)?;
+        let layout = self.layout;
+        let plan = layout.plan(bytes.len());
+        // the lowest free frames of every class the copy needs, lowest last
+        let mut free = vec![vec![]; layout.class_count()];
+        for (class, positions) in free.iter_mut().enumerate() {
+            let class = class as u8;
+            let needed = plan.iter().filter(|planned| **planned == class).count();
+            if needed == 0 {
+                continue;
+            }
+            let mut cursor = self.first_free_frame(class);
+            while cursor != 0 {
+                if cursor < end {
+                    positions.push(cursor);
+                }
+                cursor = self.read_frame(cursor)?.next;
+            }
+            if positions.len() < needed {
+                return Ok(None);
+            }
+            positions.sort_unstable();
+            positions.truncate(needed);
+            positions.reverse();
+        }
+        let targets: Vec<usize> = plan
+            .iter()
+            .filter_map(|class| free[*class as usize].pop())
+            .collect();
+        for (target, class) in targets.iter().zip(&plan) {
+            self.unlink_free_frame(*target, *class)?;
+        }
+        for (index, (class, chunk)) in layout.chunks(&plan, &bytes).enumerate() {
+            let target = targets[index];
+            self.update_frame(Frame {
+                position: target,
+                body_size: 0,
+                state: FrameState::Live,
+                next: targets.get(index + 1).copied().unwrap_or(0),
+                class,
+            })?;
+            self.write_frame_body(target, chunk)?;
+        }
+        self.write_header()?;
+        self.touch()?;
+        self.flush()?;
+        Ok(Some(targets[0]))
+    }
+
+    /// the number of bytes the file would shrink by if all live frames got
+    /// packed at its front and `shrink_to_fit` released the rest, without
+    /// moving anything
+    ///
+    /// runtime: O(n) in the number of frames of the file
+    pub fn compaction_savings(&self) -> Result<usize, Box<dyn Error>> {
+        let mut live = 0;
+        for scanned in self.scan_frames().live_only() {
+            live += self.layout.units(scanned?.1.class);
+        }
+        let packed = self.offset() + live * self.layout.unit();
+        Ok(self.size.saturating_sub(packed))
+    }
+
+    /// drop all frames behind the last live one and shrink the file to end
+    /// right after it, returning the number of bytes released. Free frames
+    /// in between stay on the free lists.
+    ///
+    /// runtime: O(n) in the number of frames of the file
+    pub fn shrink_to_fit(&mut self) -> Result<usize, Box<dyn Error>> {
+        let end = self.live_end()?;
+        if end == self.size {
+            return Ok(0);
+        }
+
+        // relink the free lists without the dropped frames, keeping their order
+        for class in 0..self.layout.class_count() as u8 {
+            let mut kept = vec![];
+            let mut cursor = self.first_free_frame(class);
+            while cursor != 0 {
+                let frame = self.read_frame(cursor)?;
+                if cursor < end {
+                    kept.push(frame);
+                }
+                cursor = frame.next;
+            }
+            let first = kept.first().map_or(0, |frame| frame.position);
+            self.set_first_free_frame(class, first);
+            let following: Vec<usize> = kept.iter().skip(1).map(|frame| frame.position).collect();
+            for (frame, next) in kept.iter_mut().zip(following.into_iter().chain([0])) {
+                frame.next = next;
+                self.update_frame(*frame)?;
+            }
+        }
+        self.header.frame_count = (end - self.offset()) / self.layout.unit();
+        self.write_header()?;
+        self.touch()?;
+        let size = self.size;
+        self.resize_file_to(end)?;
+        Ok(size - end)
+    }
+
+    /// the position right behind the last live frame
+    fn live_end(&self) -> Result<usize, Box<dyn Error>> {
+        let unit = self.layout.unit();
+        // frames of a single size can be checked from the end backwards
+        if self.layout.is_single() {
+            let mut count = self.header.frame_count;
+            while count > 0 {
+                let frame = self.read_frame(self.offset() + (count - 1) * unit)?;
+                if frame.state == FrameState::Live {
+                    break;
+                }
+                count -= 1;
+            }
+            return Ok(self.offset() + count * unit);
+        }
+        let mut end = self.offset();
+        for scanned in self.scan_frames().live_only() {
+            let (position, frame, _) = scanned?;
+            end = position + self.layout.frame_size(frame.class);
+        }
+        Ok(end)
+    }
+
+    /// the number of frames of the file, whether in use or free
+    pub fn frame_count(&self) -> usize {
+        self.header.frame_count
+    }
+
+    /// put every frame that fits into the current file size onto the free
+    /// list, linked in ascending order so they get handed out front to back.
+    /// With size classes, these are frames of the smallest class.
+    pub fn preallocate_frames(&mut self) -> Result<(), Box<dyn Error>> {
+        let unit = self.layout.unit();
+        let count = (self.size - self.offset()) / unit;
+        for index in (0..count).rev() {
+            let frame = Frame {
+                position: self.offset() + index * unit,
+                body_size: 0,
+                state: FrameState::Free,
+                next: self.header.first_free_frame,
+                class: 0,
+            };
+            self.header.first_free_frame = frame.position;
+            self.update_frame(frame)?;
+        }
+        self.header.frame_count = count;
+        self.write_header()?;
+        self.flush()
+    }
 }
@@ -0,0 +1,89 @@
+use std::ops::Range;
+
+// beyond this many separate ranges, one flush of the whole mapping is cheaper
+const MAX_RANGES: usize = 64;
+
+/// The byte ranges of the mapping written since the last flush, sorted and
+/// merged whenever they overlap or touch, so a frame header and its body
+/// end up in one range.
+#[derive(Debug, Default)]
+pub struct DirtyRanges {
+    ranges: Vec<Range<usize>>,
+    // too many ranges were written to track them all
+    overflowed: bool,
+}
+
+impl DirtyRanges {
+    /// record a written range of bytes
+    pub fn add(&mut self, range: Range<usize>) {
+        if self.overflowed || range.is_empty() {
+            return;
+        }
+        // the first range that ends at or behind the start of the new one
+        let first = self.ranges.partition_point(|other| other.end < range.start);
+        // the ranges from `first` up to `last` overlap or touch the new one
+        let last = first
+            + self.ranges[first..]
+                .iter()
+                .take_while(|other| other.start <= range.end)
+                .count();
+        let merged = match self.ranges[first..last] {
+            [] => range,
+            ref touched => {
+                let start = touched[0].start.min(range.start);
+                let end = touched[touched.len() - 1].end.max(range.end);
+                start..end
+            }
+        };
+        self.ranges.splice(first..last, [merged]);
+        if self.ranges.len() > MAX_RANGES {
+            self.ranges.clear();
+            self.overflowed = true;
+        }
+    }
+
+    /// the recorded ranges in ascending order, or `None` if there were too
+    /// many and everything needs to be flushed. Starts over afterwards.
+    pub fn take(&mut self) -> Option<Vec<Range<usize>>> {
+        let overflowed = std::mem::take(&mut self.overflowed);
+        let ranges = std::mem::take(&mut self.ranges);
+        (!overflowed).then_some(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_touching_ranges() {
+        let mut dirty = DirtyRanges::default();
+        dirty.add(100..110);
+        dirty.add(0..10);
+        dirty.add(50..60);
+        dirty.add(110..120);
+        dirty.add(5..5);
+        assert_eq!(dirty.take(), Some(vec![0..10, 50..60, 100..120]));
+        assert_eq!(dirty.take(), Some(vec![]));
+
+        // a range spanning several others swallows them
+        dirty.add(0..10);
+        dirty.add(20..30);
+        dirty.add(40..50);
+        dirty.add(5..45);
+        dirty.add(60..70);
+        assert_eq!(dirty.take(), Some(vec![0..50, 60..70]));
+    }
+
+    #[test]
+    fn overflow() {
+        let mut dirty = DirtyRanges::default();
+        for i in 0..=MAX_RANGES {
+            dirty.add(i * 10..i * 10 + 5);
+        }
+        assert_eq!(dirty.take(), None);
+        let range = 0..5;
+        dirty.add(range.clone());
+        assert_eq!(dirty.take(), Some(vec![range]));
+    }
+}
@@ -1,42 +1,242 @@
 use super::Backend;
+use crate::error::WiredError;
+use crate::options::Options;
 use memmap2::{MmapMut, MmapOptions};
 use std::error::Error;
 use std::fs::File;
+use std::io;
 
 impl Backend {
-    pub fn open_file(file: &File) -> Result<(usize, MmapMut), Box<dyn Error>> {
-        let size = ensure_minimum_file_size(&file)?;
-        let mapped_file = create_file_mapping(&file, size)?;
+    pub fn open_file(file: &File, options: &Options) -> Result<(usize, MmapMut), Box<dyn Error>> {
+        let size = ensure_minimum_file_size(file, options.initial_bytes)?;
+        let mapped_file = create_file_mapping(file, size)?;
         Ok((size, mapped_file))
     }
 
+    /// anonymous memory of at least a page, which reads as zeros until
+    /// written to, just like a new file
+    pub fn map_anonymous(size: usize) -> Result<(usize, MmapMut), Box<dyn Error>> {
+        let size = size.max(page_size::get());
+        let mapped_file = MmapOptions::new().len(size).map_anon()?;
+        Ok((size, mapped_file))
+    }
+
+    /// double the file size and map it again, see `resize_file_to`
     pub fn resize_file(&mut self) -> Result<(), Box<dyn Error>> {
-        let new_size = self.size * 2;
-        self.file.set_len(new_size as u64)?;
-        self.size = new_size;
-        let new_mapped_file = create_file_mapping(&self.file, new_size)?;
-        self.mapped_file = new_mapped_file;
+        self.resize_file_to(self.size * 2)
+    }
+
+    /// grow or shrink the file to the given size and map it again.
+    ///
+    /// everything is flushed before the file length changes. If resizing the
+    /// file or mapping it fails, the previous mapping stays in place, so the
+    /// backend remains usable with its current size.
+    pub fn resize_file_to(&mut self, new_size: usize) -> Result<(), Box<dyn Error>> {
+        #[cfg(feature = "tracing")]
+        let _span =
+            tracing::debug_span!("resize", from_bytes = self.size, to_bytes = new_size).entered();
+        #[cfg(feature = "tracing")]
+        let started = std::time::Instant::now();
+        self.flush()?;
+        self.release_mapping()?;
+        let result = self
+            .set_file_length(new_size)
+            .and_then(|()| self.create_mapping(new_size));
+        match result {
+            Ok(new_mapped_file) => {
+                self.mapped_file = new_mapped_file;
+                self.size = new_size;
+                self.stats.resizes += 1;
+                #[cfg(feature = "tracing")]
+                tracing::debug!(
+                    bytes = new_size,
+                    duration_us = started.elapsed().as_micros() as u64,
+                    "file resized"
+                );
+                Ok(())
+            }
+            Err(error) => {
+                self.restore_mapping()?;
+                Err(error)
+            }
+        }
+    }
+
+    fn set_file_length(&self, size: usize) -> Result<(), Box<dyn Error>> {
+        #[cfg(test)]
+        if self.quota.is_some_and(|quota| size > quota) {
+            return Err(WiredError::DiskFull.into());
+        }
+        match &self.file {
+            Some(file) => file.set_len(size as u64).map_err(io_error),
+            None => Ok(()),
+        }
+    }
+
+    /// map the file again, or copy an anonymous mapping into a new one
+    fn create_mapping(&self, size: usize) -> Result<MmapMut, Box<dyn Error>> {
+        match &self.file {
+            Some(file) => create_file_mapping(file, size),
+            None => {
+                let mut mapped_file = MmapOptions::new().len(size).map_anon()?;
+                let kept = size.min(self.size);
+                mapped_file[..kept].copy_from_slice(&self.mapped_file[..kept]);
+                Ok(mapped_file)
+            }
+        }
+    }
+
+    /// Windows refuses to change the length of a file while a mapping of it
+    /// is alive, so the mapping gets swapped out for a tiny anonymous one.
+    #[cfg(windows)]
+    fn release_mapping(&mut self) -> Result<(), Box<dyn Error>> {
+        // an anonymous mapping gets copied, so it has to stay
+        if self.file.is_some() {
+            self.mapped_file = MmapOptions::new().len(1).map_anon()?;
+        }
+        Ok(())
+    }
+
+    /// other platforms keep the old mapping until the new one is ready
+    #[cfg(not(windows))]
+    fn release_mapping(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    fn restore_mapping(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(file) = &self.file {
+            self.mapped_file = create_file_mapping(file, self.size)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(windows))]
+    fn restore_mapping(&mut self) -> Result<(), Box<dyn Error>> {
         Ok(())
     }
 
-    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
-        let result = self.mapped_file.flush()?;
-        Ok(result)
+    /// write the ranges of the mapping changed since the last flush to the
+    /// file, or the whole mapping if too many ranges changed. Ranges get
+    /// flushed back to front, so the header at the very start goes last,
+    /// after the frames it counts. An anonymous mapping has nowhere to
+    /// write to, so flushing it only forgets the changed ranges.
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.stats.flushes += 1;
+        if self.file.is_none() {
+            self.dirty.take();
+            return Ok(());
+        }
+        let Some(ranges) = self.dirty.take() else {
+            #[cfg(test)]
+            self.flushed.push(0..self.mapped_file.len());
+            return self.mapped_file.flush().map_err(io_error);
+        };
+        for range in ranges.into_iter().rev() {
+            #[cfg(test)]
+            self.flushed.push(range.clone());
+            self.mapped_file
+                .flush_range(range.start, range.len())
+                .map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    /// flush the mapping, and sync the file along with its metadata if it
+    /// changed its length since the last sync. Flushing alone writes the
+    /// pages, but a grown file may lose its new length on a crash.
+    pub fn sync(&mut self) -> Result<(), Box<dyn Error>> {
+        self.flush()?;
+        if let (Some(file), true) = (&self.file, self.synced_size != self.size) {
+            file.sync_all().map_err(io_error)?;
+            self.synced_size = self.size;
+            self.stats.syncs += 1;
+        }
+        Ok(())
     }
 }
 
 fn create_file_mapping(file: &File, size: usize) -> Result<MmapMut, Box<dyn Error>> {
-    let mapped_file = unsafe { MmapOptions::new().len(size).map_mut(&file)? };
+    let mapped_file = unsafe { MmapOptions::new().len(size).map_mut(file)? };
     Ok(mapped_file)
 }
 
-fn ensure_minimum_file_size(file: &File) -> Result<usize, Box<dyn Error>> {
+fn ensure_minimum_file_size(
+    file: &File,
+    initial_bytes: Option<usize>,
+) -> Result<usize, Box<dyn Error>> {
     let current_size: usize = file.metadata()?.len() as usize;
     if current_size == 0 {
-        let min_size: usize = page_size::get();
-        file.set_len(min_size as u64)?;
+        let page_size: usize = page_size::get();
+        let min_size = initial_bytes.map_or(page_size, |bytes| bytes.max(page_size));
+        file.set_len(min_size as u64).map_err(io_error)?;
         Ok(min_size)
     } else {
         Ok(current_size)
     }
 }
+
+/// turn a full filesystem into a typed error, keep any other error as is
+fn io_error(error: io::Error) -> Box<dyn Error> {
+    match error.kind() {
+        io::ErrorKind::StorageFull => WiredError::DiskFull.into(),
+        _ => error.into(),
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// collects the fields of every event as text
+    #[derive(Clone, Default)]
+    struct Capture {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!("{}={:?} ", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields(String::new());
+            event.record(&mut fields);
+            self.events.lock().unwrap().push(fields.0);
+        }
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn resize_event() {
+        let capture = Capture::default();
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create mmap");
+        let size = backend.size;
+        tracing::subscriber::with_default(capture.clone(), || {
+            backend.resize_file().expect("could not resize");
+        });
+        let events = capture.events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].starts_with("message=file resized "));
+        assert!(events[0].contains(&format!("bytes={} ", size * 2)));
+        assert!(events[0].contains("duration_us="));
+    }
+}
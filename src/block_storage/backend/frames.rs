@@ -1,6 +1,7 @@
-// use super::header::Header;
 use super::Backend;
+use crate::error::WiredError;
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 use std::error::Error;
 use std::io::Write;
 use std::ops::Range;
@@ -8,58 +9,159 @@ use std::ops::Range;
 const FRAME_SIZE: usize = 1024;
 // const FRAME_SIZE: usize = 32 * 1024;
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+// the serialized size of `StoredFrame`, padded to a multiple of 8 bytes
+const HEADER_SIZE: usize = 32;
+
+/// lifecycle of a single frame, persisted as one byte
+///
+/// `Live` and `Free` are encoded as `0` and `1`, which is exactly how the
+/// former `deleted: bool` flag was stored, so existing files stay readable.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum FrameState {
+    // holds (a part of) a live record
+    #[default]
+    Live,
+    // holds no live data and sits on the free list, ready to be reused
+    Free,
+    // holds no live data, but is not on the free list (yet)
+    Tombstone,
+}
+
+impl From<FrameState> for u8 {
+    fn from(state: FrameState) -> Self {
+        match state {
+            FrameState::Live => 0,
+            FrameState::Free => 1,
+            FrameState::Tombstone => 2,
+        }
+    }
+}
+
+impl TryFrom<u8> for FrameState {
+    type Error = String;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            0 => Ok(FrameState::Live),
+            1 => Ok(FrameState::Free),
+            2 => Ok(FrameState::Tombstone),
+            _ => Err(format!("invalid frame state: {}", byte)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Frame {
     // first byte position in file of this frame
     pub position: usize,
     // length of the body
     pub body_size: usize,
-    // is this block deleted?
-    pub deleted: bool,
+    // does this frame hold live data, or can it be reused?
+    pub state: FrameState,
     // if not 0, read the next block in addition to this one and treat them as one logical unit
     pub next: usize,
+    // the size class of the frame, see `Layout`, always 0 without size classes
+    pub class: u8,
+}
+
+/// a frame header as stored on disk, where positions are always 64 bit wide
+/// regardless of the pointer width of the build target
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct StoredFrame {
+    position: u64,
+    body_size: u64,
+    state: FrameState,
+    next: u64,
+    // a byte of the padding that files without size classes never read
+    class: u8,
 }
 
 impl Frame {
-    pub fn header_size() -> usize {
-        std::mem::size_of::<Self>()
+    pub const fn header_size() -> usize {
+        HEADER_SIZE
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let stored: StoredFrame = bincode::deserialize_from(bytes)?;
+        Ok(Frame {
+            position: to_usize(stored.position)?,
+            body_size: to_usize(stored.body_size)?,
+            state: stored.state,
+            next: to_usize(stored.next)?,
+            class: stored.class,
+        })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(bincode::serialize(&self.stored())?)
     }
 
-    pub fn capacity() -> usize {
+    /// like `encode`, but straight into the given bytes without allocating
+    pub fn encode_into(&self, target: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        Ok(bincode::serialize_into(target, &self.stored())?)
+    }
+
+    fn stored(&self) -> StoredFrame {
+        StoredFrame {
+            position: self.position as u64,
+            body_size: self.body_size as u64,
+            state: self.state,
+            next: self.next as u64,
+            class: self.class,
+        }
+    }
+
+    pub const fn capacity() -> usize {
         FRAME_SIZE - Self::header_size()
     }
 
-    pub fn total_size() -> usize {
+    pub const fn total_size() -> usize {
         FRAME_SIZE
     }
 }
 
 impl Backend {
-    pub fn create_frame(&mut self, position: usize) -> Result<Frame, Box<dyn Error>> {
+    pub fn create_frame(&mut self, position: usize, class: u8) -> Result<Frame, Box<dyn Error>> {
         let frame = Frame {
-            position: position,
-            deleted: false,
+            position,
+            state: FrameState::Live,
             next: 0,
             body_size: 0,
+            class,
         };
         self.update_frame(frame)?;
         self.read_frame(position)
     }
 
+    /// the header of the frame at the given position, which fails with
+    /// `WiredError::Truncated` if the frame does not fit into the file, e.g.
+    /// when a truncated file got opened with its frames clamped
     pub fn read_frame(&self, position: usize) -> Result<Frame, Box<dyn Error>> {
         let start = position;
         let end = Frame::header_size() + position;
+        let truncated = |expected| WiredError::Truncated {
+            expected,
+            actual: self.mapped_file.len(),
+        };
         let range = Range { start, end };
-        let bytes = &self.mapped_file[range];
-        Ok(bincode::deserialize_from(bytes)?)
+        let header = self.mapped_file.get(range).ok_or_else(|| truncated(end))?;
+        let index = position.saturating_sub(self.offset()) / self.layout.unit();
+        let frame = self.layout.checked(Frame::decode(header)?, index)?;
+        let frame_end = position + self.layout.frame_size(frame.class);
+        if frame_end > self.mapped_file.len() {
+            return Err(truncated(frame_end).into());
+        }
+        Ok(frame)
     }
 
     pub fn update_frame(&mut self, frame: Frame) -> Result<(), Box<dyn Error>> {
         let start = frame.position;
         let end = Frame::header_size() + frame.position;
         let range = Range { start, end };
-        let bytes: Vec<u8> = bincode::serialize(&frame)?;
+        let bytes = frame.encode()?;
         (&mut self.mapped_file[range]).write_all(&bytes)?;
+        self.dirty.add(start..end);
         Ok(())
     }
 
@@ -71,6 +173,29 @@ impl Backend {
         Ok(&self.mapped_file[range])
     }
 
+    /// write a record that fits into a single frame in one go: the header
+    /// is encoded once, right in front of the body, and nothing is read back
+    pub fn write_single_frame(
+        &mut self,
+        position: usize,
+        class: u8,
+        bytes: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let frame = Frame {
+            position,
+            body_size: bytes.len(),
+            state: FrameState::Live,
+            next: 0,
+            class,
+        };
+        let start = position + Frame::header_size();
+        let end = start + bytes.len();
+        frame.encode_into(&mut self.mapped_file[position..start])?;
+        self.mapped_file[start..end].copy_from_slice(bytes);
+        self.dirty.add(position..end);
+        Ok(())
+    }
+
     pub fn write_frame_body(
         &mut self,
         position: usize,
@@ -81,29 +206,62 @@ impl Backend {
         let start = frame.position + Frame::header_size();
         let end = start + body_size;
         let range = Range { start, end };
-        (&mut self.mapped_file[range]).write_all(&bytes)?;
+        (&mut self.mapped_file[range]).write_all(bytes)?;
+        self.dirty.add(start..end);
         frame.body_size = body_size;
         self.update_frame(frame)?;
         Ok(())
     }
+}
+
+/// a position read from disk, which may not be addressable in memory on
+/// targets with less than 64 bit pointers
+fn to_usize(position: u64) -> Result<usize, WiredError> {
+    usize::try_from(position).map_err(|_| WiredError::PositionOverflow { position })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_positions() {
+        let stored = StoredFrame {
+            position: 5 << 30,
+            body_size: 1000,
+            state: FrameState::Live,
+            next: u64::MAX - 1024,
+            class: 2,
+        };
+        let bytes = bincode::serialize(&stored).unwrap();
+        assert!(bytes.len() <= Frame::header_size());
+        assert_eq!(bincode::deserialize::<StoredFrame>(&bytes).unwrap(), stored);
+
+        match Frame::decode(&bytes) {
+            Ok(frame) => {
+                assert_eq!(frame.position as u64, stored.position);
+                assert_eq!(frame.next as u64, stored.next);
+                assert_eq!(frame.encode().unwrap(), bytes);
+            }
+            Err(error) => {
+                // only targets with less than 64 bit pointers end up here
+                assert!(usize::try_from(stored.position).is_err());
+                let error = error.downcast_ref::<WiredError>();
+                let position = stored.position;
+                assert_eq!(error, Some(&WiredError::PositionOverflow { position }));
+            }
+        }
+    }
 
-    // pub fn collect_head_nodes(&self) -> Result<Vec<usize>, Box<dyn Error>> {
-    //     let mut result: Vec<usize> = vec![];
-    //     let mut tail_nodes: Vec<usize> = vec![];
-    //     let offset = Header::size();
-    //     let frame_size = Frame::total_size();
-    //     let mut index: usize = 0;
-    //     while index < self.header.frame_count {
-    //         let position = offset + index * frame_size;
-    //         let frame = self.read_frame(position)?;
-    //         if frame.next != 0 {
-    //             tail_nodes.push(frame.next);
-    //         }
-    //         if !tail_nodes.contains(&position) && !frame.deleted {
-    //             result.push(position);
-    //         }
-    //         index += 1;
-    //     }
-    //     Ok(result)
-    // }
+    #[test]
+    fn position_overflow() {
+        assert_eq!(to_usize(4096), Ok(4096));
+        if usize::BITS < 64 {
+            let position = u64::from(u32::MAX) + 1;
+            assert_eq!(
+                to_usize(position),
+                Err(WiredError::PositionOverflow { position })
+            );
+        }
+    }
 }
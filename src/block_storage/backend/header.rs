@@ -1,20 +1,54 @@
 use super::Backend;
+use crate::clock::Clock;
+use crate::error::WiredError;
+use crate::format::{self, Feature};
 use memmap2::MmapMut;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::io::Write;
 use std::ops::RangeTo;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// bytes reserved for the header in front of the first frame of a new file.
+/// This is independent of the size of `Header`, so later format versions
+/// can append fields without moving any frame.
+pub const REGION_SIZE: usize = 1024;
+
+/// the serialized size of `Header`. bincode writes every `usize` with 8
+/// bytes, so it is the same on targets with less than 64 bit pointers,
+/// unlike the size of `Header` in memory.
+const HEADER_SIZE: usize = 80;
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Header {
     pub frame_count: usize,
-    pub version: usize,
+    // together with the two fields below, this takes the 8 bytes of the
+    // `usize` version of older files, whose upper bytes are always zero
+    pub format_version: u16,
+    pub reserved: u16,
+    // see `format::Feature`, 0 in files written before the field
+    pub feature_flags: u32,
     pub first_free_frame: usize,
+    // UNIX seconds, 0 if unknown because the file predates the field
+    pub created_at: u64,
+    // UNIX seconds of the latest modification, 0 if unknown
+    pub modified_at: u64,
+    // UNIX seconds of the latest compaction, 0 if there was none yet
+    pub compacted_at: u64,
+    // bytes in front of the first frame, stored since the reserved size
+    // might change in later versions
+    pub region_size: usize,
+    // first frame of the metadata record of the application, 0 if there
+    // is none. Files written before the field hold zeros here.
+    pub meta_position: usize,
+    // first frame on the free list of every size class but the smallest,
+    // whose list starts at `first_free_frame`. Zeros without size classes.
+    pub first_free_class_frames: [usize; 2],
 }
 
 impl Header {
-    pub fn size() -> usize {
-        std::mem::size_of::<Self>()
+    pub const fn size() -> usize {
+        HEADER_SIZE
     }
 
     pub fn update(&self, mmap: &mut MmapMut) -> Result<(), Box<dyn Error>> {
@@ -27,15 +61,300 @@ impl Header {
 }
 
 impl Backend {
-    pub fn initialize_header(mapped_file: &mut MmapMut) -> Result<Header, Box<dyn Error>> {
+    /// read the header of the file, or set up the one of a new file. That
+    /// one gets written along with the first write, so just opening a file
+    /// never changes it.
+    pub fn initialize_header(
+        mapped_file: &MmapMut,
+        clock: &dyn Clock,
+    ) -> Result<Header, Box<dyn Error>> {
         let end = Header::size();
+        if mapped_file.len() < end {
+            let actual = mapped_file.len();
+            return Err(WiredError::Truncated {
+                expected: end,
+                actual,
+            }
+            .into());
+        }
         let range = RangeTo { end };
         let bytes = &mapped_file[range];
         let mut header: Header = bincode::deserialize_from(bytes)?;
-        if header.version == 0 {
-            header.version = 1;
-            header.update(mapped_file)?;
+        if header.format_version == 0 {
+            header.format_version = format::CURRENT_VERSION as u16;
+            header.created_at = unix_seconds(clock);
+            header.modified_at = header.created_at;
+            header.region_size = REGION_SIZE;
+        }
+        let flags = format::unsupported_flags(header.feature_flags, format::known_flags());
+        if flags != 0 {
+            return Err(WiredError::UnsupportedFeatures { flags }.into());
         }
         Ok(header)
     }
+
+    /// record a modification, but write the header only when the stored
+    /// time changes, which happens at most once per second
+    pub fn touch(&mut self) -> Result<(), Box<dyn Error>> {
+        let now = unix_seconds(self.clock.as_ref());
+        if now > self.header.modified_at {
+            self.header.modified_at = now;
+            self.write_header()?;
+        }
+        Ok(())
+    }
+
+    /// write the header into the mapping, to be flushed with the next flush
+    pub fn write_header(&mut self) -> Result<(), Box<dyn Error>> {
+        self.header.update(&mut self.mapped_file)?;
+        self.dirty.add(0..Header::size());
+        Ok(())
+    }
+
+    /// the header as currently stored in the mapping, including changes
+    /// of other handles to the same file. `None` for a new file until the
+    /// first write.
+    fn stored_header(&self) -> Option<Header> {
+        bincode::deserialize(&self.mapped_file[..Header::size()])
+            .ok()
+            .filter(|header: &Header| header.format_version != 0)
+    }
+
+    /// the position of the metadata record, as stored by any handle
+    pub fn meta_position(&self) -> usize {
+        self.stored_header()
+            .map_or(self.header.meta_position, |header| header.meta_position)
+    }
+
+    /// whether the file uses the feature
+    pub fn has_feature(&self, feature: Feature) -> bool {
+        self.header.feature_flags & feature.flag() != 0
+    }
+
+    /// record that the file uses the feature from now on
+    #[allow(dead_code)] // until a feature can be turned on for existing files
+    pub fn enable_feature(&mut self, feature: Feature) -> Result<(), Box<dyn Error>> {
+        if !self.has_feature(feature) {
+            self.header.feature_flags |= feature.flag();
+            self.write_header()?;
+        }
+        Ok(())
+    }
+
+    pub fn set_meta_position(&mut self, position: usize) -> Result<(), Box<dyn Error>> {
+        self.header.meta_position = position;
+        self.write_header()
+    }
+
+    pub fn created_at(&self) -> Option<SystemTime> {
+        let seconds = self
+            .stored_header()
+            .map_or(self.header.created_at, |header| header.created_at);
+        to_system_time(seconds)
+    }
+
+    pub fn modified_at(&self) -> Option<SystemTime> {
+        let seconds = self
+            .stored_header()
+            .map_or(self.header.modified_at, |header| header.modified_at);
+        to_system_time(seconds)
+    }
+}
+
+// the header stores whole seconds
+fn unix_seconds(clock: &dyn Clock) -> u64 {
+    clock.now() / 1000
+}
+
+fn to_system_time(seconds: u64) -> Option<SystemTime> {
+    match seconds {
+        0 => None,
+        seconds => Some(UNIX_EPOCH + Duration::from_secs(seconds)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::options::Options;
+    use std::fs::File;
+    use std::io::{Read, Seek, SeekFrom};
+
+    #[test]
+    fn timestamps() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file.try_clone().unwrap(), &Options::default())
+            .expect("could not create mmap");
+        let created_at = backend.created_at().expect("should be set");
+        assert!(created_at <= SystemTime::now());
+        assert_eq!(backend.modified_at(), Some(created_at));
+
+        // pretend the last write happened long ago
+        backend.header.modified_at = 1000;
+        backend.header.update(&mut backend.mapped_file).unwrap();
+        let position = backend.create(b"hello").expect("could not create");
+        let modified_at = backend.modified_at().expect("should be set");
+        assert!(modified_at >= created_at);
+
+        // reads change nothing
+        backend.header.modified_at = 1000;
+        backend.header.update(&mut backend.mapped_file).unwrap();
+        backend.read(position).expect("could not read");
+        let old = Some(UNIX_EPOCH + Duration::from_secs(1000));
+        assert_eq!(backend.modified_at(), old);
+
+        // every kind of write updates it
+        for write in 0..3 {
+            backend.header.modified_at = 1000;
+            backend.header.update(&mut backend.mapped_file).unwrap();
+            match write {
+                0 => backend
+                    .update(position, b"world")
+                    .expect("could not update"),
+                1 => backend.patch(position, 0, b"W").expect("could not patch"),
+                _ => backend.delete(position).expect("could not delete"),
+            }
+            assert!(backend.modified_at() > old);
+        }
+        let modified_at = backend.modified_at();
+
+        // both survive a reopen
+        drop(backend);
+        let backend = Backend::new(file, &Options::default()).expect("could not open mmap");
+        assert_eq!(backend.created_at(), Some(created_at));
+        assert_eq!(backend.modified_at(), modified_at);
+    }
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new(5_000_000);
+        let options = Options::new().clock(clock.clone());
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &options).expect("could not create mmap");
+        let at = |seconds| Some(UNIX_EPOCH + Duration::from_secs(seconds));
+        assert_eq!(backend.created_at(), at(5000));
+        assert_eq!(backend.modified_at(), at(5000));
+
+        // a millisecond short of the next second keeps the stored time
+        clock.set(5_000_999);
+        let position = backend.create(b"hello").expect("could not create");
+        assert_eq!(backend.modified_at(), at(5000));
+
+        // exactly at the next second it moves on
+        clock.advance(Duration::from_millis(1));
+        backend
+            .update(position, b"world")
+            .expect("could not update");
+        assert_eq!(backend.modified_at(), at(5001));
+
+        // a clock turned back never moves the time backwards
+        clock.set(1_000);
+        backend.delete(position).expect("could not delete");
+        assert_eq!(backend.modified_at(), at(5001));
+        assert_eq!(backend.created_at(), at(5000));
+    }
+
+    #[test]
+    fn opening_changes_nothing() {
+        // a file of zeros, as left behind by a preallocation
+        let mut file = tempfile::tempfile().expect("could not create tempfile");
+        file.set_len(8 * REGION_SIZE as u64).unwrap();
+        let bytes = |file: &mut File| {
+            let mut bytes = vec![];
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut bytes).unwrap();
+            bytes
+        };
+        let before = bytes(&mut file);
+
+        let mut backend = Backend::new(file.try_clone().unwrap(), &Options::default())
+            .expect("could not open mmap");
+        assert!(backend.is_empty());
+        assert!(backend.created_at().is_some());
+        backend.lock_exclusive().unwrap();
+        backend.unlock().unwrap();
+        backend.flush().unwrap();
+        drop(backend);
+        assert_eq!(bytes(&mut file), before);
+
+        // the first write stores the header as well
+        let mut backend = Backend::new(file.try_clone().unwrap(), &Options::default())
+            .expect("could not open mmap");
+        let position = backend.create(b"hello").unwrap();
+        backend.flush().unwrap();
+        drop(backend);
+        let header: Header = bincode::deserialize(&bytes(&mut file)).unwrap();
+        assert_eq!(header.format_version, format::CURRENT_VERSION as u16);
+        assert_eq!(header.frame_count, 1);
+        let backend = Backend::new(file, &Options::default()).expect("could not open mmap");
+        assert_eq!(backend.read(position).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn feature_flags() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file.try_clone().unwrap(), &Options::default())
+            .expect("could not create mmap");
+        assert_eq!(backend.header.feature_flags, 0);
+        let feature = Feature::optional(3);
+        assert!(!backend.has_feature(feature));
+        backend.enable_feature(feature).unwrap();
+        assert!(backend.has_feature(feature));
+        backend.flush().unwrap();
+
+        // survives a reopen, although this crate does not know the feature
+        drop(backend);
+        let mut backend = Backend::new(file.try_clone().unwrap(), &Options::default())
+            .expect("could not open mmap");
+        assert!(backend.has_feature(feature));
+        assert!(!backend.has_feature(Feature::required(3)));
+
+        // an unknown required feature can not be ignored
+        let required = Feature::required(3);
+        backend.enable_feature(required).unwrap();
+        backend.flush().unwrap();
+        drop(backend);
+        let error = Backend::new(file, &Options::default()).err().unwrap();
+        let expected = WiredError::UnsupportedFeatures {
+            flags: required.flag(),
+        };
+        assert_eq!(error.downcast_ref::<WiredError>(), Some(&expected));
+    }
+
+    #[test]
+    fn serialized_size() {
+        let header = Header {
+            frame_count: usize::MAX,
+            first_free_class_frames: [usize::MAX; 2],
+            ..Header::default()
+        };
+        let size = bincode::serialized_size(&header).unwrap();
+        assert_eq!(size, Header::size() as u64);
+    }
+
+    #[test]
+    fn reserved_region() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create mmap");
+        assert!(Header::size() < REGION_SIZE);
+        assert_eq!(backend.create(b"hello").unwrap(), REGION_SIZE);
+
+        // the stored size wins over the one of new files
+        let mut file = tempfile::tempfile().expect("could not create tempfile");
+        let header = Header {
+            format_version: format::CURRENT_VERSION as u16,
+            created_at: 1000,
+            modified_at: 1000,
+            region_size: 2 * REGION_SIZE,
+            ..Header::default()
+        };
+        file.write_all(&bincode::serialize(&header).unwrap())
+            .unwrap();
+        file.set_len(4 * REGION_SIZE as u64).unwrap();
+        let mut backend = Backend::new(file, &Options::default()).expect("could not open mmap");
+        let position = backend.create(b"hello").unwrap();
+        assert_eq!(position, 2 * REGION_SIZE);
+        assert_eq!(backend.read(position).unwrap(), b"hello");
+    }
 }
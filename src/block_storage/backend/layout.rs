@@ -0,0 +1,219 @@
+use super::frames::Frame;
+use crate::error::WiredError;
+use std::error::Error;
+
+/// How the frames of a file are sized.
+///
+/// Positions and record indices count in units of `unit` bytes. A file
+/// without `format::SIZE_CLASSES` has a single class of one unit, which is
+/// exactly the frame of `Frame::total_size`. With it, a unit is 256 bytes
+/// and frames come in three classes of 256 bytes, 4KB and 64KB. The bytes
+/// of a record go into the smallest class holding them, unless a chain of
+/// frames of the class below takes less space, and whatever does not fit
+/// into the largest class continues in further frames the same way. A
+/// frame keeps its class for its whole life, free frames sit on the free
+/// list of their class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    unit: usize,
+    // the size of every class in units, ascending
+    classes: &'static [usize],
+}
+
+impl Layout {
+    pub const SINGLE: Layout = Layout {
+        unit: Frame::total_size(),
+        classes: &[1],
+    };
+
+    pub const SIZE_CLASSES: Layout = Layout {
+        unit: 256,
+        classes: &[1, 16, 256],
+    };
+
+    pub fn unit(&self) -> usize {
+        self.unit
+    }
+
+    pub fn is_single(&self) -> bool {
+        self.classes.len() == 1
+    }
+
+    pub fn class_count(&self) -> usize {
+        self.classes.len()
+    }
+
+    /// the number of units a frame of the class spans
+    pub fn units(&self, class: u8) -> usize {
+        self.classes[class as usize]
+    }
+
+    /// the bytes a frame of the class spans, including its header
+    pub fn frame_size(&self, class: u8) -> usize {
+        self.units(class) * self.unit
+    }
+
+    /// the bytes of a record a frame of the class holds
+    pub fn capacity(&self, class: u8) -> usize {
+        self.frame_size(class) - Frame::header_size()
+    }
+
+    /// the classes of the frames a new record of the given length gets, in
+    /// the order of its bytes
+    pub fn plan(&self, len: usize) -> Vec<u8> {
+        self.classes_from(self.class_for(len), len).collect()
+    }
+
+    /// like `plan`, but with the class of the first frame given, since an
+    /// updated record keeps its first frame
+    pub fn plan_from(&self, first: u8, len: usize) -> Vec<u8> {
+        self.classes_from(first, len).collect()
+    }
+
+    /// the number of units a new record of the given length spans
+    pub fn units_needed(&self, len: usize) -> usize {
+        if self.is_single() {
+            return len.div_ceil(Frame::capacity()).max(1);
+        }
+        self.classes_from(self.class_for(len), len)
+            .map(|class| self.units(class))
+            .sum()
+    }
+
+    /// the bytes of a record split along a plan, along with the class of
+    /// the frame each part goes into. An empty record still gets its frame.
+    pub fn chunks<'a>(
+        &self,
+        plan: &'a [u8],
+        bytes: &'a [u8],
+    ) -> impl Iterator<Item = (u8, &'a [u8])> + 'a {
+        let layout = *self;
+        let mut rest = bytes;
+        plan.iter().map(move |class| {
+            let (chunk, tail) = rest.split_at(rest.len().min(layout.capacity(*class)));
+            rest = tail;
+            (*class, chunk)
+        })
+    }
+
+    /// the frame as decoded, with the class that files without size classes
+    /// never set. Fails for a class the layout lacks, and for a body that
+    /// does not fit into a frame of its class.
+    pub fn checked(&self, mut frame: Frame, index: usize) -> Result<Frame, Box<dyn Error>> {
+        if self.is_single() {
+            frame.class = 0;
+        } else if frame.class as usize >= self.classes.len() {
+            return Err(WiredError::Corrupted { index }.into());
+        }
+        if frame.body_size > self.capacity(frame.class) {
+            return Err(WiredError::Corrupted { index }.into());
+        }
+        Ok(frame)
+    }
+
+    fn classes_from(&self, first: u8, len: usize) -> impl Iterator<Item = u8> + '_ {
+        let mut next = Some(first);
+        let mut rest = len;
+        std::iter::from_fn(move || {
+            let class = next?;
+            rest = rest.saturating_sub(self.capacity(class));
+            next = (rest > 0).then(|| self.class_for(rest));
+            Some(class)
+        })
+    }
+
+    /// the class of the next frame for the given number of bytes: the
+    /// smallest one holding all of them, unless frames of the class below
+    /// take less space, and the largest one if none holds them
+    fn class_for(&self, len: usize) -> u8 {
+        let last = self.classes.len() - 1;
+        let class = (0..last)
+            .find(|class| len <= self.capacity(*class as u8))
+            .unwrap_or(last) as u8;
+        if class == 0 || len > self.capacity(class) {
+            return class;
+        }
+        let below = class - 1;
+        let chained = len.div_ceil(self.capacity(below)) * self.frame_size(below);
+        if chained < self.frame_size(class) {
+            below
+        } else {
+            class
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_plan() {
+        let layout = Layout::SINGLE;
+        let capacity = Frame::capacity();
+        assert_eq!(layout.plan(0), [0]);
+        assert_eq!(layout.plan(capacity), [0]);
+        assert_eq!(layout.plan(capacity + 1), [0, 0]);
+        assert_eq!(layout.units_needed(3 * capacity + 1), 4);
+        assert_eq!(layout.units_needed(0), 1);
+        assert_eq!(layout.frame_size(0), Frame::total_size());
+        let chunks: Vec<_> = layout.chunks(&[0, 0], &[1; 1000]).collect();
+        assert_eq!(chunks[0], (0, &[1; 992][..]));
+        assert_eq!(chunks[1], (0, &[1; 8][..]));
+    }
+
+    #[test]
+    fn size_class_plan() {
+        let layout = Layout::SIZE_CLASSES;
+        let [small, medium, large] = [0, 1, 2].map(|class| layout.capacity(class));
+        assert_eq!((small, medium, large), (224, 4064, 65504));
+        assert_eq!(layout.plan(0), [0]);
+        assert_eq!(layout.plan(small), [0]);
+        // two small frames take less space than a medium one
+        assert_eq!(layout.plan(small + 1), [0, 0]);
+        assert_eq!(layout.plan(1000), [0; 5]);
+        assert_eq!(layout.plan(4000), [1]);
+        assert_eq!(layout.plan(medium + 1), [1, 0]);
+        assert_eq!(layout.plan(large), [2]);
+        // larger records chain frames, the rest goes into small ones
+        assert_eq!(layout.plan(large + 10), [2, 0]);
+        assert_eq!(layout.plan(3 * large + 1000), [2, 2, 2, 0, 0, 0, 0, 0]);
+        assert_eq!(layout.units_needed(large + 10), 257);
+        // an updated record keeps its first frame
+        assert_eq!(layout.plan_from(0, 5000), [0, 1, 0, 0, 0, 0]);
+        assert_eq!(layout.plan_from(2, 10), [2]);
+        let chunks: Vec<_> = layout.chunks(&[0], &[]).collect();
+        assert_eq!(chunks, [(0, &[][..])]);
+    }
+
+    #[test]
+    fn checked_class() {
+        let frame = Frame {
+            class: 3,
+            ..Frame::default()
+        };
+        assert_eq!(Layout::SINGLE.checked(frame, 7).unwrap().class, 0);
+        let error = Layout::SIZE_CLASSES.checked(frame, 7).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&WiredError::Corrupted { index: 7 })
+        );
+
+        // a body that overflows its frame
+        let frame = Frame {
+            body_size: Layout::SIZE_CLASSES.capacity(1) + 1,
+            class: 1,
+            ..Frame::default()
+        };
+        let error = Layout::SIZE_CLASSES.checked(frame, 7).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&WiredError::Corrupted { index: 7 })
+        );
+        let frame = Frame {
+            body_size: Frame::capacity(),
+            ..Frame::default()
+        };
+        assert_eq!(Layout::SINGLE.checked(frame, 7).unwrap(), frame);
+    }
+}
@@ -0,0 +1,99 @@
+use super::header::Header;
+use super::Backend;
+use fs2::FileExt;
+use memmap2::MmapOptions;
+use std::error::Error;
+use std::fs::File;
+
+impl Backend {
+    /// take an exclusive advisory lock on the file, blocking until other
+    /// handles released theirs, and pick up their changes afterwards. No
+    /// other handle can share an anonymous mapping, so it needs no lock.
+    pub fn lock_exclusive(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        file.lock_exclusive()?;
+        if let Err(error) = self.refresh() {
+            self.unlock()?;
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// take a shared advisory lock on the file, for reads only
+    pub fn lock_shared(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(file) = &self.file {
+            file.lock_shared()?;
+        }
+        Ok(())
+    }
+
+    pub fn unlock(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(file) = &self.file {
+            file.unlock()?;
+        }
+        Ok(())
+    }
+
+    /// a second handle of the file once another handle has grown it beyond
+    /// the mapping, which then misses the frames behind its end until
+    /// `refresh`, or `None` while the mapping still covers the whole file.
+    /// No other handle can grow an anonymous mapping.
+    pub fn outgrown_file(&self) -> Result<Option<File>, Box<dyn Error>> {
+        let Some(file) = &self.file else {
+            return Ok(None);
+        };
+        if file.metadata()?.len() as usize > self.size {
+            return Ok(Some(file.try_clone()?));
+        }
+        Ok(None)
+    }
+
+    /// re-read the header from the mapping, which sees the writes of every
+    /// other handle, and map the file again if another handle has resized it
+    fn refresh(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let size = file.metadata()?.len() as usize;
+        if size != self.size {
+            self.mapped_file = unsafe { MmapOptions::new().len(size).map_mut(file)? };
+            self.size = size;
+        }
+        let header: Header = bincode::deserialize(&self.mapped_file[..Header::size()])?;
+        // a new file has no header until the first write of any handle
+        if header.format_version != 0 {
+            self.header = header;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Options;
+
+    #[test]
+    fn sees_writes_of_other_handles() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut first = Backend::new(file.try_clone().unwrap(), &Options::default())
+            .expect("could not create mmap");
+        let mut second = Backend::new(file, &Options::default()).expect("could not open mmap");
+
+        // grow the file through the first handle
+        let data = (0..10_000).map(|i| i as u8).collect::<Vec<u8>>();
+        let position = first.create(&data).expect("could not create");
+        assert!(first.size > second.size);
+
+        // the second handle allocates behind the frames of the first one
+        second.lock_exclusive().expect("could not lock");
+        assert_eq!(second.size, first.size);
+        let other = second.create(b"hello").expect("could not create");
+        second.unlock().expect("could not unlock");
+        assert!(other > position + data.len());
+        assert_eq!(first.read(position).expect("could not read"), data);
+        assert_eq!(first.read(other).expect("could not read"), b"hello");
+    }
+}
@@ -0,0 +1,472 @@
+use super::frames::Frame;
+use super::Backend;
+use crate::error::WiredError;
+use crate::format;
+use crate::options::Options;
+use crate::progress::ProgressSink;
+use memmap2::Mmap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::ControlFlow;
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+mod v1;
+mod v2;
+
+/// upgrades a file from one format version to the next one
+pub trait Migration {
+    /// the format version this migration upgrades from, to `source_version() + 1`
+    fn source_version(&self) -> usize;
+
+    /// the serialized size of the header of the source version, which the
+    /// frames of `Frame::total_size()` follow
+    fn header_size(&self) -> usize;
+
+    /// the header of a truncated image of the source version, counting only
+    /// the given number of frames that survived. The free list may lead into
+    /// the lost frames, so it gets dropped, see `Backend::clamp_frames`.
+    fn clamp_header(&self, header: &[u8], frame_count: usize) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    /// stream the old image into the empty target file, using the layout of
+    /// the next format version. `progress` receives the number of frames done
+    /// so far and the total number of frames, and may cancel the migration.
+    /// An image shorter than its header or the frames it counts fails with
+    /// `WiredError::Truncated`.
+    fn migrate(
+        &self,
+        source: &[u8],
+        target: &mut File,
+        progress: &mut dyn ProgressSink,
+    ) -> Result<(), Box<dyn Error>>;
+}
+
+/// every migration between released format versions, ordered by version
+const MIGRATIONS: &[&dyn Migration] = &[&v1::HeaderTimestamps, &v2::ReservedHeader];
+
+impl Backend {
+    /// bring the file up to the current format version before it gets mapped
+    pub fn migrate(
+        file: &mut File,
+        path: Option<&Path>,
+        options: &Options,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut progress = |done: u64, total: Option<u64>| {
+            if let Some(callback) = &options.migration_progress {
+                callback(done as usize, total.unwrap_or(done) as usize);
+            }
+            ControlFlow::Continue(())
+        };
+        upgrade(
+            file,
+            path,
+            MIGRATIONS,
+            format::CURRENT_VERSION,
+            options.truncated_tolerant,
+            &mut progress,
+        )
+    }
+}
+
+/// run the chain of migrations until the file has the target version.
+///
+/// every step writes a complete new image into a temporary file, so the
+/// original stays untouched until the very end. When the path of the file is
+/// known, the result atomically replaces it via rename, which is crash-safe.
+/// Otherwise the result gets copied back into the given file handle. A
+/// cancelled migration discards the temporary file and keeps the original.
+///
+/// the length of the file gets checked against its header first, see
+/// `clamped_image`, so a truncated file fails before any migration runs, or
+/// gets migrated with only the frames that survived if `truncated_tolerant`.
+pub fn upgrade(
+    file: &mut File,
+    path: Option<&Path>,
+    migrations: &[&dyn Migration],
+    target: usize,
+    truncated_tolerant: bool,
+    progress: &mut dyn ProgressSink,
+) -> Result<(), Box<dyn Error>> {
+    let mut version = read_version(file)?;
+    if version == 0 || version == target {
+        return Ok(());
+    }
+    let unsupported = WiredError::UnsupportedVersion {
+        version,
+        supported: target,
+    };
+    if version > target {
+        return Err(unsupported.into());
+    }
+
+    let directory = path.and_then(|path| path.parent());
+    let temporary = || match directory {
+        Some(directory) => NamedTempFile::new_in(directory),
+        None => NamedTempFile::new(),
+    };
+    let migration_from = |version: usize| {
+        migrations
+            .iter()
+            .find(|migration| migration.source_version() == version)
+            .ok_or_else(|| unsupported.clone())
+    };
+
+    let mut current: Option<NamedTempFile> = None;
+    let original = unsafe { Mmap::map(&*file)? };
+    if let Some(image) = clamped_image(*migration_from(version)?, &original, truncated_tolerant)? {
+        let mut clamped = temporary()?;
+        clamped.write_all(&image)?;
+        current = Some(clamped);
+    }
+    drop(original);
+
+    while version < target {
+        let migration = migration_from(version)?;
+        let source = match &current {
+            Some(temp) => unsafe { Mmap::map(temp.as_file())? },
+            None => unsafe { Mmap::map(&*file)? },
+        };
+        let mut next = temporary()?;
+        migration.migrate(&source, next.as_file_mut(), progress)?;
+        next.as_file().sync_all()?;
+        current = Some(next);
+        version += 1;
+    }
+
+    let migrated = current.expect("at least one migration ran");
+    match path {
+        Some(path) => {
+            migrated.persist(path)?;
+            *file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)?;
+        }
+        None => {
+            let mut source = migrated.reopen()?;
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            std::io::copy(&mut source, file)?;
+            file.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+/// check an image of the source version of the migration against the length
+/// its header claims, like `Backend::from_mapping` does for the current
+/// version. An image shorter than its header, or one missing frames unless
+/// `truncated_tolerant`, fails with `WiredError::Truncated`. Otherwise, a
+/// truncated image comes back with only the whole frames that survived,
+/// while a complete one needs no copy.
+fn clamped_image(
+    migration: &dyn Migration,
+    source: &[u8],
+    truncated_tolerant: bool,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let header = image_until(source, migration.header_size())?;
+    // the frame count leads the header of every format version
+    let frame_count: u64 = bincode::deserialize(header)?;
+    let expected = (frame_count as usize)
+        .saturating_mul(Frame::total_size())
+        .saturating_add(header.len());
+    if source.len() >= expected {
+        return Ok(None);
+    }
+    if !truncated_tolerant {
+        let actual = source.len();
+        return Err(WiredError::Truncated { expected, actual }.into());
+    }
+    let frames = (source.len() - header.len()) / Frame::total_size();
+    let mut image = migration.clamp_header(header, frames)?;
+    image.extend_from_slice(&source[header.len()..][..frames * Frame::total_size()]);
+    Ok(Some(image))
+}
+
+/// the first bytes of an old image up to `end`, like its header or the
+/// frames its header counts, failing with `WiredError::Truncated` if the
+/// image is shorter, e.g. after an external truncation
+fn image_until(source: &[u8], end: usize) -> Result<&[u8], WiredError> {
+    source.get(..end).ok_or(WiredError::Truncated {
+        expected: end,
+        actual: source.len(),
+    })
+}
+
+/// the format version of a file, `0` for a new or empty file. The version
+/// sits right after the frame count in the header of every format version,
+/// as a `usize` before version 3 and as a `u16` since, which reads the same.
+pub fn read_version(file: &mut File) -> Result<usize, Box<dyn Error>> {
+    // the frame count and the version, both 8 bytes wide on every target
+    const PREFIX: usize = 16;
+    if file.metadata()?.len() < PREFIX as u64 {
+        return Ok(0);
+    }
+    let mut bytes = [0; PREFIX];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut bytes)?;
+    let (_frame_count, version): (u64, u16) = bincode::deserialize(&bytes)?;
+    Ok(version as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::header::{Header, REGION_SIZE};
+    use super::*;
+    use crate::progress;
+
+    // the tests chain made up versions on top of the current one
+    const V: usize = format::CURRENT_VERSION;
+
+    /// flips every body byte, applying it twice restores the original data
+    struct Invert {
+        from: usize,
+    }
+
+    impl Migration for Invert {
+        fn source_version(&self) -> usize {
+            self.from
+        }
+
+        fn header_size(&self) -> usize {
+            REGION_SIZE
+        }
+
+        fn clamp_header(
+            &self,
+            region: &[u8],
+            frame_count: usize,
+        ) -> Result<Vec<u8>, Box<dyn Error>> {
+            let mut header: Header = bincode::deserialize(&region[..Header::size()])?;
+            header.frame_count = frame_count;
+            header.first_free_frame = 0;
+            header.first_free_class_frames = [0; 2];
+            let mut region = region.to_vec();
+            let header_bytes = bincode::serialize(&header)?;
+            region[..header_bytes.len()].copy_from_slice(&header_bytes);
+            Ok(region)
+        }
+
+        fn migrate(
+            &self,
+            source: &[u8],
+            target: &mut File,
+            progress: &mut dyn ProgressSink,
+        ) -> Result<(), Box<dyn Error>> {
+            let mut header: Header = bincode::deserialize(&source[..Header::size()])?;
+            header.format_version = self.from as u16 + 1;
+            let mut region = source[..header.region_size].to_vec();
+            let header_bytes = bincode::serialize(&header)?;
+            region[..header_bytes.len()].copy_from_slice(&header_bytes);
+            target.write_all(&region)?;
+            for index in 0..header.frame_count {
+                let start = header.region_size + index * Frame::total_size();
+                let frame_bytes = &source[start..start + Frame::total_size()];
+                let frame = Frame::decode(frame_bytes)?;
+                let body = &frame_bytes[Frame::header_size()..][..frame.body_size];
+                let mut bytes = frame_bytes.to_vec();
+                for (i, byte) in body.iter().enumerate() {
+                    bytes[Frame::header_size() + i] = !byte;
+                }
+                target.write_all(&bytes)?;
+                progress::checkpoint(progress, index + 1, header.frame_count)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn current_file() -> (File, Vec<(usize, Vec<u8>)>) {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend =
+            Backend::new(file.try_clone().unwrap(), &Options::default()).expect("could not create");
+        let mut records = vec![];
+        for i in 0..5_usize {
+            let data = (0..(i * 700)).map(|j| (i + j) as u8).collect::<Vec<u8>>();
+            records.push((backend.create(&data).expect("could not create"), data));
+        }
+        let (position, _) = records.remove(1);
+        backend.delete(position).expect("could not delete");
+        (file, records)
+    }
+
+    #[test]
+    fn chained_migrations() {
+        let (mut file, records) = current_file();
+        assert_eq!(read_version(&mut file).unwrap(), V);
+
+        let migrations: &[&dyn Migration] = &[&Invert { from: V + 1 }, &Invert { from: V }];
+        let mut reports = 0;
+        let mut progress = |done: u64, total: Option<u64>| {
+            assert!(done <= total.unwrap());
+            reports += 1;
+            ControlFlow::Continue(())
+        };
+        upgrade(&mut file, None, migrations, V + 2, false, &mut progress)
+            .expect("could not migrate");
+        assert!(reports > 0);
+        assert_eq!(read_version(&mut file).unwrap(), V + 2);
+
+        // inverted twice, every record reads back as before
+        let backend = Backend::new(file, &Options::default()).expect("could not open");
+        for (position, data) in records {
+            assert_eq!(backend.read(position).expect("could not read"), data);
+        }
+    }
+
+    #[test]
+    fn atomic_swap_by_path() {
+        let directory = tempfile::tempdir().expect("could not create tempdir");
+        let path = directory.path().join("db.wired");
+        let (mut file, records) = current_file();
+        let mut target = File::create(&path).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        std::io::copy(&mut file, &mut target).unwrap();
+
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let migrations: &[&dyn Migration] = &[&Invert { from: V }];
+        upgrade(
+            &mut file,
+            Some(&path),
+            migrations,
+            V + 1,
+            false,
+            &mut progress::ignore,
+        )
+        .expect("could not migrate");
+        assert_eq!(read_version(&mut file).unwrap(), V + 1);
+
+        // no temporary files are left behind
+        let entries = std::fs::read_dir(directory.path()).unwrap().count();
+        assert_eq!(entries, 1);
+
+        // bodies are inverted once
+        let backend = Backend::new(file, &Options::default()).expect("could not open");
+        let (position, data) = &records[0];
+        let inverted: Vec<u8> = data.iter().map(|byte| !byte).collect();
+        assert_eq!(&backend.read(*position).expect("could not read"), &inverted);
+    }
+
+    #[test]
+    fn unsupported_versions() {
+        // no migration path available, the file stays untouched
+        let (mut file, _) = current_file();
+        let error = upgrade(&mut file, None, &[], V + 1, false, &mut progress::ignore).err();
+        let expected = WiredError::UnsupportedVersion {
+            version: V,
+            supported: V + 1,
+        };
+        let error = error.expect("should fail");
+        assert_eq!(error.downcast_ref::<WiredError>(), Some(&expected));
+        assert_eq!(read_version(&mut file).unwrap(), V);
+
+        // a file written by a newer version
+        file.seek(SeekFrom::Start(8)).unwrap();
+        file.write_all(&(V as u64 + 4).to_le_bytes()).unwrap();
+        let error = upgrade(
+            &mut file,
+            None,
+            &[&Invert { from: V }],
+            V + 1,
+            false,
+            &mut progress::ignore,
+        )
+        .err();
+        let expected = WiredError::UnsupportedVersion {
+            version: V + 4,
+            supported: V + 1,
+        };
+        let error = error.expect("should fail");
+        assert_eq!(error.downcast_ref::<WiredError>(), Some(&expected));
+    }
+
+    #[test]
+    fn cancelled_migration() {
+        let directory = tempfile::tempdir().expect("could not create tempdir");
+        let path = directory.path().join("db.wired");
+        let (mut file, records) = current_file();
+        let mut target = File::create(&path).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        std::io::copy(&mut file, &mut target).unwrap();
+        let original = std::fs::read(&path).unwrap();
+
+        // stop halfway through the frames
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let migrations: &[&dyn Migration] = &[&Invert { from: V }];
+        let mut progress = |done: u64, total: Option<u64>| {
+            if done * 2 >= total.unwrap() {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        };
+        let error = upgrade(
+            &mut file,
+            Some(&path),
+            migrations,
+            V + 1,
+            false,
+            &mut progress,
+        )
+        .expect_err("should be cancelled");
+        assert_eq!(
+            error.downcast_ref::<WiredError>(),
+            Some(&WiredError::Cancelled)
+        );
+
+        // the original is untouched, without temporary files around
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+        let entries = std::fs::read_dir(directory.path()).unwrap().count();
+        assert_eq!(entries, 1);
+        let backend = Backend::new(file, &Options::default()).expect("could not open");
+        let (position, data) = &records[0];
+        assert_eq!(&backend.read(*position).expect("could not read"), data);
+    }
+
+    #[test]
+    fn truncated_source() {
+        let (mut file, _) = current_file();
+        let len = REGION_SIZE + 3 * Frame::total_size() + 100;
+        file.set_len(len as u64).unwrap();
+        let migrations: &[&dyn Migration] = &[&Invert { from: V }];
+
+        // refused before the migration runs, the file stays untouched
+        let error = upgrade(
+            &mut file,
+            None,
+            migrations,
+            V + 1,
+            false,
+            &mut progress::ignore,
+        )
+        .expect_err("should fail");
+        match error.downcast_ref::<WiredError>() {
+            Some(WiredError::Truncated { actual, .. }) => assert_eq!(*actual, len),
+            _ => panic!("unexpected error: {}", error),
+        }
+        assert_eq!(read_version(&mut file).unwrap(), V);
+        assert_eq!(file.metadata().unwrap().len(), len as u64);
+
+        // tolerated, only the whole frames get migrated
+        upgrade(
+            &mut file,
+            None,
+            migrations,
+            V + 1,
+            true,
+            &mut progress::ignore,
+        )
+        .expect("could not migrate");
+        assert_eq!(read_version(&mut file).unwrap(), V + 1);
+        let backend = Backend::new(file, &Options::default()).expect("could not open");
+        assert_eq!(backend.frame_count(), 3);
+    }
+}
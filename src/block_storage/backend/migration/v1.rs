@@ -0,0 +1,82 @@
+use super::v2::{HeaderV2, HEADER_V2_SIZE};
+use super::{image_until, Migration};
+use crate::block_storage::backend::frames::Frame;
+use crate::progress::{self, ProgressSink};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// the serialized size of `HeaderV1`, where every `usize` takes 8 bytes
+/// regardless of the pointer width of the build target
+const HEADER_V1_SIZE: usize = 24;
+
+/// the storage header as written by format version 1
+#[derive(Serialize, Deserialize)]
+struct HeaderV1 {
+    frame_count: usize,
+    version: usize,
+    first_free_frame: usize,
+}
+
+/// version 2 appends timestamps to the storage header, which moves every
+/// frame back by the size of the new fields. Frame positions and pointers
+/// are absolute, so they get shifted as well. The timestamps of migrated
+/// files stay unknown.
+pub struct HeaderTimestamps;
+
+impl Migration for HeaderTimestamps {
+    fn source_version(&self) -> usize {
+        1
+    }
+
+    fn header_size(&self) -> usize {
+        HEADER_V1_SIZE
+    }
+
+    fn clamp_header(&self, header: &[u8], frame_count: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let header = HeaderV1 {
+            frame_count,
+            first_free_frame: 0,
+            ..bincode::deserialize(header)?
+        };
+        Ok(bincode::serialize(&header)?)
+    }
+
+    fn migrate(
+        &self,
+        source: &[u8],
+        target: &mut File,
+        progress: &mut dyn ProgressSink,
+    ) -> Result<(), Box<dyn Error>> {
+        let old_offset = HEADER_V1_SIZE;
+        let shift = |position: usize| match position {
+            0 => 0,
+            position => position - old_offset + HEADER_V2_SIZE,
+        };
+
+        let old: HeaderV1 = bincode::deserialize(image_until(source, old_offset)?)?;
+        let end = old_offset.saturating_add(old.frame_count.saturating_mul(Frame::total_size()));
+        let frames = &image_until(source, end)?[old_offset..];
+        let header = HeaderV2 {
+            frame_count: old.frame_count,
+            version: 2,
+            first_free_frame: shift(old.first_free_frame),
+            ..HeaderV2::default()
+        };
+        let mut target = BufWriter::new(target);
+        target.write_all(&bincode::serialize(&header)?)?;
+        for (index, bytes) in frames.chunks_exact(Frame::total_size()).enumerate() {
+            let mut bytes = bytes.to_vec();
+            let mut frame = Frame::decode(&bytes)?;
+            frame.position = shift(frame.position);
+            frame.next = shift(frame.next);
+            let frame_bytes = frame.encode()?;
+            bytes[..frame_bytes.len()].copy_from_slice(&frame_bytes);
+            target.write_all(&bytes)?;
+            progress::checkpoint(progress, index + 1, old.frame_count)?;
+        }
+        target.flush()?;
+        Ok(())
+    }
+}
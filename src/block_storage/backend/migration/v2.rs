@@ -0,0 +1,91 @@
+use super::{image_until, Migration};
+use crate::block_storage::backend::frames::Frame;
+use crate::block_storage::backend::header::{Header, REGION_SIZE};
+use crate::progress::{self, ProgressSink};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// the serialized size of `HeaderV2`, where every `usize` takes 8 bytes
+/// regardless of the pointer width of the build target
+pub const HEADER_V2_SIZE: usize = 48;
+
+/// the storage header as written by format version 2
+#[derive(Serialize, Deserialize, Default)]
+pub struct HeaderV2 {
+    pub frame_count: usize,
+    pub version: usize,
+    pub first_free_frame: usize,
+    pub created_at: u64,
+    pub modified_at: u64,
+    pub compacted_at: u64,
+}
+
+/// version 3 reserves a fixed region for the storage header instead of
+/// placing the first frame right behind it, so the header can grow without
+/// moving frames again. Frame positions and pointers are absolute, so they
+/// get shifted once more.
+pub struct ReservedHeader;
+
+impl Migration for ReservedHeader {
+    fn source_version(&self) -> usize {
+        2
+    }
+
+    fn header_size(&self) -> usize {
+        HEADER_V2_SIZE
+    }
+
+    fn clamp_header(&self, header: &[u8], frame_count: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let header = HeaderV2 {
+            frame_count,
+            first_free_frame: 0,
+            ..bincode::deserialize(header)?
+        };
+        Ok(bincode::serialize(&header)?)
+    }
+
+    fn migrate(
+        &self,
+        source: &[u8],
+        target: &mut File,
+        progress: &mut dyn ProgressSink,
+    ) -> Result<(), Box<dyn Error>> {
+        let old_offset = HEADER_V2_SIZE;
+        let shift = |position: usize| match position {
+            0 => 0,
+            position => position - old_offset + REGION_SIZE,
+        };
+
+        let old: HeaderV2 = bincode::deserialize(image_until(source, old_offset)?)?;
+        let end = old_offset.saturating_add(old.frame_count.saturating_mul(Frame::total_size()));
+        let frames = &image_until(source, end)?[old_offset..];
+        let header = Header {
+            frame_count: old.frame_count,
+            format_version: 3,
+            first_free_frame: shift(old.first_free_frame),
+            created_at: old.created_at,
+            modified_at: old.modified_at,
+            compacted_at: old.compacted_at,
+            region_size: REGION_SIZE,
+            ..Header::default()
+        };
+        let mut region = bincode::serialize(&header)?;
+        region.resize(REGION_SIZE, 0);
+        let mut target = BufWriter::new(target);
+        target.write_all(&region)?;
+        for (index, bytes) in frames.chunks_exact(Frame::total_size()).enumerate() {
+            let mut bytes = bytes.to_vec();
+            let mut frame = Frame::decode(&bytes)?;
+            frame.position = shift(frame.position);
+            frame.next = shift(frame.next);
+            let frame_bytes = frame.encode()?;
+            bytes[..frame_bytes.len()].copy_from_slice(&frame_bytes);
+            target.write_all(&bytes)?;
+            progress::checkpoint(progress, index + 1, old.frame_count)?;
+        }
+        target.flush()?;
+        Ok(())
+    }
+}
@@ -1,58 +1,199 @@
 mod allocation;
+mod dirty;
 mod file_mapping;
 mod frames;
 mod header;
+mod layout;
+mod locking;
+mod migration;
+mod scan;
 
+use super::Stats;
+use crate::clock::Clock;
+use crate::error::WiredError;
+use crate::format;
+use crate::options::Options;
+use dirty::DirtyRanges;
+use frames::FrameState;
+use layout::Layout;
 use memmap2::MmapMut;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
 use std::fs::File;
+use std::sync::Arc;
 
 pub struct Backend {
     size: usize,
     mapped_file: MmapMut,
-    file: File,
+    // `None` for an anonymous mapping, whose memory no file backs
+    file: Option<File>,
     header: header::Header,
+    // the sizes of the frames, fixed when the file gets created
+    layout: Layout,
+    stats: Stats,
+    // counted separately, since reading only borrows the backend
+    reads: Cell<usize>,
+    borrowed_reads: Cell<usize>,
+    // written since the last flush, which flushes only these
+    dirty: DirtyRanges,
+    // the size of the file when its metadata was synced the last time
+    synced_size: usize,
+    // the source of the timestamps within the header
+    clock: Arc<dyn Clock>,
+    // the file can not grow beyond this size, to simulate a full disk
+    #[cfg(test)]
+    quota: Option<usize>,
+    // every range handed to msync, to check what a flush covers
+    #[cfg(test)]
+    flushed: Vec<std::ops::Range<usize>>,
+    // write every record frame by frame, to compare with the fast path
+    #[cfg(test)]
+    chunked_writes_only: bool,
 }
 
 impl Backend {
-    pub fn new(file: File) -> Result<Self, Box<dyn Error>> {
-        let (size, mut mapped_file) = Self::open_file(&file)?;
-        let header = Self::initialize_header(&mut mapped_file)?;
-        let backend = Self {
+    pub fn new(file: File, options: &Options) -> Result<Self, Box<dyn Error>> {
+        let is_new_file = file.metadata()?.len() == 0;
+        let (size, mapped_file) = Self::open_file(&file, options)?;
+        Self::from_mapping(Some(file), size, mapped_file, is_new_file, options)
+    }
+
+    /// a backend over anonymous memory of at least the given size instead of
+    /// a file. Pages get committed once written to, nothing ever reaches the
+    /// disk and the memory is released on drop.
+    pub fn anonymous(size: usize, options: &Options) -> Result<Self, Box<dyn Error>> {
+        let (size, mapped_file) = Self::map_anonymous(size)?;
+        Self::from_mapping(None, size, mapped_file, true, options)
+    }
+
+    fn from_mapping(
+        file: Option<File>,
+        size: usize,
+        mapped_file: MmapMut,
+        is_new_file: bool,
+        options: &Options,
+    ) -> Result<Self, Box<dyn Error>> {
+        let clock = options.clock_or_default();
+        let header = Self::initialize_header(&mapped_file, clock.as_ref())?;
+        // a new header gets written and flushed along with the first write
+        let mut dirty = DirtyRanges::default();
+        dirty.add(0..header::Header::size());
+        let mut backend = Self {
             header,
+            layout: Layout::SINGLE,
             file,
             mapped_file,
             size,
+            stats: Stats::default(),
+            reads: Cell::new(0),
+            borrowed_reads: Cell::new(0),
+            dirty,
+            synced_size: 0,
+            clock,
+            #[cfg(test)]
+            quota: None,
+            #[cfg(test)]
+            flushed: vec![],
+            #[cfg(test)]
+            chunked_writes_only: false,
         };
+        // set along with the rest of the new header, just like its version
+        if is_new_file && options.size_classes {
+            backend.header.feature_flags |= format::SIZE_CLASSES.flag();
+        }
+        if backend.has_feature(format::SIZE_CLASSES) {
+            backend.layout = Layout::SIZE_CLASSES;
+        }
+        let expected = backend.offset() + backend.header.frame_count * backend.unit();
+        if size < expected && options.truncated_tolerant {
+            backend.clamp_frames()?;
+        } else if size < expected {
+            let actual = size;
+            return Err(WiredError::Truncated { expected, actual }.into());
+        }
+        if is_new_file && options.preallocate {
+            backend.preallocate_frames()?;
+        }
         Ok(backend)
     }
 
+    /// count only the whole frames left in a truncated file. The free lists
+    /// may lead into the lost frames, so they get dropped, which leaves
+    /// their surviving frames unused, just like a metadata record that
+    /// starts behind the last frame. The header gets written into the
+    /// mapping right away, so locking the file does not read the old one.
+    fn clamp_frames(&mut self) -> Result<(), Box<dyn Error>> {
+        let frames = self.size.saturating_sub(self.offset()) / self.unit();
+        self.header.frame_count = frames;
+        self.header.first_free_frame = 0;
+        self.header.first_free_class_frames = [0; 2];
+        if self.header.meta_position >= self.offset() + frames * self.unit() {
+            self.header.meta_position = 0;
+        }
+        self.write_header()
+    }
+
     /// runtime: O(n)
     pub fn create(&mut self, bytes: &[u8]) -> Result<usize, Box<dyn Error>> {
-        let start = self.next_free_frame()?;
-        self.write_bytes_starting_at(start, bytes)?;
+        let plan = self.layout.plan(bytes.len());
+        self.reserve_frames(&plan)?;
+        let start = self.next_free_frame(plan[0])?;
+        self.write_bytes_starting_at(start, &plan, bytes)?;
+        self.touch()?;
         self.flush()?;
         Ok(start)
     }
 
+    /// the number of frames a record of the given length occupies, counted
+    /// in units of the smallest frame with size classes
+    pub fn frames_needed(&self, len: usize) -> usize {
+        self.layout.units_needed(len)
+    }
+
+    /// make sure records of the given total length fit without growing the
+    /// file, see `reserve_frames`
+    pub fn reserve_bytes(&mut self, len: usize) -> Result<(), Box<dyn Error>> {
+        self.reserve_frames(&self.layout.plan(len))
+    }
+
     fn write_bytes_starting_at(
         &mut self,
         start: usize,
+        plan: &[u8],
         bytes: &[u8],
     ) -> Result<(), Box<dyn Error>> {
+        let layout = self.layout;
+        #[cfg(test)]
+        let fast_path = !self.chunked_writes_only;
+        #[cfg(not(test))]
+        let fast_path = true;
+        // an empty record still occupies its frame, like with `append_at`
+        if bytes.is_empty() {
+            let position = self.unlink_free_frame(start, plan[0])?;
+            return self.write_single_frame(position, plan[0], bytes);
+        }
+        // most records fit into a single frame
+        if fast_path && plan.len() == 1 {
+            let position = self.unlink_free_frame(start, plan[0])?;
+            self.write_single_frame(position, plan[0], bytes)?;
+            self.stats.single_frame_writes += 1;
+            return Ok(());
+        }
+
         // prepare for looping
-        let chunk_size = frames::Frame::capacity();
         let mut last_frame_position: Option<usize> = None;
-        for (index, byte_chunk) in bytes.chunks(chunk_size).enumerate() {
+        for (index, (class, byte_chunk)) in layout.chunks(plan, bytes).enumerate() {
             // use the given position on first iteration
             let position = if index == 0 {
-                self.unlink_free_frame(start)?
+                self.unlink_free_frame(start, class)?
             } else {
-                self.next_free_frame()?
+                self.next_free_frame(class)?
             };
 
             // persist the chunk into a frame
-            self.create_frame(position)?;
+            self.create_frame(position, class)?;
             self.write_frame_body(position, byte_chunk)?;
 
             // set the "next" pointer of the last frame to this frame
@@ -68,23 +209,230 @@ impl Backend {
 
     /// runtime: O(1)
     pub fn read(&self, position: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        #[cfg(feature = "tracing")]
+        let started = std::time::Instant::now();
         let mut bytes: Vec<u8> = vec![];
         let mut cursor: usize = position;
+        #[cfg(feature = "tracing")]
+        let mut frames = 0;
         while cursor != 0 {
             let frame = self.read_frame(cursor)?;
-            if frame.deleted == false {
+            if frame.state == FrameState::Live {
                 let body = self.read_frame_body(cursor)?;
                 bytes.extend_from_slice(body);
             }
             cursor = frame.next;
+            #[cfg(feature = "tracing")]
+            {
+                frames += 1;
+            }
+        }
+        self.reads.set(self.reads.get() + 1);
+        #[cfg(feature = "tracing")]
+        if frames > 1 {
+            tracing::trace!(
+                bytes = bytes.len(),
+                frames,
+                duration_us = started.elapsed().as_micros() as u64,
+                "multi-frame read"
+            );
         }
         Ok(bytes)
     }
 
+    /// like `read`, but a record within a single frame is borrowed from the
+    /// mapping instead of copied
+    ///
+    /// runtime: O(1)
+    pub fn read_borrowed(&self, position: usize) -> Result<Cow<'_, [u8]>, Box<dyn Error>> {
+        let frame = self.read_frame(position)?;
+        if frame.state != FrameState::Live || frame.next != 0 {
+            return Ok(Cow::Owned(self.read(position)?));
+        }
+        self.reads.set(self.reads.get() + 1);
+        self.borrowed_reads.set(self.borrowed_reads.get() + 1);
+        Ok(Cow::Borrowed(self.read_frame_body(position)?))
+    }
+
+    /// the record one frame body at a time, so the whole record never has
+    /// to be held in memory at once. Stops after the first error.
+    ///
+    /// runtime: O(1) per chunk
+    pub fn read_chunked(
+        &self,
+        position: usize,
+    ) -> impl Iterator<Item = Result<Vec<u8>, Box<dyn Error>>> + '_ {
+        self.reads.set(self.reads.get() + 1);
+        let mut cursor: usize = position;
+        std::iter::from_fn(move || {
+            while cursor != 0 {
+                let frame = match self.read_frame(cursor) {
+                    Ok(frame) => frame,
+                    Err(error) => {
+                        cursor = 0;
+                        return Some(Err(error));
+                    }
+                };
+                let current = cursor;
+                cursor = frame.next;
+                if frame.state == FrameState::Live {
+                    let body = self.read_frame_body(current).map(<[u8]>::to_vec);
+                    if body.is_err() {
+                        cursor = 0;
+                    }
+                    return Some(body);
+                }
+            }
+            None
+        })
+    }
+
+    /// the length of a record, from the frame headers only
+    ///
+    /// runtime: O(n) in the number of frames of the record
+    pub fn record_size(&self, position: usize) -> Result<usize, Box<dyn Error>> {
+        Ok(self.record_extent(position)?.0)
+    }
+
+    /// the length of a record and the number of frames it spans, counted
+    /// like `frames_needed`, from the frame headers only
+    ///
+    /// runtime: O(n) in the number of frames of the record
+    pub fn record_extent(&self, position: usize) -> Result<(usize, usize), Box<dyn Error>> {
+        let mut size = 0;
+        let mut frames = 0;
+        let mut cursor: usize = position;
+        while cursor != 0 {
+            let frame = self.read_frame(cursor)?;
+            if frame.state == FrameState::Live {
+                size += frame.body_size;
+                frames += self.layout.units(frame.class);
+            }
+            cursor = frame.next;
+        }
+        Ok((size, frames))
+    }
+
+    /// the total length of all records, from the frame headers only
+    ///
+    /// runtime: O(n) in the number of frames of the file
+    pub fn live_bytes(&self) -> Result<usize, Box<dyn Error>> {
+        let mut size = 0;
+        for scanned in self.scan_frames().live_only() {
+            size += scanned?.2.len();
+        }
+        Ok(size)
+    }
+
+    /// the positions of the live frames of a record, in the order of its
+    /// bytes
+    ///
+    /// runtime: O(n) in the number of frames of the record
+    pub fn record_frames(&self, position: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+        let mut frames = vec![];
+        let mut cursor: usize = position;
+        while cursor != 0 {
+            let frame = self.read_frame(cursor)?;
+            if frame.state == FrameState::Live {
+                frames.push(cursor);
+            }
+            cursor = frame.next;
+        }
+        Ok(frames)
+    }
+
+    /// the positions of the first frames of all live records, in the order
+    /// of the file. A live frame is a first frame unless another live frame
+    /// continues with it.
+    ///
+    /// runtime: O(n) in the number of frames of the file
+    pub fn record_heads(&self) -> Result<Vec<usize>, Box<dyn Error>> {
+        let mut live = vec![];
+        let mut continuations = HashSet::new();
+        for scanned in self.scan_frames().live_only() {
+            let (position, frame, _) = scanned?;
+            live.push(position);
+            if frame.next != 0 {
+                continuations.insert(frame.next);
+            }
+        }
+        live.retain(|position| !continuations.contains(position));
+        Ok(live)
+    }
+
+    /// the number of runs of adjacent free frames by their length in frames,
+    /// to tell a few large holes from many tiny ones
+    ///
+    /// runtime: O(n log n) in the number of free frames
+    pub fn free_space_histogram(&self) -> Result<BTreeMap<usize, usize>, Box<dyn Error>> {
+        // the position of every free frame along with the one right behind it
+        let mut free = vec![];
+        for class in 0..self.layout.class_count() as u8 {
+            let mut cursor = self.first_free_frame(class);
+            while cursor != 0 {
+                free.push((cursor, cursor + self.layout.frame_size(class)));
+                cursor = self.read_frame(cursor)?.next;
+            }
+        }
+        free.sort_unstable();
+        let mut histogram = BTreeMap::new();
+        let mut run = 0;
+        for (index, (_, behind)) in free.iter().enumerate() {
+            run += 1;
+            if free.get(index + 1).map(|(next, _)| next) != Some(behind) {
+                *histogram.entry(run).or_insert(0) += 1;
+                run = 0;
+            }
+        }
+        Ok(histogram)
+    }
+
+    /// overwrite a part of an existing record in place, without reallocating
+    /// any frames. The bytes must fit within the current record length.
+    ///
+    /// runtime: O(n) in the number of frames up to the patched range
+    pub fn patch(
+        &mut self,
+        position: usize,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut cursor: usize = position;
+        let mut offset = offset;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            if cursor == 0 {
+                return Err(WiredError::OutOfBounds.into());
+            }
+            let frame = self.read_frame(cursor)?;
+            if frame.state != FrameState::Live {
+                return Err(WiredError::OutOfBounds.into());
+            }
+            if offset < frame.body_size {
+                let length = (frame.body_size - offset).min(bytes.len());
+                let start = cursor + frames::Frame::header_size() + offset;
+                self.mapped_file[start..start + length].copy_from_slice(&bytes[..length]);
+                self.dirty.add(start..start + length);
+                bytes = &bytes[length..];
+                offset = 0;
+            } else {
+                offset -= frame.body_size;
+            }
+            cursor = frame.next;
+        }
+        self.touch()?;
+        self.flush()
+    }
+
     // runtime: O(n) - is delete + create
     pub fn update(&mut self, position: usize, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        // the frames of the old record are not counted, which may grow the
+        // file a bit early, but it never loses the old record
+        let class = self.read_frame(position)?.class;
+        let plan = self.layout.plan_from(class, bytes.len());
+        self.reserve_frames(&plan)?;
         self.delete(position)?;
-        self.write_bytes_starting_at(position, bytes)?;
+        self.write_bytes_starting_at(position, &plan, bytes)?;
         self.flush()?;
         Ok(())
     }
@@ -96,26 +444,79 @@ impl Backend {
             let mut frame = self.read_frame(cursor)?;
             let current = cursor;
             cursor = frame.next;
-            frame.deleted = true;
-            frame.next = self.header.first_free_frame;
-            self.header.first_free_frame = current;
+            frame.state = FrameState::Free;
+            frame.next = self.first_free_frame(frame.class);
+            self.set_first_free_frame(frame.class, current);
             self.update_frame(frame)?;
         }
-        self.header.update(&mut self.mapped_file)?;
+        self.write_header()?;
+        self.touch()?;
         self.flush()?;
         Ok(())
     }
 
-    pub fn offset() -> usize {
-        header::Header::size()
+    /// the file below the mapping, `None` for an anonymous mapping
+    pub fn file(&self) -> Option<&File> {
+        self.file.as_ref()
+    }
+
+    /// the position of the first frame, behind the header region
+    pub fn offset(&self) -> usize {
+        self.header.region_size
     }
 
-    pub fn block_size() -> usize {
-        frames::Frame::total_size()
+    /// the size of a frame, or of the smallest one with size classes, which
+    /// every position is a multiple of
+    pub fn unit(&self) -> usize {
+        self.layout.unit()
     }
 
+    /// true as long as the very first block was never written, which also
+    /// holds for a preallocated file where every frame is still on the free list
     pub fn is_empty(&self) -> bool {
-        self.header.frame_count == 0
+        if self.header.frame_count == 0 {
+            return true;
+        }
+        match self.read_frame(self.offset()) {
+            Ok(frame) => frame.state != FrameState::Live,
+            Err(_) => true,
+        }
+    }
+
+    /// whether a live frame starts at the position, to validate pointers
+    /// that may refer to deleted records after a crash
+    pub fn is_live(&self, position: usize) -> bool {
+        let unit = self.layout.unit();
+        let in_bounds = position >= self.offset()
+            && (position - self.offset()).is_multiple_of(unit)
+            && (position - self.offset()) / unit < self.header.frame_count;
+        // with size classes, a position may point into the body of a frame
+        in_bounds
+            && self.read_frame(position).is_ok_and(|frame| {
+                frame.state == FrameState::Live
+                    && (self.layout.is_single() || frame.position == position)
+            })
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            reads: self.reads.get(),
+            borrowed_reads: self.borrowed_reads.get(),
+            ..self.stats
+        }
+    }
+
+    /// let the file grow no further than the given size, to simulate a
+    /// full disk
+    #[cfg(test)]
+    pub fn set_quota(&mut self, bytes: usize) {
+        self.quota = Some(bytes);
+    }
+
+    #[cfg(test)]
+    pub fn live_frames(&self) -> usize {
+        let live: Result<Vec<_>, _> = self.scan_frames().live_only().collect();
+        live.expect("could not read frame").len()
     }
 }
 
@@ -127,45 +528,87 @@ mod tests {
     fn create() {
         // prepare
         let file = tempfile::tempfile().expect("could not create tempfile");
-        let mut backend = Backend::new(file).expect("could not create mmap");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create mmap");
 
         // insert simple element
         let position = backend.create(b"hello").expect("could not create");
-        assert_eq!(position, 24);
+        assert_eq!(position, 1024);
 
         // confirm by reading back
         let data = backend.read(position).expect("could not read");
         assert_eq!(data, b"hello");
 
         // insert multi-frame element
-        let long_data = (0..1025).map(|_| 1 as u8).collect::<Vec<u8>>();
+        let long_data = (0..1025).map(|_| 1_u8).collect::<Vec<u8>>();
         let position = backend.create(&long_data).expect("could not create");
-        assert_eq!(position, 24 + 1024);
+        assert_eq!(position, 2 * 1024);
 
         // confirm by reading back
         let long_data = backend.read(position).expect("could not read");
         assert_eq!(long_data.len(), 1025);
     }
 
+    #[test]
+    fn read_chunked() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create mmap");
+        let long_data = (0..5000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let position = backend.create(&long_data).expect("could not create");
+
+        let chunks = backend
+            .read_chunked(position)
+            .collect::<Result<Vec<Vec<u8>>, _>>()
+            .expect("could not read");
+        assert_eq!(
+            chunks.len(),
+            long_data.len().div_ceil(frames::Frame::capacity())
+        );
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.len() <= frames::Frame::capacity()));
+        assert_eq!(chunks.concat(), backend.read(position).unwrap());
+
+        // records of a single frame yield exactly one chunk
+        let position = backend.create(b"hello").expect("could not create");
+        let chunks: Vec<_> = backend.read_chunked(position).collect();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].as_ref().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn read_borrowed() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create mmap");
+        let position = backend.create(b"hello").expect("could not create");
+        let bytes = backend.read_borrowed(position).expect("could not read");
+        assert!(matches!(bytes, Cow::Borrowed(b"hello")));
+
+        // records of several frames get copied into one buffer
+        let long_data = (0..5000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let position = backend.create(&long_data).expect("could not create");
+        let bytes = backend.read_borrowed(position).expect("could not read");
+        assert!(matches!(&bytes, Cow::Owned(owned) if *owned == long_data));
+    }
+
     #[test]
     fn update() {
         // prepare
         let file = tempfile::tempfile().expect("could not create tempfile");
-        let mut backend = Backend::new(file).expect("could not create mmap");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create mmap");
 
         // insert multi-frame element
-        let long_data = (0..1025).map(|_| 1 as u8).collect::<Vec<u8>>();
+        let long_data = (0..1025).map(|_| 1_u8).collect::<Vec<u8>>();
         let position = backend.create(&long_data).expect("could not create");
-        assert_eq!(position, 24);
+        assert_eq!(position, 1024);
 
         // confirm by reading back
         let long_data = backend.read(position).expect("could not read");
         assert_eq!(long_data.len(), 1025);
 
         // update with simple element
-        let data = (0..10).map(|_| 1 as u8).collect::<Vec<u8>>();
+        let data = (0..10).map(|_| 1_u8).collect::<Vec<u8>>();
         backend.update(position, &data).expect("could not create");
-        assert_eq!(position, 24);
+        assert_eq!(position, 1024);
 
         // confirm by reading back
         let data = backend.read(position).expect("could not read");
@@ -175,4 +618,566 @@ mod tests {
         let data = backend.read(position + 1024).expect("could not read");
         assert_eq!(data.len(), 0);
     }
+
+    #[test]
+    fn preallocate() {
+        // prepare
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let options = Options::new().initial_bytes(64 * 1024).preallocate(true);
+        let mut backend = Backend::new(file, &options).expect("could not create mmap");
+        let frame_count = backend.header.frame_count;
+        assert_eq!(frame_count, 63);
+        assert!(backend.is_empty());
+
+        // frames are handed out front to back without growing the file
+        let position = backend.create(b"hello").expect("could not create");
+        assert_eq!(position, 1024);
+        assert!(!backend.is_empty());
+        for _ in 1..frame_count {
+            backend.create(b"hello").expect("could not create");
+        }
+        assert_eq!(backend.header.frame_count, frame_count);
+        assert_eq!(backend.stats().resizes, 0);
+
+        // only now the file has to grow
+        backend.create(b"hello").expect("could not create");
+        assert_eq!(backend.header.frame_count, frame_count + 1);
+        assert_eq!(backend.stats().resizes, 1);
+    }
+
+    #[test]
+    fn options_ignored_for_existing_file() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file.try_clone().unwrap(), &Options::default())
+            .expect("could not create mmap");
+        backend.create(b"hello").expect("could not create");
+        let size = backend.size;
+        drop(backend);
+
+        let options = Options::new().initial_bytes(64 * 1024).preallocate(true);
+        let backend = Backend::new(file, &options).expect("could not open mmap");
+        assert_eq!(backend.size, size);
+        assert_eq!(backend.header.frame_count, 1);
+    }
+
+    #[test]
+    fn frame_states() {
+        // prepare
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create mmap");
+        let state = |backend: &Backend, position| backend.read_frame(position).unwrap().state;
+
+        // written frames are live
+        let long_data = (0..1025).map(|_| 1_u8).collect::<Vec<u8>>();
+        let position = backend.create(&long_data).expect("could not create");
+        assert_eq!(state(&backend, position), FrameState::Live);
+        assert_eq!(state(&backend, position + 1024), FrameState::Live);
+
+        // deleted frames are free
+        backend.delete(position).expect("could not delete");
+        assert_eq!(state(&backend, position), FrameState::Free);
+        assert_eq!(state(&backend, position + 1024), FrameState::Free);
+
+        // taking a frame from the free list turns it into a tombstone
+        let taken = backend.next_free_frame(0).expect("could not allocate");
+        assert_eq!(taken, position + 1024);
+        assert_eq!(state(&backend, taken), FrameState::Tombstone);
+        assert_eq!(backend.read(taken).expect("could not read").len(), 0);
+
+        // so does unlinking a frame from the free list
+        backend
+            .unlink_free_frame(position, 0)
+            .expect("could not unlink");
+        assert_eq!(state(&backend, position), FrameState::Tombstone);
+        assert_eq!(backend.header.first_free_frame, 0);
+
+        // and writing into a tombstone makes it live again
+        backend
+            .create_frame(taken, 0)
+            .expect("could not create frame");
+        assert_eq!(state(&backend, taken), FrameState::Live);
+    }
+
+    #[test]
+    fn frame_state_encoding() {
+        let bytes = |state| bincode::serialize(&state).unwrap();
+        assert_eq!(bytes(FrameState::Live), vec![0]);
+        assert_eq!(bytes(FrameState::Free), vec![1]);
+        assert_eq!(bytes(FrameState::Tombstone), vec![2]);
+
+        // compatible with the former boolean flag
+        let state: FrameState = bincode::deserialize(&bincode::serialize(&true).unwrap()).unwrap();
+        assert_eq!(state, FrameState::Free);
+        assert!(bincode::deserialize::<FrameState>(&[3]).is_err());
+    }
+
+    #[test]
+    fn resize_under_load() {
+        // prepare
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file.try_clone().unwrap(), &Options::default())
+            .expect("could not create mmap");
+
+        // enough records to grow the file several times
+        let mut positions = vec![];
+        for i in 0..100_usize {
+            let data = (0..(i * 37)).map(|j| (i + j) as u8).collect::<Vec<u8>>();
+            positions.push(backend.create(&data).expect("could not create"));
+        }
+        assert!(backend.stats().resizes >= 3);
+        assert_eq!(file.metadata().unwrap().len() as usize, backend.size);
+
+        // confirm by reading back, also after reopen
+        drop(backend);
+        let backend = Backend::new(file, &Options::default()).expect("could not open mmap");
+        for (i, position) in positions.into_iter().enumerate() {
+            let data = backend.read(position).expect("could not read");
+            let expected = (0..(i * 37)).map(|j| (i + j) as u8).collect::<Vec<u8>>();
+            assert_eq!(data, expected);
+        }
+    }
+
+    #[test]
+    fn sync_after_growth() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create mmap");
+
+        // the first sync covers the length of the new file
+        backend.sync().expect("could not sync");
+        let flushes = backend.stats().flushes;
+        backend.sync().expect("could not sync");
+        assert_eq!(backend.stats().syncs, 1);
+        assert_eq!(backend.stats().flushes, flushes + 1);
+
+        // only a grown file needs its metadata synced again
+        backend.create(&[1; 5000]).expect("could not create");
+        assert!(backend.stats().resizes > 0);
+        backend.sync().expect("could not sync");
+        assert_eq!(backend.stats().syncs, 2);
+    }
+
+    #[test]
+    fn truncated_file() {
+        // prepare
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file.try_clone().unwrap(), &Options::default())
+            .expect("could not create mmap");
+        for _ in 0..10 {
+            backend.create(b"hello").expect("could not create");
+        }
+        drop(backend);
+
+        // cut off the last frames
+        file.set_len(6 * 1024).unwrap();
+        let error = Backend::new(file.try_clone().unwrap(), &Options::default())
+            .err()
+            .expect("should fail");
+        let error = error.downcast_ref::<WiredError>().expect("should be typed");
+        let expected = 11 * 1024;
+        let actual = 6 * 1024;
+        assert_eq!(error, &WiredError::Truncated { expected, actual });
+
+        // clamped to the frames that survived
+        let options = Options {
+            truncated_tolerant: true,
+            ..Options::default()
+        };
+        let backend = Backend::new(file.try_clone().unwrap(), &options).expect("could not open");
+        assert_eq!(backend.frame_count(), 5);
+        assert_eq!(backend.read(backend.offset() + 4 * 1024).unwrap(), b"hello");
+        let error = backend.read(backend.offset() + 5 * 1024).unwrap_err();
+        let expected = actual + frames::Frame::header_size();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&WiredError::Truncated { expected, actual })
+        );
+        drop(backend);
+
+        // cut within the header, which can not be clamped
+        file.set_len(10).unwrap();
+        let error = Backend::new(file, &options).err().expect("should fail");
+        let expected = header::Header::size();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&WiredError::Truncated {
+                expected,
+                actual: 10
+            })
+        );
+    }
+
+    #[test]
+    fn patch() {
+        // prepare
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create mmap");
+        let data = (0..2000).map(|_| 1_u8).collect::<Vec<u8>>();
+        let position = backend.create(&data).expect("could not create");
+
+        // patch a range crossing the frame boundary
+        let capacity = frames::Frame::capacity();
+        backend
+            .patch(position, capacity - 2, &[7, 7, 7, 7])
+            .expect("could not patch");
+        let patched = backend.read(position).expect("could not read");
+        assert_eq!(patched.len(), 2000);
+        assert_eq!(&patched[capacity - 3..capacity + 3], &[1, 7, 7, 7, 7, 1]);
+
+        // patching beyond the record fails
+        let error = backend
+            .patch(position, 1999, &[7, 7])
+            .expect_err("should fail");
+        assert_eq!(
+            error.downcast_ref::<WiredError>(),
+            Some(&WiredError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn flush_written_ranges() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        // a clock standing still keeps the timestamp in the header unchanged
+        let options = Options::new().clock(crate::clock::ManualClock::new(1_000_000));
+        let mut backend = Backend::new(file, &options).expect("could not create mmap");
+        let header = 0..header::Header::size();
+        let frame_header = frames::Frame::header_size();
+
+        // the frame and then the header that counts it
+        let position = backend.create(b"hello").expect("could not create");
+        let frame = position..position + frame_header + 5;
+        assert_eq!(backend.flushed, [frame, header.clone()]);
+
+        // just the patched bytes
+        backend.flushed.clear();
+        backend.patch(position, 1, b"EL").expect("could not patch");
+        let body = position + frame_header;
+        let patched = body + 1..body + 3;
+        assert_eq!(backend.flushed, [patched]);
+
+        // many scattered frame headers flush everything at once
+        let large = backend.create(&[1; 100_000]).expect("could not create");
+        backend.flushed.clear();
+        backend.delete(large).expect("could not delete");
+        let everything = 0..backend.size;
+        assert_eq!(backend.flushed, [everything]);
+
+        // nothing written, nothing flushed
+        backend.flushed.clear();
+        backend.flush().expect("could not flush");
+        assert!(backend.flushed.is_empty());
+    }
+
+    #[test]
+    fn single_frame_fast_path() {
+        // the same writes with and without the fast path, on a clock
+        // standing still, so the timestamps in the header agree as well
+        let run = |chunked_writes_only: bool| {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let options = Options::new().clock(crate::clock::ManualClock::new(1_000_000));
+            let mut backend = Backend::new(file, &options).expect("could not create mmap");
+            backend.chunked_writes_only = chunked_writes_only;
+            let capacity = frames::Frame::capacity();
+            let mut positions = vec![];
+            for len in [1, 5, capacity - 1, capacity, capacity + 1, 3000, 0] {
+                positions.push(backend.create(&vec![len as u8; len]).unwrap());
+            }
+            backend.delete(positions[1]).unwrap();
+            backend.update(positions[5], b"shrunk").unwrap();
+            backend.update(positions[0], &[7; 2000]).unwrap();
+            positions.push(backend.create(b"reused").unwrap());
+            backend.flush().unwrap();
+            (backend.mapped_file.to_vec(), backend.stats(), positions)
+        };
+        let (fast, stats, positions) = run(false);
+        let (chunked, chunked_stats, chunked_positions) = run(true);
+        assert!(fast == chunked, "the file contents differ");
+        assert_eq!(positions, chunked_positions);
+        assert_eq!(stats.single_frame_writes, 6);
+        assert_eq!(chunked_stats.single_frame_writes, 0);
+    }
+
+    #[test]
+    fn borrowed_reads() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create mmap");
+        let small = backend.create(b"hello").unwrap();
+        let large = backend.create(&[1; 3000]).unwrap();
+        assert!(matches!(
+            backend.read_borrowed(small).unwrap(),
+            Cow::Borrowed(b"hello")
+        ));
+        assert!(matches!(
+            backend.read_borrowed(large).unwrap(),
+            Cow::Owned(_)
+        ));
+        backend.read(small).unwrap();
+        let stats = backend.stats();
+        assert_eq!((stats.reads, stats.borrowed_reads), (3, 1));
+    }
+
+    #[test]
+    fn flush_covers_every_change() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create mmap");
+        let mut positions = vec![];
+        for step in 0..60_usize {
+            let mut before = backend.mapped_file.to_vec();
+            backend.flushed.clear();
+            let data = vec![step as u8; (step * 97) % 3000];
+            match step % 4 {
+                0 | 1 => positions.push(backend.create(&data).expect("could not create")),
+                2 => {
+                    let position = positions[step % positions.len()];
+                    backend.update(position, &data).expect("could not update");
+                }
+                _ => {
+                    let position = positions.remove(step % positions.len());
+                    backend.delete(position).expect("could not delete");
+                }
+            }
+            // a grown file reads as zeros beyond its old size
+            before.resize(backend.mapped_file.len(), 0);
+            for (offset, (old, new)) in before.iter().zip(backend.mapped_file.iter()).enumerate() {
+                if old != new {
+                    assert!(
+                        backend.flushed.iter().any(|range| range.contains(&offset)),
+                        "byte {} changed in step {} without a flush",
+                        offset,
+                        step
+                    );
+                }
+            }
+        }
+    }
+
+    /// check the structural invariants of the frames and the free lists
+    fn verify(backend: &Backend) {
+        let frame_count = backend.header.frame_count;
+        assert!(backend.offset() + frame_count * backend.unit() <= backend.size);
+        let is_frame = |position: usize| {
+            position >= backend.offset()
+                && (position - backend.offset()).is_multiple_of(backend.unit())
+                && (position - backend.offset()) / backend.unit() < frame_count
+        };
+
+        // the free lists hold exactly the free frames of their class,
+        // without cycles
+        let mut on_free_list = 0;
+        for class in 0..backend.layout.class_count() as u8 {
+            let mut cursor = backend.first_free_frame(class);
+            while cursor != 0 {
+                assert!(is_frame(cursor), "free list points outside: {}", cursor);
+                let frame = backend.read_frame(cursor).unwrap();
+                assert_eq!(frame.state, FrameState::Free);
+                assert_eq!(frame.class, class);
+                on_free_list += 1;
+                assert!(on_free_list <= frame_count, "free list has a cycle");
+                cursor = frame.next;
+            }
+        }
+        let mut free = 0;
+        for scanned in backend.scan_frames() {
+            let (_, frame, _) = scanned.unwrap();
+            match frame.state {
+                FrameState::Free => free += 1,
+                FrameState::Live if frame.next != 0 => {
+                    assert!(is_frame(frame.next), "chain points outside: {}", frame.next);
+                    let next = backend.read_frame(frame.next).unwrap();
+                    assert_eq!(next.state, FrameState::Live);
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(free, on_free_list);
+    }
+
+    #[test]
+    fn free_space_histogram() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create");
+        assert!(backend.free_space_histogram().unwrap().is_empty());
+        let positions: Vec<usize> = (0..12)
+            .map(|_| backend.create(b"frame").expect("could not create"))
+            .collect();
+
+        // holes of 1, 3, 1 and 2 frames, deleted out of order
+        for index in [0, 4, 2, 3, 6, 10, 9] {
+            backend.delete(positions[index]).expect("could not delete");
+        }
+        let histogram = backend.free_space_histogram().unwrap();
+        assert_eq!(
+            histogram.into_iter().collect::<Vec<_>>(),
+            [(1, 2), (2, 1), (3, 1)]
+        );
+
+        // a record spanning two frames fills the hole freed last
+        backend.create(&[1; 1500]).expect("could not create");
+        let histogram = backend.free_space_histogram().unwrap();
+        assert_eq!(histogram.into_iter().collect::<Vec<_>>(), [(1, 2), (3, 1)]);
+    }
+
+    #[test]
+    fn compaction() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create");
+        let long_data = vec![3_u8; 2500];
+        let positions: Vec<usize> = (0..6)
+            .map(|_| backend.create(&long_data).expect("could not create"))
+            .collect();
+        let last = backend.create(b"last").expect("could not create");
+        for position in &positions[..5] {
+            backend.delete(*position).expect("could not delete");
+        }
+
+        // copies go into the lowest free frames, in order
+        let copy = backend.copy_to_front(positions[5]).unwrap().unwrap();
+        assert_eq!(copy, backend.offset());
+        assert_eq!(backend.record_frames(copy).unwrap(), [1024, 2048, 3072]);
+        assert_eq!(backend.read(copy).unwrap(), long_data);
+        assert_eq!(backend.read(positions[5]).unwrap(), long_data);
+        backend.delete(positions[5]).expect("could not delete");
+        verify(&backend);
+
+        // nothing to gain for a record already at the front
+        assert_eq!(backend.copy_to_front(copy).unwrap(), None);
+
+        // only the frames behind the last live one get released
+        let moved = backend.copy_to_front(last).unwrap().unwrap();
+        backend.delete(last).expect("could not delete");
+        let size = backend.size;
+        let released = backend.shrink_to_fit().expect("could not shrink");
+        assert_eq!(backend.header.frame_count, 4);
+        assert_eq!(backend.size, backend.offset() + 4 * 1024);
+        assert_eq!(released, size - backend.size);
+        assert_eq!(backend.read(moved).unwrap(), b"last");
+        assert_eq!(backend.shrink_to_fit().unwrap(), 0);
+        verify(&backend);
+
+        // and the file grows again as usual
+        let position = backend.create(&long_data).expect("could not create");
+        assert_eq!(backend.read(position).unwrap(), long_data);
+        verify(&backend);
+    }
+
+    #[test]
+    fn empty_records() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create");
+        let first = backend.create(&[]).expect("could not create");
+        let second = backend.create(b"data").expect("could not create");
+        assert_eq!(backend.read_frame(first).unwrap().position, first);
+        assert_eq!(backend.read(first).unwrap(), Vec::<u8>::new());
+
+        // deleting and reusing the frame keeps the stored header intact
+        backend.delete(first).expect("could not delete");
+        let stored = bincode::serialize(&backend.header).unwrap();
+        assert_eq!(backend.mapped_file[..stored.len()], stored[..]);
+        assert_eq!(backend.create(&[]).expect("could not create"), first);
+        backend.update(second, &[]).expect("could not update");
+        assert_eq!(backend.read(second).unwrap(), Vec::<u8>::new());
+        backend.delete(second).expect("could not delete");
+        verify(&backend);
+    }
+
+    #[test]
+    fn size_classes() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let options = Options::new().size_classes(true);
+        let mut backend = Backend::new(file.try_clone().unwrap(), &options).unwrap();
+        assert_eq!(backend.unit(), 256);
+        let records: Vec<Vec<u8>> = [0, 10, 300, 4000, 5000, 70_000, 10, 150_000]
+            .iter()
+            .map(|len| (0..*len).map(|i| (i % 251) as u8).collect())
+            .collect();
+        let mut positions: Vec<usize> = records
+            .iter()
+            .map(|record| backend.create(record).expect("could not create"))
+            .collect();
+        verify(&backend);
+        // tiny records take a fraction of a single size frame
+        assert_eq!(positions[1] - positions[0], 256);
+        assert_eq!(backend.record_extent(positions[2]).unwrap(), (300, 2));
+        // a position within the body of a larger frame is no record
+        assert!(!backend.is_live(positions[3] + 256));
+
+        // freed frames get reused by records of their class
+        backend.delete(positions[1]).expect("could not delete");
+        backend.delete(positions[3]).expect("could not delete");
+        assert_eq!(backend.create(b"tiny").unwrap(), positions[1]);
+        assert_eq!(backend.create(&records[3]).unwrap(), positions[3]);
+        verify(&backend);
+
+        // an update keeps the first frame, whatever its class
+        backend.update(positions[0], &records[4]).unwrap();
+        assert_eq!(backend.read(positions[0]).unwrap(), records[4]);
+        backend.update(positions[5], b"short").unwrap();
+        assert_eq!(backend.read(positions[5]).unwrap(), b"short");
+        verify(&backend);
+
+        // compaction moves records into free frames of their classes
+        for index in [4, 5, 7] {
+            backend.delete(positions[index]).expect("could not delete");
+        }
+        let savings = backend.compaction_savings().unwrap();
+        let last = positions[6];
+        let copy = backend.copy_to_front(last).unwrap().unwrap();
+        assert!(copy < last);
+        assert_eq!(backend.read(copy).unwrap(), records[6]);
+        backend.delete(last).expect("could not delete");
+        positions[6] = copy;
+        let size = backend.size;
+        let released = backend.shrink_to_fit().unwrap();
+        assert!(released > 0 && released <= savings);
+        assert_eq!(backend.size, size - released);
+        assert!(!backend.free_space_histogram().unwrap().is_empty());
+        verify(&backend);
+
+        // the layout sticks to the file, whatever the options say
+        drop(backend);
+        let backend = Backend::new(file, &Options::default()).unwrap();
+        assert_eq!(backend.unit(), 256);
+        assert_eq!(backend.read(positions[0]).unwrap(), records[4]);
+        assert_eq!(backend.read(positions[1]).unwrap(), b"tiny");
+        for index in [2, 3, 6] {
+            assert_eq!(backend.read(positions[index]).unwrap(), records[index]);
+        }
+        verify(&backend);
+    }
+
+    #[test]
+    fn disk_full() {
+        // records of one up to several frames fill the disk at different steps
+        for frames in 1..=5_usize {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut backend = Backend::new(file, &Options::default()).expect("could not create");
+            backend.quota = Some(4 * backend.size);
+            let data = vec![frames as u8; frames * frames::Frame::capacity()];
+            let mut positions = vec![];
+            let error = loop {
+                match backend.create(&data) {
+                    Ok(position) => positions.push(position),
+                    Err(error) => break error,
+                }
+            };
+            assert_eq!(error.downcast_ref(), Some(&WiredError::DiskFull));
+            verify(&backend);
+            assert!(backend.size <= backend.quota.unwrap());
+
+            // all records are intact, an update that does not fit keeps the old one
+            let bigger = vec![0; (frames + 1) * frames::Frame::capacity()];
+            let error = backend
+                .update(positions[0], &bigger)
+                .expect_err("should fail");
+            assert_eq!(error.downcast_ref(), Some(&WiredError::DiskFull));
+            verify(&backend);
+            for position in positions.iter() {
+                assert_eq!(&backend.read(*position).expect("could not read"), &data);
+            }
+
+            // deleting makes room again
+            backend.delete(positions[0]).expect("could not delete");
+            backend.create(&data).expect("could not create");
+            verify(&backend);
+        }
+    }
 }
@@ -0,0 +1,162 @@
+use super::frames::{Frame, FrameState};
+use super::layout::Layout;
+use super::Backend;
+use crate::error::WiredError;
+use std::error::Error;
+
+/// Walks all frames of the file front to back, straight through the
+/// mapping, instead of looking up every frame by its position.
+///
+/// Every frame yields its position, its decoded header and its body as a
+/// slice of the mapping, so nothing gets allocated per frame. Only the
+/// frames counted in the header get visited, so a tail written beyond them,
+/// e.g. by an interrupted bulk load, is not part of the scan.
+pub struct FrameScanner<'a> {
+    // the whole mapped file
+    bytes: &'a [u8],
+    offset: usize,
+    layout: Layout,
+    frame_count: usize,
+    // the index of the next frame to visit, in units of the layout
+    index: usize,
+    live_only: bool,
+}
+
+impl<'a> FrameScanner<'a> {
+    /// skip free frames and tombstones
+    pub fn live_only(mut self) -> Self {
+        self.live_only = true;
+        self
+    }
+
+    /// the next frame, stepping over it even if its body is corrupted
+    fn next_frame(&mut self) -> Result<(usize, Frame, &'a [u8]), Box<dyn Error>> {
+        let (all, index, layout) = (self.bytes, self.index, self.layout);
+        // until the size of the frame is known
+        self.index += 1;
+        let position = self.offset + index * layout.unit();
+        let truncated = |size: usize| WiredError::Truncated {
+            expected: position + size,
+            actual: all.len(),
+        };
+        let header = all
+            .get(position..position + Frame::header_size())
+            .ok_or_else(|| truncated(layout.unit()))?;
+        let frame = layout.checked(Frame::decode(header)?, index)?;
+        self.index = index + layout.units(frame.class);
+        let size = layout.frame_size(frame.class);
+        let bytes = all
+            .get(position..position + size)
+            .ok_or_else(|| truncated(size))?;
+        let body = bytes
+            .get(Frame::header_size()..Frame::header_size() + frame.body_size)
+            .ok_or(WiredError::Corrupted { index })?;
+        Ok((position, frame, body))
+    }
+}
+
+impl<'a> Iterator for FrameScanner<'a> {
+    type Item = Result<(usize, Frame, &'a [u8]), Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < self.frame_count {
+            let (position, frame, body) = match self.next_frame() {
+                Ok(scanned) => scanned,
+                Err(error) => return Some(Err(error)),
+            };
+            if !self.live_only || frame.state == FrameState::Live {
+                return Some(Ok((position, frame, body)));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.frame_count - self.index))
+    }
+}
+
+impl Backend {
+    /// all frames of the file in the order of their positions, see
+    /// `FrameScanner`
+    ///
+    /// runtime: O(n) in the number of frames of the file
+    pub fn scan_frames(&self) -> FrameScanner<'_> {
+        FrameScanner {
+            bytes: &self.mapped_file,
+            offset: self.offset(),
+            layout: self.layout,
+            frame_count: self.header.frame_count,
+            index: 0,
+            live_only: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Options;
+
+    #[test]
+    fn matches_frame_reads() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create");
+        let mut positions = vec![];
+        for len in [0, 10, Frame::capacity(), 3 * Frame::capacity() + 7, 500] {
+            positions.push(backend.create(&vec![len as u8; len]).unwrap());
+        }
+        // deleted frames in the middle, some of them reused by a chain
+        backend.delete(positions[3]).unwrap();
+        backend.delete(positions[1]).unwrap();
+        backend.create(&vec![7; 2 * Frame::capacity()]).unwrap();
+        // a tail written beyond the counted frames
+        let frame_count = backend.header.frame_count;
+        backend.append_at(frame_count, &[1; 100]).unwrap();
+
+        let scanned: Vec<(usize, Frame, &[u8])> =
+            backend.scan_frames().collect::<Result<_, _>>().unwrap();
+        assert_eq!(scanned.len(), frame_count);
+        for (index, (position, frame, body)) in scanned.iter().enumerate() {
+            assert_eq!(*position, backend.offset() + index * Frame::total_size());
+            assert_eq!(*frame, backend.read_frame(*position).unwrap());
+            assert_eq!(*body, backend.read_frame_body(*position).unwrap());
+        }
+        assert!(scanned
+            .iter()
+            .any(|(_, frame, _)| frame.state != FrameState::Live));
+
+        let live: Vec<usize> = backend
+            .scan_frames()
+            .live_only()
+            .map(|scanned| scanned.unwrap().0)
+            .collect();
+        let expected: Vec<usize> = scanned
+            .iter()
+            .filter(|(_, frame, _)| frame.state == FrameState::Live)
+            .map(|(position, _, _)| *position)
+            .collect();
+        assert_eq!(live, expected);
+        assert_eq!(live.len(), backend.live_frames());
+    }
+
+    #[test]
+    fn corrupted_body_size() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut backend = Backend::new(file, &Options::default()).expect("could not create");
+        backend.create(b"first").unwrap();
+        let position = backend.create(b"second").unwrap();
+        let mut frame = backend.read_frame(position).unwrap();
+        frame.body_size = Frame::total_size();
+        backend.update_frame(frame).unwrap();
+
+        let mut scanner = backend.scan_frames();
+        assert_eq!(scanner.next().unwrap().unwrap().2, b"first");
+        let error = scanner.next().unwrap().expect_err("should fail");
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&WiredError::Corrupted { index: 1 })
+        );
+        assert!(scanner.next().is_none());
+    }
+}
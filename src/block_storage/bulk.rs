@@ -0,0 +1,104 @@
+use super::recorder::Op;
+use super::BlockStorage;
+use std::error::Error;
+
+/// Fills a fresh file with records front to back, for large initial
+/// imports.
+///
+/// Every record goes into the frames right behind the previous one, so no
+/// record consults the free list, updates the header or gets flushed on its
+/// own. `finish` counts all frames in the header and flushes once. Records
+/// get the same indices as if they were created one after another.
+pub struct BulkLoader<'a> {
+    storage: &'a mut BlockStorage,
+    // frames written so far, which is also the index of the next record
+    frame_count: usize,
+}
+
+impl<'a> BulkLoader<'a> {
+    pub(super) fn new(storage: &'a mut BlockStorage) -> Self {
+        Self {
+            storage,
+            frame_count: 0,
+        }
+    }
+
+    /// the number of frames a record of the given length occupies, see
+    /// `BlockStorage::frames_for`
+    pub fn frames_for(&self, len: usize) -> usize {
+        self.storage.frames_for(len)
+    }
+
+    /// the index the next appended record will get
+    pub fn next_index(&self) -> usize {
+        self.frame_count
+    }
+
+    /// grow the file at once for the given number of frames in total, so
+    /// appending never has to grow it step by step
+    pub fn reserve(&mut self, frames: usize) -> Result<(), Box<dyn Error>> {
+        self.storage.backend.grow_to_frames(frames)?;
+        self.storage.record(Op::BulkReserve { frames });
+        Ok(())
+    }
+
+    /// write a record behind all previous ones and return its index
+    pub fn append(&mut self, bytes: &[u8]) -> Result<usize, Box<dyn Error>> {
+        self.storage.inject_fault()?;
+        let index = self.frame_count;
+        self.storage.backend.append_at(index, bytes)?;
+        self.frame_count += self.storage.frames_for(bytes.len());
+        self.storage.record(Op::BulkAppend {
+            index,
+            bytes: bytes.into(),
+        });
+        Ok(index)
+    }
+
+    /// make all appended records part of the file. Without this, the file
+    /// stays empty.
+    pub fn finish(self) -> Result<(), Box<dyn Error>> {
+        self.storage.backend.finish_appending(self.frame_count)?;
+        let frame_count = self.frame_count;
+        self.storage.record(Op::BulkFinish { frame_count });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::WiredError;
+    use crate::options::Options;
+
+    #[test]
+    fn bulk_loader() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut store = BlockStorage::with_options(file.try_clone().unwrap(), &Options::default())
+            .expect("could not create");
+        let large = vec![7; 3000];
+        let mut loader = store.bulk_loader().unwrap();
+        loader.reserve(100).unwrap();
+        assert_eq!(loader.append(b"first").unwrap(), 0);
+        assert_eq!(loader.append(&large).unwrap(), 1);
+        assert_eq!(loader.append(b"").unwrap(), 5);
+        assert_eq!(loader.append(b"last").unwrap(), 6);
+        loader.finish().unwrap();
+        // one flush before growing the file, one when finishing
+        assert_eq!(store.stats().resizes, 1);
+        assert_eq!(store.stats().flushes, 2);
+        drop(store);
+
+        let mut store = BlockStorage::with_options(file, &Options::default()).unwrap();
+        assert_eq!(store.read(0).unwrap(), b"first");
+        assert_eq!(store.read(1).unwrap(), large);
+        assert_eq!(store.read(5).unwrap(), b"");
+        assert_eq!(store.read(6).unwrap(), b"last");
+        assert_eq!(store.live_frames(), 7);
+        // regular writes continue behind the loaded records
+        assert_eq!(store.create(b"next").unwrap(), 7);
+
+        let error = store.bulk_loader().err().unwrap();
+        assert_eq!(error.downcast_ref(), Some(&WiredError::NotEmpty));
+    }
+}
@@ -1,58 +1,494 @@
 mod backend;
+mod bulk;
+mod recorder;
+mod registry;
+mod stats;
 
+use crate::error::WiredError;
+use crate::options::Options;
 use backend::Backend;
+pub use bulk::BulkLoader;
+pub use recorder::{replay, ReplayReport};
+use recorder::{Op, Recorder};
+use registry::Registration;
+pub use stats::Stats;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::time::SystemTime;
 
+/// the largest metadata blob of an application, see `BlockStorage::set_meta`
+pub const MAX_META_BYTES: usize = 64 * 1024;
+
+/// Records addressed by the index of their first frame.
+///
+/// An index stays valid from `create` until `delete` as long as no
+/// compaction runs: `update` rewrites a record starting at its first frame,
+/// so the databases may persist indices, like the disk index does. A
+/// compaction does move records, by writing a copy with `copy_to_front`
+/// that the database then refers to instead of the original, which gives
+/// the record a new index. There is no indirection from stable ids to
+/// positions, so indices handed out to callers break on compaction.
 pub struct BlockStorage {
     backend: Backend,
+    // released on drop, `None` until a container registers its kind
+    registration: Option<Registration>,
+    // appends every write to the log of `Options::record_ops`
+    recorder: Option<Recorder>,
+    // number of writes that still succeed before one fails, for tests
+    #[cfg(test)]
+    writes_until_fault: Option<usize>,
 }
 
 impl BlockStorage {
-    pub fn new(file: File) -> Result<Self, Box<dyn Error>> {
-        let backend = Backend::new(file)?;
-        Ok(Self { backend })
+    pub fn with_options(mut file: File, options: &Options) -> Result<Self, Box<dyn Error>> {
+        Backend::migrate(&mut file, None, options)?;
+        let backend = Backend::new(file, options)?;
+        Self::from_backend(backend, options)
+    }
+
+    /// records in anonymous memory of at least the given size, which grows
+    /// like a file but never touches the disk
+    pub fn anonymous(size: usize, options: &Options) -> Result<Self, Box<dyn Error>> {
+        let backend = Backend::anonymous(size, options)?;
+        Self::from_backend(backend, options)
+    }
+
+    /// open or create the file at the given path, which also allows
+    /// migrations to replace the file atomically
+    pub fn open(path: &Path, options: &Options) -> Result<Self, Box<dyn Error>> {
+        Self::open_file(path, options, true)
+    }
+
+    /// like `open`, but fails with an `io::Error` of kind `NotFound`
+    /// instead of creating a missing file
+    pub fn open_existing(path: &Path, options: &Options) -> Result<Self, Box<dyn Error>> {
+        Self::open_file(path, options, false)
+    }
+
+    /// like `open_existing`, but a file truncated behind its header keeps
+    /// only the frames that still fit into it instead of failing, see
+    /// `Backend::clamp_frames`, or `Migration::clamp_header` for a file of
+    /// an older format version
+    pub fn open_truncated_tolerant(path: &Path, options: &Options) -> Result<Self, Box<dyn Error>> {
+        let options = Options {
+            truncated_tolerant: true,
+            ..options.clone()
+        };
+        Self::open_file(path, &options, false)
+    }
+
+    fn open_file(path: &Path, options: &Options, create: bool) -> Result<Self, Box<dyn Error>> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(false)
+            .open(path)?;
+        Backend::migrate(&mut file, Some(path), options)?;
+        let backend = Backend::new(file, options)?;
+        Self::from_backend(backend, options)
+    }
+
+    fn from_backend(backend: Backend, options: &Options) -> Result<Self, Box<dyn Error>> {
+        let recorder = match &options.record_ops {
+            Some(path) => Some(Recorder::open(path)?),
+            None => None,
+        };
+        Ok(Self {
+            backend,
+            registration: None,
+            recorder,
+            #[cfg(test)]
+            writes_until_fault: None,
+        })
+    }
+
+    /// claim the file for a kind of container within this process, which
+    /// fails while it is open as another kind
+    pub fn register(&mut self, kind: &'static str) -> Result<(), Box<dyn Error>> {
+        // nothing else can open an anonymous mapping
+        if let Some(file) = self.backend.file() {
+            self.registration = Registration::acquire(file, kind)?;
+        }
+        Ok(())
     }
 
     pub fn create(&mut self, bytes: &[u8]) -> Result<usize, Box<dyn Error>> {
+        self.inject_fault()?;
         let position = self.backend.create(bytes)?;
-        let index = position_to_index(position);
+        let index = self.position_to_index(position);
+        self.record(Op::Create {
+            index,
+            bytes: bytes.into(),
+        });
         Ok(index)
     }
 
     pub fn read(&self, index: usize) -> Result<Vec<u8>, Box<dyn Error>> {
-        let position = index_to_position(index);
+        let position = self.index_to_position(index);
         self.backend.read(position)
     }
 
+    /// the record without copying it if it fits into a single frame
+    pub fn read_borrowed(&self, index: usize) -> Result<Cow<'_, [u8]>, Box<dyn Error>> {
+        let position = self.index_to_position(index);
+        self.backend.read_borrowed(position)
+    }
+
     pub fn update(&mut self, index: usize, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
-        let position = index_to_position(index);
-        self.backend.update(position, bytes)
+        self.inject_fault()?;
+        let position = self.index_to_position(index);
+        self.backend.update(position, bytes)?;
+        self.record(Op::Update {
+            index,
+            bytes: bytes.into(),
+        });
+        Ok(())
+    }
+
+    /// the bytes of a record in pieces of at most one frame, to process huge
+    /// records without holding them in memory as a whole
+    pub fn read_chunked(
+        &self,
+        index: usize,
+    ) -> impl Iterator<Item = Result<Vec<u8>, Box<dyn Error>>> + '_ {
+        let position = self.index_to_position(index);
+        self.backend.read_chunked(position)
+    }
+
+    /// the length of a record without reading its bytes
+    pub fn record_size(&self, index: usize) -> Result<usize, Box<dyn Error>> {
+        let position = self.index_to_position(index);
+        self.backend.record_size(position)
+    }
+
+    /// the length of a record and its number of frames without reading it,
+    /// counted like `frames_for`
+    pub fn record_extent(&self, index: usize) -> Result<(usize, usize), Box<dyn Error>> {
+        let position = self.index_to_position(index);
+        self.backend.record_extent(position)
+    }
+
+    /// the number of frames of a record whatever their size, unlike
+    /// `record_extent`
+    pub fn record_frames(&self, index: usize) -> Result<usize, Box<dyn Error>> {
+        let position = self.index_to_position(index);
+        Ok(self.backend.record_frames(position)?.len())
+    }
+
+    /// the total length of all records except the metadata, without
+    /// reading their bytes
+    pub fn live_bytes(&self) -> Result<usize, Box<dyn Error>> {
+        let meta_bytes = match self.backend.meta_position() {
+            0 => 0,
+            position => self.backend.record_size(position)?,
+        };
+        Ok(self.backend.live_bytes()? - meta_bytes)
+    }
+
+    /// the indices of all live records except the metadata, in ascending
+    /// order, from the frame headers only
+    pub fn iter_blocks(&self) -> Result<impl Iterator<Item = usize> + '_, Box<dyn Error>> {
+        let meta_position = self.backend.meta_position();
+        let heads = self.backend.record_heads()?;
+        Ok(heads
+            .into_iter()
+            .filter(move |position| *position != meta_position)
+            .map(move |position| self.position_to_index(position)))
+    }
+
+    /// the indices of all live records including the metadata, the one
+    /// that reaches furthest into the file first, to move them towards the
+    /// front one after the other
+    pub fn records_from_end(&self) -> Result<Vec<usize>, Box<dyn Error>> {
+        let mut records = vec![];
+        for position in self.backend.record_heads()? {
+            let end = self.backend.record_frames(position)?.into_iter().max();
+            records.push((end.unwrap_or(position), self.position_to_index(position)));
+        }
+        records.sort_unstable_by(|a, b| b.cmp(a));
+        Ok(records.into_iter().map(|(_, index)| index).collect())
+    }
+
+    /// the number of runs of adjacent free frames by their length, see
+    /// `Backend::free_space_histogram`
+    pub fn free_space_histogram(&self) -> Result<BTreeMap<usize, usize>, Box<dyn Error>> {
+        self.backend.free_space_histogram()
+    }
+
+    /// whether the record at the index holds the metadata blob
+    pub fn is_meta(&self, index: usize) -> bool {
+        self.backend.meta_position() == self.index_to_position(index)
+    }
+
+    /// write a copy of a record into free frames closer to the front of the
+    /// file, leaving the original as it is. Returns the index of the copy,
+    /// `None` if there is no room for it before the end of the original.
+    pub fn copy_to_front(&mut self, index: usize) -> Result<Option<usize>, Box<dyn Error>> {
+        self.inject_fault()?;
+        let position = self.index_to_position(index);
+        let copy = self.backend.copy_to_front(position)?;
+        let copy = copy.map(|position| self.position_to_index(position));
+        self.record(Op::CopyToFront { index, copy });
+        Ok(copy)
+    }
+
+    /// move the metadata blob into free frames closer to the front of the
+    /// file, returning whether there was room for it
+    pub fn move_meta_to_front(&mut self) -> Result<bool, Box<dyn Error>> {
+        self.inject_fault()?;
+        let position = self.backend.meta_position();
+        let moved = match self.backend.copy_to_front(position)? {
+            Some(copy) => {
+                self.backend.set_meta_position(copy)?;
+                self.backend.delete(position)?;
+                true
+            }
+            None => false,
+        };
+        self.record(Op::MoveMetaToFront { moved });
+        Ok(moved)
+    }
+
+    /// the number of bytes a complete compaction would release
+    pub fn compaction_savings(&self) -> Result<usize, Box<dyn Error>> {
+        self.backend.compaction_savings()
+    }
+
+    /// release the free frames at the end of the file, returning the number
+    /// of bytes the file shrank by
+    pub fn shrink_to_fit(&mut self) -> Result<usize, Box<dyn Error>> {
+        self.inject_fault()?;
+        let released = self.backend.shrink_to_fit()?;
+        self.record(Op::ShrinkToFit);
+        Ok(released)
+    }
+
+    /// the metadata blob of the application, `None` if none was set
+    pub fn meta(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match self.backend.meta_position() {
+            0 => Ok(None),
+            position => Ok(Some(self.backend.read(position)?)),
+        }
+    }
+
+    /// replace the metadata blob of the application. It lives in a record
+    /// of its own that the storage header refers to, apart from the records
+    /// of the container, so the blob works the same for every container.
+    pub fn set_meta(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        if bytes.len() > MAX_META_BYTES {
+            let (len, limit) = (bytes.len(), MAX_META_BYTES);
+            return Err(WiredError::MetaTooLarge { len, limit }.into());
+        }
+        self.inject_fault()?;
+        match self.backend.meta_position() {
+            0 => {
+                let position = self.backend.create(bytes)?;
+                self.backend.set_meta_position(position)?;
+            }
+            position => self.backend.update(position, bytes)?,
+        }
+        self.record(Op::SetMeta {
+            bytes: bytes.into(),
+        });
+        Ok(())
+    }
+
+    /// drop every record and shrink the file back to the header region,
+    /// leaving only the given container header as the record at index 0.
+    /// The metadata blob survives in a record of its own behind it.
+    pub fn clear_and_shrink(&mut self, header: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.inject_fault()?;
+        let meta = self.meta()?;
+        self.backend.clear()?;
+        self.backend.create(header)?;
+        if let Some(meta) = meta {
+            let position = self.backend.create(&meta)?;
+            self.backend.set_meta_position(position)?;
+        }
+        self.record(Op::ClearAndShrink {
+            header: header.into(),
+        });
+        Ok(())
+    }
+
+    /// a loader to fill a fresh file much faster than with `create`, which
+    /// fails with `WiredError::NotEmpty` as soon as the file holds any frame
+    pub fn bulk_loader(&mut self) -> Result<BulkLoader<'_>, Box<dyn Error>> {
+        if self.backend.frame_count() != 0 {
+            return Err(WiredError::NotEmpty.into());
+        }
+        Ok(BulkLoader::new(self))
+    }
+
+    /// overwrite a part of an existing record without reallocating it
+    pub fn patch(
+        &mut self,
+        index: usize,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        self.inject_fault()?;
+        let position = self.index_to_position(index);
+        self.backend.patch(position, offset, bytes)?;
+        self.record(Op::Patch {
+            index,
+            offset,
+            bytes: bytes.into(),
+        });
+        Ok(())
     }
 
     pub fn delete(&mut self, index: usize) -> Result<(), Box<dyn Error>> {
-        let position = index_to_position(index);
-        self.backend.delete(position)
+        self.inject_fault()?;
+        let position = self.index_to_position(index);
+        self.backend.delete(position)?;
+        self.record(Op::Delete { index });
+        Ok(())
     }
 
     pub fn is_empty(&self) -> bool {
         self.backend.is_empty()
     }
 
-    // pub fn list_indices(&self) -> Result<Vec<usize>, Box<dyn Error>> {
-    //     let positions = self.backend.collect_head_nodes()?;
-    //     let indexes = positions
-    //         .iter()
-    //         .map(|pos| position_to_index(*pos))
-    //         .collect();
-    //     Ok(indexes)
-    // }
+    /// whether the index refers to a live record, without reading it
+    pub fn is_live(&self, index: usize) -> bool {
+        let position = self.index_to_position(index);
+        self.backend.is_live(position)
+    }
+
+    /// grow the file now, so records of the given total length fit without
+    /// growing it later. Frames on the free list count as available.
+    pub fn reserve(&mut self, bytes: usize) -> Result<(), Box<dyn Error>> {
+        self.backend.reserve_bytes(bytes)?;
+        self.record(Op::Reserve { bytes });
+        Ok(())
+    }
+
+    /// the number of frames a record of the given length occupies, with
+    /// size classes in units of the smallest frame
+    pub fn frames_for(&self, len: usize) -> usize {
+        self.backend.frames_needed(len)
+    }
+
+    /// the size of a frame including its own header, with size classes the
+    /// one of the smallest frame, which every frame count refers to
+    pub fn frame_size(&self) -> usize {
+        self.backend.unit()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.backend.stats()
+    }
+
+    /// write the changed pages of the mapping to the file, without syncing
+    /// a grown file length like `sync` does
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.backend.flush()
+    }
+
+    /// make all writes so far durable, see `Backend::sync`
+    pub fn sync(&mut self) -> Result<(), Box<dyn Error>> {
+        self.backend.sync()
+    }
+
+    /// when the file was initialized, `None` for files predating the field
+    pub fn created_at(&self) -> Option<SystemTime> {
+        self.backend.created_at()
+    }
+
+    /// when any record was written the last time, with a resolution of seconds
+    pub fn modified_at(&self) -> Option<SystemTime> {
+        self.backend.modified_at()
+    }
+
+    /// exclusive access across handles and processes until `unlock`, with
+    /// the changes of all other handles visible
+    pub fn lock(&mut self) -> Result<(), Box<dyn Error>> {
+        self.backend.lock_exclusive()
+    }
+
+    /// shared access for reading until `unlock`
+    pub fn lock_shared(&self) -> Result<(), Box<dyn Error>> {
+        self.backend.lock_shared()
+    }
+
+    pub fn unlock(&self) -> Result<(), Box<dyn Error>> {
+        self.backend.unlock()
+    }
+
+    /// a second mapping of the file for reads through a shared reference
+    /// once another handle has grown it, since only `lock` maps the file
+    /// again. `None` while the mapping still covers the whole file.
+    pub fn outgrown_view(&self) -> Result<Option<Self>, Box<dyn Error>> {
+        match self.backend.outgrown_file()? {
+            Some(file) => Ok(Some(Self::with_options(file, &Options::default())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// append a completed write to the log of `Options::record_ops`
+    fn record(&mut self, op: Op<'_>) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(&op);
+        }
+    }
+
+    fn position_to_index(&self, position: usize) -> usize {
+        (position - self.backend.offset()) / self.backend.unit()
+    }
+
+    /// the byte offset of the block in the file
+    pub fn index_to_position(&self, index: usize) -> usize {
+        self.backend.offset() + self.backend.unit() * index
+    }
 }
 
-fn position_to_index(position: usize) -> usize {
-    (position - Backend::offset()) / Backend::block_size()
+#[cfg(not(test))]
+impl BlockStorage {
+    #[inline(always)]
+    fn inject_fault(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
 }
 
-fn index_to_position(index: usize) -> usize {
-    Backend::offset() + Backend::block_size() * index
+/// helpers to test how databases cope with failing writes
+#[cfg(test)]
+impl BlockStorage {
+    /// let the given number of writes succeed and fail the one after them
+    pub fn fail_after(&mut self, writes: usize) {
+        self.writes_until_fault = Some(writes);
+    }
+
+    /// stop failing writes
+    pub fn clear_fault(&mut self) {
+        self.writes_until_fault = None;
+    }
+
+    /// fail to grow the file beyond the given size, like on a full disk
+    pub fn set_quota(&mut self, bytes: usize) {
+        self.backend.set_quota(bytes);
+    }
+
+    fn inject_fault(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.writes_until_fault {
+            Some(0) => {
+                self.writes_until_fault = None;
+                Err("injected write failure".into())
+            }
+            Some(writes) => {
+                self.writes_until_fault = Some(writes - 1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// the number of frames holding live data, to detect leaked blocks
+    pub fn live_frames(&self) -> usize {
+        self.backend.live_frames()
+    }
 }
@@ -0,0 +1,253 @@
+use super::BlockStorage;
+use crate::encoding::deserialize_bounded;
+use crate::options::Options;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+/// A write to a `BlockStorage` as it gets appended to the log of
+/// `Options::record_ops`, with the indices the storage handed out, so a
+/// replay can tell when it allocates differently.
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) enum Op<'a> {
+    Create {
+        index: usize,
+        bytes: Cow<'a, [u8]>,
+    },
+    Update {
+        index: usize,
+        bytes: Cow<'a, [u8]>,
+    },
+    Patch {
+        index: usize,
+        offset: usize,
+        bytes: Cow<'a, [u8]>,
+    },
+    Delete {
+        index: usize,
+    },
+    CopyToFront {
+        index: usize,
+        copy: Option<usize>,
+    },
+    MoveMetaToFront {
+        moved: bool,
+    },
+    ShrinkToFit,
+    SetMeta {
+        bytes: Cow<'a, [u8]>,
+    },
+    ClearAndShrink {
+        header: Cow<'a, [u8]>,
+    },
+    Reserve {
+        bytes: usize,
+    },
+    BulkReserve {
+        frames: usize,
+    },
+    BulkAppend {
+        index: usize,
+        bytes: Cow<'a, [u8]>,
+    },
+    BulkFinish {
+        frame_count: usize,
+    },
+}
+
+/// Appends every successful write of a storage to the log file.
+///
+/// Each write of the storage is complete before it gets logged, so failing
+/// to log must not fail the write itself. The recorder gives up after the
+/// first failure instead, and a replay of the shortened log then reports
+/// the divergence.
+pub(super) struct Recorder {
+    log: Option<File>,
+}
+
+impl Recorder {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let log = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { log: Some(log) })
+    }
+
+    /// a single write per operation, so handles sharing the log never
+    /// interleave their entries
+    pub fn record(&mut self, op: &Op<'_>) {
+        let Some(log) = &mut self.log else {
+            return;
+        };
+        let written = bincode::serialize(op)
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|bytes| Ok(log.write_all(&bytes)?));
+        if written.is_err() {
+            self.log = None;
+        }
+    }
+}
+
+/// The outcome of `replay`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// the number of logged operations that were applied
+    pub operations: usize,
+    /// the number of the first operation that failed or allocated another
+    /// index than logged, nothing after it gets applied
+    pub diverged_at: Option<usize>,
+    /// the number of live records in the replayed file, without the
+    /// metadata blob
+    pub live_records: usize,
+    /// a hash over the index and bytes of every live record, in the order
+    /// of their indices
+    pub content_hash: u64,
+}
+
+/// Re-execute a log written with `Options::record_ops` against a fresh file.
+///
+/// The log describes the writes of the storage below a container, so the
+/// replayed file can be opened as the same kind of container afterwards.
+/// The allocator places records the same way for the same sequence of
+/// writes, which a replay checks for every record it creates. The log has
+/// to be recorded from a fresh file as well, otherwise the replay diverges
+/// at the first write that refers to an earlier record.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let options = wired::Options::new().record_ops("queue.ops");
+/// let mut queue = wired::Queue::<String>::with_options(tempfile::tempfile()?, options)?;
+/// queue.enqueue("hello".to_string())?;
+/// drop(queue);
+///
+/// let report = wired::replay("queue.ops", tempfile::tempfile()?)?;
+/// assert_eq!(report.diverged_at, None);
+/// # Ok(())
+/// # }
+/// ```
+pub fn replay<P: AsRef<Path>>(log: P, target: File) -> Result<ReplayReport, Box<dyn Error>> {
+    let log = File::open(log)?;
+    let limit = log.metadata()?.len() as usize;
+    let mut reader = BufReader::new(log);
+    let mut store = BlockStorage::with_options(target, &Options::default())?;
+    let mut operations = 0;
+    let mut diverged_at = None;
+    while !reader.fill_buf()?.is_empty() {
+        let op: Op<'static> = deserialize_bounded(&mut reader, limit)?;
+        match apply(&mut store, op) {
+            Ok(true) => operations += 1,
+            Ok(false) | Err(_) => {
+                diverged_at = Some(operations);
+                break;
+            }
+        }
+    }
+    store.flush()?;
+    let (live_records, content_hash) = content_hash(&store)?;
+    Ok(ReplayReport {
+        operations,
+        diverged_at,
+        live_records,
+        content_hash,
+    })
+}
+
+/// apply a logged operation, returning whether it allocated the same as
+/// when it was recorded
+fn apply(store: &mut BlockStorage, op: Op<'_>) -> Result<bool, Box<dyn Error>> {
+    // a write to a record the replay lacks would touch arbitrary frames
+    let target = match &op {
+        Op::Update { index, .. }
+        | Op::Patch { index, .. }
+        | Op::Delete { index }
+        | Op::CopyToFront { index, .. } => Some(*index),
+        _ => None,
+    };
+    if target.is_some_and(|index| !store.is_live(index)) {
+        return Ok(false);
+    }
+    match op {
+        Op::Create { index, bytes } => return Ok(store.create(&bytes)? == index),
+        Op::Update { index, bytes } => store.update(index, &bytes)?,
+        Op::Patch {
+            index,
+            offset,
+            bytes,
+        } => store.patch(index, offset, &bytes)?,
+        Op::Delete { index } => store.delete(index)?,
+        Op::CopyToFront { index, copy } => return Ok(store.copy_to_front(index)? == copy),
+        Op::MoveMetaToFront { moved } => return Ok(store.move_meta_to_front()? == moved),
+        Op::ShrinkToFit => {
+            store.shrink_to_fit()?;
+        }
+        Op::SetMeta { bytes } => store.set_meta(&bytes)?,
+        Op::ClearAndShrink { header } => store.clear_and_shrink(&header)?,
+        Op::Reserve { bytes } => store.reserve(bytes)?,
+        Op::BulkReserve { frames } => store.backend.grow_to_frames(frames)?,
+        Op::BulkAppend { index, bytes } => {
+            store.backend.append_at(index, &bytes)?;
+        }
+        Op::BulkFinish { frame_count } => store.backend.finish_appending(frame_count)?,
+    }
+    Ok(true)
+}
+
+/// FNV-1a over the index and bytes of every live record
+fn content_hash(store: &BlockStorage) -> Result<(usize, u64), Box<dyn Error>> {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash = (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+    let mut records = 0;
+    for index in store.iter_blocks()? {
+        feed(&(index as u64).to_le_bytes());
+        feed(&store.read(index)?);
+        records += 1;
+    }
+    Ok((records, hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay() {
+        let log = tempfile::NamedTempFile::new().unwrap();
+        let options = Options::new().record_ops(log.path());
+        let file = tempfile::tempfile().unwrap();
+        let mut store = BlockStorage::with_options(file.try_clone().unwrap(), &options).unwrap();
+        assert_eq!(store.create(b"header").unwrap(), 0);
+        assert_eq!(store.create(&[7; 3000]).unwrap(), 1);
+        let last = store.create(b"last").unwrap();
+        store.delete(1).unwrap();
+        store.patch(last, 1, b"o").unwrap();
+        store.set_meta(b"meta").unwrap();
+        drop(store);
+
+        let report = super::replay(log.path(), tempfile::tempfile().unwrap()).unwrap();
+        assert_eq!(report.operations, 6);
+        assert_eq!(report.diverged_at, None);
+        assert_eq!(report.live_records, 2);
+        let original = BlockStorage::with_options(file.try_clone().unwrap(), &options).unwrap();
+        assert_eq!(report.content_hash, content_hash(&original).unwrap().1);
+        drop(original);
+
+        // writes recorded on top of an existing file refer to records the
+        // fresh file lacks
+        let log = tempfile::NamedTempFile::new().unwrap();
+        let options = Options::new().record_ops(log.path());
+        let mut store = BlockStorage::with_options(file, &options).unwrap();
+        store.update(last, b"next").unwrap();
+        store.create(b"more").unwrap();
+        drop(store);
+        let report = super::replay(log.path(), tempfile::tempfile().unwrap()).unwrap();
+        assert_eq!(report.operations, 0);
+        assert_eq!(report.diverged_at, Some(0));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::error::WiredError;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+// device and inode, so every path and hard link to a file shares an entry
+type FileId = (u64, u64);
+
+/// the container kind every file of this process is open as, and how many
+/// handles of that kind are open
+fn open_files() -> &'static Mutex<HashMap<FileId, (&'static str, usize)>> {
+    static OPEN_FILES: OnceLock<Mutex<HashMap<FileId, (&'static str, usize)>>> = OnceLock::new();
+    OPEN_FILES.get_or_init(Default::default)
+}
+
+#[cfg(unix)]
+fn file_id(file: &File) -> Result<Option<FileId>, Box<dyn Error>> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = file.metadata()?;
+    Ok(Some((metadata.dev(), metadata.ino())))
+}
+
+/// stable Rust offers no file identity on other platforms, so files are
+/// not tracked there
+#[cfg(not(unix))]
+fn file_id(_file: &File) -> Result<Option<FileId>, Box<dyn Error>> {
+    Ok(None)
+}
+
+/// Marks a file as open by one kind of container until dropped.
+///
+/// Handles of the same kind may share a file, since their writes are
+/// coordinated by file locks. A container of another kind would initialize
+/// its own header over the existing one, so it gets rejected instead.
+#[derive(Debug)]
+pub struct Registration {
+    id: FileId,
+}
+
+impl Registration {
+    pub fn acquire(file: &File, kind: &'static str) -> Result<Option<Self>, Box<dyn Error>> {
+        let id = match file_id(file)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let mut open_files = open_files().lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = open_files.entry(id).or_insert((kind, 0));
+        if entry.0 != kind {
+            return Err(WiredError::AlreadyOpen { as_kind: entry.0 }.into());
+        }
+        entry.1 += 1;
+        Ok(Some(Self { id }))
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut open_files = open_files().lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(entry) = open_files.get_mut(&self.id) {
+            entry.1 -= 1;
+            if entry.1 == 0 {
+                open_files.remove(&self.id);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn open(path: &std::path::Path) -> File {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .expect("could not open file")
+    }
+
+    fn already_open(result: Result<Option<Registration>, Box<dyn Error>>) -> &'static str {
+        match result
+            .expect_err("should be rejected")
+            .downcast_ref::<WiredError>()
+        {
+            Some(WiredError::AlreadyOpen { as_kind }) => as_kind,
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn same_path() {
+        let directory = tempfile::tempdir().expect("could not create tempdir");
+        let path = directory.path().join("db.wired");
+        let first = Registration::acquire(&open(&path), "Queue").unwrap();
+        let second = Registration::acquire(&open(&path), "Queue").unwrap();
+        assert_eq!(
+            already_open(Registration::acquire(&open(&path), "Stack")),
+            "Queue"
+        );
+
+        // the entry stays until the last handle is gone
+        drop(first);
+        assert_eq!(
+            already_open(Registration::acquire(&open(&path), "Stack")),
+            "Queue"
+        );
+        drop(second);
+        assert!(Registration::acquire(&open(&path), "Stack").is_ok());
+        assert!(Registration::acquire(&open(&path), "Queue").is_ok());
+    }
+
+    #[test]
+    fn hard_links() {
+        let directory = tempfile::tempdir().expect("could not create tempdir");
+        let path = directory.path().join("db.wired");
+        let link = directory.path().join("link.wired");
+        let file = open(&path);
+        std::fs::hard_link(&path, &link).expect("could not link");
+        let _registration = Registration::acquire(&file, "KeyValue").unwrap();
+        assert_eq!(
+            already_open(Registration::acquire(&open(&link), "Queue")),
+            "KeyValue"
+        );
+    }
+}
@@ -0,0 +1,21 @@
+/// Runtime statistics of a single database handle.
+///
+/// The counters start at zero whenever a file is opened and are not persisted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// how often the file had to be grown and mapped again
+    pub resizes: usize,
+    /// how many records were read in full
+    pub reads: usize,
+    /// how many of the `reads` were borrowed straight from the mapping,
+    /// since the record fits into a single frame
+    pub borrowed_reads: usize,
+    /// how many records were written into a single frame in one go,
+    /// instead of frame by frame
+    pub single_frame_writes: usize,
+    /// how often written pages were flushed to the file
+    pub flushes: usize,
+    /// how often the file was synced along with its metadata by a barrier,
+    /// which only happens after it grew
+    pub syncs: usize,
+}
@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The source of the current time for everything a database stores about
+/// time, like the timestamps of a file.
+///
+/// `SystemClock` is used unless `Options::clock` sets another one. Tests
+/// pass a `ManualClock` instead, to control exactly when time moves on.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::time::{Duration, UNIX_EPOCH};
+///
+/// # let file = tempfile::tempfile()?;
+/// let clock = wired::ManualClock::new(1_000_000);
+/// let options = wired::Options::new().clock(clock.clone());
+/// let mut queue = wired::Queue::<String>::with_options(file, options)?;
+/// assert_eq!(queue.created_at(), Some(UNIX_EPOCH + Duration::from_secs(1000)));
+///
+/// clock.advance(Duration::from_secs(60));
+/// queue.enqueue(String::from("later"))?;
+/// assert_eq!(queue.last_modified(), Some(UNIX_EPOCH + Duration::from_secs(1060)));
+/// # Ok(())
+/// # }
+/// ```
+pub trait Clock: Send + Sync {
+    /// milliseconds since the UNIX epoch
+    fn now(&self) -> u64;
+}
+
+/// the wall clock of the operating system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_millis() as u64)
+    }
+}
+
+/// A clock that stands still until it gets moved explicitly.
+///
+/// Clones share the same time, so a test keeps one to advance the clock
+/// that it handed to a database.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    millis: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    /// a clock standing at the given milliseconds since the UNIX epoch
+    pub fn new(millis: u64) -> Self {
+        Self {
+            millis: Arc::new(AtomicU64::new(millis)),
+        }
+    }
+
+    /// move the clock to the given milliseconds since the UNIX epoch, which
+    /// may also turn it back
+    pub fn set(&self, millis: u64) {
+        self.millis.store(millis, Ordering::SeqCst);
+    }
+
+    /// move the clock forward
+    pub fn advance(&self, duration: Duration) {
+        self.millis
+            .fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.millis.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new(1500);
+        let shared = clock.clone();
+        assert_eq!(shared.now(), 1500);
+        clock.advance(Duration::from_millis(250));
+        assert_eq!(shared.now(), 1750);
+        clock.set(10);
+        assert_eq!(shared.now(), 10);
+        assert!(SystemClock.now() > 0);
+    }
+}
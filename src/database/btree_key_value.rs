@@ -0,0 +1,909 @@
+use super::{collect_garbage, count_down, decode_header, GcReport};
+use crate::block_storage::{BlockStorage, Stats};
+use crate::error::WiredError;
+use crate::options::Options;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+#[cfg(unix)]
+use std::os::unix::io::OwnedFd;
+#[cfg(windows)]
+use std::os::windows::io::OwnedHandle;
+use std::path::Path;
+use std::time::SystemTime;
+
+// the most keys a node holds, one more splits it in two
+const MAX_KEYS: usize = 64;
+// the fewest keys of a node besides the root, one less makes it borrow from
+// or merge with a sibling
+const MIN_KEYS: usize = MAX_KEYS / 2;
+
+/// an ordered Key Value Database
+///
+/// Unlike [`KeyValue`](crate::KeyValue), the keys are not held in memory but
+/// in a B+ tree within the file, sorted by their `Ord` implementation. This
+/// allows range queries and sorted iteration over more keys than fit into
+/// RAM: a lookup reads one node per level of the tree, and iterating reads
+/// one leaf after the other.
+///
+/// Every node is a record of up to 64 keys. Leaves hold the keys along with
+/// the blocks of their values, which are separate records, and point to the
+/// next leaf in order. Branches hold the keys separating their children.
+/// A node that grows beyond 64 keys splits into two halves, which adds a key
+/// to its parent, and a root that splits adds a level on top. A node that
+/// shrinks below 32 keys takes keys from a neighbour, or merges with it if
+/// both fit into a single node, and a root left with a single child gets
+/// replaced by it.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let file = tempfile::tempfile()?;
+/// let mut kv = wired::BTreeKeyValue::<u32, String>::new(file)?;
+/// kv.set(2, String::from("two"))?;
+/// kv.set(1, String::from("one"))?;
+/// kv.set(3, String::from("three"))?;
+///
+/// let value = kv.get(&2)?; // Some("two")
+/// for entry in kv.range(2..)? {
+///     let (key, value) = entry?; // (2, "two"), (3, "three")
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct BTreeKeyValue<K, V> {
+    store: BlockStorage,
+    header: Header,
+    data_type: PhantomData<(K, V)>,
+}
+
+impl<K, V> BTreeKeyValue<K, V>
+where
+    K: Serialize + Ord + Clone,
+    for<'de> K: Deserialize<'de>,
+    V: Serialize,
+    for<'de> V: Deserialize<'de>,
+{
+    pub fn new(file: File) -> Result<Self, Box<dyn Error>> {
+        Self::with_options(file, Options::default())
+    }
+
+    /// Open a database from a file descriptor opened elsewhere, taking
+    /// ownership of it, see [`Queue::from_fd`](crate::Queue::from_fd)
+    #[cfg(unix)]
+    pub fn from_fd(fd: impl Into<OwnedFd>) -> Result<Self, Box<dyn Error>> {
+        Self::new(File::from(fd.into()))
+    }
+
+    /// like `from_fd`, but for a handle of a file on Windows
+    #[cfg(windows)]
+    pub fn from_handle(handle: impl Into<OwnedHandle>) -> Result<Self, Box<dyn Error>> {
+        Self::new(File::from(handle.into()))
+    }
+
+    /// Open a database from a borrowed file through a duplicate of it, see
+    /// [`Queue::from_file`](crate::Queue::from_file)
+    pub fn from_file(file: &File) -> Result<Self, Box<dyn Error>> {
+        Self::new(file.try_clone()?)
+    }
+
+    /// Create a new database or open an existing one, tuning how a new file
+    /// gets initialized. See [`Options`](crate::Options) for details.
+    pub fn with_options(file: File, options: Options) -> Result<Self, Box<dyn Error>> {
+        let store = BlockStorage::with_options(file, &options)?;
+        Self::from_store(store)
+    }
+
+    /// Open the database at the given path, creating the file if needed.
+    ///
+    /// Prefer this over `new` when possible: knowing the path allows upgrades
+    /// of older file formats to replace the file atomically.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::open_with_options(path, Options::default())
+    }
+
+    /// Open the existing database at the given path, like `open`, but fail
+    /// with an `io::Error` of kind `NotFound` instead of creating a new
+    /// empty database if there is no file, e.g. because of a mistyped path.
+    pub fn open_existing<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let store = BlockStorage::open_existing(path.as_ref(), &Options::default())?;
+        Self::from_store(store)
+    }
+
+    /// Open the database at the given path, tuning how a new file gets
+    /// initialized. See [`Options`](crate::Options) for details.
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        options: Options,
+    ) -> Result<Self, Box<dyn Error>> {
+        let store = BlockStorage::open(path.as_ref(), &options)?;
+        Self::from_store(store)
+    }
+
+    fn from_store(mut store: BlockStorage) -> Result<Self, Box<dyn Error>> {
+        store.register("BTreeKeyValue")?;
+        let header = Self::read_header(&mut store)?;
+        Ok(Self {
+            store,
+            header,
+            data_type: PhantomData,
+        })
+    }
+
+    /// read the header, or create it along with an empty root leaf
+    fn read_header(store: &mut BlockStorage) -> Result<Header, Box<dyn Error>> {
+        let bytes = store.read(0)?;
+        if store.is_empty() {
+            let mut header = Header::default();
+            store.create(&bincode::serialize(&header)?)?;
+            let root = Node::<K>::Leaf {
+                entries: vec![],
+                next: 0,
+            };
+            header.root = store.create(&bincode::serialize(&root)?)?;
+            store.update(0, &bincode::serialize(&header)?)?;
+            Ok(header)
+        } else {
+            decode_header(bytes.as_slice())
+        }
+    }
+
+    fn save_header(&mut self) -> Result<(), Box<dyn Error>> {
+        let bytes: Vec<u8> = bincode::serialize(&self.header)?;
+        self.store.update(0, bytes.as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.header.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// whether the number of keys in the header would have dropped below
+    /// zero, which means it drifted from the leaves, e.g. after a crash.
+    /// Operations keep working with the count held at zero.
+    pub fn counter_drift(&self) -> bool {
+        self.header.counter_drift
+    }
+
+    /// runtime statistics of this handle, like the number of file resizes
+    pub fn stats(&self) -> Stats {
+        self.store.stats()
+    }
+
+    /// the number of runs of adjacent free blocks by their length, to judge
+    /// whether the file is fragmented into many small holes and a compaction
+    /// would pay off
+    pub fn free_space_histogram(&self) -> Result<BTreeMap<usize, usize>, Box<dyn Error>> {
+        self.store.free_space_histogram()
+    }
+
+    /// hand all pending writes of this handle to the operating system, see
+    /// [`Queue::flush`](crate::Queue::flush)
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.store.flush()
+    }
+
+    /// return once all writes of this handle are durable, see
+    /// [`Queue::barrier`](crate::Queue::barrier)
+    pub fn barrier(&mut self) -> Result<(), Box<dyn Error>> {
+        self.store.sync()
+    }
+
+    /// when the database file was created, `None` for files written by
+    /// versions of this crate that did not record it yet
+    pub fn created_at(&self) -> Option<SystemTime> {
+        self.store.created_at()
+    }
+
+    /// when the database was modified the last time, with a resolution of
+    /// seconds. Reads never change it.
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.store.modified_at()
+    }
+
+    /// the value of the key, `None` if the key does not exist
+    pub fn get(&self, key: &K) -> Result<Option<V>, Box<dyn Error>> {
+        let (entries, _) = self.find_leaf(Bound::Included(key))?;
+        match entries.binary_search_by(|(stored, _)| stored.cmp(key)) {
+            Ok(position) => Ok(Some(self.read_value(entries[position].1)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool, Box<dyn Error>> {
+        let (entries, _) = self.find_leaf(Bound::Included(key))?;
+        Ok(entries
+            .binary_search_by(|(stored, _)| stored.cmp(key))
+            .is_ok())
+    }
+
+    /// insert the value for the key, replacing the previous one
+    pub fn set(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        let bytes: Vec<u8> = bincode::serialize(&value)?;
+        let root = self.header.root;
+        if let Some((separator, right)) = self.insert(root, key, &bytes)? {
+            // the root split, so the tree grows by one level
+            let root = Node::Branch {
+                keys: vec![separator],
+                children: vec![root, right],
+            };
+            self.header.root = self.create_node(&root)?;
+            self.save_header()?;
+        }
+        Ok(())
+    }
+
+    /// remove the key and its value, if it exists
+    pub fn remove(&mut self, key: &K) -> Result<(), Box<dyn Error>> {
+        if let Some(value_index) = self.remove_entry(key)? {
+            self.store.delete(value_index)?;
+        }
+        Ok(())
+    }
+
+    /// remove the key and return its value, `None` if it does not exist
+    pub fn take(&mut self, key: &K) -> Result<Option<V>, Box<dyn Error>> {
+        match self.remove_entry(key)? {
+            Some(value_index) => {
+                let value = self.read_value(value_index)?;
+                self.store.delete(value_index)?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// remove all keys and shrink the file back to its header and an empty
+    /// root leaf, see [`Queue::clear_and_shrink`](crate::Queue::clear_and_shrink)
+    pub fn clear_and_shrink(&mut self) -> Result<(), Box<dyn Error>> {
+        self.header = Header::default();
+        self.store
+            .clear_and_shrink(&bincode::serialize(&self.header)?)?;
+        self.header.root = self.create_node(&Node::Leaf {
+            entries: vec![],
+            next: 0,
+        })?;
+        self.save_header()
+    }
+
+    /// delete orphaned blocks that the tree does not reach, see
+    /// [`Queue::gc`](crate::Queue::gc). Every node gets read, so a node
+    /// that does not decode fails it before anything got deleted.
+    pub fn gc(&mut self) -> Result<GcReport, Box<dyn Error>> {
+        let mut reachable = HashSet::from([0]);
+        let mut pending = vec![self.header.root];
+        while let Some(index) = pending.pop() {
+            if !reachable.insert(index) {
+                continue;
+            }
+            match self.read_node(index)? {
+                Node::Leaf { entries, .. } => {
+                    reachable.extend(entries.into_iter().map(|(_, value)| value));
+                }
+                Node::Branch { children, .. } => pending.extend(children),
+            }
+        }
+        collect_garbage(&mut self.store, &reachable)
+    }
+
+    /// iterate over all keys and their values in ascending order of keys
+    pub fn iter(&self) -> Result<OrderedIter<'_, K, V>, Box<dyn Error>> {
+        self.range(..)
+    }
+
+    /// iterate over the keys within the range and their values in ascending
+    /// order of keys. Only one leaf is held in memory at a time.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::BTreeKeyValue::<String, u64>::new(file)?;
+    /// kv.set(String::from("apple"), 1)?;
+    /// kv.set(String::from("banana"), 2)?;
+    /// kv.set(String::from("cherry"), 3)?;
+    /// let from = String::from("b");
+    /// let to = String::from("c");
+    /// let entries = kv.range(from..to)?.collect::<Result<Vec<_>, _>>()?; // [("banana", 2)]
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn range<R>(&self, range: R) -> Result<OrderedIter<'_, K, V>, Box<dyn Error>>
+    where
+        R: RangeBounds<K>,
+    {
+        let start = range.start_bound();
+        let (mut entries, next_leaf) = self.find_leaf(start)?;
+        let before_start = entries.partition_point(|(key, _)| match start {
+            Bound::Included(start) => key < start,
+            Bound::Excluded(start) => key <= start,
+            Bound::Unbounded => false,
+        });
+        entries.drain(..before_start);
+        Ok(OrderedIter {
+            db: self,
+            entries: entries.into_iter(),
+            next_leaf,
+            end: range.end_bound().cloned(),
+            finished: false,
+        })
+    }
+
+    /// the entries and successor of the leaf where a key at the bound
+    /// belongs, going down from the root
+    fn find_leaf(&self, bound: Bound<&K>) -> Result<LeafContent<K>, Box<dyn Error>> {
+        let mut index = self.header.root;
+        loop {
+            match self.read_node(index)? {
+                Node::Branch { keys, children } => {
+                    index = match bound {
+                        Bound::Included(key) | Bound::Excluded(key) => {
+                            children[child_position(&keys, key)]
+                        }
+                        Bound::Unbounded => children[0],
+                    };
+                }
+                Node::Leaf { entries, next } => return Ok((entries, next)),
+            }
+        }
+    }
+
+    /// insert below the node, returning the first key and the block of a
+    /// new right sibling if the node had to split
+    fn insert(
+        &mut self,
+        index: usize,
+        key: K,
+        value: &[u8],
+    ) -> Result<Option<(K, usize)>, Box<dyn Error>> {
+        match self.read_node(index)? {
+            Node::Leaf { mut entries, next } => {
+                match entries.binary_search_by(|(stored, _)| stored.cmp(&key)) {
+                    Ok(position) => {
+                        self.store.update(entries[position].1, value)?;
+                        return Ok(None);
+                    }
+                    Err(position) => {
+                        let value_index = self.store.create(value)?;
+                        entries.insert(position, (key, value_index));
+                        self.header.len += 1;
+                        self.save_header()?;
+                    }
+                }
+                if entries.len() <= MAX_KEYS {
+                    self.write_node(index, &Node::Leaf { entries, next })?;
+                    return Ok(None);
+                }
+                let right_entries = entries.split_off(entries.len() / 2);
+                let separator = right_entries[0].0.clone();
+                let right = self.create_node(&Node::Leaf {
+                    entries: right_entries,
+                    next,
+                })?;
+                self.write_node(
+                    index,
+                    &Node::Leaf {
+                        entries,
+                        next: right,
+                    },
+                )?;
+                Ok(Some((separator, right)))
+            }
+            Node::Branch {
+                mut keys,
+                mut children,
+            } => {
+                let position = child_position(&keys, &key);
+                let Some((separator, right)) = self.insert(children[position], key, value)? else {
+                    return Ok(None);
+                };
+                keys.insert(position, separator);
+                children.insert(position + 1, right);
+                if keys.len() <= MAX_KEYS {
+                    self.write_node(index, &Node::Branch { keys, children })?;
+                    return Ok(None);
+                }
+                // the middle key moves up, separating both halves
+                let middle = keys.len() / 2;
+                let right_keys = keys.split_off(middle + 1);
+                let separator = keys.pop().expect("a branch beyond its capacity");
+                let right_children = children.split_off(middle + 1);
+                let right = self.create_node(&Node::Branch {
+                    keys: right_keys,
+                    children: right_children,
+                })?;
+                self.write_node(index, &Node::Branch { keys, children })?;
+                Ok(Some((separator, right)))
+            }
+        }
+    }
+
+    /// unlink the key from the tree and return the block of its value
+    fn remove_entry(&mut self, key: &K) -> Result<Option<usize>, Box<dyn Error>> {
+        let root = self.header.root;
+        let (removed, remaining) = self.remove_below(root, key)?;
+        if removed.is_none() {
+            return Ok(None);
+        }
+        self.header.counter_drift |= count_down(&mut self.header.len);
+        if remaining == 0 {
+            // a root branch without keys has a single child left
+            if let Node::Branch { children, .. } = self.read_node(root)? {
+                self.header.root = children[0];
+                self.store.delete(root)?;
+            }
+        }
+        self.save_header()?;
+        Ok(removed)
+    }
+
+    /// remove the key below the node, returning the block of its value and
+    /// the number of keys the node holds afterwards
+    fn remove_below(
+        &mut self,
+        index: usize,
+        key: &K,
+    ) -> Result<(Option<usize>, usize), Box<dyn Error>> {
+        match self.read_node(index)? {
+            Node::Leaf { mut entries, next } => {
+                match entries.binary_search_by(|(stored, _)| stored.cmp(key)) {
+                    Ok(position) => {
+                        let (_, value_index) = entries.remove(position);
+                        let remaining = entries.len();
+                        self.write_node(index, &Node::Leaf { entries, next })?;
+                        Ok((Some(value_index), remaining))
+                    }
+                    Err(_) => Ok((None, entries.len())),
+                }
+            }
+            Node::Branch {
+                mut keys,
+                mut children,
+            } => {
+                let position = child_position(&keys, key);
+                let (removed, remaining) = self.remove_below(children[position], key)?;
+                if removed.is_some() && remaining < MIN_KEYS {
+                    self.rebalance(&mut keys, &mut children, position)?;
+                    let remaining = keys.len();
+                    self.write_node(index, &Node::Branch { keys, children })?;
+                    return Ok((removed, remaining));
+                }
+                Ok((removed, keys.len()))
+            }
+        }
+    }
+
+    /// refill the child at the position, which has too few keys, from its
+    /// neighbour: both share their keys evenly, or merge into the left one
+    /// if they fit into a single node
+    fn rebalance(
+        &mut self,
+        keys: &mut Vec<K>,
+        children: &mut Vec<usize>,
+        position: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let left_position = position.saturating_sub(1);
+        let (left_index, right_index) = (children[left_position], children[left_position + 1]);
+        match (self.read_node(left_index)?, self.read_node(right_index)?) {
+            (
+                Node::Leaf {
+                    entries: mut left, ..
+                },
+                Node::Leaf {
+                    entries: right,
+                    next,
+                },
+            ) => {
+                left.extend(right);
+                if left.len() <= MAX_KEYS {
+                    self.write_node(
+                        left_index,
+                        &Node::Leaf {
+                            entries: left,
+                            next,
+                        },
+                    )?;
+                    self.store.delete(right_index)?;
+                    keys.remove(left_position);
+                    children.remove(left_position + 1);
+                } else {
+                    let right = left.split_off(left.len() / 2);
+                    keys[left_position] = right[0].0.clone();
+                    self.write_node(
+                        right_index,
+                        &Node::Leaf {
+                            entries: right,
+                            next,
+                        },
+                    )?;
+                    self.write_node(
+                        left_index,
+                        &Node::Leaf {
+                            entries: left,
+                            next: right_index,
+                        },
+                    )?;
+                }
+            }
+            (
+                Node::Branch {
+                    keys: mut left_keys,
+                    children: mut left_children,
+                },
+                Node::Branch {
+                    keys: right_keys,
+                    children: right_children,
+                },
+            ) => {
+                // the separator moves down between the keys of both
+                left_keys.push(keys[left_position].clone());
+                left_keys.extend(right_keys);
+                left_children.extend(right_children);
+                if left_keys.len() <= MAX_KEYS {
+                    let merged = Node::Branch {
+                        keys: left_keys,
+                        children: left_children,
+                    };
+                    self.write_node(left_index, &merged)?;
+                    self.store.delete(right_index)?;
+                    keys.remove(left_position);
+                    children.remove(left_position + 1);
+                } else {
+                    let middle = left_keys.len() / 2;
+                    let right_keys = left_keys.split_off(middle + 1);
+                    keys[left_position] = left_keys.pop().expect("a branch with keys");
+                    let right_children = left_children.split_off(middle + 1);
+                    let right = Node::Branch {
+                        keys: right_keys,
+                        children: right_children,
+                    };
+                    self.write_node(right_index, &right)?;
+                    let left = Node::Branch {
+                        keys: left_keys,
+                        children: left_children,
+                    };
+                    self.write_node(left_index, &left)?;
+                }
+            }
+            // all leaves are at the same depth
+            _ => return Err(WiredError::Corrupted { index: right_index }.into()),
+        }
+        Ok(())
+    }
+
+    fn read_node(&self, index: usize) -> Result<Node<K>, Box<dyn Error>> {
+        let bytes = self.store.read(index)?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    fn write_node(&mut self, index: usize, node: &Node<K>) -> Result<(), Box<dyn Error>> {
+        let bytes: Vec<u8> = bincode::serialize(node)?;
+        self.store.update(index, bytes.as_slice())
+    }
+
+    fn create_node(&mut self, node: &Node<K>) -> Result<usize, Box<dyn Error>> {
+        let bytes: Vec<u8> = bincode::serialize(node)?;
+        self.store.create(bytes.as_slice())
+    }
+
+    fn read_value(&self, index: usize) -> Result<V, Box<dyn Error>> {
+        let bytes = self.store.read(index)?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
+}
+
+/// the child of a branch where the key belongs: every key of a branch is
+/// the smallest key of the child to its right
+fn child_position<K: Ord>(keys: &[K], key: &K) -> usize {
+    keys.partition_point(|separator| separator <= key)
+}
+
+/// the entries of a leaf along with the block of the next one
+type LeafContent<K> = (Vec<(K, usize)>, usize);
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Header {
+    // the block of the root node, a leaf until the first split
+    root: usize,
+    // the number of keys within all leaves
+    len: usize,
+    // set once the number of keys would have dropped below zero
+    counter_drift: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Node<K> {
+    // sorted keys along with the blocks of their values, and the leaf with
+    // the following keys, `0` for the last one
+    Leaf {
+        entries: Vec<(K, usize)>,
+        next: usize,
+    },
+    // one more child than keys
+    Branch {
+        keys: Vec<K>,
+        children: Vec<usize>,
+    },
+}
+
+/// An iterator over the entries of a [`BTreeKeyValue`](crate::BTreeKeyValue)
+/// in ascending order of keys, created by `iter` or `range`. Stops after the
+/// first error.
+pub struct OrderedIter<'a, K, V> {
+    db: &'a BTreeKeyValue<K, V>,
+    entries: std::vec::IntoIter<(K, usize)>,
+    next_leaf: usize,
+    end: Bound<K>,
+    finished: bool,
+}
+
+impl<'a, K, V> Iterator for OrderedIter<'a, K, V>
+where
+    K: Serialize + Ord + Clone,
+    for<'de> K: Deserialize<'de>,
+    V: Serialize,
+    for<'de> V: Deserialize<'de>,
+{
+    type Item = Result<(K, V), Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.finished {
+            if let Some((key, value_index)) = self.entries.next() {
+                let within = match &self.end {
+                    Bound::Included(end) => key <= *end,
+                    Bound::Excluded(end) => key < *end,
+                    Bound::Unbounded => true,
+                };
+                if !within {
+                    break;
+                }
+                let value = self.db.read_value(value_index);
+                self.finished = value.is_err();
+                return Some(value.map(|value| (key, value)));
+            }
+            if self.next_leaf == 0 {
+                break;
+            }
+            match self.db.read_node(self.next_leaf) {
+                Ok(Node::Leaf { entries, next }) => {
+                    self.entries = entries.into_iter();
+                    self.next_leaf = next;
+                }
+                Ok(Node::Branch { .. }) => {
+                    self.finished = true;
+                    let index = self.next_leaf;
+                    return Some(Err(WiredError::Corrupted { index }.into()));
+                }
+                Err(error) => {
+                    self.finished = true;
+                    return Some(Err(error));
+                }
+            }
+        }
+        self.finished = true;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fabricate_drift;
+
+    /// the keys 0..count in a scattered order
+    fn scattered(count: u64) -> impl Iterator<Item = u64> {
+        (0..count).map(move |i| i * 7919 % count)
+    }
+
+    /// check the order and fill of every node, and that all leaves have the
+    /// same depth, returning the depth and the keys of the subtree
+    fn verify(kv: &BTreeKeyValue<u64, String>, index: usize, is_root: bool) -> (usize, Vec<u64>) {
+        match kv.read_node(index).unwrap() {
+            Node::Leaf { entries, .. } => {
+                assert!(is_root || entries.len() >= MIN_KEYS);
+                assert!(entries.len() <= MAX_KEYS);
+                (0, entries.into_iter().map(|(key, _)| key).collect())
+            }
+            Node::Branch { keys, children } => {
+                assert!(is_root || keys.len() >= MIN_KEYS);
+                assert!(!keys.is_empty() && keys.len() <= MAX_KEYS);
+                assert_eq!(children.len(), keys.len() + 1);
+                let mut all = vec![];
+                let mut depths = vec![];
+                for (position, child) in children.into_iter().enumerate() {
+                    let (depth, child_keys) = verify(kv, child, false);
+                    if position > 0 {
+                        assert!(child_keys[0] >= keys[position - 1]);
+                    }
+                    if position < keys.len() {
+                        assert!(*child_keys.last().unwrap() < keys[position]);
+                    }
+                    depths.push(depth);
+                    all.extend(child_keys);
+                }
+                assert!(depths.iter().all(|depth| *depth == depths[0]));
+                (depths[0] + 1, all)
+            }
+        }
+    }
+
+    fn keys_of(kv: &BTreeKeyValue<u64, String>) -> Vec<u64> {
+        kv.iter()
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn ordered_iteration() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv = BTreeKeyValue::<u64, String>::new(file.try_clone().unwrap()).unwrap();
+        assert!(kv.is_empty());
+        assert_eq!(kv.iter().unwrap().count(), 0);
+
+        // enough keys for three levels
+        let count = 5000;
+        for key in scattered(count) {
+            kv.set(key, format!("value {}", key)).unwrap();
+        }
+        assert_eq!(kv.len(), count as usize);
+        let (depth, keys) = verify(&kv, kv.header.root, true);
+        assert_eq!(depth, 2);
+        assert_eq!(keys, (0..count).collect::<Vec<_>>());
+
+        // overwriting keeps the number of keys
+        kv.set(42, String::from("changed")).unwrap();
+        assert_eq!(kv.len(), count as usize);
+        assert_eq!(kv.get(&42).unwrap(), Some(String::from("changed")));
+        assert_eq!(kv.get(&count).unwrap(), None);
+
+        // works after reopen
+        drop(kv);
+        let kv = BTreeKeyValue::<u64, String>::new(file).unwrap();
+        assert_eq!(kv.len(), count as usize);
+        let entries = kv.iter().unwrap().map(|entry| entry.unwrap());
+        for (expected, (key, value)) in (0..count).zip(entries) {
+            assert_eq!(key, expected);
+            if key != 42 {
+                assert_eq!(value, format!("value {}", key));
+            }
+        }
+    }
+
+    #[test]
+    fn drifted_len() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv = BTreeKeyValue::<u64, String>::new(file.try_clone().unwrap()).unwrap();
+        for key in 0..3 {
+            kv.set(key, key.to_string()).unwrap();
+        }
+        // a header that lost track of the keys
+        kv.header.len = 0;
+        kv.save_header().unwrap();
+        fabricate_drift();
+        assert!(!kv.counter_drift());
+
+        kv.remove(&1).unwrap();
+        assert_eq!(kv.len(), 0);
+        assert!(kv.counter_drift());
+        assert_eq!(keys_of(&kv), [0, 2]);
+        assert_eq!(kv.get(&2).unwrap(), Some(String::from("2")));
+
+        drop(kv);
+        let kv = BTreeKeyValue::<u64, String>::new(file).unwrap();
+        assert!(kv.counter_drift());
+        assert_eq!(keys_of(&kv), [0, 2]);
+    }
+
+    #[test]
+    fn gc() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv = BTreeKeyValue::<u64, String>::new(file).expect("could not create");
+        for key in scattered(500) {
+            kv.set(key, key.to_string()).expect("can not set");
+        }
+        assert_eq!(kv.gc().unwrap(), GcReport::default());
+
+        // the value of a set that failed to write its leaf
+        kv.store.fail_after(1);
+        kv.set(1000, String::from("lost")).expect_err("should fail");
+        assert_eq!(kv.gc().unwrap().blocks, 1);
+        assert_eq!(keys_of(&kv), (0..500).collect::<Vec<_>>());
+        assert_eq!(kv.get(&250).unwrap(), Some(String::from("250")));
+    }
+
+    #[test]
+    fn range_queries() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv = BTreeKeyValue::<u64, String>::new(file).unwrap();
+        // only even keys, so bounds fall between keys as well
+        for key in scattered(1000) {
+            kv.set(key * 2, key.to_string()).unwrap();
+        }
+        let range = |range: (Bound<u64>, Bound<u64>)| {
+            kv.range(range)
+                .unwrap()
+                .map(|entry| entry.unwrap().0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            range((Bound::Included(100), Bound::Excluded(110))),
+            vec![100, 102, 104, 106, 108]
+        );
+        assert_eq!(
+            range((Bound::Excluded(100), Bound::Included(110))),
+            vec![102, 104, 106, 108, 110]
+        );
+        assert_eq!(
+            range((Bound::Included(101), Bound::Included(105))),
+            vec![102, 104]
+        );
+        assert_eq!(
+            range((Bound::Included(1995), Bound::Unbounded)),
+            vec![1996, 1998]
+        );
+        assert_eq!(range((Bound::Unbounded, Bound::Excluded(4))), vec![0, 2]);
+        assert_eq!(range((Bound::Included(3000), Bound::Unbounded)), vec![]);
+        assert_eq!(range((Bound::Included(50), Bound::Excluded(50))), vec![]);
+
+        // a range spanning many leaves
+        let keys = kv
+            .range(500..1500)
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(keys.len(), 500);
+        assert_eq!(keys[0], (500, String::from("250")));
+        assert_eq!(keys[499], (1498, String::from("749")));
+    }
+
+    #[test]
+    fn many_inserts_and_removes() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv = BTreeKeyValue::<u64, String>::new(file).unwrap();
+        let count = 3000;
+        for key in scattered(count) {
+            kv.set(key, key.to_string()).unwrap();
+        }
+
+        // remove every key not divisible by 3, merging and sharing nodes
+        for key in scattered(count).filter(|key| key % 3 != 0) {
+            assert_eq!(kv.take(&key).unwrap(), Some(key.to_string()));
+        }
+        kv.remove(&1).unwrap();
+        assert_eq!(kv.take(&1).unwrap(), None);
+        assert_eq!(kv.len(), 1000);
+        let (_, keys) = verify(&kv, kv.header.root, true);
+        assert_eq!(
+            keys,
+            (0..count).filter(|key| key % 3 == 0).collect::<Vec<_>>()
+        );
+        assert_eq!(keys_of(&kv), keys);
+        assert!(kv.contains_key(&3).unwrap());
+        assert!(!kv.contains_key(&4).unwrap());
+
+        for key in scattered(count) {
+            kv.set(key, key.to_string()).unwrap();
+        }
+        verify(&kv, kv.header.root, true);
+
+        // removing everything shrinks the tree to an empty root leaf, and
+        // leaves no block behind besides the header
+        for key in scattered(count) {
+            kv.remove(&key).unwrap();
+        }
+        assert!(kv.is_empty());
+        let (depth, keys) = verify(&kv, kv.header.root, true);
+        assert_eq!((depth, keys), (0, vec![]));
+        assert_eq!(kv.iter().unwrap().count(), 0);
+        assert_eq!(kv.store.live_frames(), 2);
+    }
+}
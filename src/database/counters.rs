@@ -0,0 +1,392 @@
+use super::{collect_garbage, decode_context, decode_header, GcReport};
+use crate::block_storage::{BlockStorage, Stats};
+use crate::encoding::deserialize_bounded;
+use crate::options::Options;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::hash::Hash;
+use std::marker::PhantomData;
+#[cfg(unix)]
+use std::os::unix::io::OwnedFd;
+#[cfg(windows)]
+use std::os::windows::io::OwnedHandle;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// a Database of integer counters
+///
+/// Every counter lives in a fixed-size slot holding an `i64`, so incrementing
+/// it is an `O(1)` in-place write: no deserialization of a generic value and
+/// no reallocation, since the size of a slot never changes. This makes
+/// `Counters` much faster than a `KeyValue<K, i64>` for metrics.
+///
+/// Keys can be arbitrary data types that can be serialized via serde and
+/// implement the `Eq` and `Hash` trait. Missing counters read as `0`.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// // create a new db
+/// # let file = tempfile::tempfile()?;
+/// let mut counters = wired::Counters::<String>::new(file)?;
+///
+/// // count some things
+/// let key = String::from("requests");
+/// counters.incr(key.clone(), 1)?;
+/// counters.incr(key.clone(), 2)?;
+///
+/// // read the current value
+/// let value = counters.get(&key)?; // 3
+///
+/// // start from zero again
+/// counters.reset(&key)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Counters<K> {
+    store: BlockStorage,
+    header: Header,
+    lookup: HashMap<K, usize>,
+    key_type: PhantomData<K>,
+}
+
+impl<K> Counters<K>
+where
+    K: Serialize + Hash + Eq,
+    for<'de> K: Deserialize<'de>,
+{
+    pub fn new(file: File) -> Result<Self, Box<dyn Error>> {
+        Self::with_options(file, Options::default())
+    }
+
+    /// Open a database from a file descriptor opened elsewhere, taking
+    /// ownership of it, see [`Queue::from_fd`](crate::Queue::from_fd)
+    #[cfg(unix)]
+    pub fn from_fd(fd: impl Into<OwnedFd>) -> Result<Self, Box<dyn Error>> {
+        Self::new(File::from(fd.into()))
+    }
+
+    /// like `from_fd`, but for a handle of a file on Windows
+    #[cfg(windows)]
+    pub fn from_handle(handle: impl Into<OwnedHandle>) -> Result<Self, Box<dyn Error>> {
+        Self::new(File::from(handle.into()))
+    }
+
+    /// Open a database from a borrowed file through a duplicate of it, see
+    /// [`Queue::from_file`](crate::Queue::from_file)
+    pub fn from_file(file: &File) -> Result<Self, Box<dyn Error>> {
+        Self::new(file.try_clone()?)
+    }
+
+    /// Create a new database or open an existing one, tuning how a new file
+    /// gets initialized. See [`Options`](crate::Options) for details.
+    pub fn with_options(file: File, options: Options) -> Result<Self, Box<dyn Error>> {
+        let store = BlockStorage::with_options(file, &options)?;
+        Self::from_store(store)
+    }
+
+    /// Open the database at the given path, creating the file if needed.
+    ///
+    /// Prefer this over `new` when possible: knowing the path allows upgrades
+    /// of older file formats to replace the file atomically.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = wired::Counters::<String>::open("path/to/db.wired")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::open_with_options(path, Options::default())
+    }
+
+    /// Open the existing database at the given path, like `open`, but fail
+    /// with an `io::Error` of kind `NotFound` instead of creating a new
+    /// empty database if there is no file, e.g. because of a mistyped path.
+    pub fn open_existing<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let store = BlockStorage::open_existing(path.as_ref(), &Options::default())?;
+        Self::from_store(store)
+    }
+
+    /// Open the database at the given path, tuning how a new file gets
+    /// initialized. See [`Options`](crate::Options) for details.
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        options: Options,
+    ) -> Result<Self, Box<dyn Error>> {
+        let store = BlockStorage::open(path.as_ref(), &options)?;
+        Self::from_store(store)
+    }
+
+    fn from_store(mut store: BlockStorage) -> Result<Self, Box<dyn Error>> {
+        store.register("Counters")?;
+        let header = Self::read_header(&mut store)?;
+        let mut counters = Self {
+            store,
+            header,
+            lookup: HashMap::new(),
+            key_type: PhantomData,
+        };
+        for index in counters.header.slot_indices.iter() {
+            let bytes = counters.store.read(*index)?;
+            let slot = deserialize_bounded(bytes.as_slice(), bytes.len());
+            let slot: Slot<K> = decode_context(slot, &counters.store, "open", *index)?;
+            counters.lookup.insert(slot.key, *index);
+        }
+        Ok(counters)
+    }
+
+    fn read_header(store: &mut BlockStorage) -> Result<Header, Box<dyn Error>> {
+        let bytes = store.read(0)?;
+        if store.is_empty() {
+            let header = Header::default();
+            let bytes: Vec<u8> = bincode::serialize(&header)?;
+            store.create(bytes.as_slice())?;
+            Ok(header)
+        } else {
+            decode_header(bytes.as_slice())
+        }
+    }
+
+    fn save_header(&mut self) -> Result<(), Box<dyn Error>> {
+        let bytes: Vec<u8> = bincode::serialize(&self.header)?;
+        self.store.update(0, bytes.as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.header.slot_indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// runtime statistics of this handle, like the number of file resizes
+    pub fn stats(&self) -> Stats {
+        self.store.stats()
+    }
+
+    /// the number of runs of adjacent free blocks by their length, to judge
+    /// whether the file is fragmented into many small holes and a compaction
+    /// would pay off
+    pub fn free_space_histogram(&self) -> Result<BTreeMap<usize, usize>, Box<dyn Error>> {
+        self.store.free_space_histogram()
+    }
+
+    /// hand all pending writes of this handle to the operating system, see
+    /// [`Queue::flush`](crate::Queue::flush)
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.store.flush()
+    }
+
+    /// return once all writes of this handle are durable, see
+    /// [`Queue::barrier`](crate::Queue::barrier)
+    pub fn barrier(&mut self) -> Result<(), Box<dyn Error>> {
+        self.store.sync()
+    }
+
+    /// when the database file was created, `None` for files written by
+    /// versions of this crate that did not record it yet
+    pub fn created_at(&self) -> Option<SystemTime> {
+        self.store.created_at()
+    }
+
+    /// when the database was modified the last time, with a resolution of
+    /// seconds. Reads never change it.
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.store.modified_at()
+    }
+
+    /// the metadata blob stored with the file by `set_meta`, `None` if
+    /// there is none
+    pub fn meta(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.store.meta()
+    }
+
+    /// stamp the file with a blob of application metadata of up to 64KB,
+    /// replacing the previous one. It is stored apart from the counters and
+    /// survives upgrades of the file format.
+    pub fn set_meta(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.store.set_meta(bytes)
+    }
+
+    /// the total number of bytes of all stored items, without any framing
+    /// or free space. Compare it with the length of the file to see the
+    /// overhead of the storage layout.
+    ///
+    /// Note: this walks the headers of all frames in the file once.
+    pub fn logical_size(&self) -> Result<usize, Box<dyn Error>> {
+        // everything except the header block are slots
+        Ok(self.store.live_bytes()? - self.store.record_size(0)?)
+    }
+
+    pub fn keys(&self) -> Vec<&K> {
+        self.lookup.keys().collect()
+    }
+
+    /// current value of a counter, `0` if it was never incremented
+    pub fn get(&self, key: &K) -> Result<i64, Box<dyn Error>> {
+        if let Some(index) = self.lookup.get(key) {
+            self.read_value(*index)
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// add `delta` to a counter and return its new value
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut counters = wired::Counters::<String>::new(file)?;
+    /// counters.incr(String::from("visits"), 1)?;
+    /// let value = counters.incr(String::from("visits"), -1)?; // 0
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn incr(&mut self, key: K, delta: i64) -> Result<i64, Box<dyn Error>> {
+        if let Some(index) = self.lookup.get(&key) {
+            let index = *index;
+            let value = self.read_value(index)?.wrapping_add(delta);
+            self.write_value(index, value)?;
+            Ok(value)
+        } else {
+            let slot = Slot { value: delta, key };
+            let bytes: Vec<u8> = bincode::serialize(&slot)?;
+            let index = self.store.create(bytes.as_slice())?;
+            self.lookup.insert(slot.key, index);
+            self.header.slot_indices.push(index);
+            self.save_header()?;
+            Ok(delta)
+        }
+    }
+
+    /// set a counter back to `0`
+    pub fn reset(&mut self, key: &K) -> Result<(), Box<dyn Error>> {
+        if let Some(index) = self.lookup.get(key) {
+            let index = *index;
+            self.write_value(index, 0)?;
+        }
+        Ok(())
+    }
+
+    /// remove all counters and shrink the file back to its header, see
+    /// [`Queue::clear_and_shrink`](crate::Queue::clear_and_shrink)
+    pub fn clear_and_shrink(&mut self) -> Result<(), Box<dyn Error>> {
+        self.header = Header::default();
+        self.lookup.clear();
+        let bytes: Vec<u8> = bincode::serialize(&self.header)?;
+        self.store.clear_and_shrink(&bytes)
+    }
+
+    /// delete orphaned blocks that hold no counter of the header, see
+    /// [`Queue::gc`](crate::Queue::gc)
+    pub fn gc(&mut self) -> Result<GcReport, Box<dyn Error>> {
+        let mut reachable = HashSet::from([0]);
+        reachable.extend(self.header.slot_indices.iter().copied());
+        collect_garbage(&mut self.store, &reachable)
+    }
+
+    fn read_value(&self, index: usize) -> Result<i64, Box<dyn Error>> {
+        let bytes = self.store.read(index)?;
+        let value: i64 = bincode::deserialize_from(bytes.as_slice())?;
+        Ok(value)
+    }
+
+    /// the value is the first field of the slot, so it can be overwritten in place
+    fn write_value(&mut self, index: usize, value: i64) -> Result<(), Box<dyn Error>> {
+        let bytes: Vec<u8> = bincode::serialize(&value)?;
+        self.store.patch(index, 0, bytes.as_slice())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Header {
+    slot_indices: Vec<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Slot<K> {
+    value: i64,
+    key: K,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::WiredError;
+
+    #[test]
+    fn works() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut counters =
+            Counters::<i32>::new(file.try_clone().unwrap()).expect("could not create");
+        assert_eq!(counters.len(), 0);
+        assert_eq!(counters.get(&1).expect("can not get"), 0);
+
+        assert_eq!(counters.incr(1, 5).expect("can not incr"), 5);
+        assert_eq!(counters.incr(1, -2).expect("can not incr"), 3);
+        assert_eq!(counters.incr(2, 1).expect("can not incr"), 1);
+        assert_eq!(counters.len(), 2);
+
+        // increments do not allocate new blocks
+        let slot_indices = counters.header.slot_indices.clone();
+        counters.incr(1, 100).expect("can not incr");
+        assert_eq!(counters.header.slot_indices, slot_indices);
+
+        counters.reset(&2).expect("can not reset");
+        assert_eq!(counters.get(&2).expect("can not get"), 0);
+        assert_eq!(counters.len(), 2);
+
+        // works after reopen
+        let counters = Counters::<i32>::new(file).expect("could not open");
+        assert_eq!(counters.get(&1).expect("can not get"), 103);
+        assert_eq!(counters.get(&2).expect("can not get"), 0);
+    }
+
+    #[test]
+    fn gc() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut counters =
+            Counters::<i32>::new(file.try_clone().unwrap()).expect("could not create");
+        counters.incr(1, 5).expect("can not incr");
+
+        // the slot of a new counter that failed to save the header
+        counters.store.fail_after(1);
+        counters.incr(2, 1).expect_err("should fail");
+        drop(counters);
+        let mut counters = Counters::<i32>::new(file).expect("could not open");
+        assert_eq!(counters.gc().unwrap().blocks, 1);
+        assert_eq!(counters.gc().unwrap(), GcReport::default());
+        assert_eq!(counters.get(&1).expect("can not get"), 5);
+        assert_eq!(counters.len(), 1);
+    }
+
+    #[test]
+    fn corrupted_length_prefix() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut counters =
+            Counters::<String>::new(file.try_clone().unwrap()).expect("could not create");
+        counters.incr(String::from("requests"), 1).unwrap();
+        // the key claims to be an exabyte long
+        let index = counters.header.slot_indices[0];
+        counters
+            .store
+            .patch(index, 8, &(1_u64 << 60).to_le_bytes())
+            .unwrap();
+        drop(counters);
+
+        let error = Counters::<String>::new(file).err().unwrap();
+        match error.downcast_ref::<WiredError>() {
+            Some(WiredError::DeserializeFailed { index: failed, .. }) => assert_eq!(*failed, index),
+            _ => panic!("unexpected error: {}", error),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use crate::block_storage::BlockStorage;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+// bucket pointers per page, so that a page fits into a single frame
+const PAGE_SLOTS: usize = 120;
+
+/// A hash table from keys to their blocks that lives in the file instead of
+/// memory, for a `KeyValue` with more keys than fit into RAM.
+///
+/// The buckets are spread over pages of `PAGE_SLOTS` pointers each, and every
+/// non-empty bucket is a record of its slots. A lookup reads one page, one
+/// bucket and the key block of every slot with a matching hash.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct DiskIndex {
+    // blocks of bucket pointers, `0` for a bucket without any keys yet
+    pages: Vec<usize>,
+    // fixed when the file gets created
+    buckets: usize,
+    // number of keys within all buckets
+    pub len: usize,
+}
+
+/// a key within a bucket of the `DiskIndex`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slot {
+    pub hash: u64,
+    pub key_index: usize,
+    pub value_index: usize,
+}
+
+impl DiskIndex {
+    /// write the empty pages for the given number of buckets
+    pub fn create(store: &mut BlockStorage, buckets: usize) -> Result<Self, Box<dyn Error>> {
+        let buckets = buckets.max(1);
+        let empty_page = bincode::serialize(&[0_usize; PAGE_SLOTS][..])?;
+        let mut pages = Vec::with_capacity(buckets.div_ceil(PAGE_SLOTS));
+        for _ in 0..buckets.div_ceil(PAGE_SLOTS) {
+            pages.push(store.create(&empty_page)?);
+        }
+        Ok(Self {
+            pages,
+            buckets,
+            len: 0,
+        })
+    }
+
+    /// the number of buckets, fixed since the file got created
+    pub fn buckets(&self) -> usize {
+        self.buckets
+    }
+
+    /// FNV-1a of a serialized key. The hash is persisted, so it must never
+    /// change between versions, unlike the hashers of the standard library.
+    pub fn hash(key_bytes: &[u8]) -> u64 {
+        key_bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    /// the page and the position within it of the bucket for a hash
+    fn locate(&self, hash: u64) -> (usize, usize) {
+        let bucket = (hash % self.buckets as u64) as usize;
+        (self.pages[bucket / PAGE_SLOTS], bucket % PAGE_SLOTS)
+    }
+
+    fn read_page(store: &BlockStorage, page: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+        Ok(bincode::deserialize(&store.read(page)?)?)
+    }
+
+    /// all slots within the same bucket as the hash
+    pub fn read_bucket(
+        &self,
+        store: &BlockStorage,
+        hash: u64,
+    ) -> Result<Vec<Slot>, Box<dyn Error>> {
+        let (page, position) = self.locate(hash);
+        match Self::read_page(store, page)?[position] {
+            0 => Ok(vec![]),
+            bucket => Ok(bincode::deserialize(&store.read(bucket)?)?),
+        }
+    }
+
+    /// replace all slots within the same bucket as the hash. The first write
+    /// into a bucket creates its record and links it from the page.
+    pub fn write_bucket(
+        &self,
+        store: &mut BlockStorage,
+        hash: u64,
+        slots: &[Slot],
+    ) -> Result<(), Box<dyn Error>> {
+        let bytes = bincode::serialize(slots)?;
+        let (page, position) = self.locate(hash);
+        let mut pointers = Self::read_page(store, page)?;
+        if pointers[position] != 0 {
+            // updates keep the index of a record, so the page stays valid
+            return store.update(pointers[position], &bytes);
+        }
+        let bucket = store.create(&bytes)?;
+        pointers[position] = bucket;
+        let result = bincode::serialize(&pointers)
+            .map_err(Into::into)
+            .and_then(|page_bytes| store.update(page, &page_bytes));
+        if result.is_err() {
+            // a failure here only leaks the block
+            let _ = store.delete(bucket);
+        }
+        result
+    }
+
+    /// the pages and the records of all non-empty buckets
+    pub fn blocks(&self, store: &BlockStorage) -> Result<Vec<usize>, Box<dyn Error>> {
+        let mut blocks = self.pages.clone();
+        for page in self.pages.iter() {
+            let pointers = Self::read_page(store, *page)?;
+            blocks.extend(pointers.into_iter().filter(|bucket| *bucket != 0));
+        }
+        Ok(blocks)
+    }
+
+    /// every slot of every bucket, page by page
+    pub fn slots<'a>(
+        &'a self,
+        store: &'a BlockStorage,
+    ) -> impl Iterator<Item = Result<Slot, Box<dyn Error>>> + 'a {
+        self.pages.iter().flat_map(move |page| {
+            let slots = Self::read_page(store, *page).and_then(|pointers| {
+                let mut slots = vec![];
+                for bucket in pointers.into_iter().filter(|bucket| *bucket != 0) {
+                    let bucket: Vec<Slot> = bincode::deserialize(&store.read(bucket)?)?;
+                    slots.extend(bucket);
+                }
+                Ok(slots)
+            });
+            match slots {
+                Ok(slots) => slots.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(error) => vec![Err(error)],
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Options;
+
+    #[test]
+    fn buckets() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut store = BlockStorage::with_options(file, &Options::default()).unwrap();
+        store.create(b"header").unwrap();
+        let index = DiskIndex::create(&mut store, 250).unwrap();
+        assert_eq!(index.pages.len(), 3);
+        assert_eq!(index.slots(&store).count(), 0);
+
+        let slot = |hash| Slot {
+            hash,
+            key_index: hash as usize,
+            value_index: 0,
+        };
+        // 3 and 253 share a bucket, 249 sits on the last page
+        index.write_bucket(&mut store, 3, &[slot(3)]).unwrap();
+        index
+            .write_bucket(&mut store, 253, &[slot(3), slot(253)])
+            .unwrap();
+        index.write_bucket(&mut store, 249, &[slot(249)]).unwrap();
+        assert_eq!(index.read_bucket(&store, 3).unwrap(), [slot(3), slot(253)]);
+        assert_eq!(index.read_bucket(&store, 4).unwrap(), []);
+        let all: Vec<Slot> = index.slots(&store).collect::<Result<_, _>>().unwrap();
+        assert_eq!(all, [slot(3), slot(253), slot(249)]);
+    }
+
+    #[test]
+    fn stable_hash() {
+        assert_eq!(DiskIndex::hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(DiskIndex::hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
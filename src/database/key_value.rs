@@ -1,10 +1,33 @@
-use crate::block_storage::BlockStorage;
+use super::disk_index::{DiskIndex, Slot};
+use super::value_cache::ValueCache;
+use super::{
+    collect_garbage, count_down, decode_context, decode_header, decode_migrating, Borrowed,
+    ContentHash, GcReport, Migrator, Usage,
+};
+use crate::block_storage::{BlockStorage, Stats};
+use crate::encoding::IntEncoding;
+use crate::error::WiredError;
+use crate::options::Options;
+use crate::progress::{self, ProgressSink};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::error::Error;
-use std::fs::File;
-use std::hash::Hash;
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, Hash};
 use std::marker::PhantomData;
+#[cfg(unix)]
+use std::os::unix::io::OwnedFd;
+#[cfg(windows)]
+use std::os::windows::io::OwnedHandle;
+use std::path::Path;
+use std::time::SystemTime;
+
+// the most entries `export_filtered` holds in memory before writing them
+const EXPORT_BATCH: usize = 1024;
 
 /// Key Value Database
 ///
@@ -25,6 +48,26 @@ use std::marker::PhantomData;
 /// on your structs should suffice. Keys must implement the `Eq` and `Hash`
 /// trait.
 ///
+/// The in-memory lookup of keys gets persisted into an index block when the
+/// database is dropped, so the next `open` does not need to scan every key.
+/// Whenever that index is outdated (e.g. after a crash), the lookup is
+/// rebuilt from the key blocks instead.
+///
+/// Tiny maps can be kept inline: when
+/// [`Options::inline_values`](crate::Options::inline_values) is set, all
+/// entries are stored directly in the header record as long as their total
+/// serialized size stays below the threshold. Once the map grows beyond it,
+/// the entries are transparently moved into individual blocks.
+///
+/// Huge key sets can keep their lookup on disk instead: with
+/// [`Options::disk_index`](crate::Options::disk_index), keys get located
+/// through an on-disk hash table, which bounds the memory use at the cost
+/// of a few extra reads per access.
+///
+/// The in-memory lookup hashes keys with `RandomState` like a `HashMap`,
+/// unless another hasher is given to [`with_hasher`](Self::with_hasher),
+/// e.g. a faster one for integer keys.
+///
 /// # Examples
 ///
 /// ```rust,no_run
@@ -55,12 +98,46 @@ use std::marker::PhantomData;
 /// kv.remove(&key)?;
 /// # Ok(())
 /// # }
-pub struct KeyValue<K, V> {
+pub struct KeyValue<K, V, S = RandomState>
+where
+    K: Serialize + Hash + Eq,
+    for<'de> K: Deserialize<'de>,
+    V: Serialize,
+    for<'de> V: Deserialize<'de>,
+    S: BuildHasher + Clone,
+{
+    // everything that works on serialized values, see `RawKeyValue`
+    raw: RawKeyValue<K, S>,
+    migrator: Option<Migrator<V>>,
+    // decoded values of recent reads, see `with_cache`
+    cache: Option<RefCell<ValueCache<V>>>,
+    value_type: PhantomData<V>,
+}
+
+/// The part of a `KeyValue` that works on serialized values only, compiled
+/// once per key type instead of for every pair of key and value type, like
+/// `RawQueue` for a `Queue`. Keys stay typed, as the lookup and the inline
+/// entries hold them deserialized.
+struct RawKeyValue<K, S>
+where
+    K: Serialize + Hash + Eq,
+    for<'de> K: Deserialize<'de>,
+    S: BuildHasher + Clone,
+{
     store: BlockStorage,
-    header: Header,
-    lookup: HashMap<K, usize>,
+    header: Header<K>,
+    lookup: HashMap<K, usize, S>,
+    // estimated memory footprint of the lookup
+    index_bytes: usize,
+    max_index_bytes: Option<usize>,
+    inline_threshold: usize,
+    // persist the lookup in a stable order, see `Options::deterministic`
+    deterministic: bool,
+    index_dirty: bool,
+    // set while a modification runs, and left set if a panic unwinds
+    // through it, which poisons the handle
+    modifying: bool,
     key_type: PhantomData<K>,
-    value_type: PhantomData<V>,
 }
 
 impl<K, V> KeyValue<K, V>
@@ -71,166 +148,3765 @@ where
     for<'de> V: Deserialize<'de>,
 {
     pub fn new(file: File) -> Result<Self, Box<dyn Error>> {
-        let mut store = BlockStorage::new(file)?;
-        let header = Self::read_header(&mut store)?;
-        let mut kv = Self {
-            store,
-            header,
-            lookup: HashMap::new(),
-            key_type: PhantomData,
-            value_type: PhantomData,
-        };
-        kv.save_header()?;
-        for index in kv.header.key_indices.iter() {
-            let bytes = kv.store.read(*index)?;
-            let entry: KeyEntry<K> = bincode::deserialize_from(bytes.as_slice())?;
-            kv.lookup.insert(entry.body, entry.value_index);
-        }
+        Self::with_options(file, Options::default())
+    }
+
+    /// Open a database from a file descriptor opened elsewhere, taking
+    /// ownership of it, see [`Queue::from_fd`](crate::Queue::from_fd)
+    #[cfg(unix)]
+    pub fn from_fd(fd: impl Into<OwnedFd>) -> Result<Self, Box<dyn Error>> {
+        Self::new(File::from(fd.into()))
+    }
+
+    /// like `from_fd`, but for a handle of a file on Windows
+    #[cfg(windows)]
+    pub fn from_handle(handle: impl Into<OwnedHandle>) -> Result<Self, Box<dyn Error>> {
+        Self::new(File::from(handle.into()))
+    }
+
+    /// Open a database from a borrowed file through a duplicate of it, see
+    /// [`Queue::from_file`](crate::Queue::from_file)
+    pub fn from_file(file: &File) -> Result<Self, Box<dyn Error>> {
+        Self::new(file.try_clone()?)
+    }
+
+    /// Create a new database or open an existing one, tuning how a new file
+    /// gets initialized. See [`Options`](crate::Options) for details.
+    pub fn with_options(file: File, options: Options) -> Result<Self, Box<dyn Error>> {
+        Self::with_hasher(file, options, RandomState::new())
+    }
+
+    /// like `new`, but keep the decoded values of up to `capacity` recently
+    /// read keys in memory, so reading a hot key again neither reads its
+    /// block nor deserializes it. The least recently used value gets evicted
+    /// first, and every write of a key drops its cached value.
+    ///
+    /// Values stored inline within the header are not cached, as reading
+    /// them needs no read from the file anyway.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, String>::with_cache(file, 1000)?;
+    /// kv.set(String::from("config"), String::from("large document"))?;
+    /// let value = kv.get(&String::from("config"))?; // read from the file
+    /// let value = kv.get(&String::from("config"))?; // read from the cache
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_cache(file: File, capacity: usize) -> Result<Self, Box<dyn Error>>
+    where
+        V: Clone,
+    {
+        let mut kv = Self::new(file)?;
+        kv.cache = Some(RefCell::new(ValueCache::new(capacity, V::clone)));
         Ok(kv)
     }
 
-    fn read_header(store: &mut BlockStorage) -> Result<Header, Box<dyn Error>> {
-        let bytes = store.read(0)?;
-        if store.is_empty() {
-            let header = Header::default();
-            let bytes: Vec<u8> = bincode::serialize(&header)?;
-            store.create(bytes.as_slice())?;
-            Ok(header)
-        } else {
-            let header = bincode::deserialize_from(bytes.as_slice())?;
-            Ok(header)
-        }
+    /// Open the database at the given path, creating the file if needed.
+    ///
+    /// Prefer this over `new` when possible: knowing the path allows upgrades
+    /// of older file formats to replace the file atomically.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = wired::KeyValue::<String, String>::open("path/to/db.wired")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::open_with_options(path, Options::default())
     }
 
-    fn save_header(&mut self) -> Result<(), Box<dyn Error>> {
-        let bytes: Vec<u8> = bincode::serialize(&self.header)?;
-        self.store.update(0, bytes.as_slice())
+    /// Open the existing database at the given path, like `open`, but fail
+    /// with an `io::Error` of kind `NotFound` instead of creating a new
+    /// empty database if there is no file, e.g. because of a mistyped path.
+    pub fn open_existing<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let options = Options::default();
+        let store = BlockStorage::open_existing(path.as_ref(), &options)?;
+        Self::from_store(store, &options, false, RandomState::new())
+    }
+
+    /// Open the database at the given path, tuning how a new file gets
+    /// initialized. See [`Options`](crate::Options) for details.
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        options: Options,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::open_with_hasher(path, options, RandomState::new())
+    }
+
+    /// Open an existing database, but ignore the persisted index block and
+    /// rebuild the lookup of keys by scanning every key block instead.
+    ///
+    /// This is an escape hatch for recovery when the index is suspected to
+    /// be stale or corrupt. The rebuilt index replaces the old one once the
+    /// database is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let kv = wired::KeyValue::<String, String>::open_rebuild_index(file)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_rebuild_index(file: File) -> Result<Self, Box<dyn Error>> {
+        let options = Options::default();
+        let store = BlockStorage::with_options(file, &options)?;
+        Self::from_store(store, &options, true, RandomState::new())
+    }
+}
+
+impl<K, V, S> KeyValue<K, V, S>
+where
+    K: Serialize + Hash + Eq,
+    for<'de> K: Deserialize<'de>,
+    V: Serialize,
+    for<'de> V: Deserialize<'de>,
+    S: BuildHasher + Clone,
+{
+    /// like `with_options`, but the in-memory lookup hashes keys with the
+    /// given hasher instead of `RandomState`. The hasher only affects the
+    /// memory of this handle, so a file can be opened with any hasher.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// use std::collections::hash_map::DefaultHasher;
+    /// use std::hash::BuildHasherDefault;
+    ///
+    /// // a hasher without random keys, e.g. for reproducible benchmarks
+    /// let hasher = BuildHasherDefault::<DefaultHasher>::default();
+    /// let kv = wired::KeyValue::<u64, String, _>::with_hasher(file, wired::Options::new(), hasher)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_hasher(file: File, options: Options, hasher: S) -> Result<Self, Box<dyn Error>> {
+        let store = BlockStorage::with_options(file, &options)?;
+        Self::from_store(store, &options, false, hasher)
+    }
+
+    /// like `open_with_options`, but with the given hasher for the
+    /// in-memory lookup, see [`with_hasher`](Self::with_hasher)
+    pub fn open_with_hasher<P: AsRef<Path>>(
+        path: P,
+        options: Options,
+        hasher: S,
+    ) -> Result<Self, Box<dyn Error>> {
+        let store = BlockStorage::open(path.as_ref(), &options)?;
+        Self::from_store(store, &options, false, hasher)
+    }
+
+    fn from_store(
+        store: BlockStorage,
+        options: &Options,
+        rebuild_index: bool,
+        hasher: S,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            raw: RawKeyValue::open(store, options, rebuild_index, hasher)?,
+            migrator: None,
+            cache: None,
+            value_type: PhantomData,
+        })
+    }
+
+    /// rebuild the key lookup by reading every single key block, which takes
+    /// a while for large databases. `progress` receives the number of keys
+    /// read so far and may cancel, which keeps the previous lookup. Does
+    /// nothing for a database with a disk index.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, String>::new(file)?;
+    /// kv.rebuild_index(&mut |done: u64, total: Option<u64>| {
+    ///     println!("{} of {:?} keys", done, total);
+    ///     std::ops::ControlFlow::Continue(())
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn rebuild_index(&mut self, progress: &mut dyn ProgressSink) -> Result<(), Box<dyn Error>> {
+        self.modify(|kv| kv.raw.rebuild_index(progress))
+    }
+
+    /// the estimated number of bytes the in-memory lookup of keys occupies.
+    /// Every key counts with its serialized size plus the size of a map
+    /// entry, while inline entries live in the header and do not count.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, i32>::new(file)?;
+    /// kv.set(String::from("key"), 1)?;
+    /// let bytes = kv.index_memory_bytes(); // 11 bytes of the key + 32 bytes per entry
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn index_memory_bytes(&self) -> usize {
+        self.raw.index_bytes
     }
 
     pub fn len(&self) -> usize {
-        self.header.key_indices.len()
+        self.raw.len()
     }
 
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 
+    /// whether a count in the header would have dropped below zero, which
+    /// means it drifted from the stored entries, e.g. after a crash.
+    /// Operations keep working with the count held at zero.
+    pub fn counter_drift(&self) -> bool {
+        self.raw.header.counter_drift
+    }
+
+    /// runtime statistics of this handle, like the number of file resizes
+    pub fn stats(&self) -> Stats {
+        self.raw.store.stats()
+    }
+
+    /// the number of runs of adjacent free blocks by their length, to judge
+    /// whether the file is fragmented into many small holes and a compaction
+    /// would pay off
+    pub fn free_space_histogram(&self) -> Result<BTreeMap<usize, usize>, Box<dyn Error>> {
+        self.raw.store.free_space_histogram()
+    }
+
+    /// hand all pending writes of this handle to the operating system, see
+    /// [`Queue::flush`](crate::Queue::flush)
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.raw.store.flush()
+    }
+
+    /// return once all writes of this handle are durable, see
+    /// [`Queue::barrier`](crate::Queue::barrier)
+    pub fn barrier(&mut self) -> Result<(), Box<dyn Error>> {
+        self.raw.store.sync()
+    }
+
+    /// when the database file was created, `None` for files written by
+    /// versions of this crate that did not record it yet
+    pub fn created_at(&self) -> Option<SystemTime> {
+        self.raw.store.created_at()
+    }
+
+    /// when the database was modified the last time, with a resolution of
+    /// seconds. Reads never change it.
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.raw.store.modified_at()
+    }
+
+    /// the metadata blob stored with the file by `set_meta`, `None` if
+    /// there is none
+    pub fn meta(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.raw.store.meta()
+    }
+
+    /// stamp the file with a blob of application metadata of up to 64KB,
+    /// replacing the previous one. It is stored apart from the keys and values and
+    /// survives upgrades of the file format.
+    pub fn set_meta(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.modify(|kv| kv.raw.store.set_meta(bytes))
+    }
+
+    /// the total number of bytes of all stored keys and values, without any
+    /// framing or free space. Compare it with the length of the file to see
+    /// the overhead of the storage layout.
+    ///
+    /// Note: this walks the frame headers of every entry, but does not read
+    /// any keys or values.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, String>::new(file.try_clone()?)?;
+    /// kv.set(String::from("key"), String::from("value"))?;
+    /// let overhead = file.metadata()?.len() as f64 / kv.logical_size()? as f64;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn logical_size(&self) -> Result<usize, Box<dyn Error>> {
+        self.raw.logical_size()
+    }
+
+    /// lifetime counters of this database, persisted across reopens
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, i32>::new(file)?;
+    /// kv.set(String::from("key"), 1)?;
+    /// kv.set(String::from("key"), 2)?;
+    /// let counters = kv.counters(); // inserts: 1, overwrites: 1, removals: 0
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn counters(&self) -> KeyValueCounters {
+        self.raw.header.counters.unwrap_or_default()
+    }
+
+    /// start a new measurement window with all totals at zero
+    pub fn reset_counters(&mut self) -> Result<(), Box<dyn Error>> {
+        self.modify(|kv| kv.raw.reset_counters())
+    }
+
+    /// the serialized bytes of all keys and values, without any pointers or
+    /// framing. Unlike `logical_size`, this needs no scan of the file.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<u32, u64>::new(file)?;
+    /// kv.set(1, 42)?;
+    /// let payload = kv.payload_bytes(); // 12
+    /// let overhead = kv.overhead_bytes(); // 2036
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn payload_bytes(&self) -> usize {
+        self.raw.header.usage.unwrap_or_default().payload_bytes()
+    }
+
+    /// the bytes of all frames holding keys and values that are not
+    /// payload: the pointers from keys to values, frame headers and the
+    /// unused rest of the last frame of every block. Inline entries live
+    /// within the header and add no overhead.
+    pub fn overhead_bytes(&self) -> usize {
+        let usage = self.raw.header.usage.unwrap_or_default();
+        usage.overhead_bytes(self.raw.store.frame_size())
+    }
+
+    /// all keys held in memory.
+    ///
+    /// Note: a database with a disk index keeps no keys in memory, so this
+    /// is always empty for it. Use [`list`](Self::list) instead.
     pub fn keys(&self) -> Vec<&K> {
-        let mut result: Vec<&K> = vec![];
-        for key in self.lookup.keys() {
-            result.push(key);
+        self.raw.keys()
+    }
+
+    /// iterate over all entries in the order their keys were first inserted,
+    /// which is stable across reopens and useful for reproducible exports.
+    ///
+    /// Note: `set` on an existing key removes and re-adds it, so an
+    /// overwritten key moves to the very end of the order. A database with
+    /// a disk index yields its entries in the order of its buckets instead.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, i32>::new(file)?;
+    /// kv.set(String::from("b"), 1)?;
+    /// kv.set(String::from("a"), 2)?;
+    /// for entry in kv.iter_insertion_order() {
+    ///     let (key, value) = entry?; // ("b", 1), then ("a", 2)
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn iter_insertion_order(
+        &self,
+    ) -> impl Iterator<Item = Result<(K, V), Box<dyn Error>>> + '_ {
+        self.raw.raw_entries().map(move |entry| {
+            let (key, value_bytes) = entry?;
+            Ok((key, self.decode_value(&value_bytes)?))
+        })
+    }
+
+    /// iterate over the entries whose stored value passes the filter, in
+    /// the order of `iter_insertion_order`, along with the serialized value.
+    /// The filter sees the raw bytes before anything gets deserialized, so
+    /// it can decode just as much of a value as it needs.
+    ///
+    /// The bytes use the [`IntEncoding`](crate::IntEncoding) of the
+    /// database. bincode writes the fields of a struct one after another in
+    /// their declared order, without names or lengths, so the leading fields
+    /// can be decoded on their own, see [`scan_where`](Self::scan_where).
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// // the first field of the value is a `u8` status, `1` means failed
+    /// let kv = wired::KeyValue::<String, (u8, String)>::new(file)?;
+    /// for entry in kv.scan_raw(|_, bytes| bytes.first() == Some(&1)) {
+    ///     let (key, bytes) = entry?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn scan_raw<'a, F>(
+        &'a self,
+        filter: F,
+    ) -> impl Iterator<Item = Result<(K, Vec<u8>), Box<dyn Error>>> + 'a
+    where
+        F: FnMut(&K, &[u8]) -> bool + 'a,
+    {
+        self.raw.scan_raw(filter)
+    }
+
+    /// iterate over the entries whose value matches the predicate, which
+    /// sees the value decoded as `P` instead of `V`. Only values that match
+    /// get deserialized as `V`, so a `P` with just the few leading fields of
+    /// `V` the predicate needs saves decoding everything else.
+    ///
+    /// Note: this relies on bincode decoding the fields of a struct in their
+    /// declared order, so `P` must repeat the leading fields of `V` with the
+    /// same types and in the same order. Values that do not decode as `P`
+    /// are skipped.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use serde::{Deserialize, Serialize};
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct Job {
+    ///     failed: bool,
+    ///     log: String,
+    /// }
+    ///
+    /// // the leading field of `Job`
+    /// #[derive(Deserialize)]
+    /// struct JobStatus {
+    ///     failed: bool,
+    /// }
+    ///
+    /// # let file = tempfile::tempfile()?;
+    /// let kv = wired::KeyValue::<String, Job>::new(file)?;
+    /// for entry in kv.scan_where(|status: &JobStatus| status.failed) {
+    ///     let (key, job) = entry?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn scan_where<'a, P, F>(
+        &'a self,
+        mut predicate: F,
+    ) -> impl Iterator<Item = Result<(K, V), Box<dyn Error>>> + 'a
+    where
+        P: DeserializeOwned,
+        F: FnMut(&P) -> bool + 'a,
+    {
+        let encoding = self.raw.header.encoding;
+        let matches = move |_: &K, value_bytes: &[u8]| {
+            encoding
+                .deserialize::<P>(value_bytes)
+                .is_ok_and(|partial| predicate(&partial))
+        };
+        self.raw.scan_raw(matches).map(move |entry| {
+            let (key, value_bytes) = entry?;
+            Ok((key, self.decode_value(&value_bytes)?))
+        })
+    }
+
+    /// summaries of all entries in the order their keys were first inserted,
+    /// with the stored size of every value but without reading any value.
+    /// See [`list_page`](Self::list_page) to fetch only a slice of them.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, String>::new(file)?;
+    /// kv.set(String::from("key"), String::from("value"))?;
+    /// for summary in kv.list() {
+    ///     let summary = summary?; // key: "key", value_bytes: 13, frames: 1
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn list(&self) -> impl Iterator<Item = Result<KeySummary<K>, Box<dyn Error>>> + '_ {
+        self.raw.list()
+    }
+
+    /// at most `limit` entry summaries, skipping the first `offset` in
+    /// insertion order. Skipped entries are not read at all, so paging
+    /// through large databases stays cheap.
+    pub fn list_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> impl Iterator<Item = Result<KeySummary<K>, Box<dyn Error>>> + '_ {
+        self.raw.list_page(offset, limit)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>, Box<dyn Error>> {
+        self.raw.check_poisoned()?;
+        match self.raw.locate_value(key)? {
+            Some(ValueLocation::Block(value_index)) => Ok(Some(self.read_cached(value_index)?)),
+            Some(location) => Ok(Some(self.read_value("get", location)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// the value of a block through the cache, if there is one
+    fn read_cached(&self, value_index: usize) -> Result<V, Box<dyn Error>> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.read_value("get", ValueLocation::Block(value_index)),
+        };
+        if let Some(value) = cache.borrow_mut().get(value_index) {
+            return Ok(value);
+        }
+        let value = self.read_value("get", ValueLocation::Block(value_index))?;
+        cache.borrow_mut().insert(value_index, &value);
+        Ok(value)
+    }
+
+    /// drop the cached value of a key before its block changes
+    fn invalidate_cached(&mut self, key: &K) -> Result<(), Box<dyn Error>> {
+        if let Some(cache) = &self.cache {
+            if let Some(value_index) = self.raw.value_index(key)? {
+                cache.borrow_mut().invalidate(value_index);
+            }
+        }
+        Ok(())
+    }
+
+    /// drop all cached values before blocks change in bulk
+    fn clear_cached(&mut self) {
+        if let Some(cache) = &self.cache {
+            cache.borrow_mut().clear();
         }
+    }
+
+    /// run a modification that may take several steps. A panic unwinding
+    /// through it, e.g. from a closure of the caller, leaves the handle
+    /// poisoned, as the lookup and the header in memory may be half updated.
+    fn modify<R>(
+        &mut self,
+        operation: impl FnOnce(&mut Self) -> Result<R, Box<dyn Error>>,
+    ) -> Result<R, Box<dyn Error>> {
+        self.raw.check_poisoned()?;
+        self.raw.modifying = true;
+        let result = operation(self);
+        self.raw.modifying = false;
         result
     }
 
-    pub fn get(&self, key: &K) -> Result<Option<V>, Box<dyn Error>> {
-        if let Some(value_index) = self.lookup.get(&key) {
-            let value_bytes = self.store.read(*value_index)?;
-            let value = bincode::deserialize_from(value_bytes.as_slice())?;
-            Ok(Some(value))
-        } else {
-            Ok(None)
+    /// read the header and the lookup of keys from the file again, which
+    /// lifts the [`Poisoned`](crate::WiredError::Poisoned) state after a
+    /// panic interrupted a modification of this handle. The file holds
+    /// whatever the modification got to write before the panic, which is
+    /// as valid as after a crash at that point.
+    ///
+    /// Until then, every fallible operation of the handle fails as
+    /// poisoned, while infallible ones like `len` or `keys` answer from the
+    /// state in memory as it was left.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, u32>::new(file)?;
+    /// match kv.get(&String::from("key")) {
+    ///     Err(error) if error.downcast_ref() == Some(&wired::WiredError::Poisoned) => {
+    ///         kv.recover_in_memory_state()?;
+    ///     }
+    ///     _ => {}
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn recover_in_memory_state(&mut self) -> Result<(), Box<dyn Error>> {
+        self.clear_cached();
+        self.raw.reload()
+    }
+
+    /// the value of a key as a guard to deserialize a type borrowing from
+    /// it, like a view with `&str` fields, without allocating its fields.
+    /// See [`Borrowed`](crate::Borrowed).
+    ///
+    /// The guard owns or borrows the bytes, since the deserialized value can
+    /// not outlive them. Values that do not deserialize as the borrowed type
+    /// fail, without a fallback to the migrator.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<u64, String>::new(file)?;
+    /// kv.set(1, String::from("some long text"))?;
+    /// let guard = kv.get_borrowed(&1)?.unwrap();
+    /// let text: &str = guard.value()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_borrowed(&self, key: &K) -> Result<Option<Borrowed<'_>>, Box<dyn Error>> {
+        self.raw.check_poisoned()?;
+        self.raw.get_borrowed(key)
+    }
+
+    /// the serialized value of a key as stored, without deserializing it,
+    /// e.g. to hand it on to another system. `None` only for a missing key,
+    /// an empty value is `Some` of no bytes.
+    ///
+    /// The bytes use the [`IntEncoding`](crate::IntEncoding) of the
+    /// database, unless they were stored with [`set_raw`](Self::set_raw).
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, u32>::new(file)?;
+    /// kv.set(String::from("answer"), 42)?;
+    /// let bytes = kv.get_raw(&String::from("answer"))?; // Some([42, 0, 0, 0])
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_raw(&self, key: &K) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.raw.check_poisoned()?;
+        match self.raw.locate_value(key)? {
+            Some(ValueLocation::Block(value_index)) => Ok(Some(self.raw.store.read(value_index)?)),
+            Some(ValueLocation::Inline(value_bytes)) => Ok(Some(value_bytes.to_vec())),
+            None => Ok(None),
         }
     }
 
-    pub fn set(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
-        if self.lookup.contains_key(&key) {
-            self.remove(&key)?;
+    /// the values of several keys as one consistent unit, like the parts of
+    /// an object stored under separate keys. All keys get located before
+    /// any value is read, and no modification can happen in between, so
+    /// the values all stem from the same generation of the database.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, String>::new(file)?;
+    /// let profile = String::from("user:1:profile");
+    /// let prefs = String::from("user:1:prefs");
+    /// kv.set(profile.clone(), String::from("Jane"))?;
+    /// let group = kv.get_group([&profile, &prefs])?;
+    /// let values = group.values; // [Some("Jane"), None]
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_group<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a K>,
+    ) -> Result<GroupSnapshot<V>, Box<dyn Error>>
+    where
+        K: 'a,
+    {
+        self.raw.check_poisoned()?;
+        let locations: Vec<Option<ValueLocation>> = keys
+            .into_iter()
+            .map(|key| self.raw.locate_value(key))
+            .collect::<Result<_, _>>()?;
+        let mut values = Vec::with_capacity(locations.len());
+        for location in locations {
+            values.push(match location {
+                Some(location) => Some(self.read_value("get_group", location)?),
+                None => None,
+            });
         }
+        Ok(GroupSnapshot {
+            generation: self.raw.header.generation,
+            values,
+        })
+    }
 
-        // insert value
-        let value_bytes: Vec<u8> = bincode::serialize(&value)?;
-        let value_index = self.store.create(value_bytes.as_slice())?;
+    /// the value at the location, naming the operation in the error of a
+    /// block that does not deserialize
+    fn read_value(
+        &self,
+        operation: &'static str,
+        location: ValueLocation,
+    ) -> Result<V, Box<dyn Error>> {
+        match location {
+            ValueLocation::Block(value_index) => {
+                let value = self.decode_value(&self.raw.store.read(value_index)?);
+                decode_context(value, &self.raw.store, operation, value_index)
+            }
+            ValueLocation::Inline(value_bytes) => self.decode_value(value_bytes),
+        }
+    }
 
-        // insert key
-        let key_entry = KeyEntry {
-            body: key,
-            value_index,
-        };
-        let key_bytes = bincode::serialize(&key_entry)?;
-        let key_index = self.store.create(key_bytes.as_slice())?;
-        self.lookup.insert(key_entry.body, key_entry.value_index);
+    /// the serialized bytes of a value in pieces of at most one frame, to
+    /// process huge values, like a `Vec<u8>` of several gigabytes, without
+    /// ever holding them in memory as a whole. Concatenated, the pieces
+    /// deserialize to the value.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, Vec<u8>>::new(file)?;
+    /// kv.set(String::from("blob"), vec![0; 10_000])?;
+    /// let mut output = std::io::sink();
+    /// if let Some(chunks) = kv.get_chunked(&String::from("blob")) {
+    ///     for chunk in chunks {
+    ///         std::io::Write::write_all(&mut output, &chunk?)?;
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_chunked(&self, key: &K) -> Option<Chunks<'_>> {
+        self.raw.get_chunked(key)
+    }
 
-        // update header
-        self.header.key_indices.push(key_index);
-        self.save_header()?;
-        Ok(())
+    /// whether a value is stored for the key, without reading the value.
+    ///
+    /// Note: with a disk index, this reads the bucket of the key from the
+    /// file, and a failing read counts as a missing key.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.raw.contains_key(key)
     }
 
-    pub fn remove(&mut self, key: &K) -> Result<(), Box<dyn Error>> {
-        let mut hit: Option<KeyEntry<K>> = None;
-        let mut hit_index: Option<usize> = None;
-        for index in self.header.key_indices.iter() {
-            let key_bytes = self.store.read(*index)?;
-            let key_entry: KeyEntry<K> = bincode::deserialize_from(key_bytes.as_slice())?;
-            if key_entry.body == *key {
-                hit = Some(key_entry);
-                hit_index = Some(*index);
+    /// whether any key maps to the given value.
+    ///
+    /// Note: this is an `O(n)` scan that reads and deserializes one value
+    /// after another until it finds a match, so use it with care on large
+    /// databases.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, i32>::new(file)?;
+    /// kv.set(String::from("key"), 42)?;
+    /// let found = kv.contains_value(&42)?; // true
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn contains_value(&self, value: &V) -> Result<bool, Box<dyn Error>>
+    where
+        V: PartialEq,
+    {
+        self.raw.check_poisoned()?;
+        for value_index in self.raw.value_blocks() {
+            let value_bytes = self.raw.store.read(value_index?)?;
+            if self.decode_value(&value_bytes)? == *value {
+                return Ok(true);
             }
         }
-        if let Some(key_entry) = hit {
-            self.store.delete(key_entry.value_index)?;
-            self.store.delete(hit_index.unwrap())?;
-            self.lookup.remove(&key_entry.body);
-            let index_position = self
-                .header
-                .key_indices
-                .iter()
-                .position(|&x| x == hit_index.unwrap())
-                .unwrap();
-            self.header.key_indices.remove(index_position);
-            self.save_header()?;
+        for (_, value_bytes) in self.raw.header.inline_entries.iter() {
+            if self.decode_value(value_bytes)? == *value {
+                return Ok(true);
+            }
         }
-        Ok(())
+        Ok(false)
     }
-}
 
-#[derive(Serialize, Deserialize, Debug, Default)]
-struct Header {
-    key_indices: Vec<usize>,
-}
+    /// decode items that no longer deserialize as `V`, e.g. after a field
+    /// was added to it, with the given migrator instead of failing. This
+    /// applies to every read of this handle, use
+    /// [`rewrite_all`](Self::rewrite_all) to upgrade the stored values for good.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// // values were plain numbers before they got a unit
+    /// let kv = wired::KeyValue::<String, (u32, String)>::new(file)?.with_migrator(|bytes| {
+    ///     let amount: u32 = bincode::deserialize(bytes)?;
+    ///     Ok((amount, String::from("kg")))
+    /// });
+    /// let value = kv.get(&String::from("weight"))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_migrator(mut self, migrator: Migrator<V>) -> Self {
+        self.migrator = Some(migrator);
+        self
+    }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
-struct KeyEntry<K> {
-    body: K,
-    value_index: usize,
-}
+    /// a hash of the logical contents of the map, see
+    /// [`Queue::content_hash`](crate::Queue::content_hash). The entries get
+    /// hashed ordered by the bytes of their keys serialized with `bincode`,
+    /// so neither the hasher, the order of insertion nor an index changes
+    /// it, each key followed by a hash of its serialized value. Inline
+    /// values hash like values in blocks of their own, and a value block
+    /// shared by several keys after `compact_dedup` counts for each of them.
+    ///
+    /// Note: this is an `O(n)` operation that reads every single entry and
+    /// keeps all serialized keys in memory to sort them.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// # let backup = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, u64>::new(file)?;
+    /// kv.set(String::from("answer"), 42)?;
+    /// let mut copy = wired::KeyValue::<String, u64>::new(backup)?;
+    /// copy.set(String::from("answer"), 42)?;
+    /// assert_eq!(kv.content_hash()?, copy.content_hash()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn content_hash(&self) -> Result<u64, Box<dyn Error>> {
+        self.raw.check_poisoned()?;
+        let mut entries = vec![];
+        for entry in self.raw.raw_entries() {
+            let (key, value_bytes) = entry?;
+            let mut value_hash = ContentHash::new();
+            value_hash.record(&value_bytes);
+            entries.push((bincode::serialize(&key)?, value_hash.finish()));
+        }
+        entries.sort_unstable();
+        let mut hash = ContentHash::new();
+        for (key_bytes, value_hash) in entries {
+            hash.record(&key_bytes);
+            hash.record(&value_hash.to_le_bytes());
+        }
+        Ok(hash.finish())
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Copy only the entries the filter accepts into a brand-new database at
+    /// the target path, which must not exist yet. Entries get written in
+    /// batches as they are read, so memory stays bounded by a batch and the
+    /// lookup of the copy. Nothing else ever touches the new file, so not
+    /// even remnants of the skipped entries can be found in it, and the
+    /// source stays untouched.
+    ///
+    /// Values that only decode through the migrator get written as the
+    /// current type. The copy uses the integer encoding of the source, but
+    /// neither inline values nor a disk index. On failure the partially
+    /// written copy gets deleted again.
+    ///
+    /// Note: this is an `O(n)` operation that reads every single entry.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, u64>::new(file)?;
+    /// kv.set(String::from("shared/answer"), 42)?;
+    /// kv.set(String::from("private/secret"), 7)?;
+    /// let report = kv.export_filtered("path/to/partner.wired", |key, _| {
+    ///     key.starts_with("shared/")
+    /// })?; // exported: 1, skipped: 1
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn export_filtered<P, F>(&self, target: P, mut f: F) -> Result<ExportReport, Box<dyn Error>>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.raw.check_poisoned()?;
+        let target = target.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(target)?;
+        let encoding = self.raw.header.encoding;
+        let result = (|| {
+            let options = Options::new()
+                .int_encoding(encoding)
+                .deterministic(self.raw.deterministic);
+            let store = BlockStorage::with_options(file, &options)?;
+            let hasher = self.raw.lookup.hasher().clone();
+            let mut export = RawKeyValue::<K, S>::open(store, &options, false, hasher)?;
+            let mut report = ExportReport::default();
+            let mut batch = vec![];
+            for entry in self.raw.raw_entries() {
+                let (key, value_bytes) = entry?;
+                let value = self.decode_value(&value_bytes)?;
+                if !f(&key, &value) {
+                    report.skipped += 1;
+                    continue;
+                }
+                let value_bytes = encoding.serialize(&value)?;
+                report.exported += 1;
+                report.payload_bytes +=
+                    bincode::serialized_size(&key)? as usize + value_bytes.len();
+                batch.push((key, value_bytes));
+                if batch.len() == EXPORT_BATCH {
+                    export.insert_new(std::mem::take(&mut batch))?;
+                }
+            }
+            export.insert_new(batch)?;
+            export.save_index()?;
+            export.store.shrink_to_fit()?;
+            export.store.flush()?;
+            Ok(report)
+        })();
+        match result {
+            Ok(mut report) => {
+                report.file_bytes = std::fs::metadata(target)?.len() as usize;
+                Ok(report)
+            }
+            Err(error) => {
+                // a failure here only leaves the incomplete copy behind
+                let _ = std::fs::remove_file(target);
+                Err(error)
+            }
+        }
+    }
 
-    #[test]
-    fn works() {
-        // setup db
-        let file = tempfile::tempfile().expect("could not create tempfile");
-        let mut kv = KeyValue::<i32, i32>::new(file).expect("could not create");
-        assert_eq!(kv.len(), 0);
-        assert_eq!(kv.keys(), Vec::<&i32>::new());
+    /// write every value back that only decodes through the migrator, as
+    /// the current type, so later reads need no migrator anymore. Values
+    /// keep their blocks, so no key moves. Returns the number of rewritten
+    /// values.
+    ///
+    /// Note: this is an `O(n)` operation that reads every single value.
+    pub fn rewrite_all(&mut self) -> Result<usize, Box<dyn Error>> {
+        self.modify(|kv| {
+            kv.clear_cached();
+            let encoding = kv.raw.header.encoding;
+            let mut rewritten = 0;
+            let mut value_indices: Vec<usize> = kv.raw.value_blocks().collect::<Result<_, _>>()?;
+            // front to back, which also keeps any growing value from
+            // depending on the order of the lookup
+            value_indices.sort_unstable();
+            for index in value_indices {
+                let bytes = kv.raw.store.read(index)?;
+                if encoding.deserialize::<V>(&bytes).is_err() {
+                    let value = kv.decode_value(&bytes)?;
+                    kv.raw.store.update(index, &encoding.serialize(&value)?)?;
+                    rewritten += 1;
+                }
+            }
+            let mut inline_rewritten = 0;
+            for position in 0..kv.raw.header.inline_entries.len() {
+                let bytes = &kv.raw.header.inline_entries[position].1;
+                if encoding.deserialize::<V>(bytes).is_err() {
+                    let value = kv.decode_value(bytes)?;
+                    kv.raw.header.inline_entries[position].1 = encoding.serialize(&value)?;
+                    inline_rewritten += 1;
+                }
+            }
+            if rewritten + inline_rewritten > 0 {
+                kv.raw.header.usage = Some(kv.raw.measure_usage()?);
+                kv.raw.save_header()?;
+            }
+            Ok(rewritten + inline_rewritten)
+        })
+    }
 
-        // insert data
-        kv.set(17, 42).expect("can not set");
-        assert_eq!(kv.len(), 1);
-        assert_eq!(kv.keys(), vec![&17]);
+    /// store every distinct value only once: keys with identical serialized
+    /// values get pointed at the same value block, and the duplicate blocks
+    /// get deleted. The header counts the keys of every shared block, so it
+    /// is only deleted along with the last key referring to it. Returns the
+    /// number of deleted value blocks.
+    ///
+    /// The counts get saved before any key moves and only shrink to their
+    /// exact values afterwards, so a crash in between leaks blocks at worst.
+    /// Inline entries live within the header and stay as they are.
+    ///
+    /// Note: this is an `O(n)` operation that reads every single value.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, String>::new(file)?;
+    /// kv.set(String::from("monday"), String::from("same config"))?;
+    /// kv.set(String::from("tuesday"), String::from("same config"))?;
+    /// let deleted = kv.compact_dedup()?; // 1
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn compact_dedup(&mut self) -> Result<usize, Box<dyn Error>> {
+        self.modify(|kv| {
+            kv.clear_cached();
+            kv.raw.compact_dedup()
+        })
+    }
 
-        // read data
-        let v = kv.get(&17).expect("can not get");
-        assert_eq!(v, Some(42));
+    /// delete orphaned blocks that no entry refers to, like the old value
+    /// of an overwrite whose cleanup failed, see
+    /// [`Queue::gc`](crate::Queue::gc). Every key block gets read to find
+    /// its value block, so a key that does not decode fails it before
+    /// anything got deleted.
+    pub fn gc(&mut self) -> Result<GcReport, Box<dyn Error>> {
+        self.modify(|kv| {
+            kv.clear_cached();
+            kv.raw.gc()
+        })
+    }
 
-        // update data
-        kv.set(17, 101).expect("can not set");
-        assert_eq!(kv.len(), 1);
-        assert_eq!(kv.keys(), vec![&17]);
+    fn decode_value(&self, bytes: &[u8]) -> Result<V, Box<dyn Error>> {
+        decode_migrating(self.raw.header.encoding, self.migrator, bytes)
+    }
 
-        // read data again
-        let v = kv.get(&17).expect("can not get");
-        assert_eq!(v, Some(101));
+    /// insert a new key or overwrite the value of an existing one.
+    ///
+    /// New blocks get written before the header that references them, and
+    /// replaced blocks get deleted only afterwards. When any step fails, the
+    /// file and this handle both keep the previous state.
+    pub fn set(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        self.modify(|kv| {
+            let value_bytes: Vec<u8> = kv.raw.header.encoding.serialize(&value)?;
+            kv.invalidate_cached(&key)?;
+            kv.raw.set_bytes(key, value_bytes)
+        })
+    }
 
-        // remove data
-        kv.remove(&17).expect("could not remove");
-        assert_eq!(kv.len(), 0);
-        assert_eq!(kv.keys(), Vec::<&i32>::new());
-        let v = kv.get(&17).expect("can not get");
-        assert_eq!(v, None);
+    /// store the given bytes as the value of a key, bypassing the codec:
+    /// they get stored exactly as they are, including no bytes at all, and
+    /// [`get_raw`](Self::get_raw) returns them unchanged.
+    ///
+    /// Nothing checks that the bytes decode as `V`. A typed read of a value
+    /// that does not fails with the decode error, or goes to the migrator
+    /// if there is one, just like for a value of an older type.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, Vec<u8>>::new(file)?;
+    /// kv.set_raw(String::from("proxied"), b"opaque bytes")?;
+    /// kv.set_raw(String::from("empty"), &[])?;
+    /// let bytes = kv.get_raw(&String::from("empty"))?; // Some([])
+    /// let bytes = kv.get_raw(&String::from("missing"))?; // None
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_raw(&mut self, key: K, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.modify(|kv| {
+            kv.invalidate_cached(&key)?;
+            kv.raw.set_bytes(key, bytes.to_vec())
+        })
+    }
+
+    /// load the value of a key, let the closure change it and store the
+    /// changed value, returning what the closure returned. `None` if the
+    /// key does not exist, without calling the closure.
+    ///
+    /// A value whose serialized length stays the same gets overwritten in
+    /// place, as long as it fits into a single frame and no other key
+    /// shares its block. Otherwise it gets stored like with `set`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, u64>::new(file)?;
+    /// kv.set(String::from("visits"), 41)?;
+    /// let previous = kv.with_value_mut(&String::from("visits"), |visits| {
+    ///     *visits += 1;
+    ///     *visits - 1
+    /// })?; // Some(41)
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_value_mut<R, F>(&mut self, key: &K, f: F) -> Result<Option<R>, Box<dyn Error>>
+    where
+        K: Clone,
+        F: FnOnce(&mut V) -> R,
+    {
+        self.modify(|kv| {
+            let mut value = match kv.raw.locate_value(key)? {
+                Some(location) => kv.read_value("with_value_mut", location)?,
+                None => return Ok(None),
+            };
+            let result = f(&mut value);
+            let value_bytes = kv.raw.header.encoding.serialize(&value)?;
+            kv.invalidate_cached(key)?;
+            if !kv.raw.overwrite_in_place(key, &value_bytes)? {
+                kv.raw.set_bytes(key.clone(), value_bytes)?;
+            }
+            Ok(Some(result))
+        })
+    }
+
+    /// load every value, let the closure change it and store it again, the
+    /// bulk counterpart of [`with_value_mut`](Self::with_value_mut) for
+    /// touching up all values at once. The header gets saved once at the
+    /// end.
+    ///
+    /// A value whose serialized length stays the same gets overwritten in
+    /// place if it fits into a single frame, a value that grows or shrinks
+    /// gets its block rewritten, and an unchanged value is not written at
+    /// all. A value block shared by several keys since `compact_dedup`
+    /// goes through the closure only once, so all of them keep sharing it.
+    ///
+    /// Note: this is an `O(n)` operation that reads every single value.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, String>::new(file)?;
+    /// kv.set(String::from("name"), String::from("  padded  "))?;
+    /// kv.map_values(|value| *value = value.trim().to_string())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn map_values<F>(&mut self, mut f: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnMut(&mut V),
+    {
+        self.modify(|kv| {
+            kv.clear_cached();
+            let encoding = kv.raw.header.encoding;
+            // every value block with the number of keys referring to it
+            let mut blocks: BTreeMap<usize, u64> = BTreeMap::new();
+            for index in kv.raw.value_blocks() {
+                *blocks.entry(index?).or_insert(0) += 1;
+            }
+            let (mut added, mut removed) = (Usage::default(), Usage::default());
+            let mut overwrites = 0;
+            for (index, keys) in blocks {
+                let bytes = kv.raw.store.read(index)?;
+                let mut value = kv.decode_value(&bytes)?;
+                f(&mut value);
+                let updated = encoding.serialize(&value)?;
+                if updated == bytes {
+                    continue;
+                }
+                removed.add(kv.raw.value_usage(index)?);
+                if kv.raw.fits_in_place(index, updated.len())? {
+                    kv.raw.store.patch(index, 0, &updated)?;
+                } else {
+                    kv.raw.store.update(index, &updated)?;
+                }
+                added.add(kv.raw.value_usage(index)?);
+                overwrites += keys;
+            }
+            for position in 0..kv.raw.header.inline_entries.len() {
+                let (key, bytes) = &kv.raw.header.inline_entries[position];
+                let mut value = kv.decode_value(bytes)?;
+                f(&mut value);
+                let updated = encoding.serialize(&value)?;
+                if updated == *bytes {
+                    continue;
+                }
+                removed.add(RawKeyValue::<K, S>::inline_usage(key, bytes)?);
+                added.add(RawKeyValue::<K, S>::inline_usage(key, &updated)?);
+                kv.raw.header.inline_entries[position].1 = updated;
+                overwrites += 1;
+            }
+            if overwrites == 0 {
+                return Ok(());
+            }
+            kv.raw.apply_usage((added, removed));
+            kv.raw.counters_mut().overwrites += overwrites;
+            kv.raw.header.generation += 1;
+            // grown inline entries may no longer fit into the header
+            let inline = &kv.raw.header.inline_entries;
+            if !inline.is_empty()
+                && bincode::serialized_size(inline)? as usize > kv.raw.inline_threshold
+            {
+                return kv.raw.spill_inline();
+            }
+            kv.raw.save_header()
+        })
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<(), Box<dyn Error>> {
+        self.modify(|kv| {
+            kv.invalidate_cached(key)?;
+            kv.raw.remove_entry(key, None)
+        })
+    }
+
+    /// keep only the entries the closure accepts and remove all others,
+    /// like `HashMap::retain`. Every value gets read and decoded once per
+    /// key, and the header gets saved once for all removed keys, which
+    /// makes this much cheaper than removing them one by one.
+    ///
+    /// With a disk index, the removed keys get collected first and then
+    /// removed one by one, just like with `remove`.
+    ///
+    /// Note: this is an `O(n)` operation that reads every single entry.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, u64>::new(file)?;
+    /// kv.set(String::from("fresh"), 1)?;
+    /// kv.set(String::from("stale"), 0)?;
+    /// kv.retain(|_, hits| *hits > 0)?; // only "fresh" is left
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn retain<F>(&mut self, mut f: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.modify(|kv| {
+            kv.clear_cached();
+            let (encoding, migrator) = (kv.raw.header.encoding, kv.migrator);
+            kv.raw
+                .retain(&mut |key, bytes| Ok(f(key, &decode_migrating(encoding, migrator, bytes)?)))
+        })
+    }
+
+    /// remove all keys and shrink the file back to its header, see
+    /// [`Queue::clear_and_shrink`](crate::Queue::clear_and_shrink). The keys
+    /// count as removed, and a disk index starts over with as many buckets.
+    pub fn clear_and_shrink(&mut self) -> Result<(), Box<dyn Error>> {
+        self.modify(|kv| {
+            kv.clear_cached();
+            kv.raw.clear_and_shrink()
+        })
+    }
+
+    /// remove a key and return its value, like `HashMap::remove`. Returns
+    /// `None` without changing anything if the key does not exist.
+    ///
+    /// Unlike a `get` followed by a `remove`, this looks up the key once
+    /// and the value gets decoded before anything is removed, so a value
+    /// that fails to decode stays in place.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut kv = wired::KeyValue::<String, u32>::new(file)?;
+    /// kv.set(String::from("job"), 42)?;
+    /// let value = kv.take(&String::from("job"))?; // Some(42)
+    /// let value = kv.take(&String::from("job"))?; // None
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn take(&mut self, key: &K) -> Result<Option<V>, Box<dyn Error>> {
+        self.modify(|kv| {
+            kv.invalidate_cached(key)?;
+            let (encoding, migrator) = (kv.raw.header.encoding, kv.migrator);
+            let mut value = None;
+            kv.raw.remove_entry(
+                key,
+                Some(&mut |bytes: &[u8]| {
+                    value = Some(decode_migrating(encoding, migrator, bytes)?);
+                    Ok(())
+                }),
+            )?;
+            Ok(value)
+        })
+    }
+}
+
+impl<K, S> RawKeyValue<K, S>
+where
+    K: Serialize + Hash + Eq,
+    for<'de> K: Deserialize<'de>,
+    S: BuildHasher + Clone,
+{
+    /// register the store as a key value database, bring the header of an
+    /// older file up to date and fill the lookup
+    fn open(
+        mut store: BlockStorage,
+        options: &Options,
+        rebuild_index: bool,
+        hasher: S,
+    ) -> Result<Self, Box<dyn Error>> {
+        store.register("KeyValue")?;
+        let is_new_file = store.is_empty();
+        let header = Self::read_header(&mut store, options.int_encoding)?;
+        let mut kv = Self {
+            store,
+            header,
+            lookup: HashMap::with_hasher(hasher),
+            index_bytes: 0,
+            max_index_bytes: options.max_index_bytes,
+            inline_threshold: options.inline_values,
+            deterministic: options.deterministic,
+            index_dirty: false,
+            modifying: false,
+            key_type: PhantomData,
+        };
+        if let (true, Some(buckets)) = (is_new_file, options.disk_index) {
+            kv.header.disk_index = Some(DiskIndex::create(&mut kv.store, buckets)?);
+            kv.save_header()?;
+        }
+        if kv.header.counters.is_none() {
+            // files written before counters existed start with the current state
+            kv.header.counters = Some(KeyValueCounters {
+                inserts: kv.len() as u64,
+                ..KeyValueCounters::default()
+            });
+            kv.save_header()?;
+        }
+        if kv.header.usage.is_none() {
+            kv.header.usage = Some(kv.measure_usage()?);
+            kv.save_header()?;
+        }
+        let in_memory = kv.header.disk_index.is_none();
+        if in_memory && (rebuild_index || !kv.load_index()?) {
+            kv.rebuild_index(&mut progress::ignore)?;
+        }
+        Ok(kv)
+    }
+
+    /// fail while a panic left a modification half done, see `KeyValue::modify`
+    fn check_poisoned(&self) -> Result<(), Box<dyn Error>> {
+        if self.modifying {
+            return Err(WiredError::Poisoned.into());
+        }
+        Ok(())
+    }
+
+    /// replace the header and the lookup by the ones of the file, after a
+    /// panic interrupted a modification
+    fn reload(&mut self) -> Result<(), Box<dyn Error>> {
+        self.header = decode_header(&self.store.read(0)?)?;
+        self.lookup.clear();
+        self.index_bytes = 0;
+        self.index_dirty = false;
+        if self.header.disk_index.is_none() && !self.load_index()? {
+            self.rebuild_index(&mut progress::ignore)?;
+        }
+        self.modifying = false;
+        Ok(())
+    }
+
+    /// fill the lookup from the persisted index block, if it is up to date
+    fn load_index(&mut self) -> Result<bool, Box<dyn Error>> {
+        if self.header.index_block == 0 || self.header.index_generation != self.header.generation {
+            return Ok(false);
+        }
+        let bytes = self.store.read(self.header.index_block)?;
+        match self.header.encoding.deserialize::<Vec<(K, usize)>>(&bytes) {
+            Ok(entries) if entries.len() == self.header.key_indices.len() => {
+                let mut lookup =
+                    HashMap::with_capacity_and_hasher(entries.len(), self.lookup.hasher().clone());
+                lookup.extend(entries);
+                self.lookup = lookup;
+                self.index_bytes = self.lookup.keys().map(Self::index_entry_bytes).sum();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn rebuild_index(&mut self, progress: &mut dyn ProgressSink) -> Result<(), Box<dyn Error>> {
+        let total = self.header.key_indices.len();
+        let mut lookup = HashMap::with_capacity_and_hasher(total, self.lookup.hasher().clone());
+        for (done, index) in self.header.key_indices.iter().enumerate() {
+            let bytes = self.store.read(*index)?;
+            let entry: KeyEntry<K> = self.header.encoding.deserialize(&bytes)?;
+            lookup.insert(entry.body, entry.value_index);
+            progress::checkpoint(progress, done + 1, total)?;
+        }
+        self.lookup = lookup;
+        self.index_bytes = self.lookup.keys().map(Self::index_entry_bytes).sum();
+        self.index_dirty = true;
+        Ok(())
+    }
+
+    fn index_entry_bytes(key: &K) -> usize {
+        let key_bytes = bincode::serialized_size(key).unwrap_or(0) as usize;
+        key_bytes + std::mem::size_of::<(K, usize)>()
+    }
+
+    /// fail with `WiredError::IndexFull` when the lookup can not grow by
+    /// the given keys without exceeding the configured limit
+    fn ensure_index_capacity<'a>(
+        &self,
+        keys: impl Iterator<Item = &'a K>,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        K: 'a,
+    {
+        if let Some(limit) = self.max_index_bytes {
+            let added: usize = keys.map(Self::index_entry_bytes).sum();
+            if self.index_bytes + added > limit {
+                return Err(WiredError::IndexFull { limit }.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// persist the lookup into the index block and mark it as up to date
+    fn save_index(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut entries: Vec<(&K, &usize)> = self.lookup.iter().collect();
+        if self.deterministic {
+            // keys sharing a value block after `compact_dedup` tie on it,
+            // their bytes break the tie, as the keys need not be ordered.
+            // A key that fails to serialize fails right below anyway.
+            entries.sort_by_cached_key(|(key, index)| {
+                (**index, bincode::serialize(key).unwrap_or_default())
+            });
+        }
+        let bytes = self.header.encoding.serialize(&entries)?;
+        if self.header.index_block == 0 {
+            self.header.index_block = self.store.create(bytes.as_slice())?;
+        } else {
+            self.store
+                .update(self.header.index_block, bytes.as_slice())?;
+        }
+        self.header.index_generation = self.header.generation;
+        self.save_header()?;
+        self.index_dirty = false;
+        Ok(())
+    }
+
+    /// read the header, or create it with the given encoding for a new file
+    fn read_header(
+        store: &mut BlockStorage,
+        encoding: IntEncoding,
+    ) -> Result<Header<K>, Box<dyn Error>> {
+        let bytes = store.read(0)?;
+        if store.is_empty() {
+            let header = Header {
+                encoding,
+                ..Header::default()
+            };
+            let bytes: Vec<u8> = bincode::serialize(&header)?;
+            store.create(bytes.as_slice())?;
+            Ok(header)
+        } else {
+            decode_header(bytes.as_slice())
+        }
+    }
+
+    fn save_header(&mut self) -> Result<(), Box<dyn Error>> {
+        let bytes: Vec<u8> = bincode::serialize(&self.header)?;
+        self.store.update(0, bytes.as_slice())
+    }
+
+    /// save the header after a modification, which outdates the persisted
+    /// index. On failure the header reverts to the snapshot taken before the
+    /// modification, which matches the one still on disk.
+    fn save_changes(&mut self, snapshot: HeaderSnapshot) -> Result<(), Box<dyn Error>> {
+        self.header.generation += 1;
+        if let Err(error) = self.save_header() {
+            snapshot.restore(&mut self.header);
+            return Err(error);
+        }
+        // a disk index is never outdated
+        self.index_dirty = self.header.disk_index.is_none();
+        Ok(())
+    }
+
+    fn snapshot(&self) -> HeaderSnapshot {
+        HeaderSnapshot {
+            key_indices: self.header.key_indices.clone(),
+            counters: self.header.counters,
+            generation: self.header.generation,
+            disk_len: self.header.disk_index.as_ref().map(|disk| disk.len),
+            usage: self.header.usage,
+            shared_values: self.header.shared_values.clone(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        let on_disk = self.header.disk_index.as_ref().map_or(0, |disk| disk.len);
+        self.header.key_indices.len() + self.header.inline_entries.len() + on_disk
+    }
+
+    fn logical_size(&self) -> Result<usize, Box<dyn Error>> {
+        let mut size = 0;
+        for index in self.key_blocks() {
+            size += self.store.record_size(index?)?;
+        }
+        for value_index in self.value_blocks() {
+            size += self.store.record_size(value_index?)?;
+        }
+        for (key, value_bytes) in self.header.inline_entries.iter() {
+            size += bincode::serialized_size(key)? as usize + value_bytes.len();
+        }
+        Ok(size)
+    }
+
+    fn reset_counters(&mut self) -> Result<(), Box<dyn Error>> {
+        self.header.counters = Some(KeyValueCounters::default());
+        self.save_header()
+    }
+
+    fn counters_mut(&mut self) -> &mut KeyValueCounters {
+        self.header
+            .counters
+            .get_or_insert_with(KeyValueCounters::default)
+    }
+
+    fn usage_mut(&mut self) -> &mut Usage {
+        self.header.usage.get_or_insert_with(Usage::default)
+    }
+
+    /// the usage of an entry stored in blocks, from the frame headers only.
+    /// A value block shared with other keys does not count, as it stays
+    /// when the entry goes.
+    fn block_usage(&self, key_index: usize, value_index: usize) -> Result<Usage, Box<dyn Error>> {
+        let (key_bytes, key_frames) = self.store.record_extent(key_index)?;
+        // the key entry ends with the index of the value block
+        let pointer = self.header.encoding.serialize(&value_index)?.len();
+        let mut usage = Usage {
+            payload_bytes: (key_bytes - pointer) as u64,
+            frames: key_frames as u64,
+        };
+        if !self.header.shared_values.contains_key(&value_index) {
+            usage.add(self.value_usage(value_index)?);
+        }
+        Ok(usage)
+    }
+
+    fn value_usage(&self, value_index: usize) -> Result<Usage, Box<dyn Error>> {
+        let (value_bytes, value_frames) = self.store.record_extent(value_index)?;
+        Ok(Usage {
+            payload_bytes: value_bytes as u64,
+            frames: value_frames as u64,
+        })
+    }
+
+    /// the usage of an entry stored within the header
+    fn inline_usage(key: &K, value_bytes: &[u8]) -> Result<Usage, Box<dyn Error>> {
+        let key_bytes = bincode::serialized_size(key)? as usize;
+        Ok(Usage {
+            payload_bytes: (key_bytes + value_bytes.len()) as u64,
+            frames: 0,
+        })
+    }
+
+    /// the usage of newly created blocks and of the blocks they replace.
+    /// Nothing references the new blocks yet, so they get deleted again
+    /// on failure.
+    fn usage_change(
+        &mut self,
+        created: (usize, usize),
+        replaced: Option<(usize, usize)>,
+    ) -> Result<(Usage, Usage), Box<dyn Error>> {
+        let change = self.block_usage(created.0, created.1).and_then(|added| {
+            let removed = match replaced {
+                Some((key_index, value_index)) => self.block_usage(key_index, value_index)?,
+                None => Usage::default(),
+            };
+            Ok((added, removed))
+        });
+        if change.is_err() {
+            self.delete_blocks(created.0, created.1);
+        }
+        change
+    }
+
+    fn apply_usage(&mut self, (added, removed): (Usage, Usage)) {
+        let usage = self.usage_mut();
+        usage.remove(removed);
+        usage.add(added);
+    }
+
+    /// add up the usage of all entries, for files that predate tracking it
+    fn measure_usage(&self) -> Result<Usage, Box<dyn Error>> {
+        let mut usage = Usage::default();
+        for key_index in self.key_blocks() {
+            let key_index = key_index?;
+            let key_bytes = self.store.read(key_index)?;
+            let key_entry: KeyEntry<K> = self.header.encoding.deserialize(&key_bytes)?;
+            usage.add(self.block_usage(key_index, key_entry.value_index)?);
+        }
+        for value_index in self.header.shared_values.keys() {
+            usage.add(self.value_usage(*value_index)?);
+        }
+        for (key, value_bytes) in self.header.inline_entries.iter() {
+            usage.add(Self::inline_usage(key, value_bytes)?);
+        }
+        Ok(usage)
+    }
+
+    fn keys(&self) -> Vec<&K> {
+        let mut result: Vec<&K> = vec![];
+        for key in self.lookup.keys() {
+            result.push(key);
+        }
+        for (key, _) in self.header.inline_entries.iter() {
+            result.push(key);
+        }
+        result
+    }
+
+    fn scan_raw<'a, F>(
+        &'a self,
+        mut filter: F,
+    ) -> impl Iterator<Item = Result<(K, Vec<u8>), Box<dyn Error>>> + 'a
+    where
+        F: FnMut(&K, &[u8]) -> bool + 'a,
+    {
+        self.raw_entries().filter(move |entry| match entry {
+            Ok((key, value_bytes)) => filter(key, value_bytes),
+            Err(_) => true,
+        })
+    }
+
+    /// all keys along with their serialized values, in insertion order
+    fn raw_entries(&self) -> impl Iterator<Item = Result<(K, Vec<u8>), Box<dyn Error>>> + '_ {
+        let blocks = self.key_blocks().map(move |index| {
+            let key_bytes = self.store.read(index?)?;
+            let key_entry: KeyEntry<K> = self.header.encoding.deserialize(&key_bytes)?;
+            let value_bytes = self.store.read(key_entry.value_index)?;
+            Ok((key_entry.body, value_bytes))
+        });
+        let inline = self
+            .header
+            .inline_entries
+            .iter()
+            .map(move |(key, value_bytes)| {
+                // keys are not `Clone`, so an owned copy comes from a round trip
+                let key = bincode::deserialize(&bincode::serialize(key)?)?;
+                Ok((key, value_bytes.clone()))
+            });
+        blocks.chain(inline)
+    }
+
+    fn list(&self) -> impl Iterator<Item = Result<KeySummary<K>, Box<dyn Error>>> + '_ {
+        self.list_page(0, self.len())
+    }
+
+    fn list_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> impl Iterator<Item = Result<KeySummary<K>, Box<dyn Error>>> + '_ {
+        let blocks = self.key_blocks().skip(offset).map(move |index| {
+            let key_bytes = self.store.read(index?)?;
+            let key_entry: KeyEntry<K> = self.header.encoding.deserialize(&key_bytes)?;
+            let (value_bytes, frames) = self.store.record_extent(key_entry.value_index)?;
+            Ok(KeySummary {
+                key: key_entry.body,
+                value_bytes,
+                frames,
+            })
+        });
+        let block_count = self.len() - self.header.inline_entries.len();
+        let inline_offset = offset.saturating_sub(block_count);
+        let inline =
+            self.header
+                .inline_entries
+                .iter()
+                .skip(inline_offset)
+                .map(|(key, value_bytes)| {
+                    // keys are not `Clone`, so an owned copy comes from a round trip
+                    let key = bincode::deserialize(&bincode::serialize(key)?)?;
+                    Ok(KeySummary {
+                        key,
+                        value_bytes: value_bytes.len(),
+                        frames: 0,
+                    })
+                });
+        blocks.chain(inline).take(limit)
+    }
+
+    /// the key blocks of all entries, in insertion order or in the order of
+    /// the buckets of a disk index
+    fn key_blocks(&self) -> Box<dyn Iterator<Item = Result<usize, Box<dyn Error>>> + '_> {
+        match &self.header.disk_index {
+            Some(disk) => Box::new(
+                disk.slots(&self.store)
+                    .map(|slot| slot.map(|slot| slot.key_index)),
+            ),
+            None => Box::new(self.header.key_indices.iter().map(|index| Ok(*index))),
+        }
+    }
+
+    /// the value blocks of all entries, in no particular order
+    fn value_blocks(&self) -> Box<dyn Iterator<Item = Result<usize, Box<dyn Error>>> + '_> {
+        match &self.header.disk_index {
+            Some(disk) => Box::new(
+                disk.slots(&self.store)
+                    .map(|slot| slot.map(|slot| slot.value_index)),
+            ),
+            None => Box::new(self.lookup.values().map(|index| Ok(*index))),
+        }
+    }
+
+    /// the bucket of a key within the disk index, and the position of the
+    /// key within that bucket if it is stored
+    fn disk_bucket(&self, disk: &DiskIndex, key: &K) -> Result<DiskBucket, Box<dyn Error>> {
+        let hash = DiskIndex::hash(&bincode::serialize(key)?);
+        let slots = disk.read_bucket(&self.store, hash)?;
+        for (position, slot) in slots.iter().enumerate() {
+            if slot.hash != hash {
+                continue;
+            }
+            let key_bytes = self.store.read(slot.key_index)?;
+            let key_entry: KeyEntry<K> = self.header.encoding.deserialize(&key_bytes)?;
+            if key_entry.body == *key {
+                let position = Some(position);
+                return Ok(DiskBucket {
+                    hash,
+                    slots,
+                    position,
+                });
+            }
+        }
+        let position = None;
+        Ok(DiskBucket {
+            hash,
+            slots,
+            position,
+        })
+    }
+
+    /// the value block of a key, from the lookup or the disk index
+    fn value_index(&self, key: &K) -> Result<Option<usize>, Box<dyn Error>> {
+        match &self.header.disk_index {
+            Some(disk) => {
+                let bucket = self.disk_bucket(disk, key)?;
+                Ok(bucket
+                    .position
+                    .map(|position| bucket.slots[position].value_index))
+            }
+            None => Ok(self.lookup.get(key).copied()),
+        }
+    }
+
+    /// where the value of a key is stored, `None` for a missing key
+    fn locate_value(&self, key: &K) -> Result<Option<ValueLocation<'_>>, Box<dyn Error>> {
+        if let Some(value_index) = self.value_index(key)? {
+            Ok(Some(ValueLocation::Block(value_index)))
+        } else {
+            let inline = self.find_inline(key);
+            Ok(inline.map(|(_, value_bytes)| ValueLocation::Inline(value_bytes)))
+        }
+    }
+
+    fn get_borrowed(&self, key: &K) -> Result<Option<Borrowed<'_>>, Box<dyn Error>> {
+        let bytes = match self.locate_value(key)? {
+            Some(ValueLocation::Block(value_index)) => self.store.read_borrowed(value_index)?,
+            Some(ValueLocation::Inline(value_bytes)) => Cow::Borrowed(value_bytes),
+            None => return Ok(None),
+        };
+        Ok(Some(Borrowed::new(bytes, self.header.encoding)))
+    }
+
+    fn get_chunked(&self, key: &K) -> Option<Chunks<'_>> {
+        match self.value_index(key) {
+            Ok(Some(value_index)) => Some(Box::new(self.store.read_chunked(value_index))),
+            Ok(None) => {
+                let (_, value_bytes) = self.find_inline(key)?;
+                Some(Box::new(std::iter::once(Ok(value_bytes.clone()))))
+            }
+            Err(error) => Some(Box::new(std::iter::once(Err(error)))),
+        }
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        matches!(self.value_index(key), Ok(Some(_))) || self.find_inline(key).is_some()
+    }
+
+    fn compact_dedup(&mut self) -> Result<usize, Box<dyn Error>> {
+        // the key block and value block of every entry, and the first
+        // value block found with the same content
+        let mut entries: Vec<(usize, usize, usize)> = vec![];
+        let mut distinct: HashMap<u64, Vec<usize>> = HashMap::new();
+        let key_indices: Vec<usize> = self.key_blocks().collect::<Result<_, _>>()?;
+        for key_index in key_indices {
+            let key_bytes = self.store.read(key_index)?;
+            let key_entry: KeyEntry<K> = self.header.encoding.deserialize(&key_bytes)?;
+            let value_index = key_entry.value_index;
+            let value_bytes = self.store.read(value_index)?;
+            let candidates = distinct.entry(DiskIndex::hash(&value_bytes)).or_default();
+            let mut target = None;
+            for candidate in candidates.iter() {
+                if *candidate == value_index || self.store.read(*candidate)? == value_bytes {
+                    target = Some(*candidate);
+                    break;
+                }
+            }
+            let target = target.unwrap_or_else(|| {
+                candidates.push(value_index);
+                value_index
+            });
+            entries.push((key_index, value_index, target));
+        }
+
+        let mut shared: BTreeMap<usize, usize> = BTreeMap::new();
+        for (_, _, target) in entries.iter() {
+            *shared.entry(*target).or_default() += 1;
+        }
+        shared.retain(|_, keys| *keys > 1);
+        let moves: Vec<&(usize, usize, usize)> = entries
+            .iter()
+            .filter(|(_, value_index, target)| value_index != target)
+            .collect();
+        if moves.is_empty() {
+            return Ok(0);
+        }
+        // never count fewer keys than refer to a block while they move
+        for (value_index, keys) in shared.iter() {
+            let counted = self.header.shared_values.entry(*value_index).or_default();
+            *counted = (*counted).max(*keys);
+        }
+        self.save_header()?;
+
+        let mut duplicates = BTreeSet::new();
+        for (key_index, value_index, target) in moves {
+            let key_bytes = self.store.read(*key_index)?;
+            let mut key_entry: KeyEntry<K> = self.header.encoding.deserialize(&key_bytes)?;
+            key_entry.value_index = *target;
+            let key_bytes = self.header.encoding.serialize(&key_entry)?;
+            self.store.update(*key_index, &key_bytes)?;
+            match &self.header.disk_index {
+                Some(disk) => {
+                    let hash = DiskIndex::hash(&bincode::serialize(&key_entry.body)?);
+                    let mut slots = disk.read_bucket(&self.store, hash)?;
+                    for slot in slots.iter_mut().filter(|slot| slot.key_index == *key_index) {
+                        slot.value_index = *target;
+                    }
+                    disk.write_bucket(&mut self.store, hash, &slots)?;
+                }
+                None => {
+                    self.lookup.insert(key_entry.body, *target);
+                }
+            }
+            duplicates.insert(*value_index);
+        }
+
+        let snapshot = self.snapshot();
+        self.header.shared_values = shared;
+        self.header.usage = Some(self.measure_usage()?);
+        self.save_changes(snapshot)?;
+        for value_index in duplicates.iter() {
+            // a failure here only leaks the block
+            let _ = self.store.delete(*value_index);
+        }
+        Ok(duplicates.len())
+    }
+
+    fn gc(&mut self) -> Result<GcReport, Box<dyn Error>> {
+        let mut reachable = HashSet::from([0, self.header.index_block]);
+        if let Some(disk) = &self.header.disk_index {
+            reachable.extend(disk.blocks(&self.store)?);
+        }
+        for key_index in self.key_blocks() {
+            let key_index = key_index?;
+            let key_bytes = self.store.read(key_index)?;
+            let key_entry: KeyEntry<K> = self.header.encoding.deserialize(&key_bytes)?;
+            reachable.insert(key_index);
+            reachable.insert(key_entry.value_index);
+        }
+        collect_garbage(&mut self.store, &reachable)
+    }
+
+    fn find_inline(&self, key: &K) -> Option<&(K, Vec<u8>)> {
+        self.header.inline_entries.iter().find(|(k, _)| k == key)
+    }
+
+    fn is_inline(&self) -> bool {
+        self.inline_threshold > 0
+            && self.header.key_indices.is_empty()
+            && self.header.disk_index.is_none()
+    }
+
+    /// insert or overwrite a key with a serialized value, see `KeyValue::set`
+    fn set_bytes(&mut self, key: K, value_bytes: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        if let Some(disk) = self.header.disk_index.clone() {
+            return self.set_on_disk(disk, key, value_bytes);
+        }
+        if self.is_inline() {
+            return self.set_inline(key, value_bytes);
+        }
+        // inline entries of a file opened without the inline option
+        self.spill_inline()?;
+        let previous = self.find_key_block(&key)?;
+        if previous.is_none() {
+            self.ensure_index_capacity(std::iter::once(&key))?;
+        }
+        let (key_index, value_index) =
+            Self::create_blocks(&mut self.store, self.header.encoding, &key, &value_bytes)?;
+        let replaced = previous
+            .as_ref()
+            .map(|previous| (previous.key_index, previous.value_index));
+        let change = self.usage_change((key_index, value_index), replaced)?;
+        let snapshot = self.snapshot();
+        let mut unshared = true;
+        if let Some(previous) = &previous {
+            self.header.key_indices.remove(previous.position);
+            unshared = self.release_value(previous.value_index);
+        }
+        self.header.key_indices.push(key_index);
+        self.apply_usage(change);
+        self.count_set(previous.is_some());
+        if let Err(error) = self.save_changes(snapshot) {
+            self.delete_blocks(key_index, value_index);
+            return Err(error);
+        }
+        if let Some(previous) = previous {
+            self.delete_released(previous.key_index, previous.value_index, unshared);
+        } else {
+            self.index_bytes += Self::index_entry_bytes(&key);
+        }
+        self.lookup.insert(key, value_index);
+        Ok(())
+    }
+
+    /// overwrite the value block of an existing key with a value of the
+    /// same length, if the block fits into a single frame, so the write can
+    /// not be torn across frames, and belongs to this key alone. Returns
+    /// whether it did, otherwise the value needs a `set_bytes`.
+    fn overwrite_in_place(&mut self, key: &K, value_bytes: &[u8]) -> Result<bool, Box<dyn Error>> {
+        let Some(value_index) = self.value_index(key)? else {
+            return Ok(false);
+        };
+        if self.header.shared_values.contains_key(&value_index)
+            || !self.fits_in_place(value_index, value_bytes.len())?
+        {
+            return Ok(false);
+        }
+        self.store.patch(value_index, 0, value_bytes)?;
+        self.count_set(true);
+        self.header.generation += 1;
+        self.save_header()?;
+        Ok(true)
+    }
+
+    /// whether a value of the given length can overwrite the value block
+    /// without being torn across frames: it has the same length and the
+    /// block is a single frame
+    fn fits_in_place(&self, value_index: usize, len: usize) -> Result<bool, Box<dyn Error>> {
+        Ok(self.store.record_size(value_index)? == len
+            && self.store.record_frames(value_index)? == 1)
+    }
+
+    /// like `set`, but with the disk index: the bucket gets updated after
+    /// the new blocks are written and before the header, and is restored
+    /// when saving the header fails
+    fn set_on_disk(
+        &mut self,
+        mut disk: DiskIndex,
+        key: K,
+        value_bytes: Vec<u8>,
+    ) -> Result<(), Box<dyn Error>> {
+        let DiskBucket {
+            hash,
+            mut slots,
+            position,
+        } = self.disk_bucket(&disk, &key)?;
+        let (key_index, value_index) =
+            Self::create_blocks(&mut self.store, self.header.encoding, &key, &value_bytes)?;
+        let replaced =
+            position.map(|position| (slots[position].key_index, slots[position].value_index));
+        let change = self.usage_change((key_index, value_index), replaced)?;
+        let slot = Slot {
+            hash,
+            key_index,
+            value_index,
+        };
+        let previous = match position {
+            Some(position) => Some(std::mem::replace(&mut slots[position], slot)),
+            None => {
+                slots.push(slot);
+                None
+            }
+        };
+        if let Err(error) = disk.write_bucket(&mut self.store, hash, &slots) {
+            self.delete_blocks(key_index, value_index);
+            return Err(error);
+        }
+        let snapshot = self.snapshot();
+        let unshared = match &previous {
+            Some(previous) => self.release_value(previous.value_index),
+            None => {
+                disk.len += 1;
+                true
+            }
+        };
+        self.header.disk_index = Some(disk);
+        self.count_set(previous.is_some());
+        self.apply_usage(change);
+        if let Err(error) = self.save_changes(snapshot) {
+            match (position, previous) {
+                (Some(position), Some(previous)) => slots[position] = previous,
+                _ => {
+                    slots.pop();
+                }
+            }
+            self.restore_bucket(hash, &slots);
+            self.delete_blocks(key_index, value_index);
+            return Err(error);
+        }
+        if let Some(previous) = previous {
+            self.delete_released(previous.key_index, previous.value_index, unshared);
+        }
+        Ok(())
+    }
+
+    /// write back the previous slots of a bucket after a failed change. A
+    /// failure here leaves the bucket pointing to blocks that get deleted,
+    /// which reads as a missing key.
+    fn restore_bucket(&mut self, hash: u64, slots: &[Slot]) {
+        if let Some(disk) = &self.header.disk_index {
+            let _ = disk.write_bucket(&mut self.store, hash, slots);
+        }
+    }
+
+    fn count_set(&mut self, overwrite: bool) {
+        let counters = self.counters_mut();
+        if overwrite {
+            counters.overwrites += 1;
+        } else {
+            counters.inserts += 1;
+        }
+    }
+
+    fn set_inline(&mut self, key: K, value_bytes: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let added = Self::inline_usage(&key, &value_bytes)?;
+        let removed = match self.find_inline(&key) {
+            Some((key, value_bytes)) => Self::inline_usage(key, value_bytes)?,
+            None => Usage::default(),
+        };
+        let snapshot = self.snapshot();
+        self.apply_usage((added, removed));
+        // an overwritten key moves to the end, just like in the block layout
+        let entries = &mut self.header.inline_entries;
+        let position = entries.iter().position(|(k, _)| *k == key);
+        let replaced = position.map(|position| entries.remove(position));
+        entries.push((key, value_bytes));
+        self.count_set(replaced.is_some());
+
+        // spill all entries into blocks once the map grows too large
+        let result = match bincode::serialized_size(&self.header.inline_entries) {
+            Ok(size) if size as usize > self.inline_threshold => self.spill_inline(),
+            Ok(_) => self.save_changes(self.snapshot()),
+            Err(error) => Err(error.into()),
+        };
+        if result.is_err() {
+            self.header.inline_entries.pop();
+            if let (Some(position), Some(entry)) = (position, replaced) {
+                self.header.inline_entries.insert(position, entry);
+            }
+            snapshot.restore(&mut self.header);
+        }
+        result
+    }
+
+    /// store entries of keys that are not in the map yet, saving the header
+    /// once for all of them. Only for maps without inline entries or a disk
+    /// index. On failure all new blocks get deleted again.
+    fn insert_new(&mut self, entries: Vec<(K, Vec<u8>)>) -> Result<(), Box<dyn Error>> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.ensure_index_capacity(entries.iter().map(|(key, _)| key))?;
+        let mut created: Vec<(usize, usize)> = vec![];
+        let mut added = Usage::default();
+        for (key, value_bytes) in entries.iter() {
+            let usage =
+                Self::create_blocks(&mut self.store, self.header.encoding, key, value_bytes)
+                    .and_then(|(key_index, value_index)| {
+                        created.push((key_index, value_index));
+                        self.block_usage(key_index, value_index)
+                    });
+            match usage {
+                Ok(usage) => added.add(usage),
+                Err(error) => {
+                    for (key_index, value_index) in created {
+                        self.delete_blocks(key_index, value_index);
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        let snapshot = self.snapshot();
+        self.apply_usage((added, Usage::default()));
+        let key_indices = created.iter().map(|(key_index, _)| *key_index);
+        self.header.key_indices.extend(key_indices);
+        self.counters_mut().inserts += entries.len() as u64;
+        if let Err(error) = self.save_changes(snapshot) {
+            for (key_index, value_index) in created {
+                self.delete_blocks(key_index, value_index);
+            }
+            return Err(error);
+        }
+        for ((key, _), (_, value_index)) in entries.into_iter().zip(created) {
+            self.index_bytes += Self::index_entry_bytes(&key);
+            self.lookup.insert(key, value_index);
+        }
+        Ok(())
+    }
+
+    /// move all inline entries into blocks and save the header. On failure
+    /// the entries stay inline and all new blocks get deleted again.
+    fn spill_inline(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.header.inline_entries.is_empty() {
+            return Ok(());
+        }
+        self.ensure_index_capacity(self.header.inline_entries.iter().map(|(key, _)| key))?;
+        let mut created: Vec<(usize, usize)> = vec![];
+        for (key, value_bytes) in self.header.inline_entries.iter() {
+            match Self::create_blocks(&mut self.store, self.header.encoding, key, value_bytes) {
+                Ok(indices) => created.push(indices),
+                Err(error) => {
+                    for (key_index, value_index) in created {
+                        self.delete_blocks(key_index, value_index);
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        let mut change = (Usage::default(), Usage::default());
+        for ((key, value_bytes), (key_index, value_index)) in
+            self.header.inline_entries.iter().zip(created.iter())
+        {
+            let usage = Self::inline_usage(key, value_bytes)
+                .and_then(|inline| Ok((self.block_usage(*key_index, *value_index)?, inline)));
+            match usage {
+                Ok((added, removed)) => {
+                    change.0.add(added);
+                    change.1.add(removed);
+                }
+                Err(error) => {
+                    for (key_index, value_index) in created {
+                        self.delete_blocks(key_index, value_index);
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        let snapshot = self.snapshot();
+        self.apply_usage(change);
+        let entries = std::mem::take(&mut self.header.inline_entries);
+        let key_indices = created.iter().map(|(key_index, _)| *key_index);
+        self.header.key_indices.extend(key_indices);
+        if let Err(error) = self.save_changes(snapshot) {
+            self.header.inline_entries = entries;
+            for (key_index, value_index) in created {
+                self.delete_blocks(key_index, value_index);
+            }
+            return Err(error);
+        }
+        for ((key, _), (_, value_index)) in entries.into_iter().zip(created) {
+            self.index_bytes += Self::index_entry_bytes(&key);
+            self.lookup.insert(key, value_index);
+        }
+        Ok(())
+    }
+
+    /// store value and key entry in their own blocks, returning the indices
+    /// of both. Nothing references them until the header gets saved, so the
+    /// value block is deleted again when the key block can not be written.
+    fn create_blocks(
+        store: &mut BlockStorage,
+        encoding: IntEncoding,
+        key: &K,
+        value_bytes: &[u8],
+    ) -> Result<(usize, usize), Box<dyn Error>> {
+        let value_index = store.create(value_bytes)?;
+        let key_entry = KeyEntry {
+            body: key,
+            value_index,
+        };
+        let key_index = encoding
+            .serialize(&key_entry)
+            .and_then(|key_bytes| store.create(key_bytes.as_slice()));
+        match key_index {
+            Ok(key_index) => Ok((key_index, value_index)),
+            Err(error) => {
+                // a failure here only leaks the block
+                let _ = store.delete(value_index);
+                Err(error)
+            }
+        }
+    }
+
+    /// delete blocks the header does not reference (anymore). A failure
+    /// here only leaks the blocks, so it does not fail the operation.
+    fn delete_blocks(&mut self, key_index: usize, value_index: usize) {
+        let _ = self.store.delete(value_index);
+        let _ = self.store.delete(key_index);
+    }
+
+    /// drop the reference of a key to its value block before the header
+    /// gets saved. Returns whether the value block may be deleted, because
+    /// no other key shares it.
+    fn release_value(&mut self, value_index: usize) -> bool {
+        match self.header.shared_values.get_mut(&value_index) {
+            Some(keys) if *keys > 2 => {
+                *keys -= 1;
+                false
+            }
+            Some(_) => {
+                self.header.shared_values.remove(&value_index);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// delete the blocks of a released entry, see `release_value`
+    fn delete_released(&mut self, key_index: usize, value_index: usize, unshared: bool) {
+        if unshared {
+            self.delete_blocks(key_index, value_index);
+        } else {
+            let _ = self.store.delete(key_index);
+        }
+    }
+
+    /// locate the key block of a key, stopping at the first hit
+    fn find_key_block(&self, key: &K) -> Result<Option<KeyBlock>, Box<dyn Error>> {
+        if !self.lookup.contains_key(key) {
+            return Ok(None);
+        }
+        for (position, index) in self.header.key_indices.iter().enumerate() {
+            let key_bytes = self.store.read(*index)?;
+            let key_entry: KeyEntry<K> = self.header.encoding.deserialize(&key_bytes)?;
+            if key_entry.body == *key {
+                return Ok(Some(KeyBlock {
+                    position,
+                    key_index: *index,
+                    value_index: key_entry.value_index,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    fn clear_and_shrink(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut counters = self.header.counters.unwrap_or_default();
+        counters.removals += self.len() as u64;
+        let buckets = self.header.disk_index.as_ref().map(DiskIndex::buckets);
+        self.header = Header {
+            generation: self.header.generation + 1,
+            counters: Some(counters),
+            encoding: self.header.encoding,
+            usage: Some(Usage::default()),
+            ..Header::default()
+        };
+        self.lookup.clear();
+        self.index_bytes = 0;
+        self.index_dirty = false;
+        let bytes: Vec<u8> = bincode::serialize(&self.header)?;
+        self.store.clear_and_shrink(&bytes)?;
+        if let Some(buckets) = buckets {
+            self.header.disk_index = Some(DiskIndex::create(&mut self.store, buckets)?);
+            self.save_header()?;
+        }
+        Ok(())
+    }
+
+    /// remove every entry whose serialized value `keep` rejects, saving the
+    /// header once. The blocks of removed entries get deleted only after
+    /// the header got saved, so a failure keeps every entry.
+    fn retain(&mut self, keep: &mut KeepEntry<'_, K>) -> Result<(), Box<dyn Error>> {
+        if self.header.disk_index.is_some() {
+            return self.retain_on_disk(keep);
+        }
+        let mut kept = vec![];
+        let mut dropped: Vec<(K, usize, usize)> = vec![];
+        let mut removed = Usage::default();
+        for &key_index in self.header.key_indices.iter() {
+            let key_bytes = self.store.read(key_index)?;
+            let key_entry: KeyEntry<K> = self.header.encoding.deserialize(&key_bytes)?;
+            let value_index = key_entry.value_index;
+            if keep(&key_entry.body, &self.store.read(value_index)?)? {
+                kept.push(key_index);
+                continue;
+            }
+            removed.add(self.block_usage(key_index, value_index)?);
+            dropped.push((key_entry.body, key_index, value_index));
+        }
+        let mut dropped_inline = vec![];
+        for (position, (key, value_bytes)) in self.header.inline_entries.iter().enumerate() {
+            if !keep(key, value_bytes)? {
+                removed.add(Self::inline_usage(key, value_bytes)?);
+                dropped_inline.push(position);
+            }
+        }
+        let count = dropped.len() + dropped_inline.len();
+        if count == 0 {
+            return Ok(());
+        }
+
+        let snapshot = self.snapshot();
+        // back to front, so the positions of the others stay valid
+        let mut inline = vec![];
+        for position in dropped_inline.into_iter().rev() {
+            inline.push((position, self.header.inline_entries.remove(position)));
+        }
+        self.header.key_indices = kept;
+        let unshared: Vec<bool> = dropped
+            .iter()
+            .map(|(_, _, value_index)| self.release_value(*value_index))
+            .collect();
+        self.usage_mut().remove(removed);
+        self.counters_mut().removals += count as u64;
+        if let Err(error) = self.save_changes(snapshot) {
+            for (position, entry) in inline.into_iter().rev() {
+                self.header.inline_entries.insert(position, entry);
+            }
+            return Err(error);
+        }
+        for ((key, key_index, value_index), unshared) in dropped.into_iter().zip(unshared) {
+            self.delete_released(key_index, value_index, unshared);
+            self.index_bytes -= Self::index_entry_bytes(&key);
+            self.lookup.remove(&key);
+        }
+        Ok(())
+    }
+
+    /// like `retain`, but every rejected key gets removed on its own, as
+    /// the disk index changes bucket by bucket
+    fn retain_on_disk(&mut self, keep: &mut KeepEntry<'_, K>) -> Result<(), Box<dyn Error>> {
+        let mut dropped = vec![];
+        for entry in self.raw_entries() {
+            let (key, value_bytes) = entry?;
+            if !keep(&key, &value_bytes)? {
+                dropped.push(key);
+            }
+        }
+        for key in dropped {
+            self.remove_entry(&key, None)?;
+        }
+        Ok(())
+    }
+
+    /// remove a key, handing its serialized value to `take` first, if
+    /// given, since reading a value from its block is not free
+    fn remove_entry(
+        &mut self,
+        key: &K,
+        take: Option<&mut TakeValue<'_>>,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(disk) = self.header.disk_index.clone() {
+            return self.remove_on_disk(disk, key, take);
+        }
+        let snapshot = self.snapshot();
+        let entries = &self.header.inline_entries;
+        if let Some(position) = entries.iter().position(|(k, _)| k == key) {
+            let (_, value_bytes) = &entries[position];
+            if let Some(take) = take {
+                take(value_bytes)?;
+            }
+            let removed = Self::inline_usage(key, value_bytes)?;
+            let entry = self.header.inline_entries.remove(position);
+            self.usage_mut().remove(removed);
+            self.counters_mut().removals += 1;
+            if let Err(error) = self.save_changes(snapshot) {
+                self.header.inline_entries.insert(position, entry);
+                return Err(error);
+            }
+        } else if let Some(block) = self.find_key_block(key)? {
+            if let Some(take) = take {
+                take(&self.store.read(block.value_index)?)?;
+            }
+            let removed = self.block_usage(block.key_index, block.value_index)?;
+            self.header.key_indices.remove(block.position);
+            let unshared = self.release_value(block.value_index);
+            self.usage_mut().remove(removed);
+            self.counters_mut().removals += 1;
+            self.save_changes(snapshot)?;
+            self.delete_released(block.key_index, block.value_index, unshared);
+            self.lookup.remove(key);
+            self.index_bytes -= Self::index_entry_bytes(key);
+        }
+        Ok(())
+    }
+
+    fn remove_on_disk(
+        &mut self,
+        mut disk: DiskIndex,
+        key: &K,
+        take: Option<&mut TakeValue<'_>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let DiskBucket {
+            hash,
+            mut slots,
+            position,
+        } = self.disk_bucket(&disk, key)?;
+        let Some(position) = position else {
+            return Ok(());
+        };
+        if let Some(take) = take {
+            take(&self.store.read(slots[position].value_index)?)?;
+        }
+        let removed = slots.remove(position);
+        let usage = self.block_usage(removed.key_index, removed.value_index)?;
+        disk.write_bucket(&mut self.store, hash, &slots)?;
+        let snapshot = self.snapshot();
+        let unshared = self.release_value(removed.value_index);
+        self.usage_mut().remove(usage);
+        self.header.counter_drift |= count_down(&mut disk.len);
+        self.header.disk_index = Some(disk);
+        self.counters_mut().removals += 1;
+        if let Err(error) = self.save_changes(snapshot) {
+            slots.insert(position, removed);
+            self.restore_bucket(hash, &slots);
+            return Err(error);
+        }
+        self.delete_released(removed.key_index, removed.value_index, unshared);
+        Ok(())
+    }
+}
+
+impl<K, S> Drop for RawKeyValue<K, S>
+where
+    K: Serialize + Hash + Eq,
+    for<'de> K: Deserialize<'de>,
+    S: BuildHasher + Clone,
+{
+    fn drop(&mut self) {
+        // a poisoned lookup may disagree with the keys in the file
+        if self.index_dirty && !self.modifying {
+            // a failure here only means the next open rebuilds the index
+            let _ = self.save_index();
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Header<K> {
+    key_indices: Vec<usize>,
+    // serialized values of a tiny map, stored right within the header
+    inline_entries: Vec<(K, Vec<u8>)>,
+    // block holding the persisted lookup, 0 if there is none
+    index_block: usize,
+    // the generation at which the persisted lookup was written
+    index_generation: u64,
+    // incremented with every modification
+    generation: u64,
+    // `None` in files written before counters existed
+    counters: Option<KeyValueCounters>,
+    // `Fixed` in files written before the encoding was configurable
+    encoding: IntEncoding,
+    // locates the keys instead of the in-memory lookup, `None` for files
+    // created without it
+    disk_index: Option<DiskIndex>,
+    // `None` in files written before the usage was tracked
+    usage: Option<Usage>,
+    // value blocks referenced by more than one key since `compact_dedup`,
+    // with the number of those keys
+    shared_values: BTreeMap<usize, usize>,
+    // set once a count would have dropped below zero
+    counter_drift: bool,
+}
+
+/// What [`KeyValue::list`](crate::KeyValue::list) knows about an entry
+/// without reading its value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySummary<K> {
+    /// the key of the entry
+    pub key: K,
+    /// the length of the serialized value
+    pub value_bytes: usize,
+    /// how many frames the value spans, `0` for values stored inline
+    pub frames: usize,
+}
+
+/// What [`KeyValue::export_filtered`](crate::KeyValue::export_filtered)
+/// copied into the new file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportReport {
+    /// the number of entries the filter accepted and got copied
+    pub exported: usize,
+    /// the number of entries the filter rejected
+    pub skipped: usize,
+    /// the bytes of the serialized keys and values of the copied entries
+    pub payload_bytes: usize,
+    /// the size of the new file
+    pub file_bytes: usize,
+}
+
+/// The values of several keys read together by
+/// [`KeyValue::get_group`](crate::KeyValue::get_group).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupSnapshot<V> {
+    /// the generation of the database the values stem from, which grows
+    /// with every modification
+    pub generation: u64,
+    /// the value of every requested key in the order of the keys, `None`
+    /// for a missing key
+    pub values: Vec<Option<V>>,
+}
+
+/// Lifetime counters of a [`KeyValue`](crate::KeyValue) database.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyValueCounters {
+    /// total number of keys set that did not exist before
+    pub inserts: u64,
+    /// total number of keys set that replaced an existing value
+    pub overwrites: u64,
+    /// total number of removed keys
+    pub removals: u64,
+}
+
+impl<K> Default for Header<K> {
+    fn default() -> Self {
+        Self {
+            key_indices: vec![],
+            inline_entries: vec![],
+            index_block: 0,
+            index_generation: 0,
+            generation: 0,
+            counters: None,
+            encoding: IntEncoding::Fixed,
+            disk_index: None,
+            usage: None,
+            shared_values: BTreeMap::new(),
+            counter_drift: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct KeyEntry<K> {
+    body: K,
+    value_index: usize,
+}
+
+/// sees the serialized value of a key before it gets removed
+type TakeValue<'a> = dyn FnMut(&[u8]) -> Result<(), Box<dyn Error>> + 'a;
+
+/// decides whether an entry stays, given its key and serialized value
+type KeepEntry<'a, K> = dyn FnMut(&K, &[u8]) -> Result<bool, Box<dyn Error>> + 'a;
+
+/// pieces of a serialized value, in order
+type Chunks<'a> = Box<dyn Iterator<Item = Result<Vec<u8>, Box<dyn Error>>> + 'a>;
+
+/// where the serialized value of a key is stored
+enum ValueLocation<'a> {
+    Block(usize),
+    Inline(&'a [u8]),
+}
+
+/// where the blocks of a key are and its position within `key_indices`
+struct KeyBlock {
+    position: usize,
+    key_index: usize,
+    value_index: usize,
+}
+
+/// the bucket of a key within the disk index
+struct DiskBucket {
+    hash: u64,
+    slots: Vec<Slot>,
+    // of the key within `slots`, `None` if the key is not stored
+    position: Option<usize>,
+}
+
+/// the parts of the header a modification may change, to undo it
+struct HeaderSnapshot {
+    key_indices: Vec<usize>,
+    counters: Option<KeyValueCounters>,
+    generation: u64,
+    disk_len: Option<usize>,
+    usage: Option<Usage>,
+    shared_values: BTreeMap<usize, usize>,
+}
+
+impl HeaderSnapshot {
+    fn restore<K>(self, header: &mut Header<K>) {
+        header.key_indices = self.key_indices;
+        header.counters = self.counters;
+        header.generation = self.generation;
+        header.usage = self.usage;
+        header.shared_values = self.shared_values;
+        if let (Some(disk), Some(len)) = (&mut header.disk_index, self.disk_len) {
+            disk.len = len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn works() {
+        // setup db
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv = KeyValue::<i32, i32>::new(file).expect("could not create");
+        assert_eq!(kv.len(), 0);
+        assert_eq!(kv.keys(), Vec::<&i32>::new());
+
+        // insert data
+        kv.set(17, 42).expect("can not set");
+        assert_eq!(kv.len(), 1);
+        assert_eq!(kv.keys(), vec![&17]);
+
+        // read data
+        let v = kv.get(&17).expect("can not get");
+        assert_eq!(v, Some(42));
+
+        // update data
+        kv.set(17, 101).expect("can not set");
+        assert_eq!(kv.len(), 1);
+        assert_eq!(kv.keys(), vec![&17]);
+
+        // read data again
+        let v = kv.get(&17).expect("can not get");
+        assert_eq!(v, Some(101));
+
+        // remove data
+        kv.remove(&17).expect("could not remove");
+        assert_eq!(kv.len(), 0);
+        assert_eq!(kv.keys(), Vec::<&i32>::new());
+        let v = kv.get(&17).expect("can not get");
+        assert_eq!(v, None);
+    }
+
+    #[test]
+    fn inline_values() {
+        // setup db
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let options = Options::new().inline_values(128);
+        let mut kv = KeyValue::<i32, i32>::with_options(file.try_clone().unwrap(), options)
+            .expect("could not create");
+
+        // a handful of entries stay within the header block
+        for i in 0..4 {
+            kv.set(i, i * 10).expect("can not set");
+        }
+        kv.set(2, 42).expect("can not set");
+        kv.remove(&3).expect("can not remove");
+        assert_eq!(kv.len(), 3);
+        assert!(kv.raw.header.key_indices.is_empty());
+        assert_eq!(kv.get(&2).expect("can not get"), Some(42));
+        assert_eq!(kv.get(&3).expect("can not get"), None);
+        let file_size = file.metadata().unwrap().len();
+        assert_eq!(file_size as usize, page_size::get());
+
+        // growing past the threshold spills everything into blocks
+        for i in 10..20 {
+            kv.set(i, i * 10).expect("can not set");
+        }
+        assert_eq!(kv.len(), 13);
+        assert!(kv.raw.header.inline_entries.is_empty());
+        assert_eq!(kv.raw.header.key_indices.len(), 13);
+        assert!(file.metadata().unwrap().len() > file_size);
+
+        // works after reopen
+        let kv = KeyValue::<i32, i32>::new(file).expect("could not open");
+        assert_eq!(kv.len(), 13);
+        assert_eq!(kv.get(&2).expect("can not get"), Some(42));
+        assert_eq!(kv.get(&19).expect("can not get"), Some(190));
+    }
+
+    #[test]
+    fn persisted_index() {
+        // setup db
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv =
+            KeyValue::<i32, i32>::new(file.try_clone().unwrap()).expect("could not create");
+        for i in 0..10 {
+            kv.set(i, i * 10).expect("can not set");
+        }
+        kv.remove(&3).expect("can not remove");
+        drop(kv);
+
+        // the index is loaded instead of rebuilt
+        let mut kv = KeyValue::<i32, i32>::new(file.try_clone().unwrap()).expect("could not open");
+        assert_ne!(kv.raw.header.index_block, 0);
+        assert!(!kv.raw.index_dirty);
+        assert_eq!(kv.len(), 9);
+        assert_eq!(kv.get(&7).expect("can not get"), Some(70));
+        assert_eq!(kv.get(&3).expect("can not get"), None);
+
+        // corrupt the index: every key points to the value of key 0
+        let value_index = *kv.raw.lookup.get(&0).unwrap();
+        let entries: Vec<(i32, usize)> = kv.raw.lookup.keys().map(|k| (*k, value_index)).collect();
+        let bytes = bincode::serialize(&entries).unwrap();
+        let index_block = kv.raw.header.index_block;
+        kv.raw.store.update(index_block, bytes.as_slice()).unwrap();
+        std::mem::forget(kv);
+        let kv = KeyValue::<i32, i32>::new(file.try_clone().unwrap()).expect("could not open");
+        assert_eq!(kv.get(&7).expect("can not get"), Some(0));
+        std::mem::forget(kv);
+
+        // rebuilding ignores the index and repairs it
+        let mut kv = KeyValue::<i32, i32>::open_rebuild_index(file.try_clone().unwrap())
+            .expect("could not open");
+        assert_eq!(kv.len(), 9);
+        assert_eq!(kv.get(&7).expect("can not get"), Some(70));
+        kv.set(10, 100).expect("can not set");
+        assert_eq!(kv.get(&10).expect("can not get"), Some(100));
+        drop(kv);
+        let kv = KeyValue::<i32, i32>::new(file.try_clone().unwrap()).expect("could not open");
+        assert!(!kv.raw.index_dirty);
+        assert_eq!(kv.get(&7).expect("can not get"), Some(70));
+        drop(kv);
+
+        // garbage in the index block falls back to a rebuild
+        let mut store = BlockStorage::with_options(file.try_clone().unwrap(), &Options::default())
+            .expect("could not open");
+        store.update(index_block, b"garbage").unwrap();
+        let kv = KeyValue::<i32, i32>::new(file).expect("could not open");
+        assert!(kv.raw.index_dirty);
+        assert_eq!(kv.get(&7).expect("can not get"), Some(70));
+    }
+
+    #[test]
+    fn counters() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv =
+            KeyValue::<i32, i32>::new(file.try_clone().unwrap()).expect("could not create");
+        for i in 0..5 {
+            kv.set(i, i).expect("can not set");
+        }
+        kv.set(1, 10).expect("can not set");
+        kv.set(2, 20).expect("can not set");
+        kv.remove(&3).expect("can not remove");
+        kv.remove(&3).expect("can not remove");
+        let expected = KeyValueCounters {
+            inserts: 5,
+            overwrites: 2,
+            removals: 1,
+        };
+        assert_eq!(kv.counters(), expected);
+        drop(kv);
+
+        // works after reopen
+        let mut kv = KeyValue::<i32, i32>::new(file).expect("could not open");
+        assert_eq!(kv.counters(), expected);
+
+        // new measurement window
+        kv.reset_counters().expect("could not reset");
+        kv.set(1, 1).expect("can not set");
+        let expected = KeyValueCounters {
+            inserts: 0,
+            overwrites: 1,
+            removals: 0,
+        };
+        assert_eq!(kv.counters(), expected);
+    }
+
+    #[test]
+    fn cancelled_rebuild() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv = KeyValue::<i32, i32>::new(file).expect("could not create");
+        for i in 0..10 {
+            kv.set(i, i * 10).expect("can not set");
+        }
+        let mut reports = vec![];
+        let error = kv
+            .rebuild_index(&mut |done: u64, total: Option<u64>| {
+                reports.push((done, total));
+                if done < 5 {
+                    std::ops::ControlFlow::Continue(())
+                } else {
+                    std::ops::ControlFlow::Break(())
+                }
+            })
+            .expect_err("should be cancelled");
+        assert_eq!(error.downcast_ref(), Some(&crate::WiredError::Cancelled));
+        assert_eq!(reports.last(), Some(&(5, Some(10))));
+
+        // the previous lookup is still complete
+        assert_eq!(kv.len(), 10);
+        assert_eq!(kv.get(&9).expect("can not get"), Some(90));
+    }
+
+    #[test]
+    fn iter_insertion_order() {
+        let collect = |kv: &KeyValue<String, i32>| {
+            kv.iter_insertion_order()
+                .collect::<Result<Vec<_>, _>>()
+                .expect("can not iterate")
+        };
+        let entries = |pairs: &[(&str, i32)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), *v))
+                .collect::<Vec<_>>()
+        };
+        for options in [Options::default(), Options::new().inline_values(1024)] {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut kv = KeyValue::<String, i32>::with_options(file.try_clone().unwrap(), options)
+                .expect("could not create");
+            for (i, key) in ["c", "a", "d", "b"].iter().enumerate() {
+                kv.set(key.to_string(), i as i32).expect("can not set");
+            }
+            assert_eq!(
+                collect(&kv),
+                entries(&[("c", 0), ("a", 1), ("d", 2), ("b", 3)])
+            );
+
+            // overwriting moves a key to the end, removing keeps the rest
+            kv.set(String::from("a"), 10).expect("can not set");
+            kv.remove(&String::from("d")).expect("can not remove");
+            let expected = entries(&[("c", 0), ("b", 3), ("a", 10)]);
+            assert_eq!(collect(&kv), expected);
+
+            // stable across reopens
+            drop(kv);
+            let kv = KeyValue::<String, i32>::new(file).expect("could not open");
+            assert_eq!(collect(&kv), expected);
+        }
+    }
+
+    fn contents(kv: &KeyValue<i32, i32>) -> Vec<(i32, i32)> {
+        kv.iter_insertion_order()
+            .collect::<Result<Vec<_>, _>>()
+            .expect("can not iterate")
+    }
+
+    #[test]
+    fn set_failures() {
+        // writes of an overwrite: value block, key block, header, old blocks
+        for fault in 0..4 {
+            for &key in &[1, 10] {
+                let file = tempfile::tempfile().expect("could not create tempfile");
+                let mut kv =
+                    KeyValue::<i32, i32>::new(file.try_clone().unwrap()).expect("could not create");
+                for i in 0..3 {
+                    kv.set(i, i).expect("can not set");
+                }
+                let before = contents(&kv);
+                let live_frames = kv.raw.store.live_frames();
+
+                kv.raw.store.fail_after(fault);
+                let result = kv.set(key, 100);
+                kv.raw.store.clear_fault();
+                if fault < 3 {
+                    // nothing changed and nothing leaked
+                    assert!(result.is_err());
+                    assert_eq!(contents(&kv), before);
+                    assert_eq!(
+                        kv.get(&key).unwrap(),
+                        before.iter().find(|e| e.0 == key).map(|e| e.1)
+                    );
+                    assert_eq!(kv.raw.store.live_frames(), live_frames);
+                    assert_eq!(kv.counters().overwrites + kv.counters().inserts, 3);
+                } else {
+                    // only the cleanup of replaced blocks failed
+                    result.expect("can not set");
+                    assert_eq!(kv.get(&key).unwrap(), Some(100));
+                }
+                let expected = contents(&kv);
+
+                // the file agrees with the handle
+                drop(kv);
+                let kv = KeyValue::<i32, i32>::new(file).expect("could not open");
+                assert_eq!(contents(&kv), expected);
+                assert_eq!(kv.raw.lookup.len(), expected.len());
+            }
+        }
+    }
+
+    #[test]
+    fn gc() {
+        // writes of an overwrite before the old blocks get deleted
+        for (options, writes) in [(Options::default(), 3), (Options::new().disk_index(16), 4)] {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut kv =
+                KeyValue::<i32, i32>::with_options(file.try_clone().unwrap(), options.clone())
+                    .expect("could not create");
+            for i in 0..3 {
+                kv.set(i, i).expect("can not set");
+            }
+            // persists the index block
+            drop(kv);
+            let mut kv =
+                KeyValue::<i32, i32>::with_options(file.try_clone().unwrap(), options.clone())
+                    .expect("could not open");
+            let live_frames = kv.raw.store.live_frames();
+            assert_eq!(kv.gc().unwrap(), GcReport::default());
+
+            // the old value of an overwrite whose cleanup failed
+            kv.raw.store.fail_after(writes);
+            kv.set(1, 100).expect("can not set");
+            assert_eq!(kv.raw.store.live_frames(), live_frames + 1);
+            assert_eq!(kv.gc().unwrap().blocks, 1);
+            assert_eq!(kv.raw.store.live_frames(), live_frames);
+            let values = |kv: &KeyValue<i32, i32>| -> Vec<Option<i32>> {
+                (0..3).map(|key| kv.get(&key).unwrap()).collect()
+            };
+            assert_eq!(values(&kv), [Some(0), Some(100), Some(2)]);
+
+            drop(kv);
+            let kv = KeyValue::<i32, i32>::with_options(file, options).expect("could not open");
+            assert_eq!(values(&kv), [Some(0), Some(100), Some(2)]);
+        }
+    }
+
+    #[test]
+    fn inline_set_failures() {
+        // the last set spills three entries: six blocks and the header
+        for fault in 0..7 {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let options = Options::new().inline_values(48);
+            let mut kv = KeyValue::<i32, i32>::with_options(file.try_clone().unwrap(), options)
+                .expect("could not create");
+            kv.set(1, 1).expect("can not set");
+            kv.set(2, 2).expect("can not set");
+            let before = contents(&kv);
+            let live_frames = kv.raw.store.live_frames();
+
+            kv.raw.store.fail_after(fault);
+            kv.set(3, 3).expect_err("should fail");
+            kv.raw.store.clear_fault();
+            assert_eq!(contents(&kv), before);
+            assert!(kv.raw.is_inline());
+            assert_eq!(kv.raw.store.live_frames(), live_frames);
+            drop(kv);
+
+            let options = Options::new().inline_values(48);
+            let mut kv = KeyValue::<i32, i32>::with_options(file, options).expect("could not open");
+            assert_eq!(contents(&kv), before);
+            kv.set(3, 3).expect("can not set");
+            assert!(!kv.raw.is_inline());
+            assert_eq!(contents(&kv), vec![(1, 1), (2, 2), (3, 3)]);
+        }
+    }
+
+    #[test]
+    fn take() {
+        for options in [
+            Options::default(),
+            Options::new().inline_values(1024),
+            Options::new().disk_index(16),
+        ] {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut kv = KeyValue::<i32, i32>::with_options(file, options).unwrap();
+            for i in 0..3 {
+                kv.set(i, i * 10).expect("can not set");
+            }
+            let payload = kv.payload_bytes();
+            assert_eq!(kv.take(&1).unwrap(), Some(10));
+            assert_eq!(kv.len(), 2);
+            assert!(kv.payload_bytes() < payload);
+            assert_eq!(kv.counters().removals, 1);
+            assert_eq!(kv.get(&1).unwrap(), None);
+
+            assert_eq!(kv.take(&1).unwrap(), None);
+            assert_eq!(kv.len(), 2);
+            assert_eq!(kv.counters().removals, 1);
+            assert_eq!(contents(&kv), vec![(0, 0), (2, 20)]);
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Account {
+        owner: String,
+        balance: u64,
+    }
+
+    #[test]
+    fn with_value_mut() {
+        for options in [
+            Options::default(),
+            Options::new().inline_values(1024),
+            Options::new().disk_index(16),
+        ] {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut kv = KeyValue::<i32, Account>::with_options(file.try_clone().unwrap(), options)
+                .expect("could not create");
+            let account = Account {
+                owner: String::from("jane"),
+                balance: 100,
+            };
+            kv.set(1, account.clone()).expect("can not set");
+            let value_index = kv.raw.value_index(&1).unwrap();
+            let generation = kv.raw.header.generation;
+
+            // the same length gets written in place
+            let old = kv
+                .with_value_mut(&1, |account| std::mem::replace(&mut account.balance, 250))
+                .unwrap();
+            assert_eq!(old, Some(100));
+            assert_eq!(kv.raw.value_index(&1).unwrap(), value_index);
+            assert!(kv.raw.header.generation > generation);
+            assert_eq!(kv.counters().overwrites, 1);
+
+            // a longer value gets stored like with `set`
+            let old = kv
+                .with_value_mut(&1, |account| {
+                    std::mem::replace(&mut account.owner, String::from("jane doe"))
+                })
+                .unwrap();
+            assert_eq!(old, Some(String::from("jane")));
+            assert_eq!(kv.counters().overwrites, 2);
+            assert_eq!(kv.len(), 1);
+
+            let mut called = false;
+            assert_eq!(kv.with_value_mut(&2, |_| called = true).unwrap(), None);
+            assert!(!called);
+            drop(kv);
+
+            let kv = KeyValue::<i32, Account>::new(file).expect("could not open");
+            let expected = Account {
+                owner: String::from("jane doe"),
+                balance: 250,
+            };
+            assert_eq!(kv.get(&1).unwrap(), Some(expected));
+            assert_eq!(kv.len(), 1);
+        }
+    }
+
+    #[test]
+    fn map_values() {
+        for options in [
+            Options::default(),
+            Options::new().inline_values(1024),
+            Options::new().disk_index(16),
+        ] {
+            // with varints, doubled values grow beyond a single byte
+            let options = options.int_encoding(IntEncoding::Varint);
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut kv = KeyValue::<i32, i64>::with_options(file.try_clone().unwrap(), options)
+                .expect("could not create");
+            for i in 0..50 {
+                kv.set(i, i64::from(i) * 10).expect("can not set");
+            }
+            let generation = kv.raw.header.generation;
+            kv.map_values(|value| *value *= 2).unwrap();
+            assert!(kv.raw.header.generation > generation);
+            // zero stays as it is
+            assert_eq!(kv.counters().overwrites, 49);
+            assert_eq!(kv.raw.header.usage, Some(kv.raw.measure_usage().unwrap()));
+            drop(kv);
+
+            let kv = KeyValue::<i32, i64>::new(file).expect("could not open");
+            for i in 0..50 {
+                assert_eq!(kv.get(&i).unwrap(), Some(i64::from(i) * 20));
+            }
+        }
+
+        // values growing across frames, and values shared by several keys
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv = KeyValue::<i32, Vec<u32>>::new(file.try_clone().unwrap()).unwrap();
+        for i in 0..10 {
+            kv.set(i, vec![(i % 2) as u32; 200]).expect("can not set");
+        }
+        assert_eq!(kv.compact_dedup().unwrap(), 8);
+        let mut calls = 0;
+        kv.map_values(|value| {
+            calls += 1;
+            value.extend(value.clone());
+        })
+        .unwrap();
+        assert_eq!(calls, 2);
+        assert_eq!(kv.raw.header.usage, Some(kv.raw.measure_usage().unwrap()));
+        drop(kv);
+        let kv = KeyValue::<i32, Vec<u32>>::new(file).expect("could not open");
+        for i in 0..10 {
+            assert_eq!(kv.get(&i).unwrap(), Some(vec![(i % 2) as u32; 400]));
+        }
+    }
+
+    #[test]
+    fn retain() {
+        for options in [
+            Options::default(),
+            Options::new().inline_values(1024),
+            Options::new().disk_index(16),
+        ] {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut kv = KeyValue::<i32, i64>::with_options(file.try_clone().unwrap(), options)
+                .expect("could not create");
+            for i in 0..50 {
+                kv.set(i, i64::from(i) * 3).expect("can not set");
+            }
+            let mut calls = 0;
+            kv.retain(|key, value| {
+                calls += 1;
+                assert_eq!(*value, i64::from(*key) * 3);
+                value % 2 == 0
+            })
+            .unwrap();
+            assert_eq!(calls, 50);
+            assert_eq!(kv.len(), 25);
+            assert_eq!(kv.counters().removals, 25);
+            assert_eq!(kv.raw.header.usage, Some(kv.raw.measure_usage().unwrap()));
+            drop(kv);
+
+            let kv = KeyValue::<i32, i64>::new(file).expect("could not open");
+            assert_eq!(kv.len(), 25);
+            for i in 0..50 {
+                let expected = (i % 2 == 0).then_some(i64::from(i) * 3);
+                assert_eq!(kv.get(&i).unwrap(), expected);
+            }
+        }
+
+        // a shared value block stays as long as one of its keys does
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv = KeyValue::<i32, Vec<u8>>::new(file).unwrap();
+        for i in 0..10 {
+            kv.set(i, vec![(i % 2) as u8; 2000]).expect("can not set");
+        }
+        assert_eq!(kv.compact_dedup().unwrap(), 8);
+        let frames = kv.raw.store.live_frames();
+        kv.retain(|key, _| *key < 3).unwrap();
+        // the key blocks of seven keys, and no value block
+        assert_eq!(kv.raw.store.live_frames(), frames - 7);
+        for i in 0..10 {
+            let expected = (i < 3).then(|| vec![(i % 2) as u8; 2000]);
+            assert_eq!(kv.get(&i).unwrap(), expected);
+        }
+        kv.retain(|key, _| *key == 0).unwrap();
+        assert_eq!(kv.raw.header.shared_values.len(), 0);
+        assert_eq!(kv.raw.header.usage, Some(kv.raw.measure_usage().unwrap()));
+    }
+
+    #[test]
+    fn compact_dedup() {
+        let value = |i: i32| vec![(i % 3) as u8; 2000];
+        for options in [Options::default(), Options::new().disk_index(16)] {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut kv =
+                KeyValue::<i32, Vec<u8>>::with_options(file.try_clone().unwrap(), options).unwrap();
+            for i in 0..30 {
+                kv.set(i, value(i)).expect("can not set");
+            }
+            let frames = kv.raw.store.live_frames();
+            assert_eq!(kv.compact_dedup().unwrap(), 27);
+            assert_eq!(kv.raw.store.live_frames(), frames - 27 * 3);
+            let distinct: HashSet<usize> = kv.raw.value_blocks().collect::<Result<_, _>>().unwrap();
+            assert_eq!(distinct.len(), 3);
+            assert_eq!(kv.raw.header.usage, Some(kv.raw.measure_usage().unwrap()));
+            assert_eq!(kv.compact_dedup().unwrap(), 0);
+            drop(kv);
+
+            let mut kv = KeyValue::<i32, Vec<u8>>::new(file).expect("could not open");
+            for i in 0..30 {
+                assert_eq!(kv.get(&i).unwrap(), Some(value(i)));
+            }
+            // a shared value stays until its last key is gone
+            let frames = kv.raw.store.live_frames();
+            for i in (3..30).step_by(3) {
+                kv.remove(&i).expect("can not remove");
+            }
+            assert_eq!(kv.raw.store.live_frames(), frames - 9);
+            assert_eq!(kv.take(&0).unwrap(), Some(value(0)));
+            assert_eq!(kv.raw.store.live_frames(), frames - 10 - 3);
+            kv.set(1, vec![9; 10]).expect("can not set");
+            assert_eq!(kv.get(&4).unwrap(), Some(value(1)));
+            assert_eq!(kv.raw.header.usage, Some(kv.raw.measure_usage().unwrap()));
+        }
+    }
+
+    #[test]
+    fn get_group() {
+        for options in [
+            Options::default(),
+            Options::new().inline_values(1024),
+            Options::new().disk_index(16),
+        ] {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut kv = KeyValue::<i32, i32>::with_options(file, options).unwrap();
+            let group = kv.get_group(&[1, 2]).unwrap();
+            assert_eq!(group.values, [None, None]);
+            let mut generation = group.generation;
+            for round in 0..5 {
+                // both keys get written as one logical unit
+                kv.set(1, round).expect("can not set");
+                kv.set(2, round * 10).expect("can not set");
+                let group = kv.get_group(&[2, 3, 1]).unwrap();
+                assert_eq!(group.values, [Some(round * 10), None, Some(round)]);
+                assert_eq!(group.generation, generation + 2);
+                generation = group.generation;
+            }
+        }
+    }
+
+    /// the multiply-rotate hash of `FxHasher`, as used within rustc
+    #[derive(Default)]
+    struct FxStyleHasher(u64);
+
+    impl std::hash::Hasher for FxStyleHasher {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+        fn write(&mut self, bytes: &[u8]) {
+            for byte in bytes {
+                self.0 =
+                    (self.0.rotate_left(5) ^ u64::from(*byte)).wrapping_mul(0x517c_c1b7_2722_0a95);
+            }
+        }
+    }
+
+    /// every key collides, so only their equality tells them apart
+    #[derive(Default)]
+    struct CollidingHasher;
+
+    impl std::hash::Hasher for CollidingHasher {
+        fn finish(&self) -> u64 {
+            42
+        }
+        fn write(&mut self, _bytes: &[u8]) {}
+    }
+
+    /// the value of every key after the same operations with the hasher
+    fn hashed_values<S: BuildHasher + Clone>(hasher: S) -> Vec<Option<i32>> {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv = KeyValue::<i32, i32, S>::with_hasher(
+            file.try_clone().unwrap(),
+            Options::default(),
+            hasher.clone(),
+        )
+        .expect("could not create");
+        for i in 0..50 {
+            kv.set(i, i).expect("can not set");
+        }
+        for i in (0..50).step_by(7) {
+            kv.remove(&i).expect("can not remove");
+        }
+        kv.set(3, 33).expect("can not set");
+        drop(kv);
+        // the persisted index gets loaded into a lookup with the hasher
+        let mut kv =
+            KeyValue::<i32, i32, S>::with_hasher(file, Options::default(), hasher).unwrap();
+        assert_eq!(kv.len(), 42);
+        kv.rebuild_index(&mut progress::ignore).unwrap();
+        (0..50).map(|i| kv.get(&i).unwrap()).collect()
+    }
+
+    #[test]
+    fn hashers() {
+        use std::hash::BuildHasherDefault;
+        let expected = hashed_values(RandomState::new());
+        assert_eq!(expected[3], Some(33));
+        assert_eq!(expected[7], None);
+        let fx = BuildHasherDefault::<FxStyleHasher>::default();
+        assert_eq!(hashed_values(fx), expected);
+        let colliding = BuildHasherDefault::<CollidingHasher>::default();
+        assert_eq!(hashed_values(colliding), expected);
+    }
+
+    #[test]
+    fn remove_failures() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv =
+            KeyValue::<i32, i32>::new(file.try_clone().unwrap()).expect("could not create");
+        for i in 0..3 {
+            kv.set(i, i).expect("can not set");
+        }
+        kv.raw.store.fail_after(0);
+        kv.remove(&1).expect_err("should fail");
+        kv.raw.store.clear_fault();
+        assert_eq!(contents(&kv), vec![(0, 0), (1, 1), (2, 2)]);
+        drop(kv);
+        let kv = KeyValue::<i32, i32>::new(file).expect("could not open");
+        assert_eq!(contents(&kv), vec![(0, 0), (1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn logical_size() {
+        for options in [Options::default(), Options::new().inline_values(1024)] {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut kv =
+                KeyValue::<String, Vec<u8>>::with_options(file, options).expect("could not create");
+            assert_eq!(kv.logical_size().unwrap(), 0);
+            let inline = kv.raw.is_inline();
+            let key_size = |key: &String| {
+                if inline {
+                    bincode::serialize(key).unwrap().len()
+                } else {
+                    let entry = KeyEntry {
+                        body: key,
+                        value_index: 0,
+                    };
+                    bincode::serialize(&entry).unwrap().len()
+                }
+            };
+            let value_size = |value: &Vec<u8>| bincode::serialize(value).unwrap().len();
+
+            let mut expected = 0;
+            for i in 0..10 {
+                let key = format!("key {}", i);
+                let value = vec![1_u8; i * 10];
+                expected += key_size(&key) + value_size(&value);
+                kv.set(key, value).expect("can not set");
+            }
+            assert_eq!(kv.raw.is_inline(), inline);
+            assert_eq!(kv.logical_size().unwrap(), expected);
+
+            // removed and replaced entries do not count anymore
+            kv.remove(&String::from("key 9")).expect("can not remove");
+            kv.set(String::from("key 8"), vec![]).expect("can not set");
+            expected -= key_size(&String::from("key 9")) + value_size(&vec![1_u8; 90]);
+            expected -= value_size(&vec![1_u8; 80]) - value_size(&vec![]);
+            assert_eq!(kv.logical_size().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn list() {
+        for options in [Options::default(), Options::new().inline_values(1024)] {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut kv =
+                KeyValue::<String, Vec<u8>>::with_options(file, options).expect("could not create");
+            let inline = kv.raw.is_inline();
+            for i in 0..10 {
+                kv.set(format!("key {}", i), vec![1_u8; i * 10])
+                    .expect("can not set");
+            }
+            assert_eq!(kv.raw.is_inline(), inline);
+
+            let reads = kv.stats().reads;
+            let summaries: Vec<KeySummary<String>> =
+                kv.list().collect::<Result<_, _>>().expect("can not list");
+            // at most the key entries were read, never any value
+            let key_reads = if inline { 0 } else { 10 };
+            assert_eq!(kv.stats().reads, reads + key_reads);
+
+            assert_eq!(summaries.len(), 10);
+            for (i, summary) in summaries.iter().enumerate() {
+                let value = kv.get(&summary.key).expect("can not get").unwrap();
+                assert_eq!(summary.key, format!("key {}", i));
+                assert_eq!(
+                    summary.value_bytes,
+                    bincode::serialize(&value).unwrap().len()
+                );
+                assert_eq!(summary.frames, if inline { 0 } else { 1 });
+            }
+
+            let page: Vec<String> = kv
+                .list_page(8, 5)
+                .map(|summary| summary.map(|summary| summary.key))
+                .collect::<Result<_, _>>()
+                .expect("can not list");
+            assert_eq!(page, ["key 8", "key 9"]);
+            assert_eq!(kv.list_page(3, 0).count(), 0);
+            assert_eq!(kv.list_page(10, 5).count(), 0);
+        }
+    }
+
+    #[test]
+    fn list_large_values() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv = KeyValue::<String, Vec<u8>>::new(file).expect("could not create");
+        kv.set(String::from("large"), vec![1_u8; 5000])
+            .expect("can not set");
+        let summary = kv.list().next().unwrap().expect("can not list");
+        assert_eq!(summary.value_bytes, 5008);
+        assert_eq!(
+            summary.frames,
+            kv.raw
+                .store
+                .record_extent(kv.raw.lookup[&summary.key])
+                .unwrap()
+                .1
+        );
+        assert!(summary.frames > 1);
+    }
+
+    #[test]
+    fn contains() {
+        for options in [Options::default(), Options::new().inline_values(1024)] {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut kv =
+                KeyValue::<String, i32>::with_options(file, options).expect("could not create");
+            assert!(!kv.contains_value(&1).unwrap());
+            for i in 0..10 {
+                kv.set(format!("key {}", i), i * 10).expect("can not set");
+            }
+            assert!(kv.contains_key(&String::from("key 3")));
+            assert!(!kv.contains_key(&String::from("key 10")));
+
+            let reads = kv.stats().reads;
+            assert!(kv.contains_value(&30).unwrap());
+            assert!(kv.stats().reads <= reads + 10);
+
+            // a miss reads every value exactly once
+            let reads = kv.stats().reads;
+            assert!(!kv.contains_value(&31).unwrap());
+            let value_reads = if kv.raw.is_inline() { 0 } else { 10 };
+            assert_eq!(kv.stats().reads, reads + value_reads);
+
+            kv.remove(&String::from("key 3")).expect("can not remove");
+            assert!(!kv.contains_value(&30).unwrap());
+        }
+    }
+
+    #[test]
+    fn index_memory_bytes() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv =
+            KeyValue::<String, i32>::new(file.try_clone().unwrap()).expect("could not create");
+        assert_eq!(kv.index_memory_bytes(), 0);
+        let entry_bytes = |key: &str| 8 + key.len() + std::mem::size_of::<(String, usize)>();
+
+        kv.set(String::from("a"), 1).expect("can not set");
+        kv.set(String::from("bcd"), 2).expect("can not set");
+        let expected = entry_bytes("a") + entry_bytes("bcd");
+        assert_eq!(kv.index_memory_bytes(), expected);
+
+        // overwrites keep the footprint
+        kv.set(String::from("a"), 3).expect("can not set");
+        assert_eq!(kv.index_memory_bytes(), expected);
+
+        kv.remove(&String::from("a")).expect("can not remove");
+        kv.remove(&String::from("missing")).expect("can not remove");
+        assert_eq!(kv.index_memory_bytes(), entry_bytes("bcd"));
+
+        // restored from the persisted index as well as from a rebuild
+        drop(kv);
+        let mut kv =
+            KeyValue::<String, i32>::new(file.try_clone().unwrap()).expect("could not open");
+        assert_eq!(kv.index_memory_bytes(), entry_bytes("bcd"));
+        kv.rebuild_index(&mut progress::ignore).unwrap();
+        assert_eq!(kv.index_memory_bytes(), entry_bytes("bcd"));
+    }
+
+    #[test]
+    fn max_index_bytes() {
+        let entry_bytes = 8 + 5 + std::mem::size_of::<(String, usize)>();
+        for inline in [0, 1024] {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let options = Options::new()
+                .inline_values(inline)
+                .max_index_bytes(3 * entry_bytes);
+            let mut kv =
+                KeyValue::<String, i32>::with_options(file, options).expect("could not create");
+            for i in 0..3 {
+                kv.set(format!("key {}", i), i).expect("can not set");
+            }
+            if inline > 0 {
+                // inline entries do not count until they spill
+                kv.set(String::from("key 3"), 3).expect("can not set");
+                let error = kv.set(String::from("x").repeat(1024), 4).unwrap_err();
+                assert!(matches!(
+                    error.downcast_ref(),
+                    Some(WiredError::IndexFull { .. })
+                ));
+                assert!(kv.raw.is_inline());
+                assert_eq!(kv.len(), 4);
+                continue;
+            }
+            assert_eq!(kv.index_memory_bytes(), 3 * entry_bytes);
+
+            let error = kv.set(String::from("key 3"), 3).unwrap_err();
+            let limit = 3 * entry_bytes;
+            assert_eq!(error.downcast_ref(), Some(&WiredError::IndexFull { limit }));
+            assert_eq!(kv.get(&String::from("key 3")).unwrap(), None);
+            assert_eq!(kv.len(), 3);
+
+            // existing keys can still be overwritten, and removals make room
+            kv.set(String::from("key 0"), 10).expect("can not set");
+            assert_eq!(kv.get(&String::from("key 0")).unwrap(), Some(10));
+            kv.remove(&String::from("key 1")).expect("can not remove");
+            kv.set(String::from("key 3"), 3).expect("can not set");
+            assert_eq!(kv.get(&String::from("key 3")).unwrap(), Some(3));
+        }
+    }
+
+    #[test]
+    fn usage() {
+        // key entries hold the index of their value block after the key
+        let expected = |entries: &HashMap<String, String>, inline: bool| {
+            let mut payload = 0;
+            let mut frames = 0;
+            for (key, value) in entries {
+                let key_size = bincode::serialized_size(key).unwrap() as usize;
+                let value_size = bincode::serialized_size(value).unwrap() as usize;
+                payload += key_size + value_size;
+                if !inline {
+                    frames += (key_size + 8).div_ceil(992) + value_size.div_ceil(992).max(1);
+                }
+            }
+            (payload, (frames * 1024).saturating_sub(payload))
+        };
+        let layouts = [
+            (Options::new(), false),
+            (Options::new().inline_values(1 << 20), true),
+            (Options::new().disk_index(16), false),
+        ];
+        for (options, inline) in layouts {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut kv = KeyValue::<String, String>::with_options(
+                file.try_clone().unwrap(),
+                options.clone(),
+            )
+            .expect("could not create");
+            let mut entries = HashMap::new();
+            for i in 0..6 {
+                let (key, value) = (format!("key {}", i), "v".repeat(i * 300));
+                kv.set(key.clone(), value.clone()).unwrap();
+                entries.insert(key, value);
+            }
+            let usage = |kv: &KeyValue<String, String>| (kv.payload_bytes(), kv.overhead_bytes());
+            assert_eq!(usage(&kv), expected(&entries, inline));
+
+            // overwrites and removals
+            let key = String::from("key 2");
+            kv.set(key.clone(), "w".repeat(2000)).unwrap();
+            entries.insert(key, "w".repeat(2000));
+            let key = String::from("key 4");
+            kv.remove(&key).unwrap();
+            entries.remove(&key);
+            assert_eq!(usage(&kv), expected(&entries, inline));
+
+            // persisted, and measured once for files that predate it
+            drop(kv);
+            let mut kv = KeyValue::<String, String>::with_options(
+                file.try_clone().unwrap(),
+                options.clone(),
+            )
+            .expect("could not open");
+            assert_eq!(usage(&kv), expected(&entries, inline));
+            kv.raw.header.usage = None;
+            kv.raw.save_header().unwrap();
+            drop(kv);
+            let kv =
+                KeyValue::<String, String>::with_options(file, options).expect("could not open");
+            assert_eq!(usage(&kv), expected(&entries, inline));
+        }
+    }
+
+    #[test]
+    fn migrator() {
+        for inline_values in [0, 64] {
+            let options = || Options::new().inline_values(inline_values);
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut old_kv =
+                KeyValue::<String, u32>::with_options(file.try_clone().unwrap(), options())
+                    .expect("could not create");
+            for id in 1..=3 {
+                old_kv.set(format!("key {}", id), id).unwrap();
+            }
+            drop(old_kv);
+
+            let key = |id| format!("key {}", id);
+            let value = |id, label: &str| (id, String::from(label));
+            let migrator: Migrator<(u32, String)> =
+                |bytes| Ok((bincode::deserialize(bytes)?, String::from("old")));
+            let kv = KeyValue::<String, (u32, String)>::with_options(
+                file.try_clone().unwrap(),
+                options(),
+            )
+            .expect("could not open");
+            assert!(kv.get(&key(1)).is_err());
+            let mut kv = kv.with_migrator(migrator);
+            kv.set(key(4), value(4, "new")).unwrap();
+            assert_eq!(kv.get(&key(1)).unwrap(), Some(value(1, "old")));
+            assert!(kv.contains_value(&value(4, "new")).unwrap());
+            let entries: Vec<_> = kv.iter_insertion_order().collect::<Result<_, _>>().unwrap();
+            assert_eq!(entries.len(), 4);
+            assert_eq!(entries[2], (key(3), value(3, "old")));
+
+            // rewritten values decode without any migrator
+            assert_eq!(kv.rewrite_all().unwrap(), 3);
+            assert_eq!(kv.rewrite_all().unwrap(), 0);
+            drop(kv);
+            let kv = KeyValue::<String, (u32, String)>::with_options(file, options())
+                .expect("could not open");
+            for id in 1..=3 {
+                assert_eq!(kv.get(&key(id)).unwrap(), Some(value(id, "old")));
+            }
+            assert_eq!(kv.get(&key(4)).unwrap(), Some(value(4, "new")));
+        }
+    }
+
+    #[test]
+    fn get_chunked() {
+        for options in [Options::default(), Options::new().inline_values(1024)] {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut kv =
+                KeyValue::<i32, Vec<u8>>::with_options(file, options).expect("could not create");
+            assert!(kv.get_chunked(&1).is_none());
+            let small = vec![7_u8; 100];
+            kv.set(1, small.clone()).expect("can not set");
+            let large = (0..5000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+            kv.set(2, large.clone()).expect("can not set");
+
+            for (key, value) in [(1, small.clone()), (2, large)] {
+                let chunks = kv
+                    .get_chunked(&key)
+                    .unwrap()
+                    .collect::<Result<Vec<Vec<u8>>, _>>()
+                    .expect("can not read");
+                let bytes = chunks.concat();
+                assert_eq!(bincode::deserialize::<Vec<u8>>(&bytes).unwrap(), value);
+            }
+            let chunks = kv.get_chunked(&2).unwrap().count();
+            assert!(chunks > 1);
+        }
+    }
+
+    #[test]
+    fn get_borrowed() {
+        for options in [Options::default(), Options::new().inline_values(1024)] {
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut kv =
+                KeyValue::<i32, String>::with_options(file, options).expect("could not create");
+            assert!(kv.get_borrowed(&1).unwrap().is_none());
+            let large = "x".repeat(5000);
+            kv.set(1, String::from("small")).expect("can not set");
+            kv.set(2, large.clone()).expect("can not set");
+
+            let small = kv.get_borrowed(&1).unwrap().unwrap();
+            assert_eq!(small.value::<&str>().unwrap(), "small");
+            assert!(small.is_zero_copy());
+            let large_guard = kv.get_borrowed(&2).unwrap().unwrap();
+            assert_eq!(large_guard.value::<&str>().unwrap(), large);
+            assert!(!large_guard.is_zero_copy());
+            assert!(small.value::<(&str, &str)>().is_err());
+        }
+    }
+
+    #[test]
+    fn disk_index() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        // few buckets, so that keys collide
+        let options = Options::new().disk_index(7);
+        let mut kv = KeyValue::<String, u32>::with_options(file.try_clone().unwrap(), options)
+            .expect("could not create");
+        for i in 0..100 {
+            kv.set(format!("key {}", i), i).expect("can not set");
+        }
+        assert_eq!(kv.len(), 100);
+        assert!(kv.raw.lookup.is_empty());
+        assert_eq!(kv.index_memory_bytes(), 0);
+        assert_eq!(kv.get(&String::from("key 42")).unwrap(), Some(42));
+        assert_eq!(kv.get(&String::from("key 100")).unwrap(), None);
+        assert!(kv.contains_key(&String::from("key 0")));
+
+        // overwrites and removals
+        kv.set(String::from("key 42"), 4200).expect("can not set");
+        assert_eq!(kv.get(&String::from("key 42")).unwrap(), Some(4200));
+        for i in 0..50 {
+            kv.remove(&format!("key {}", i)).expect("can not remove");
+        }
+        kv.remove(&String::from("key 0")).expect("can not remove");
+        assert_eq!(kv.len(), 50);
+        assert_eq!(kv.get(&String::from("key 42")).unwrap(), None);
+        assert_eq!(
+            kv.counters(),
+            KeyValueCounters {
+                inserts: 100,
+                overwrites: 1,
+                removals: 50,
+            }
+        );
+        assert!(kv.contains_value(&99).unwrap());
+        let mut keys: Vec<String> = kv.list().map(|summary| summary.unwrap().key).collect();
+        keys.sort();
+        assert_eq!(keys.len(), 50);
+        assert_eq!(keys[0], "key 50");
+        drop(kv);
+
+        // the index is read from the file, ignoring options for existing files
+        let kv = KeyValue::<String, u32>::new(file).expect("could not open");
+        assert_eq!(kv.len(), 50);
+        assert!(kv.raw.lookup.is_empty());
+        for i in 50..100 {
+            assert_eq!(kv.get(&format!("key {}", i)).unwrap(), Some(i));
+        }
+        let entries: Vec<(String, u32)> = kv
+            .iter_insertion_order()
+            .collect::<Result<_, _>>()
+            .expect("can not iterate");
+        assert_eq!(entries.len(), 50);
+        assert!(entries
+            .iter()
+            .all(|(key, value)| *key == format!("key {}", value)));
+        assert_eq!(kv.raw.store.live_frames(), 1 + 1 + 7 + 2 * 50);
+    }
+
+    #[test]
+    fn disk_index_failures() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let options = Options::new().disk_index(3);
+        let mut kv = KeyValue::<i32, i32>::with_options(file, options).expect("could not create");
+        kv.set(1, 1).expect("can not set");
+        kv.set(2, 2).expect("can not set");
+        let frames = kv.raw.store.live_frames();
+
+        // every step of a set or remove can fail, without any trace
+        for step in 0.. {
+            kv.raw.store.fail_after(step);
+            let result = kv.set(1, 10);
+            kv.raw.store.clear_fault();
+            if result.is_ok() {
+                break;
+            }
+            assert_eq!(kv.get(&1).unwrap(), Some(1));
+            assert_eq!(kv.len(), 2);
+            assert_eq!(kv.raw.store.live_frames(), frames);
+        }
+        assert_eq!(kv.get(&1).unwrap(), Some(10));
+        for step in 0.. {
+            kv.raw.store.fail_after(step);
+            let result = kv.remove(&2);
+            kv.raw.store.clear_fault();
+            if result.is_ok() {
+                break;
+            }
+            assert_eq!(kv.get(&2).unwrap(), Some(2));
+            assert_eq!(kv.len(), 2);
+        }
+        assert_eq!(kv.get(&2).unwrap(), None);
+        assert_eq!(kv.len(), 1);
+    }
+
+    /// a payload that counts how often it gets deserialized
+    #[derive(Serialize, Debug, PartialEq)]
+    struct CountedPayload(String);
+
+    static PAYLOADS_DECODED: std::sync::atomic::AtomicUsize =
+        std::sync::atomic::AtomicUsize::new(0);
+
+    impl<'de> Deserialize<'de> for CountedPayload {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            PAYLOADS_DECODED.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(CountedPayload(String::deserialize(deserializer)?))
+        }
+    }
+
+    #[test]
+    fn scan_with_partial_values() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Job {
+            status: u8,
+            payload: CountedPayload,
+        }
+
+        #[derive(Deserialize)]
+        struct JobStatus {
+            status: u8,
+        }
+
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut kv = KeyValue::<u32, Job>::new(file).unwrap();
+        for id in 0..100 {
+            let payload = CountedPayload("x".repeat(200));
+            let status = (id % 10) as u8;
+            kv.set(id, Job { status, payload }).unwrap();
+        }
+        let decoded = PAYLOADS_DECODED.load(std::sync::atomic::Ordering::SeqCst);
+
+        // only the ten matches get deserialized in full
+        let failed = kv
+            .scan_where(|job: &JobStatus| job.status == 3)
+            .map(|entry| entry.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(failed.len(), 10);
+        assert!(failed
+            .iter()
+            .all(|(id, job)| id % 10 == 3 && job.status == 3));
+        let after_scan = PAYLOADS_DECODED.load(std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(after_scan, decoded + 10);
+
+        // the raw bytes start with the status
+        let keys = kv
+            .scan_raw(|_, bytes| bytes[0] == 7)
+            .map(|entry| entry.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![7, 17, 27, 37, 47, 57, 67, 77, 87, 97]);
+        let after_raw = PAYLOADS_DECODED.load(std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(after_raw, after_scan);
+
+        // decoding everything touches every payload
+        assert_eq!(kv.iter_insertion_order().count(), 100);
+        let after_iter = PAYLOADS_DECODED.load(std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(after_iter, after_raw + 100);
     }
 }
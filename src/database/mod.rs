@@ -1,3 +1,521 @@
+pub mod btree_key_value;
+pub mod counters;
+mod disk_index;
 pub mod key_value;
 pub mod queue;
+pub mod slot_store;
 pub mod stack;
+mod value_cache;
+
+use crate::block_storage::BlockStorage;
+use crate::encoding::{deserialize_bounded, IntEncoding};
+use crate::error::WiredError;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::{BufRead, Read, Write};
+
+/// A fallback for items written by an older version of their type, which is
+/// called only when the stored bytes do not deserialize as the current type
+/// anymore. It receives the serialized item, written with the
+/// [`IntEncoding`](crate::IntEncoding) of the container, and usually decodes
+/// it as the old type and converts it.
+pub type Migrator<T> = fn(&[u8]) -> Result<T, Box<dyn Error>>;
+
+/// decode an item, handing it to the migrator if there is one and the item
+/// does not decode as the current type
+fn decode_migrating<T: DeserializeOwned>(
+    encoding: IntEncoding,
+    migrator: Option<Migrator<T>>,
+    bytes: &[u8],
+) -> Result<T, Box<dyn Error>> {
+    match (encoding.deserialize(bytes), migrator) {
+        (Err(_), Some(migrator)) => migrator(bytes),
+        (result, _) => result,
+    }
+}
+
+/// name the operation and the record in the error of an item that does not
+/// deserialize, since the error of bincode alone does not tell which one
+fn decode_context<R>(
+    result: Result<R, Box<dyn Error>>,
+    store: &BlockStorage,
+    operation: &'static str,
+    index: usize,
+) -> Result<R, Box<dyn Error>> {
+    result.map_err(|error| match error.downcast_ref::<bincode::Error>() {
+        Some(reason) => WiredError::DeserializeFailed {
+            operation,
+            index,
+            position: store.index_to_position(index),
+            reason: reason.to_string(),
+        }
+        .into(),
+        None => error,
+    })
+}
+
+/// count one record less, returning whether the count was zero already.
+/// Then the header drifted from its records, e.g. after a crash, so the
+/// count stays at zero for the caller to flag instead of panicking.
+///
+/// In unit tests a drift is a bug of the counting itself, unless the test
+/// fabricated the drifted header on purpose with `fabricate_drift`.
+fn count_down(count: &mut usize) -> bool {
+    match count.checked_sub(1) {
+        Some(rest) => {
+            *count = rest;
+            false
+        }
+        None => {
+            #[cfg(test)]
+            assert!(
+                FABRICATED_DRIFT.with(std::cell::Cell::get),
+                "a count of the header went below zero"
+            );
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    static FABRICATED_DRIFT: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// let `count_down` absorb a drift on this test thread, for tests that
+/// fabricate a header which lost track of its records
+#[cfg(test)]
+fn fabricate_drift() {
+    FABRICATED_DRIFT.with(|drift| drift.set(true));
+}
+
+/// FNV-1a over a sequence of records for the `content_hash` of the
+/// containers. Every record gets prefixed with its length, so moving bytes
+/// from one record into the next changes the hash.
+struct ContentHash(u64);
+
+impl ContentHash {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn record(&mut self, bytes: &[u8]) {
+        self.feed(&(bytes.len() as u64).to_le_bytes());
+        self.feed(bytes);
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(self) -> u64 {
+        self.0
+    }
+}
+
+/// A stored item, serialized, ready to deserialize into a type that borrows
+/// from it, like a struct with `&str` fields, so reading it copies no field.
+/// It gets handed out by
+/// [`KeyValue::get_borrowed`](crate::KeyValue::get_borrowed) and
+/// [`Queue::scan_borrowed`](crate::Queue::scan_borrowed).
+///
+/// An item within a single frame is borrowed straight from the mapped file.
+/// Larger items and compressed ones get copied into a buffer owned by the
+/// guard, so they work the same, with a single allocation.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// #[derive(serde::Serialize, serde::Deserialize)]
+/// struct User {
+///     name: String,
+///     email: String,
+/// }
+///
+/// #[derive(serde::Deserialize)]
+/// struct UserView<'a> {
+///     name: &'a str,
+///     email: &'a str,
+/// }
+///
+/// # let file = tempfile::tempfile()?;
+/// let mut kv = wired::KeyValue::<u64, User>::new(file)?;
+/// let user = User { name: "Jane".into(), email: "jane@example.com".into() };
+/// kv.set(1, user)?;
+/// if let Some(guard) = kv.get_borrowed(&1)? {
+///     let view: UserView = guard.value()?;
+///     assert_eq!(view.name, "Jane");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Borrowed<'a> {
+    bytes: Cow<'a, [u8]>,
+    encoding: IntEncoding,
+}
+
+impl<'a> Borrowed<'a> {
+    fn new(bytes: Cow<'a, [u8]>, encoding: IntEncoding) -> Self {
+        Self { bytes, encoding }
+    }
+
+    /// deserialize the item into a type that may borrow from this guard.
+    /// Unlike the owned reads, this never calls a migrator.
+    pub fn value<'b, T: Deserialize<'b>>(&'b self) -> Result<T, Box<dyn Error>> {
+        self.encoding.deserialize(&self.bytes)
+    }
+
+    /// the serialized item
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// whether the item is borrowed without a copy, which is the case for
+    /// items within a single frame that are not compressed
+    pub fn is_zero_copy(&self) -> bool {
+        matches!(self.bytes, Cow::Borrowed(_))
+    }
+}
+
+/// join the links of a `Queue` or `Stack` element with its serialized body.
+/// Files created with `Options::compress_above` store a flag in between,
+/// and a body beyond the threshold gets compressed if that makes it smaller.
+/// Other files keep the plain layout of links followed by the body.
+fn join_element<L: Serialize>(
+    links: &L,
+    body: &[u8],
+    encoding: IntEncoding,
+    compress_above: Option<usize>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut bytes = encoding.serialize(links)?;
+    let threshold = match compress_above {
+        Some(threshold) => threshold,
+        None => {
+            bytes.extend_from_slice(body);
+            return Ok(bytes);
+        }
+    };
+    if body.len() > threshold {
+        let compressed = lz4_flex::compress_prepend_size(body);
+        if compressed.len() < body.len() {
+            bytes.extend(encoding.serialize(&true)?);
+            bytes.extend(compressed);
+            return Ok(bytes);
+        }
+    }
+    bytes.extend(encoding.serialize(&false)?);
+    bytes.extend_from_slice(body);
+    Ok(bytes)
+}
+
+/// the bytes of the frames an element with a serialized body of the given
+/// length takes, see `join_element`. The body counts uncompressed, so this
+/// is an upper bound for bodies that compression makes smaller.
+fn element_bytes<L: Serialize>(
+    store: &BlockStorage,
+    links: &L,
+    body_len: usize,
+    encoding: IntEncoding,
+    compress_above: Option<usize>,
+) -> Result<usize, Box<dyn Error>> {
+    let mut len = encoding.serialized_size(links)? + body_len;
+    if compress_above.is_some() {
+        len += encoding.serialized_size(&false)?;
+    }
+    Ok(store.frames_for(len) * store.frame_size())
+}
+
+/// the links of a stored element, whether its body was compressed and the
+/// serialized body
+type SplitElement<'a, L> = (L, bool, Cow<'a, [u8]>);
+
+/// split a stored element into its links, whether the body was compressed
+/// and the serialized body, decompressed if needed. See `join_element`.
+fn split_element<L: Serialize + DeserializeOwned>(
+    bytes: &[u8],
+    encoding: IntEncoding,
+    compress_above: Option<usize>,
+) -> Result<SplitElement<'_, L>, Box<dyn Error>> {
+    let links: L = encoding.deserialize(bytes)?;
+    let body = &bytes[encoding.serialized_size(&links)?..];
+    if compress_above.is_none() {
+        return Ok((links, false, Cow::Borrowed(body)));
+    }
+    let compressed: bool = encoding.deserialize(body)?;
+    let body = &body[encoding.serialized_size(&compressed)?..];
+    if compressed {
+        let body = lz4_flex::decompress_size_prepended(body)?;
+        Ok((links, true, Cow::Owned(body)))
+    } else {
+        Ok((links, false, Cow::Borrowed(body)))
+    }
+}
+
+// more zero bytes than all fields added to any header since its first version
+const HEADER_PADDING: u64 = 1024;
+
+/// decode a container header that may have been written by an older version.
+///
+/// headers only ever grow by appending fields, so any trailing field missing
+/// in an older file is decoded from zero bytes, which yields `0`, `false`,
+/// `None` or an empty collection. The padding is limited, so bytes that are
+/// no such header fail instead of decoding a huge collection from zeros, and
+/// so does a length prefix claiming more than the padded bytes.
+fn decode_header<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Box<dyn Error>> {
+    let padded = bytes.chain(std::io::repeat(0).take(HEADER_PADDING));
+    deserialize_bounded(padded, bytes.len() + HEADER_PADDING as usize)
+}
+
+/// The outcome of comparing the header of a [`Queue`](crate::Queue) or
+/// [`Stack`](crate::Stack) with the chain of elements it points to, which
+/// may disagree after an unclean shutdown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainCheck {
+    /// the number of elements the header claims
+    pub stored_count: usize,
+    /// the number of elements actually reachable through the chain
+    pub reachable_count: usize,
+    /// whether a pointer referred to a block that holds no element
+    pub broken_links: bool,
+    /// whether the header and links were corrected to match the chain
+    pub repaired: bool,
+}
+
+impl ChainCheck {
+    /// true if the header matches the chain, nothing needs a repair
+    pub fn is_consistent(&self) -> bool {
+        self.stored_count == self.reachable_count && !self.broken_links
+    }
+}
+
+/// What [`Queue::open_checked`](crate::Queue::open_checked) validated
+/// during its scan of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenReport {
+    /// the comparison of the header with the chain, repaired if needed
+    pub chain: ChainCheck,
+    /// the number of items that were read and decoded successfully
+    pub items_verified: usize,
+    /// the number of bytes of all records read by the scan
+    pub bytes_scanned: usize,
+    /// whether a count of the header had drifted below zero before, see
+    /// [`Queue::counter_drift`](crate::Queue::counter_drift), which the
+    /// repair of the scan cleared
+    pub counter_drift: bool,
+}
+
+/// What a garbage collection like [`Queue::gc`](crate::Queue::gc) reclaimed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// the number of unreachable records that got deleted
+    pub blocks: usize,
+    /// the bytes of all frames of those records, free for reuse now
+    pub bytes: usize,
+}
+
+/// delete every record that is neither reachable from the container nor
+/// the metadata blob, after all of them were found
+fn collect_garbage(
+    store: &mut BlockStorage,
+    reachable: &HashSet<usize>,
+) -> Result<GcReport, Box<dyn Error>> {
+    let orphans: Vec<usize> = store
+        .iter_blocks()?
+        .filter(|index| !reachable.contains(index))
+        .collect();
+    let mut report = GcReport::default();
+    for index in orphans {
+        let (_, frames) = store.record_extent(index)?;
+        store.delete(index)?;
+        report.blocks += 1;
+        report.bytes += frames * store.frame_size();
+    }
+    Ok(report)
+}
+
+/// How [`Queue::drain_raw_to`](crate::Queue::drain_raw_to) and
+/// [`Stack::drain_raw_to`](crate::Stack::drain_raw_to) delimit the records
+/// they write, and how `load_raw_from` reads them back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordFraming {
+    /// every record follows its length as a little-endian `u64`, which
+    /// works for any payload
+    LengthPrefixed,
+    /// every record is followed by a newline byte, which works only for
+    /// payloads without one, like strings without line breaks
+    NewlineDelimited,
+}
+
+impl RecordFraming {
+    fn write_record(self, writer: &mut impl Write, payload: &[u8]) -> Result<(), Box<dyn Error>> {
+        match self {
+            RecordFraming::LengthPrefixed => {
+                writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+                writer.write_all(payload)?;
+            }
+            RecordFraming::NewlineDelimited => {
+                if payload.contains(&b'\n') {
+                    return Err(WiredError::NewlineInRecord.into());
+                }
+                writer.write_all(payload)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// the next record, `None` at the end of the stream
+    fn read_record(self, reader: &mut impl BufRead) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        if reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let mut payload = vec![];
+        match self {
+            RecordFraming::LengthPrefixed => {
+                let mut len = [0; 8];
+                reader.read_exact(&mut len)?;
+                let len = u64::from_le_bytes(len);
+                // a corrupted length only reads up to the end of the stream
+                reader.take(len).read_to_end(&mut payload)?;
+                if (payload.len() as u64) < len {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+            }
+            RecordFraming::NewlineDelimited => {
+                reader.read_until(b'\n', &mut payload)?;
+                // the last record may lack its newline
+                if payload.last() == Some(&b'\n') {
+                    payload.pop();
+                }
+            }
+        }
+        Ok(Some(payload))
+    }
+}
+
+/// How much work a single [`Queue::compact_step`](crate::Queue::compact_step)
+/// may do before it returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactBudget {
+    /// the most records to move towards the front of the file
+    pub blocks: usize,
+}
+
+impl Default for CompactBudget {
+    fn default() -> Self {
+        Self { blocks: 64 }
+    }
+}
+
+/// What a single [`Queue::compact_step`](crate::Queue::compact_step) achieved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactProgress {
+    /// the number of records moved towards the front of the file
+    pub relocated: usize,
+    /// the number of bytes the file shrank by, only ever in the last step
+    pub released_bytes: usize,
+    /// whether no record can move any further, so the compaction is finished
+    /// and further steps do nothing until the file gets fragmented again
+    pub done: bool,
+}
+
+/// A container that knows every reference to its records, so a compaction
+/// may move them within the file.
+trait Relocate {
+    fn store_mut(&mut self) -> &mut BlockStorage;
+
+    /// refer to the copy at `to` instead of the record at `from` everywhere
+    /// and persist that. Returns `false` without changing anything if the
+    /// container does not refer to `from` at all.
+    fn relocate(&mut self, from: usize, to: usize) -> Result<bool, Box<dyn Error>>;
+}
+
+/// move up to the budget of records from the end of the file into free
+/// frames closer to the front, the furthest one first. Once the furthest
+/// record can not move anymore, the file gets shrunk to end right after it.
+///
+/// every record is copied first, the container refers to the copy next and
+/// the original gets deleted last, so a crash in between leaves an orphan
+/// for `gc` at worst. The header and records the container does not know
+/// never move, which ends the compaction early.
+fn compact_step<C: Relocate>(
+    container: &mut C,
+    budget: CompactBudget,
+) -> Result<CompactProgress, Box<dyn Error>> {
+    let mut progress = CompactProgress::default();
+    for index in container.store_mut().records_from_end()? {
+        if progress.relocated == budget.blocks {
+            return Ok(progress);
+        }
+        let store = container.store_mut();
+        if index == 0 {
+            break;
+        } else if store.is_meta(index) {
+            if !store.move_meta_to_front()? {
+                break;
+            }
+        } else {
+            let Some(copy) = store.copy_to_front(index)? else {
+                break;
+            };
+            if !container.relocate(index, copy)? {
+                container.store_mut().delete(copy)?;
+                break;
+            }
+            container.store_mut().delete(index)?;
+        }
+        progress.relocated += 1;
+    }
+    progress.released_bytes = container.store_mut().shrink_to_fit()?;
+    progress.done = true;
+    Ok(progress)
+}
+
+/// The space taken by the items of a container, kept in its header so it is
+/// known without reading any item. `None` in headers written before it
+/// existed, which get measured once when opened.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Usage {
+    // serialized bytes of the items themselves
+    payload_bytes: u64,
+    // frames of all records holding items, including their pointers
+    frames: u64,
+}
+
+impl Usage {
+    /// the usage of a record holding the given number of payload bytes in
+    /// the given number of frames, see `BlockStorage::frames_for`
+    fn of_record(payload_bytes: usize, frames: usize) -> Self {
+        Self {
+            payload_bytes: payload_bytes as u64,
+            frames: frames as u64,
+        }
+    }
+
+    fn add(&mut self, other: Usage) {
+        self.payload_bytes += other.payload_bytes;
+        self.frames += other.frames;
+    }
+
+    fn remove(&mut self, other: Usage) {
+        self.payload_bytes = self.payload_bytes.saturating_sub(other.payload_bytes);
+        self.frames = self.frames.saturating_sub(other.frames);
+    }
+
+    fn payload_bytes(&self) -> usize {
+        self.payload_bytes as usize
+    }
+
+    /// bytes of the frames that do not hold payload: pointers, frame
+    /// headers and the unused rest of the last frame of every record
+    fn overhead_bytes(&self, frame_size: usize) -> usize {
+        let allocated = self.frames as usize * frame_size;
+        allocated.saturating_sub(self.payload_bytes())
+    }
+}
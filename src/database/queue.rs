@@ -1,8 +1,26 @@
-use crate::block_storage::BlockStorage;
+use super::{
+    collect_garbage, compact_step, count_down, decode_context, decode_header, decode_migrating,
+    element_bytes, join_element, split_element, Borrowed, ChainCheck, CompactBudget,
+    CompactProgress, ContentHash, GcReport, Migrator, OpenReport, RecordFraming, Relocate,
+    SplitElement, Usage,
+};
+use crate::block_storage::{BlockStorage, Stats};
+use crate::encoding::IntEncoding;
+use crate::error::WiredError;
+use crate::options::Options;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
 use std::fs::File;
+use std::io::{BufRead, Write};
 use std::marker::PhantomData;
+#[cfg(unix)]
+use std::os::unix::io::OwnedFd;
+#[cfg(windows)]
+use std::os::windows::io::OwnedHandle;
+use std::path::Path;
+use std::time::SystemTime;
 
 /// a First-In-First-Out Database
 ///
@@ -27,6 +45,17 @@ use std::marker::PhantomData;
 /// >
 /// > -- <cite>[Wikipedia](https://en.wikipedia.org/wiki/Queue_(abstract_data_type))</cite>
 ///
+/// Several handles may use the same file at once, even from different
+/// processes, like a producer that enqueues while a consumer dequeues. Every
+/// operation takes an advisory lock on the file and re-reads the header, so
+/// no handle works with an outdated view. Open each handle separately
+/// instead of cloning the `File`, since cloned handles share their lock.
+///
+/// Every operation flushes its writes to the file before it returns, so
+/// another process sees them in its next operation, or when it opens the
+/// file later on. Use [`barrier`](Self::barrier) to make them survive a
+/// crash of the whole machine as well.
+///
 /// # Examples
 ///
 /// ```rust,no_run
@@ -57,11 +86,24 @@ use std::marker::PhantomData;
 /// # Ok(())
 /// # }
 /// ```
-
 pub struct Queue<T> {
+    // everything that works on serialized items, see `RawQueue`
+    raw: RawQueue,
+    migrator: Option<Migrator<T>>,
+    data_type: PhantomData<T>,
+}
+
+/// The part of a `Queue` that works on serialized items only: the chains of
+/// elements, the header and the bookkeeping around them. It does not
+/// depend on the type of the items, so it gets compiled once instead of for
+/// every `T`, and `Queue<T>` only adds the serialization at its boundary.
+struct RawQueue {
     store: BlockStorage,
     header: Header,
-    data_type: PhantomData<T>,
+    // how often a level may be passed over in a row, see `with_level_weight`
+    level_weight: Option<u64>,
+    // the level whose chain is swapped into the header, see `at_level`
+    active_level: Level,
 }
 
 impl<T> Queue<T>
@@ -101,177 +143,3959 @@ where
     /// # }
     /// ```
     pub fn new(file: File) -> Result<Self, Box<dyn Error>> {
-        let mut store = BlockStorage::new(file)?;
-        let header = Self::read_header(&mut store)?;
-        let data_type = PhantomData;
-        let mut queue = Self {
-            store,
-            header,
-            data_type,
-        };
-        queue.save_header()?;
-        Ok(queue)
+        Self::with_options(file, Options::default())
     }
 
-    pub fn len(&self) -> usize {
-        self.header.elements_count
+    /// Open a database from a file descriptor opened elsewhere, e.g. passed
+    /// down by systemd or a sandbox broker, where no path is available. The
+    /// database takes ownership of the descriptor and closes it on drop.
+    /// Like for `new`, the file must be open for reading and writing.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let fd: std::os::unix::io::OwnedFd = file.into();
+    /// let queue = wired::Queue::<String>::from_fd(fd)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(unix)]
+    pub fn from_fd(fd: impl Into<OwnedFd>) -> Result<Self, Box<dyn Error>> {
+        Self::new(File::from(fd.into()))
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
+    /// like `from_fd`, but for a handle of a file on Windows
+    #[cfg(windows)]
+    pub fn from_handle(handle: impl Into<OwnedHandle>) -> Result<Self, Box<dyn Error>> {
+        Self::new(File::from(handle.into()))
     }
 
-    fn read_header(store: &mut BlockStorage) -> Result<Header, Box<dyn Error>> {
-        let bytes = store.read(0)?;
-        if store.is_empty() {
-            let header = Header::default();
-            let bytes: Vec<u8> = bincode::serialize(&header)?;
-            store.create(bytes.as_slice())?;
-            Ok(header)
-        } else {
-            let header = bincode::deserialize_from(bytes.as_slice())?;
-            Ok(header)
-        }
+    /// Open a database from a borrowed file, which stays usable for the
+    /// caller. The database works on a duplicate of it from `try_clone`,
+    /// which refers to the same open file and gets closed on drop.
+    ///
+    /// Note: a duplicate shares the advisory lock of the original, so do not
+    /// use the lent `File` for another handle of the same database at the
+    /// same time. Open such handles separately instead.
+    pub fn from_file(file: &File) -> Result<Self, Box<dyn Error>> {
+        Self::new(file.try_clone()?)
     }
 
-    fn save_header(&mut self) -> Result<(), Box<dyn Error>> {
-        let bytes: Vec<u8> = bincode::serialize(&self.header)?;
-        self.store.update(0, bytes.as_slice())
+    /// Create a new database or open an existing one, tuning how a new file
+    /// gets initialized. See [`Options`](crate::Options) for details.
+    pub fn with_options(file: File, options: Options) -> Result<Self, Box<dyn Error>> {
+        let store = BlockStorage::with_options(file, &options)?;
+        Self::from_store(store, &options)
     }
 
-    /// insert a new item in front of the queue and persist to disk
+    /// Open the database at the given path, creating the file if needed.
+    ///
+    /// Prefer this over `new` when possible: knowing the path allows upgrades
+    /// of older file formats to replace the file atomically.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = wired::Queue::<String>::open("path/to/db.wired")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::open_with_options(path, Options::default())
+    }
+
+    /// Open the existing database at the given path, like `open`, but fail
+    /// with an `io::Error` of kind `NotFound` instead of creating a new
+    /// empty database if there is no file, e.g. because of a mistyped path.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = wired::Queue::<String>::open_existing("path/to/db.wired")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_existing<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let options = Options::default();
+        let store = BlockStorage::open_existing(path.as_ref(), &options)?;
+        Self::from_store(store, &options)
+    }
+
+    /// Open a database whose file got truncated, e.g. by a partial copy,
+    /// to salvage the items that survived. Opening it like `open` fails with
+    /// [`WiredError::Truncated`](crate::WiredError::Truncated) instead.
+    ///
+    /// Only the frames that still fit into the file are kept, also in a
+    /// file of an older format version, before it gets migrated. Reading an
+    /// item with a frame behind the end fails with `Truncated`, or fails to
+    /// deserialize once the file grew over the lost frames, so the oldest
+    /// items at the front of the file can be dequeued until the first such
+    /// error. A file cut before the end of the first frame, which holds the
+    /// header of the queue, still fails with `Truncated`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut queue = wired::Queue::<String>::open_truncated_tolerant("path/to/db.wired")?;
+    /// let mut salvaged = vec![];
+    /// while let Ok(Some(item)) = queue.dequeue() {
+    ///     salvaged.push(item);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_truncated_tolerant<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let options = Options::default();
+        let store = BlockStorage::open_truncated_tolerant(path.as_ref(), &options)?;
+        Self::from_store(store, &options)
+    }
+
+    /// Open the database at the given path, tuning how a new file gets
+    /// initialized. See [`Options`](crate::Options) for details.
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        options: Options,
+    ) -> Result<Self, Box<dyn Error>> {
+        let store = BlockStorage::open(path.as_ref(), &options)?;
+        Self::from_store(store, &options)
+    }
+
+    /// Open the database and validate it in a single sequential scan along
+    /// the chain of elements, for a fast and safe start after a restart.
+    /// The scan compares the header with the chain and repairs it like
+    /// `check_counts(true)`, decodes every item, and reads every record once,
+    /// so the queue is served from a warm page cache afterwards.
+    ///
+    /// An item that does not decode fails with `WiredError::Corrupted`
+    /// before anything gets repaired.
+    ///
+    /// Note: this is an `O(n)` operation that reads and deserializes every
+    /// single item, and takes longer than `new` on large queues.
     ///
     /// # Examples
     ///
     /// ```rust,no_run
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// # let file = tempfile::tempfile()?;
-    /// let mut queue = wired::Queue::<String>::new(file)?;
-    /// let item = String::from("some item");
-    /// queue.enqueue(item)?;
+    /// let (queue, report) = wired::Queue::<String>::open_checked(file)?;
+    /// println!("{} items verified", report.items_verified);
     /// # Ok(())
     /// # }
     /// ```
-    pub fn enqueue(&mut self, data: T) -> Result<(), Box<dyn Error>> {
-        let mut element = Element {
-            body: data,
-            next: 0,
-            prev: 0,
-        };
-        if self.header.first_element != 0 {
-            element.next = self.header.first_element;
-        }
-        let bytes: Vec<u8> = bincode::serialize(&element)?;
-        let index = self.store.create(bytes.as_slice())?;
+    pub fn open_checked(file: File) -> Result<(Self, OpenReport), Box<dyn Error>> {
+        let mut queue = Self::new(file)?;
+        let report = queue.raw.synchronized(|raw| {
+            let counter_drift = raw.header.counter_drift;
+            let (encoding, compress_above) = (raw.header.encoding, raw.header.compress_above);
+            let (mut items_verified, mut bytes_scanned) = (0, 0);
+            let chain = raw.check_counts_unsynchronized(true, &mut |index, record| {
+                bytes_scanned += record.len();
+                split_element::<Links>(record, encoding, compress_above)
+                    .and_then(|(_, _, body)| encoding.deserialize::<T>(&body))
+                    .map_err(|_| WiredError::Corrupted { index })?;
+                items_verified += 1;
+                Ok(())
+            })?;
+            Ok(OpenReport {
+                chain,
+                items_verified,
+                bytes_scanned,
+                counter_drift,
+            })
+        })?;
+        Ok((queue, report))
+    }
 
-        if self.header.first_element != 0 {
-            let first_index = self.header.first_element;
-            let first_bytes = self.store.read(first_index)?;
-            let mut first: Element<T> = bincode::deserialize_from(first_bytes.as_slice())?;
-            first.prev = index;
-            let first_bytes: Vec<u8> = bincode::serialize(&first)?;
-            self.store.update(first_index, first_bytes.as_slice())?;
-        }
-        if self.header.last_element == 0 {
-            self.header.last_element = index;
-        }
-        self.header.first_element = index;
-        self.header.elements_count += 1;
-        self.save_header()?;
-        Ok(())
+    /// Fill a new file with all items at once, much faster than enqueuing
+    /// them one by one. The items are written front to back without
+    /// consulting the free list, and the file is flushed once at the end
+    /// instead of after every item. The first item is the first to be
+    /// dequeued, just like with `enqueue`.
+    ///
+    /// `size_hint` is the expected number of items. The file grows once to
+    /// hold that many items of the size of the first one. It is only an
+    /// estimate: too few grows the file as usual, too many leaves the
+    /// space for later items.
+    ///
+    /// Fails with `WiredError::NotEmpty` unless the file is new.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let items = (0..1_000_000).map(|id| format!("item {}", id));
+    /// let queue = wired::Queue::<String>::bulk_load(file, items, 1_000_000)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn bulk_load<I>(file: File, items: I, size_hint: usize) -> Result<Self, Box<dyn Error>>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        Self::bulk_load_with_options(file, items, size_hint, Options::default())
     }
 
-    /// remove the item at the back of the queue, persist to disk and return the item
+    /// Fill a new file with all items at once like `bulk_load`, tuning how
+    /// the file gets initialized and the items get stored, e.g. with
+    /// `Options::compress_above`. See [`Options`](crate::Options) for details.
     ///
-    /// Note: if you discard the dequeued item it will be lost permanently!
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let items = (0..1_000_000).map(|id| format!("item {}", id));
+    /// let options = wired::Options::new().compress_above(256);
+    /// let queue = wired::Queue::<String>::bulk_load_with_options(file, items, 1_000_000, options)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn bulk_load_with_options<I>(
+        file: File,
+        items: I,
+        size_hint: usize,
+        options: Options,
+    ) -> Result<Self, Box<dyn Error>>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let mut store = BlockStorage::with_options(file, &options)?;
+        let encoding = options.int_encoding;
+        let mut bodies = items.into_iter().map(|item| encoding.serialize(&item));
+        store.register("Queue")?;
+        store.lock()?;
+        let result = RawQueue::load(&mut store, &mut bodies, size_hint, &options);
+        store.unlock()?;
+        result?;
+        Self::from_store(store, &options)
+    }
+
+    fn from_store(store: BlockStorage, options: &Options) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            raw: RawQueue::open(store, options)?,
+            migrator: None,
+            data_type: PhantomData,
+        })
+    }
+
+    /// run the operation with exclusive access to the file and the latest
+    /// header, see `RawQueue::synchronized`
+    fn synchronized<R>(
+        &mut self,
+        operation: impl FnOnce(&mut Self) -> Result<R, Box<dyn Error>>,
+    ) -> Result<R, Box<dyn Error>> {
+        self.raw.store.lock()?;
+        let result = self.raw.refresh_header().and_then(|()| operation(self));
+        self.raw.store.unlock()?;
+        result
+    }
+
+    /// the number of elements of all levels, including those enqueued by
+    /// other handles
+    ///
+    /// Note: other handles may live in other processes, so every call takes
+    /// a shared lock of the file and reads and decodes the header block.
+    /// That is cheap, but not free: poll it sparingly in hot loops, or count
+    /// the results of `dequeue` instead.
+    pub fn len(&self) -> usize {
+        self.raw.len()
+    }
+
+    /// the number of elements of every level, from the highest to the lowest
     ///
     /// # Examples
     ///
     /// ```rust,no_run
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// # let file = tempfile::tempfile()?;
+    /// use wired::Level;
+    ///
     /// let mut queue = wired::Queue::<String>::new(file)?;
-    /// queue.enqueue(String::from("some item"))?;
-    /// let item = queue.dequeue()?;
+    /// queue.enqueue_with_level(String::from("urgent"), Level::High)?;
+    /// let levels = queue.len_by_level(); // [(High, 1), (Normal, 0), (Low, 0)]
     /// # Ok(())
     /// # }
     /// ```
-    pub fn dequeue(&mut self) -> Result<Option<T>, Box<dyn Error>> {
-        if self.header.elements_count == 0 {
-            return Ok(None);
-        }
-        let index = self.header.last_element;
-        let bytes = self.store.read(index)?;
-        let element: Element<T> = bincode::deserialize_from(bytes.as_slice())?;
-        self.store.delete(index)?;
-        self.header.last_element = element.prev;
-        self.header.elements_count -= 1;
-        self.save_header()?;
-        Ok(Some(element.body))
+    pub fn len_by_level(&self) -> [(Level, usize); 3] {
+        self.raw.len_by_level()
     }
-}
 
-impl<T> Iterator for Queue<T>
-where
-    T: Serialize,
-    for<'de> T: Deserialize<'de>,
-{
-    type Item = T;
+    /// whether there are no elements at any level, including those enqueued
+    /// by other handles
+    ///
+    /// Note: this reads the header from the file under a shared lock on
+    /// every call, just like `len`. A `dequeue` returning `None` tells the
+    /// same without an extra read.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.dequeue().unwrap_or(None)
+    /// whether the number of items in the header would have dropped below
+    /// zero, which means it drifted from the stored items, e.g. after a
+    /// crash. Operations keep working with the count held at zero, and
+    /// `check_counts(true)` corrects it and clears this flag.
+    pub fn counter_drift(&self) -> bool {
+        self.raw.header.counter_drift
     }
-}
 
-#[derive(Serialize, Deserialize, Debug, Default)]
-struct Header {
-    first_element: usize,
-    last_element: usize,
-    elements_count: usize,
-}
+    /// the block index of the oldest item at the front of the queue, which
+    /// `dequeue` removes next unless other levels hold items. `None` if the
+    /// queue is empty. Only items of `Level::Normal` count.
+    pub fn front_index(&self) -> Option<usize> {
+        self.raw.ends().map(|(_, last)| last)
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Element<T> {
-    next: usize,
-    prev: usize,
-    body: T,
-}
+    /// the block index of the newest item at the back of the queue, as
+    /// returned by `enqueue_indexed`, which `dequeue_back` removes. `None` if
+    /// the queue is empty. Only items of `Level::Normal` count.
+    pub fn back_index(&self) -> Option<usize> {
+        self.raw.ends().map(|(first, _)| first)
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// runtime statistics of this handle, like the number of file resizes
+    pub fn stats(&self) -> Stats {
+        self.raw.store.stats()
+    }
 
-    #[test]
-    fn works() {
-        let file = tempfile::tempfile().expect("could not create tempfile");
-        let mut queue = Queue::<i32>::new(file).expect("could not create");
-        assert_eq!(queue.len(), 0);
+    /// the number of runs of adjacent free blocks by their length, to judge
+    /// whether the file is fragmented into many small holes and a compaction
+    /// would pay off
+    pub fn free_space_histogram(&self) -> Result<BTreeMap<usize, usize>, Box<dyn Error>> {
+        self.raw.store.free_space_histogram()
+    }
 
-        queue.enqueue(1).expect("could not enqueue");
-        queue.enqueue(2).expect("could not enqueue");
-        assert_eq!(queue.len(), 2);
+    /// grow the file right away so items of the given total serialized size
+    /// fit, failing now instead of halfway through a batch when the disk is
+    /// full. No item gets written, so the queue stays as it is either way.
+    /// Free blocks count as available space.
+    ///
+    /// Note: every item occupies at least one frame of about a kilobyte, so
+    /// many tiny items need more space than their total size. On file
+    /// systems with sparse files, the space may only get allocated once it
+    /// is written.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<Vec<u8>>::new(file)?;
+    /// let batch = vec![vec![0_u8; 100_000]; 100];
+    /// queue.try_reserve(batch.iter().map(|item| item.len()).sum())?;
+    /// for item in batch {
+    ///     queue.enqueue(item)?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn try_reserve(&mut self, additional_bytes: usize) -> Result<(), Box<dyn Error>> {
+        self.raw.store.reserve(additional_bytes)
+    }
 
-        let data = queue.dequeue().expect("could not dequeue");
-        assert_eq!(data, Some(1));
-        assert_eq!(queue.len(), 1);
+    /// hand all pending writes of this handle to the operating system, e.g.
+    /// before a checkpoint in your own logic. Every operation already
+    /// flushes its writes, so this is a cheap and safe call at any time.
+    /// Unlike [`barrier`](Self::barrier), it does not sync the length of a
+    /// grown file, so prefer `barrier` when the writes must survive a crash.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("some job"))?;
+    /// queue.flush()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.raw.store.flush()
+    }
 
-        let data = queue.dequeue().expect("could not dequeue");
-        assert_eq!(data, Some(2));
-        assert_eq!(queue.len(), 0);
+    /// return once all writes of this handle are durable: the written pages
+    /// are flushed and, if the file grew since the last barrier, the file
+    /// is synced along with its metadata through `File::sync_all`. Call it
+    /// before announcing items to another process, e.g. over a socket, when
+    /// the announcement must not outlive the items in a crash.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("some job"))?;
+    /// queue.barrier()?;
+    /// // now notify the consumer
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn barrier(&mut self) -> Result<(), Box<dyn Error>> {
+        self.raw.store.sync()
+    }
 
-        let data = queue.dequeue().expect("could not dequeue");
-        assert_eq!(data, None);
+    /// when the database file was created, `None` for files written by
+    /// versions of this crate that did not record it yet
+    pub fn created_at(&self) -> Option<SystemTime> {
+        self.raw.store.created_at()
     }
 
-    #[test]
-    fn iteration() {
-        let file = tempfile::tempfile().expect("could not create tempfile");
-        let mut queue = Queue::<i32>::new(file).expect("could not create");
-        queue.enqueue(1).expect("could not enqueue");
-        queue.enqueue(2).expect("could not enqueue");
-        assert_eq!(queue.len(), 2);
+    /// when the database was modified the last time, with a resolution of
+    /// seconds. Reads never change it.
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.raw.store.modified_at()
+    }
 
-        let vec: Vec<i32> = queue.collect();
-        assert_eq!(vec, vec![1, 2]);
+    /// the metadata blob stored with the file by `set_meta`, `None` if
+    /// there is none
+    pub fn meta(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.raw.meta()
+    }
+
+    /// stamp the file with a blob of application metadata of up to 64KB,
+    /// like a schema version or the name of the producer, replacing the
+    /// previous one. It is stored apart from the items, so it does not
+    /// count towards any size of the queue, and survives upgrades of the
+    /// file format.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.set_meta(br#"{"schema": 2}"#)?;
+    /// let meta = queue.meta()?; // Some(b"{\"schema\": 2}")
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_meta(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.raw.set_meta(bytes)
+    }
+
+    /// the total number of bytes of all stored items, without any framing
+    /// or free space. Compare it with the length of the file to see the
+    /// overhead of the storage layout.
+    ///
+    /// Note: this walks the headers of all frames in the file once.
+    pub fn logical_size(&self) -> Result<usize, Box<dyn Error>> {
+        self.raw.logical_size()
+    }
+
+    /// lifetime counters of this queue, persisted across reopens
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("some item"))?;
+    /// queue.dequeue()?;
+    /// let counters = queue.counters(); // max_depth: 1, enqueued: 1, dequeued: 1
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn counters(&self) -> QueueCounters {
+        self.raw.counters()
+    }
+
+    /// start a new measurement window: the maximum depth is set to the
+    /// current length and the totals start from zero again
+    pub fn reset_counters(&mut self) -> Result<(), Box<dyn Error>> {
+        self.raw.reset_counters()
+    }
+
+    /// the serialized bytes of all items, without their pointers or any
+    /// framing. Unlike `logical_size`, this needs no scan of the file.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<u64>::new(file)?;
+    /// queue.enqueue(42)?;
+    /// let payload = queue.payload_bytes(); // 8
+    /// let overhead = queue.overhead_bytes(); // 1016
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn payload_bytes(&self) -> usize {
+        self.raw.header.usage.unwrap_or_default().payload_bytes()
+    }
+
+    /// the bytes of all frames holding items that are not payload: the
+    /// pointers of the elements, frame headers and the unused rest of the
+    /// last frame of every element
+    pub fn overhead_bytes(&self) -> usize {
+        let usage = self.raw.header.usage.unwrap_or_default();
+        usage.overhead_bytes(self.raw.store.frame_size())
+    }
+
+    /// the bytes the item would take in the file once enqueued: its
+    /// serialized size plus the pointers of the element and the headers and
+    /// unused rest of all frames it spans, so a bounded store can check
+    /// whether a batch fits before writing any of it. After an `enqueue`,
+    /// `payload_bytes` and `overhead_bytes` together grow by exactly this.
+    ///
+    /// Note: with [`Options::compress_above`](crate::Options::compress_above),
+    /// large items count uncompressed, so their estimate is an upper bound.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<Vec<u8>>::new(file)?;
+    /// let batch = vec![vec![0_u8; 3000]; 10];
+    /// let mut needed = 0;
+    /// for item in &batch {
+    ///     needed += queue.estimate_bytes(item)?; // 4096 each
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn estimate_bytes(&self, item: &T) -> Result<usize, Box<dyn Error>> {
+        let body_len = self.raw.header.encoding.serialized_size(item)?;
+        self.raw.element_bytes(body_len)
+    }
+
+    /// run the operation with the chain of the level swapped into the
+    /// header, see `RawQueue::at_level`
+    fn at_level<R>(
+        &mut self,
+        level: Level,
+        operation: impl FnOnce(&mut Self) -> Result<R, Box<dyn Error>>,
+    ) -> Result<R, Box<dyn Error>> {
+        if level == Level::Normal {
+            return operation(self);
+        }
+        self.raw.enter_level(level);
+        let result = operation(self);
+        self.raw.leave_level();
+        result
+    }
+
+    /// run a removal at the dequeue end of the level to serve next, which
+    /// is the highest level holding items unless a lower one was passed
+    /// over too often, see `with_level_weight`
+    fn at_next_level<R>(
+        &mut self,
+        operation: impl FnOnce(&mut Self) -> Result<R, Box<dyn Error>>,
+    ) -> Result<R, Box<dyn Error>> {
+        let Some((level, waiting)) = self.raw.next_level() else {
+            return operation(self);
+        };
+        let count = self.raw.header.total_count();
+        let result = self.at_level(level, operation)?;
+        if self.raw.header.total_count() < count {
+            self.raw.pass_over(level, &waiting)?;
+        }
+        Ok(result)
+    }
+
+    /// insert a new item in front of the queue and persist to disk
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// let item = String::from("some item");
+    /// queue.enqueue(item)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn enqueue(&mut self, data: T) -> Result<(), Box<dyn Error>> {
+        self.synchronized(|queue| queue.enqueue_unsynchronized(data))?;
+        Ok(())
+    }
+
+    /// like `enqueue`, but returns the index of the new item, to remove
+    /// exactly this item later on through `remove_index`, e.g. to cancel a
+    /// job. The index stays valid until the item leaves the queue, unless
+    /// `compact_step` moves the item to another block in between.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// let job = queue.enqueue_indexed(String::from("some job"))?;
+    /// let cancelled = queue.remove_index(job)?; // Some("some job")
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn enqueue_indexed(&mut self, data: T) -> Result<usize, Box<dyn Error>> {
+        self.synchronized(|queue| queue.enqueue_unsynchronized(data))
+    }
+
+    /// like `enqueue`, but with the given priority. `dequeue` serves the
+    /// items of higher levels first, and the items of a level in FIFO order.
+    /// Use `with_level_weight` so lower levels do not starve.
+    ///
+    /// Note: positional operations like `remove_at`, `remove_index`,
+    /// `remove_first_where`, `iter_resumable` and `front_index` only see
+    /// the items of `Level::Normal`, which `enqueue` uses.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// use wired::Level;
+    ///
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("report"))?;
+    /// queue.enqueue_with_level(String::from("alert"), Level::High)?;
+    /// let item = queue.dequeue()?; // Some("alert")
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn enqueue_with_level(&mut self, data: T, level: Level) -> Result<(), Box<dyn Error>> {
+        self.synchronized(|queue| {
+            queue.at_level(level, |queue| queue.enqueue_unsynchronized(data))
+        })?;
+        Ok(())
+    }
+
+    /// let `dequeue` serve a waiting item of a lower level once higher
+    /// levels were served `weight` times in a row, so lower levels do not
+    /// starve while higher ones keep getting new items. Without a weight,
+    /// higher levels strictly go first. How often a level was passed over
+    /// is stored in the file, so it carries over to other handles.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// // every third item comes from a lower level, if one is waiting
+    /// let mut queue = wired::Queue::<String>::new(file)?.with_level_weight(2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_level_weight(mut self, weight: u64) -> Self {
+        self.raw.level_weight = Some(weight);
+        self
+    }
+
+    fn enqueue_unsynchronized(&mut self, data: T) -> Result<usize, Box<dyn Error>> {
+        let body = self.raw.header.encoding.serialize(&data)?;
+        let index = self.raw.insert_body(&body)?;
+        self.raw.save_header()?;
+        Ok(index)
+    }
+
+    /// move all items of the other queue behind the items of this one,
+    /// leaving the other queue empty. Items keep their order and level, so
+    /// they get dequeued from here in the same order as from the other
+    /// queue. The serialized items get copied as they are, without
+    /// deserializing them unless both files use different encodings.
+    ///
+    /// Note: all items get written here before any of them gets removed
+    /// from the other queue, so a crash in between leaves them in both
+    /// queues rather than losing them. The other queue must use another file.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// # let shard_file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// let mut shard = wired::Queue::<String>::new(shard_file)?;
+    /// shard.enqueue(String::from("moved"))?;
+    /// queue.append_queue(&mut shard)?;
+    /// assert!(shard.is_empty());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn append_queue(&mut self, other: &mut Queue<T>) -> Result<(), Box<dyn Error>> {
+        self.synchronized(|queue| {
+            other.synchronized(|other| {
+                let mut moved = vec![];
+                for level in Level::ALL.iter().copied() {
+                    if other.raw.header.count_of(level) == 0 {
+                        continue;
+                    }
+                    let copied = queue.at_level(level, |queue| {
+                        other.at_level(level, |other| queue.copy_chain(other))
+                    })?;
+                    moved.push((level, copied));
+                }
+                if moved.is_empty() {
+                    return Ok(());
+                }
+                queue.raw.save_header()?;
+                for (level, copied) in moved {
+                    other.at_level(level, |other| other.raw.clear_chain(&copied))?;
+                }
+                other.raw.save_header()
+            })
+        })
+    }
+
+    /// insert the items of the chain of the other queue in FIFO order,
+    /// returning the blocks they came from
+    fn copy_chain(&mut self, other: &Queue<T>) -> Result<Vec<usize>, Box<dyn Error>> {
+        let mut copied = Vec::with_capacity(other.raw.header.elements_count);
+        let mut index = other.raw.header.last_element;
+        for _ in 0..other.raw.header.elements_count {
+            let bytes = other.raw.store.read(index)?;
+            let (links, _, body) = other.raw.split_element(&bytes)?;
+            if other.raw.header.encoding == self.raw.header.encoding {
+                self.raw.insert_body(&body)?;
+            } else {
+                let item = other.decode_element("append_queue", index, &bytes)?.body;
+                self.raw
+                    .insert_body(&self.raw.header.encoding.serialize(&item)?)?;
+            }
+            copied.push(index);
+            index = links.prev;
+        }
+        Ok(copied)
+    }
+
+    /// remove all items of every level and shrink the file back to its
+    /// header, returning the disk space to the filesystem. The items count
+    /// as dequeued, also for positions taken before, and the metadata blob
+    /// stays.
+    ///
+    /// Note: other handles pick up the smaller file with their next write.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("some item"))?;
+    /// queue.clear_and_shrink()?;
+    /// assert!(queue.is_empty());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn clear_and_shrink(&mut self) -> Result<(), Box<dyn Error>> {
+        self.raw.clear_and_shrink()
+    }
+
+    /// remove the item at the back of the queue, persist to disk and return the item
+    ///
+    /// Note: if you discard the dequeued item it will be lost permanently!
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("some item"))?;
+    /// let item = queue.dequeue()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn dequeue(&mut self) -> Result<Option<T>, Box<dyn Error>> {
+        self.synchronized(|queue| queue.at_next_level(|queue| queue.dequeue_unsynchronized()))
+    }
+
+    /// remove the most recently enqueued item and return it, the one at
+    /// the other end than `dequeue`. With levels, this is the newest item
+    /// of the lowest level holding any, which would be dequeued last.
+    ///
+    /// Like `remove_at`, this invalidates all positions taken before.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("first"))?;
+    /// queue.enqueue(String::from("second"))?;
+    /// let item = queue.dequeue_back()?; // Some("second")
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn dequeue_back(&mut self) -> Result<Option<T>, Box<dyn Error>> {
+        self.synchronized(|queue| {
+            let lowest = Level::ALL
+                .iter()
+                .rev()
+                .copied()
+                .find(|&level| queue.raw.header.count_of(level) > 0);
+            let Some(level) = lowest else {
+                return Ok(None);
+            };
+            queue.at_level(level, |queue| {
+                let index = queue.raw.header.first_element;
+                queue.remove_block("dequeue_back", index).map(Some)
+            })
+        })
+    }
+
+    /// delete the `n` oldest items, the ones `dequeue` would return next,
+    /// without reading them, and return how many got deleted, fewer if the
+    /// queue holds fewer. The header gets saved once for all of them.
+    ///
+    /// With levels, the items of the highest level go first, regardless of
+    /// `with_level_weight`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<u32>::new(file)?;
+    /// for i in 0..10 {
+    ///     queue.enqueue(i)?;
+    /// }
+    /// let removed = queue.trim_front(3)?; // 3
+    /// let item = queue.dequeue()?; // Some(3)
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn trim_front(&mut self, n: usize) -> Result<usize, Box<dyn Error>> {
+        self.synchronized(|queue| {
+            let mut removed = 0;
+            for level in Level::ALL {
+                if removed == n {
+                    break;
+                }
+                if queue.raw.header.count_of(level) == 0 {
+                    continue;
+                }
+                removed += queue.at_level(level, |queue| queue.raw.trim_last(n - removed))?;
+            }
+            if removed > 0 {
+                queue.raw.save_header()?;
+            }
+            Ok(removed)
+        })
+    }
+
+    /// remove all items in the order of `dequeue` and write their
+    /// serialized bytes to the writer as they are, delimited by the
+    /// framing, without deserializing them. Returns the number of records
+    /// written. The header gets saved once at the end.
+    ///
+    /// Levels are not part of the records, so `load_raw_from` enqueues all
+    /// of them as `Level::Normal`. When writing fails, the records written
+    /// before are removed and all others stay.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// # let archive_file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("old entry"))?;
+    /// let archive = std::io::BufWriter::new(archive_file);
+    /// let exported = queue.drain_raw_to(archive, wired::RecordFraming::LengthPrefixed)?; // 1
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn drain_raw_to<W: Write>(
+        &mut self,
+        mut writer: W,
+        framing: RecordFraming,
+    ) -> Result<u64, Box<dyn Error>> {
+        self.synchronized(|queue| {
+            let mut exported = 0;
+            let mut result = Ok(());
+            for level in Level::ALL {
+                if result.is_err() {
+                    break;
+                }
+                if queue.raw.header.count_of(level) == 0 {
+                    continue;
+                }
+                result = queue.at_level(level, |queue| {
+                    queue.raw.drain_last_to(&mut writer, framing, &mut exported)
+                });
+            }
+            if exported > 0 {
+                queue.raw.save_header()?;
+            }
+            result?;
+            writer.flush()?;
+            Ok(exported)
+        })
+    }
+
+    /// enqueue every record read from the reader, as written by
+    /// `drain_raw_to` with the same framing, without deserializing them.
+    /// Returns the number of records enqueued. The header gets saved once
+    /// at the end, also when reading fails after some records.
+    ///
+    /// Nothing checks that the records decode as `T`, like for
+    /// [`KeyValue::set_raw`](crate::KeyValue::set_raw).
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// # let archive_file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// let archive = std::io::BufReader::new(archive_file);
+    /// let loaded = queue.load_raw_from(archive, wired::RecordFraming::LengthPrefixed)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn load_raw_from<R: BufRead>(
+        &mut self,
+        mut reader: R,
+        framing: RecordFraming,
+    ) -> Result<u64, Box<dyn Error>> {
+        self.synchronized(|queue| {
+            let mut loaded = 0;
+            let result = queue.raw.load_records(&mut reader, framing, &mut loaded);
+            if loaded > 0 {
+                queue.raw.save_header()?;
+            }
+            result?;
+            Ok(loaded)
+        })
+    }
+
+    fn dequeue_unsynchronized(&mut self) -> Result<Option<T>, Box<dyn Error>> {
+        if self.raw.header.elements_count == 0 {
+            return Ok(None);
+        }
+        let index = self.raw.header.last_element;
+        let bytes = self.raw.store.read(index)?;
+        let element = self.decode_element("dequeue", index, &bytes)?;
+        self.raw.remove_last(element.prev, &bytes)?;
+        Ok(Some(element.body))
+    }
+
+    /// like `dequeue`, but an item that no longer deserializes as `T` gets
+    /// decoded as the older type `Old` instead and converted, regardless of
+    /// the migrator of this handle.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// // items were enqueued as plain numbers before they got a label
+    /// let mut queue = wired::Queue::<(u32, String)>::new(file)?;
+    /// let item = queue.dequeue_migrating(|id: u32| (id, String::from("unlabeled")))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn dequeue_migrating<Old, F>(&mut self, convert: F) -> Result<Option<T>, Box<dyn Error>>
+    where
+        for<'de> Old: Deserialize<'de>,
+        F: FnOnce(Old) -> T,
+    {
+        self.synchronized(|queue| {
+            queue.at_next_level(|queue| {
+                if queue.raw.header.elements_count == 0 {
+                    return Ok(None);
+                }
+                let bytes = queue.raw.store.read(queue.raw.header.last_element)?;
+                let encoding = queue.raw.header.encoding;
+                let element = queue.decode_element_or(&bytes, |body| {
+                    Ok(convert(encoding.deserialize::<Old>(body)?))
+                })?;
+                queue.raw.remove_last(element.prev, &bytes)?;
+                Ok(Some(element.body))
+            })
+        })
+    }
+
+    /// decode items that no longer deserialize as `T`, e.g. after a field
+    /// was added to it, with the given migrator instead of failing. This
+    /// applies to every read of this handle, use
+    /// [`rewrite_all`](Self::rewrite_all) to upgrade the stored items for good.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use serde::{Deserialize, Serialize};
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct OldJob {
+    ///     id: u32,
+    /// }
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct Job {
+    ///     id: u32,
+    ///     name: String,
+    /// }
+    ///
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<Job>::new(file)?.with_migrator(|bytes| {
+    ///     let old: OldJob = bincode::deserialize(bytes)?;
+    ///     Ok(Job { id: old.id, name: String::new() })
+    /// });
+    /// let job = queue.dequeue()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_migrator(mut self, migrator: Migrator<T>) -> Self {
+        self.migrator = Some(migrator);
+        self
+    }
+
+    /// write every item back that only decodes through the migrator, as the
+    /// current type, so later reads need no migrator anymore. Items keep
+    /// their position within the queue. Returns the number of rewritten items.
+    ///
+    /// Note: this is an `O(n)` operation that reads every single item.
+    pub fn rewrite_all(&mut self) -> Result<usize, Box<dyn Error>> {
+        self.synchronized(|queue| {
+            let mut rewritten = 0;
+            for level in Level::ALL {
+                if queue.raw.header.count_of(level) > 0 {
+                    rewritten += queue.at_level(level, |queue| queue.rewrite_chain())?;
+                }
+            }
+            if rewritten > 0 {
+                queue.raw.header.usage = Some(queue.raw.measure_usage()?);
+                queue.raw.save_header()?;
+            }
+            Ok(rewritten)
+        })
+    }
+
+    /// rewrite the items of the chain in the header, see `rewrite_all`
+    fn rewrite_chain(&mut self) -> Result<usize, Box<dyn Error>> {
+        let mut rewritten = 0;
+        let mut index = self.raw.header.last_element;
+        for _ in 0..self.raw.header.elements_count {
+            let bytes = self.raw.store.read(index)?;
+            let (links, _, body) = self.raw.split_element(&bytes)?;
+            if self.raw.header.encoding.deserialize::<T>(&body).is_err() {
+                let element = self.decode_element("rewrite_all", index, &bytes)?;
+                let body = self.raw.header.encoding.serialize(&element.body)?;
+                let record = self.raw.join_element(&element.links(), &body)?;
+                self.raw.store.update(index, &record)?;
+                rewritten += 1;
+            }
+            index = links.prev;
+        }
+        Ok(rewritten)
+    }
+
+    /// decode an element, using the migrator for a body that does not
+    /// deserialize as `T`
+    fn decode_element(
+        &self,
+        operation: &'static str,
+        index: usize,
+        bytes: &[u8],
+    ) -> Result<Element<T>, Box<dyn Error>> {
+        let (links, _, body) = self.raw.split_element(bytes)?;
+        let body = decode_migrating(self.raw.header.encoding, self.migrator, &body);
+        Ok(Element {
+            next: links.next,
+            prev: links.prev,
+            body: decode_context(body, &self.raw.store, operation, index)?,
+        })
+    }
+
+    /// decode an element, handing the body to the fallback if it does not
+    /// deserialize as `T`
+    fn decode_element_or<F>(&self, bytes: &[u8], fallback: F) -> Result<Element<T>, Box<dyn Error>>
+    where
+        F: FnOnce(&[u8]) -> Result<T, Box<dyn Error>>,
+    {
+        let (links, _, body) = self.raw.split_element(bytes)?;
+        let body = match self.raw.header.encoding.deserialize(&body) {
+            Ok(body) => body,
+            Err(_) => fallback(&body)?,
+        };
+        Ok(Element {
+            next: links.next,
+            prev: links.prev,
+            body,
+        })
+    }
+
+    /// like `dequeue`, but items that can not be deserialized anymore, e.g.
+    /// after the type changed incompatibly, do not block the queue forever:
+    /// their raw bytes move to the given dead-letter queue and dequeuing
+    /// continues with the next item.
+    ///
+    /// Note: a dead letter is enqueued before it gets removed here, so a
+    /// crash in between leaves it in both queues rather than losing it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// # let dead_letters_file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// let mut dead_letters = wired::Queue::<Vec<u8>>::new(dead_letters_file)?;
+    /// queue.enqueue(String::from("some item"))?;
+    /// let item = queue.dequeue_or_deadletter(&mut dead_letters)?; // Some("some item")
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn dequeue_or_deadletter(
+        &mut self,
+        dead_letters: &mut Queue<Vec<u8>>,
+    ) -> Result<Option<T>, Box<dyn Error>> {
+        self.synchronized(|queue| {
+            while queue.raw.header.total_count() > 0 {
+                let item = queue.at_next_level(|queue| {
+                    let index = queue.raw.header.last_element;
+                    let bytes = queue.raw.store.read(index)?;
+                    if let Ok(element) =
+                        queue.decode_element("dequeue_or_deadletter", index, &bytes)
+                    {
+                        queue.raw.remove_last(element.prev, &bytes)?;
+                        return Ok(Some(element.body));
+                    }
+                    let (links, _, body) = queue.raw.split_element(&bytes)?;
+                    dead_letters.enqueue(body.into_owned())?;
+                    queue.raw.remove_last(links.prev, &bytes)?;
+                    Ok(None)
+                })?;
+                if item.is_some() {
+                    return Ok(item);
+                }
+            }
+            Ok(None)
+        })
+    }
+
+    /// dequeue all items, yielding an error for every item that can not be
+    /// deserialized anymore instead of stopping there. Such an item gets
+    /// removed all the same, so draining always makes progress, unlike
+    /// `dequeue` which fails on the same item again and again. The
+    /// iteration only ends early when the file itself fails, e.g. when
+    /// even the pointers of an element are unreadable.
+    ///
+    /// Note: an item yielded as an error is lost, use
+    /// `dequeue_or_deadletter` to keep its bytes instead.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("some item"))?;
+    /// for item in queue.drain_lossy() {
+    ///     match item {
+    ///         Ok(item) => println!("{}", item),
+    ///         Err(error) => println!("skipped a corrupt item: {}", error),
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn drain_lossy(&mut self) -> impl Iterator<Item = Result<T, Box<dyn Error>>> + '_ {
+        let mut finished = false;
+        std::iter::from_fn(move || {
+            if finished {
+                return None;
+            }
+            match self.synchronized(|queue| queue.at_next_level(|queue| queue.dequeue_lossy())) {
+                Ok(item) => item,
+                Err(error) => {
+                    finished = true;
+                    Some(Err(error))
+                }
+            }
+        })
+    }
+
+    /// remove the item at the dequeue end even if its body does not
+    /// deserialize, which yields the error of the body instead
+    fn dequeue_lossy(&mut self) -> Result<Option<LossyItem<T>>, Box<dyn Error>> {
+        if self.raw.header.elements_count == 0 {
+            return Ok(None);
+        }
+        let index = self.raw.header.last_element;
+        let bytes = self.raw.store.read(index)?;
+        let (prev, item) = match self.decode_element("drain_lossy", index, &bytes) {
+            Ok(element) => (element.prev, Ok(element.body)),
+            Err(error) => {
+                let links: Links = self.raw.header.encoding.deserialize(&bytes)?;
+                (links.prev, Err(error))
+            }
+        };
+        self.raw.remove_last(prev, &bytes)?;
+        Ok(Some(item))
+    }
+
+    /// remove the first item, seen from the dequeue end, that matches the
+    /// predicate and return it. The remaining items keep their order.
+    ///
+    /// Note: this is an `O(n)` operation that stops scanning at the first hit.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("stuck"))?;
+    /// queue.enqueue(String::from("fine"))?;
+    /// let item = queue.remove_first_where(|item| item == "stuck")?; // Some("stuck")
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn remove_first_where<F>(&mut self, predicate: F) -> Result<Option<T>, Box<dyn Error>>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        self.synchronized(|queue| queue.remove_first_where_unsynchronized(predicate))
+    }
+
+    fn remove_first_where_unsynchronized<F>(
+        &mut self,
+        mut predicate: F,
+    ) -> Result<Option<T>, Box<dyn Error>>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        let mut index = self.raw.header.last_element;
+        for _ in 0..self.raw.header.elements_count {
+            let bytes = self.raw.store.read(index)?;
+            let element = self.decode_element("remove_first_where", index, &bytes)?;
+            if predicate(&element.body) {
+                self.raw.remove_element(index, &element.links(), &bytes)?;
+                return Ok(Some(element.body));
+            }
+            index = element.prev;
+        }
+        Ok(None)
+    }
+
+    /// remove the item at the given position, counted from the dequeue end
+    /// like the order of `snapshot_items`, and return it. Returns `None` if
+    /// the queue holds fewer items. The remaining items keep their order.
+    ///
+    /// Note: this is an `O(n)` operation that walks from the closer end of
+    /// the queue, deserializing only the item it removes.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("first"))?;
+    /// queue.enqueue(String::from("cancelled"))?;
+    /// queue.enqueue(String::from("last"))?;
+    /// let item = queue.remove_at(1)?; // Some("cancelled")
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn remove_at(&mut self, index: usize) -> Result<Option<T>, Box<dyn Error>> {
+        self.synchronized(|queue| {
+            let Some(block) = queue.raw.block_at(index)? else {
+                return Ok(None);
+            };
+            queue.remove_block("remove_at", block).map(Some)
+        })
+    }
+
+    /// remove the item with an index returned by `enqueue_indexed` and
+    /// return it. Returns `None` if no item of this queue has this index,
+    /// e.g. because it got dequeued already. The remaining items keep their
+    /// order.
+    ///
+    /// Note: once an item left the queue, a later item may reuse its index.
+    pub fn remove_index(&mut self, index: usize) -> Result<Option<T>, Box<dyn Error>> {
+        self.synchronized(|queue| {
+            if !queue.raw.is_element(index) || !queue.raw.is_normal_element(index) {
+                return Ok(None);
+            }
+            queue.remove_block("remove_index", index).map(Some)
+        })
+    }
+
+    /// replace the item at a position taken through `iter_resumable` or
+    /// `iter_from`, keeping its place in line. Returns `false` if the item
+    /// got dequeued in the meantime. The item may grow or shrink, it gets
+    /// spread over as many blocks as needed.
+    ///
+    /// Removing an item from the middle of the queue invalidates all
+    /// positions taken before, which fails with
+    /// `WiredError::PositionInvalidated`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<(String, u32)>::new(file)?;
+    /// queue.enqueue((String::from("job"), 0))?;
+    /// let (position, (job, retries)) = queue.iter_resumable().next().unwrap()?;
+    /// let updated = queue.update_at(&position, &(job, retries + 1))?; // true
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn update_at(&mut self, position: &Position, data: &T) -> Result<bool, Box<dyn Error>> {
+        self.synchronized(|queue| {
+            let body = queue.raw.header.encoding.serialize(data)?;
+            queue.raw.update_body(*position, &body)
+        })
+    }
+
+    /// remove the element at the index from the chain and return its item
+    fn remove_block(&mut self, operation: &'static str, index: usize) -> Result<T, Box<dyn Error>> {
+        let bytes = self.raw.store.read(index)?;
+        let element = self.decode_element(operation, index, &bytes)?;
+        self.raw.remove_element(index, &element.links(), &bytes)?;
+        Ok(element.body)
+    }
+
+    /// compare the number of elements and both ends stored in the header
+    /// with the actual chain of elements, which may disagree after an
+    /// unclean shutdown. With `repair`, the header gets corrected to match
+    /// the chain. An end that refers to a deleted block is replaced by the
+    /// last element still reachable from the other end.
+    ///
+    /// Note: this is an `O(n)` operation that reads every element, but does
+    /// not deserialize any item. Use `Options::repair_on_open` to run it
+    /// whenever the file gets opened.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// let check = queue.check_counts(true)?;
+    /// if !check.is_consistent() {
+    ///     println!("repaired: {} instead of {} items", check.reachable_count, check.stored_count);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn check_counts(&mut self, repair: bool) -> Result<ChainCheck, Box<dyn Error>> {
+        self.raw.check_counts(repair)
+    }
+
+    /// delete orphaned blocks: records that got written but are not
+    /// reachable from the header, e.g. the element of an enqueue that
+    /// failed to save the header. Returns how much got reclaimed.
+    ///
+    /// It is conservative: when the chain of any level disagrees with the
+    /// header, it fails with `WiredError::Inconsistent` without deleting
+    /// anything, since elements cut off from a chain may still be wanted.
+    /// Run `check_counts(true)` first to repair the chains in that case.
+    ///
+    /// Note: this is an `O(n)` operation that reads every element and the
+    /// header of every frame in the file.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// let report = queue.gc()?;
+    /// println!("reclaimed {} blocks of {} bytes", report.blocks, report.bytes);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn gc(&mut self) -> Result<GcReport, Box<dyn Error>> {
+        self.raw.gc()
+    }
+
+    /// do a bounded part of a compaction: move up to `budget.blocks` items
+    /// from the end of the file into free frames closer to the front. Once
+    /// nothing can move any further, the file gets shrunk to release the
+    /// free space at its end and the progress is `done`. Unlike a compaction
+    /// that rewrites the whole file at once, the queue stays usable between
+    /// the steps, and every other operation may happen in between.
+    ///
+    /// Note: moved items get a new block index, so indices returned by
+    /// `enqueue_indexed` and positions of `iter_resumable` may no longer
+    /// refer to them, and using such a position fails with
+    /// `WiredError::PositionInvalidated`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// use wired::CompactBudget;
+    ///
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// loop {
+    ///     let progress = queue.compact_step(CompactBudget { blocks: 100 })?;
+    ///     if progress.done {
+    ///         break;
+    ///     }
+    ///     // serve some requests in between
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn compact_step(
+        &mut self,
+        budget: CompactBudget,
+    ) -> Result<CompactProgress, Box<dyn Error>> {
+        self.raw.synchronized(|raw| compact_step(raw, budget))
+    }
+
+    /// the number of bytes compacting with `compact_step` until it is done
+    /// would release, without moving anything: the length of the file
+    /// minus all frames in use. This is an upper bound, since a record
+    /// spanning several frames only moves into a gap it fits into.
+    ///
+    /// Note: this walks the headers of all frames in the file once.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// use wired::CompactBudget;
+    ///
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// if queue.compaction_savings()? > 64 * 1024 * 1024 {
+    ///     while !queue.compact_step(CompactBudget::default())?.done {}
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn compaction_savings(&self) -> Result<usize, Box<dyn Error>> {
+        self.raw.store.compaction_savings()
+    }
+
+    /// a hash of the logical contents of the queue, to verify cheaply
+    /// that a backup, a migration or a compaction kept them intact.
+    ///
+    /// The serialized items get hashed in the order `dequeue` would return
+    /// them, each one decompressed and prefixed with its length. Nothing
+    /// physical is part of it: not the blocks or links of the elements, not
+    /// the frame layout, not the timestamps or counters of the file. Levels
+    /// only count through the order they imply, so the hash survives
+    /// `drain_raw_to` and `load_raw_from`. The items stay serialized in the
+    /// integer encoding of the file, so files created with different
+    /// `Options::int_encoding` hash differently.
+    ///
+    /// Note: this is an `O(n)` operation that reads every single item.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// use wired::CompactBudget;
+    ///
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("some item"))?;
+    /// let before = queue.content_hash()?;
+    /// while !queue.compact_step(CompactBudget::default())?.done {}
+    /// assert_eq!(queue.content_hash()?, before);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn content_hash(&self) -> Result<u64, Box<dyn Error>> {
+        let mut hash = ContentHash::new();
+        self.raw.read_in_order(|_, record| {
+            let (_, _, body) = self.raw.split_element(record)?;
+            hash.record(&body);
+            Ok(())
+        })?;
+        Ok(hash.finish())
+    }
+
+    /// read all items in the order `dequeue` would return them without
+    /// removing them from the queue: by level, and FIFO within a level
+    ///
+    /// Note: this is an `O(n)` operation that reads and deserializes every
+    /// single item into memory, so use it with care on large queues.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("some item"))?;
+    /// let items = queue.snapshot_items()?; // ["some item"]
+    /// assert_eq!(queue.len(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn snapshot_items(&self) -> Result<Vec<T>, Box<dyn Error>> {
+        let mut items = vec![];
+        self.raw.read_in_order(|index, record| {
+            let element = self.decode_element("snapshot_items", index, record)?;
+            items.push(element.body);
+            Ok(())
+        })?;
+        Ok(items)
+    }
+
+    /// iterate over all items in dequeue order without removing them.
+    ///
+    /// The header gets read once at the first step, and from then on the
+    /// iteration only follows the links between the elements, so a full
+    /// scan reads every element once and nothing else. Unlike
+    /// `iter_resumable`, it does not lock the file, so items that another
+    /// handle dequeues in the meantime may end it with an error.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("first"))?;
+    /// queue.enqueue(String::from("second"))?;
+    /// for item in queue.iter() {
+    ///     let item = item?; // "first", then "second"
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn iter(&self) -> QueueIter<'_, T> {
+        QueueIter {
+            queue: self,
+            chains: None,
+            finished: false,
+        }
+    }
+
+    /// iterate over all items in FIFO order without removing them, along
+    /// with a `Position` to continue after each item later on through
+    /// `iter_from`, even after a restart of the process.
+    ///
+    /// Every step reads a single item with the latest state of the file, so
+    /// items enqueued in the meantime show up as well.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<String>::new(file)?;
+    /// queue.enqueue(String::from("first"))?;
+    /// queue.enqueue(String::from("second"))?;
+    /// let (position, item) = queue.iter_resumable().next().unwrap()?; // "first"
+    /// for entry in queue.iter_from(position)? {
+    ///     let (position, item) = entry?; // "second"
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn iter_resumable(&mut self) -> ResumableIter<'_, T> {
+        ResumableIter {
+            queue: self,
+            after: None,
+            finished: false,
+        }
+    }
+
+    /// continue iterating like `iter_resumable` after the item at the given
+    /// position. If that item was dequeued in the meantime, the iteration
+    /// continues with the oldest item still in the queue, since everything
+    /// enqueued before it is gone as well.
+    ///
+    /// Removing an item from the middle of the queue, e.g. through
+    /// `remove_at`, invalidates all positions taken before, which fails with
+    /// `WiredError::PositionInvalidated`.
+    pub fn iter_from(
+        &mut self,
+        position: Position,
+    ) -> Result<ResumableIter<'_, T>, Box<dyn Error>> {
+        self.raw.synchronized(|raw| raw.following(Some(position)))?;
+        Ok(ResumableIter {
+            queue: self,
+            after: Some(position),
+            finished: false,
+        })
+    }
+
+    /// iterate over all items in dequeue order without removing them, as
+    /// guards to deserialize types borrowing from them, like a view with
+    /// `&str` fields, without allocating its fields. See
+    /// [`Borrowed`](crate::Borrowed).
+    ///
+    /// The file stays locked from the start of the scan until it is dropped,
+    /// so other handles can not change the queue in between. Items that do
+    /// not deserialize as the borrowed type fail, without a fallback to the
+    /// migrator. The scan stops after the first error.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut queue = wired::Queue::<(u64, String)>::new(file)?;
+    /// queue.enqueue((1, String::from("first")))?;
+    /// for guard in queue.scan_borrowed()? {
+    ///     let guard = guard?;
+    ///     let (id, name): (u64, &str) = guard.value()?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn scan_borrowed(&mut self) -> Result<BorrowedScan<'_>, Box<dyn Error>> {
+        self.raw.scan_borrowed()
+    }
+
+    /// read the element following the position, see `RawQueue::following`
+    fn read_after(&self, after: Option<Position>) -> Result<Option<(Position, T)>, Box<dyn Error>> {
+        let (index, ordinal) = match self.raw.following(after)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let bytes = self.raw.store.read(index)?;
+        let element = self.decode_element("iter_resumable", index, &bytes)?;
+        let position = Position {
+            index: index as u64,
+            ordinal,
+            removed: self.raw.header.generations.unwrap_or_default().removed,
+        };
+        Ok(Some((position, element.body)))
+    }
+}
+
+impl RawQueue {
+    /// register the store as a queue and bring the header of an older file
+    /// up to date
+    fn open(mut store: BlockStorage, options: &Options) -> Result<Self, Box<dyn Error>> {
+        store.register("Queue")?;
+        let mut raw = Self {
+            store,
+            header: Header {
+                encoding: options.int_encoding,
+                compress_above: options.compress_above,
+                ..Header::default()
+            },
+            level_weight: None,
+            active_level: Level::Normal,
+        };
+        raw.synchronized(|raw| {
+            if raw.header.counters.is_none() {
+                // files written before counters existed start with the current state
+                let count = raw.header.elements_count as u64;
+                raw.header.counters = Some(QueueCounters {
+                    max_depth: count,
+                    enqueued: count,
+                    dequeued: 0,
+                });
+                raw.save_header()?;
+            }
+            if raw.header.usage.is_none() {
+                raw.header.usage = Some(raw.measure_usage()?);
+                raw.save_header()?;
+            }
+            if options.repair_on_open {
+                raw.check_counts_unsynchronized(true, &mut |_, _| Ok(()))?;
+            }
+            Ok(())
+        })?;
+        Ok(raw)
+    }
+
+    /// write the header and every serialized item with a bulk loader, see
+    /// `Queue::bulk_load`
+    fn load(
+        store: &mut BlockStorage,
+        bodies: &mut dyn Iterator<Item = Result<Vec<u8>, Box<dyn Error>>>,
+        size_hint: usize,
+        options: &Options,
+    ) -> Result<(), Box<dyn Error>> {
+        let (encoding, compress_above) = (options.int_encoding, options.compress_above);
+        let mut header = Header {
+            encoding,
+            compress_above,
+            ..Header::default()
+        };
+        let mut usage = Usage::default();
+        let mut loader = store.bulk_loader()?;
+        // the header gets its content once all elements are written
+        loader.append(&bincode::serialize(&header)?)?;
+        let mut bodies = bodies.peekable();
+        while let Some(body) = bodies.next() {
+            // the body along with its flag, without any links
+            let body = join_element(&(), &body?, encoding, compress_above)?;
+            let index = loader.next_index();
+            let mut links = Links {
+                next: header.first_element,
+                prev: 0,
+            };
+            // the newer element follows right behind, but with varints the
+            // pointer to it may change the length of this one
+            if bodies.peek().is_some() {
+                loop {
+                    let length = encoding.serialize(&links)?.len() + body.len();
+                    let newer = index + loader.frames_for(length);
+                    if links.prev == newer {
+                        break;
+                    }
+                    links.prev = newer;
+                }
+            }
+            let mut record = encoding.serialize(&links)?;
+            record.extend_from_slice(&body);
+            if header.elements_count == 0 {
+                loader.reserve(1 + size_hint * loader.frames_for(record.len()))?;
+            }
+            loader.append(&record)?;
+            let frames = loader.frames_for(record.len());
+            usage.add(Usage::of_record(body.len(), frames));
+            if header.last_element == 0 {
+                header.last_element = index;
+            }
+            header.first_element = index;
+            header.elements_count += 1;
+        }
+        loader.finish()?;
+        let count = header.elements_count as u64;
+        header.counters = Some(QueueCounters {
+            max_depth: count,
+            enqueued: count,
+            dequeued: 0,
+        });
+        header.usage = Some(usage);
+        store.update(0, &bincode::serialize(&header)?)
+    }
+
+    /// run the operation with exclusive access to the file and the latest
+    /// header, which other handles may have changed in the meantime
+    fn synchronized<R>(
+        &mut self,
+        operation: impl FnOnce(&mut Self) -> Result<R, Box<dyn Error>>,
+    ) -> Result<R, Box<dyn Error>> {
+        self.store.lock()?;
+        let result = self.refresh_header().and_then(|()| operation(self));
+        self.store.unlock()?;
+        result
+    }
+
+    /// replace the header by the stored one, while the file is locked
+    fn refresh_header(&mut self) -> Result<(), Box<dyn Error>> {
+        let (encoding, compress_above) = (self.header.encoding, self.header.compress_above);
+        self.header = Self::read_header(&mut self.store, encoding, compress_above)?;
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.current_header()
+            .map_or(self.header.total_count(), |header| header.total_count())
+    }
+
+    fn len_by_level(&self) -> [(Level, usize); 3] {
+        let header = self
+            .current_header()
+            .unwrap_or_else(|_| self.header.clone());
+        Level::ALL.map(|level| (level, header.count_of(level)))
+    }
+
+    /// the first and last element, including changes by other handles
+    fn ends(&self) -> Option<(usize, usize)> {
+        let ends = |header: &Header| {
+            (header.elements_count > 0).then_some((header.first_element, header.last_element))
+        };
+        match self.current_header() {
+            Ok(header) => ends(&header),
+            Err(_) => ends(&self.header),
+        }
+    }
+
+    fn meta(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.store.lock_shared()?;
+        let meta = self.store.meta();
+        self.store.unlock()?;
+        meta
+    }
+
+    fn set_meta(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.synchronized(|raw| raw.store.set_meta(bytes))
+    }
+
+    fn logical_size(&self) -> Result<usize, Box<dyn Error>> {
+        self.store.lock_shared()?;
+        let size = self.store.live_bytes().and_then(|live_bytes| {
+            // everything except the header block are elements
+            Ok(live_bytes - self.store.record_size(0)?)
+        });
+        self.store.unlock()?;
+        size
+    }
+
+    fn counters(&self) -> QueueCounters {
+        self.current_header()
+            .map_or(self.header.counters, |header| header.counters)
+            .unwrap_or_default()
+    }
+
+    fn reset_counters(&mut self) -> Result<(), Box<dyn Error>> {
+        self.synchronized(|raw| {
+            raw.header.counters = Some(QueueCounters {
+                max_depth: raw.header.total_count() as u64,
+                ..QueueCounters::default()
+            });
+            raw.save_header()
+        })
+    }
+
+    fn counters_mut(&mut self) -> &mut QueueCounters {
+        self.header
+            .counters
+            .get_or_insert_with(QueueCounters::default)
+    }
+
+    fn generations_mut(&mut self) -> &mut Generations {
+        self.header
+            .generations
+            .get_or_insert_with(Generations::default)
+    }
+
+    fn usage_mut(&mut self) -> &mut Usage {
+        self.header.usage.get_or_insert_with(Usage::default)
+    }
+
+    /// the usage of a stored element, whose body follows its links
+    fn record_usage(&self, record: &[u8]) -> Result<Usage, Box<dyn Error>> {
+        let links: Links = self.header.encoding.deserialize(record)?;
+        let body_start = self.header.encoding.serialize(&links)?.len();
+        let frames = self.store.frames_for(record.len());
+        Ok(Usage::of_record(record.len() - body_start, frames))
+    }
+
+    /// add up the usage of all elements, for files that predate tracking it
+    fn measure_usage(&self) -> Result<Usage, Box<dyn Error>> {
+        let mut usage = Usage::default();
+        let levels = self.header.levels.unwrap_or_default();
+        let chains = [
+            (self.header.last_element, self.header.elements_count),
+            (levels.high.last_element, levels.high.elements_count),
+            (levels.low.last_element, levels.low.elements_count),
+        ];
+        for (mut index, count) in chains {
+            for _ in 0..count {
+                // a salvaged file may have lost the rest of the chain
+                let Some((links, bytes)) = self.read_record(index) else {
+                    break;
+                };
+                usage.add(self.record_usage(&bytes)?);
+                index = links.prev;
+            }
+        }
+        Ok(usage)
+    }
+
+    /// hand every stored element with its block to `visit`, in the order
+    /// `dequeue` would return them, under a shared lock of the file and with
+    /// the stored header, so nothing changes and the walk sees the latest
+    /// state of every handle
+    fn read_in_order<F>(&self, mut visit: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnMut(usize, &[u8]) -> Result<(), Box<dyn Error>>,
+    {
+        if self.store.is_empty() {
+            return Ok(());
+        }
+        self.store.lock_shared()?;
+        let result = self.store.outgrown_view().and_then(|view| {
+            let store = view.as_ref().unwrap_or(&self.store);
+            let header: Header = decode_header(&store.read(0)?)?;
+            for level in Level::ALL {
+                let (mut index, count) = header.chain_of(level);
+                for _ in 0..count {
+                    let record = store.read(index)?;
+                    visit(index, &record)?;
+                    index = header.encoding.deserialize::<Links>(&record)?.prev;
+                }
+            }
+            Ok(())
+        });
+        self.store.unlock()?;
+        result
+    }
+
+    /// read the header without changing anything, falling back to the
+    /// state of the last operation of this handle when that fails
+    fn current_header(&self) -> Result<Header, Box<dyn Error>> {
+        if self.store.is_empty() {
+            return Ok(Header::default());
+        }
+        self.store.lock_shared()?;
+        let header = self.store.read(0).and_then(|bytes| decode_header(&bytes));
+        self.store.unlock()?;
+        header
+    }
+
+    /// read the header, or create it with the given layout for a new file
+    fn read_header(
+        store: &mut BlockStorage,
+        encoding: IntEncoding,
+        compress_above: Option<usize>,
+    ) -> Result<Header, Box<dyn Error>> {
+        let bytes = store.read(0)?;
+        if store.is_empty() {
+            let header = Header {
+                encoding,
+                compress_above,
+                ..Header::default()
+            };
+            let bytes: Vec<u8> = bincode::serialize(&header)?;
+            store.create(bytes.as_slice())?;
+            Ok(header)
+        } else {
+            decode_header(bytes.as_slice())
+        }
+    }
+
+    fn save_header(&mut self) -> Result<(), Box<dyn Error>> {
+        let bytes: Vec<u8> = match self.active_level {
+            Level::Normal => bincode::serialize(&self.header)?,
+            level => {
+                // store the chains where they belong, not as swapped in
+                let mut header = self.header.clone();
+                header.swap_chain(level);
+                bincode::serialize(&header)?
+            }
+        };
+        self.store.update(0, bytes.as_slice())
+    }
+
+    /// run the operation with the chain of the level swapped into the
+    /// header, so everything working on the chain of the header works on
+    /// the level instead
+    fn at_level<R>(
+        &mut self,
+        level: Level,
+        operation: impl FnOnce(&mut Self) -> Result<R, Box<dyn Error>>,
+    ) -> Result<R, Box<dyn Error>> {
+        if level == Level::Normal {
+            return operation(self);
+        }
+        self.enter_level(level);
+        let result = operation(self);
+        self.leave_level();
+        result
+    }
+
+    /// swap the chain of a level other than `Level::Normal` into the header
+    fn enter_level(&mut self, level: Level) {
+        self.header.swap_chain(level);
+        self.active_level = level;
+    }
+
+    /// swap the chain of the active level back out of the header
+    fn leave_level(&mut self) {
+        self.header.swap_chain(self.active_level);
+        self.active_level = Level::Normal;
+    }
+
+    /// the level whose item to dequeue next along with all levels holding
+    /// items, `None` if the file has no levels or no items
+    fn next_level(&self) -> Option<(Level, Vec<Level>)> {
+        let levels = self.header.levels?;
+        let waiting: Vec<Level> = Level::ALL
+            .iter()
+            .copied()
+            .filter(|&level| self.header.count_of(level) > 0)
+            .collect();
+        let overdue = self.level_weight.and_then(|weight| {
+            waiting
+                .iter()
+                .skip(1)
+                .find(|&&level| levels.passed_over[level as usize] >= weight)
+        });
+        let level = *overdue.or(waiting.first())?;
+        Some((level, waiting))
+    }
+
+    /// record that an item of the level got dequeued while the other
+    /// levels were waiting, and save the header
+    fn pass_over(&mut self, level: Level, waiting: &[Level]) -> Result<(), Box<dyn Error>> {
+        let levels = self.header.levels.get_or_insert_with(Levels::default);
+        for &waiting_level in waiting {
+            let passed_over = &mut levels.passed_over[waiting_level as usize];
+            if waiting_level == level {
+                *passed_over = 0;
+            } else if waiting_level > level {
+                *passed_over += 1;
+            }
+        }
+        self.save_header()
+    }
+
+    /// link a serialized item in front of the queue, without saving the header
+    fn insert_body(&mut self, body: &[u8]) -> Result<usize, Box<dyn Error>> {
+        let links = Links {
+            next: self.header.first_element,
+            prev: 0,
+        };
+        let bytes = self.join_element(&links, body)?;
+        let added = self.record_usage(&bytes)?;
+        let index = self.store.create(bytes.as_slice())?;
+        self.usage_mut().add(added);
+
+        if self.header.first_element != 0 {
+            self.update_links(self.header.first_element, |first| first.prev = index)?;
+        }
+        if self.header.last_element == 0 {
+            self.header.last_element = index;
+        }
+        self.header.first_element = index;
+        self.header.elements_count += 1;
+        let depth = self.header.total_count() as u64;
+        let counters = self.counters_mut();
+        counters.enqueued += 1;
+        counters.max_depth = counters.max_depth.max(depth);
+        Ok(index)
+    }
+
+    fn clear_and_shrink(&mut self) -> Result<(), Box<dyn Error>> {
+        self.synchronized(|raw| {
+            for level in Level::ALL.iter().copied() {
+                raw.at_level(level, |raw| {
+                    let count = raw.header.elements_count as u64;
+                    raw.header.first_element = 0;
+                    raw.header.last_element = 0;
+                    raw.header.elements_count = 0;
+                    raw.counters_mut().dequeued += count;
+                    raw.generations_mut().dequeued += count;
+                    Ok(())
+                })?;
+            }
+            if let Some(levels) = &mut raw.header.levels {
+                levels.passed_over = [0; 3];
+            }
+            raw.header.usage = Some(Usage::default());
+            let bytes: Vec<u8> = bincode::serialize(&raw.header)?;
+            raw.store.clear_and_shrink(&bytes)
+        })
+    }
+
+    /// delete the given elements, which make up the whole chain, as if they
+    /// got dequeued
+    fn clear_chain(&mut self, elements: &[usize]) -> Result<(), Box<dyn Error>> {
+        for index in elements {
+            let removed = self.record_usage(&self.store.read(*index)?)?;
+            self.store.delete(*index)?;
+            self.usage_mut().remove(removed);
+        }
+        let count = self.header.elements_count as u64;
+        self.header.first_element = 0;
+        self.header.last_element = 0;
+        self.header.elements_count = 0;
+        self.counters_mut().dequeued += count;
+        self.generations_mut().dequeued += count;
+        Ok(())
+    }
+
+    /// join links and a serialized body in the layout of this file, see
+    /// `join_element`
+    fn join_element(&self, links: &Links, body: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        join_element(
+            links,
+            body,
+            self.header.encoding,
+            self.header.compress_above,
+        )
+    }
+
+    /// the bytes an element with a body of the given length takes once it
+    /// gets enqueued, see `element_bytes`
+    fn element_bytes(&self, body_len: usize) -> Result<usize, Box<dyn Error>> {
+        let links = Links {
+            next: self.header.first_element,
+            prev: 0,
+        };
+        element_bytes(
+            &self.store,
+            &links,
+            body_len,
+            self.header.encoding,
+            self.header.compress_above,
+        )
+    }
+
+    /// the links, compression flag and serialized body of a stored element
+    fn split_element<'a>(
+        &self,
+        bytes: &'a [u8],
+    ) -> Result<SplitElement<'a, Links>, Box<dyn Error>> {
+        split_element(bytes, self.header.encoding, self.header.compress_above)
+    }
+
+    /// delete the element at the dequeue end, given its previous neighbour
+    /// and stored bytes, and save the header
+    fn remove_last(&mut self, prev: usize, record: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.drop_last(prev, record)?;
+        self.save_header()
+    }
+
+    /// delete up to `n` elements at the dequeue end without decoding their
+    /// items and without saving the header, returning how many got deleted
+    fn trim_last(&mut self, n: usize) -> Result<usize, Box<dyn Error>> {
+        let count = n.min(self.header.elements_count);
+        for _ in 0..count {
+            let record = self.store.read(self.header.last_element)?;
+            let links: Links = self.header.encoding.deserialize(&record)?;
+            self.drop_last(links.prev, &record)?;
+        }
+        Ok(count)
+    }
+
+    /// write the bodies of all elements to the writer, deleting each one
+    /// from the dequeue end once written, without saving the header
+    fn drain_last_to(
+        &mut self,
+        writer: &mut impl Write,
+        framing: RecordFraming,
+        exported: &mut u64,
+    ) -> Result<(), Box<dyn Error>> {
+        while self.header.elements_count > 0 {
+            let record = self.store.read(self.header.last_element)?;
+            let (links, _, body) = self.split_element(&record)?;
+            framing.write_record(writer, &body)?;
+            self.drop_last(links.prev, &record)?;
+            *exported += 1;
+        }
+        Ok(())
+    }
+
+    /// insert every record of the reader as a body in front of the queue,
+    /// without saving the header
+    fn load_records(
+        &mut self,
+        reader: &mut impl BufRead,
+        framing: RecordFraming,
+        loaded: &mut u64,
+    ) -> Result<(), Box<dyn Error>> {
+        while let Some(body) = framing.read_record(reader)? {
+            self.insert_body(&body)?;
+            *loaded += 1;
+        }
+        Ok(())
+    }
+
+    fn drop_last(&mut self, prev: usize, record: &[u8]) -> Result<(), Box<dyn Error>> {
+        let removed = self.record_usage(record)?;
+        self.store.delete(self.header.last_element)?;
+        self.usage_mut().remove(removed);
+        self.header.last_element = prev;
+        self.header.counter_drift |= count_down(&mut self.header.elements_count);
+        if self.header.elements_count == 0 {
+            self.header.first_element = 0;
+        }
+        self.counters_mut().dequeued += 1;
+        self.generations_mut().dequeued += 1;
+        Ok(())
+    }
+
+    /// the element at the given position counted from the dequeue end,
+    /// walking from the closer end of the chain, `None` if there are fewer
+    fn block_at(&self, index: usize) -> Result<Option<usize>, Box<dyn Error>> {
+        let count = self.header.elements_count;
+        if index >= count {
+            return Ok(None);
+        }
+        let block = if index <= count / 2 {
+            self.follow_links(self.header.last_element, index, |links| links.prev)?
+        } else {
+            let steps = count - 1 - index;
+            self.follow_links(self.header.first_element, steps, |links| links.next)?
+        };
+        Ok(Some(block))
+    }
+
+    /// whether the block at the index holds an element linked into the
+    /// chain, as opposed to the header, a free block or a stale pointer
+    fn is_element(&self, index: usize) -> bool {
+        let Some(links) = self.read_links(index) else {
+            return false;
+        };
+        let older_linked = index == self.header.last_element
+            || self
+                .read_links(links.next)
+                .is_some_and(|older| older.prev == index);
+        let newer_linked = index == self.header.first_element
+            || self
+                .read_links(links.prev)
+                .is_some_and(|newer| newer.next == index);
+        older_linked && newer_linked
+    }
+
+    /// whether an element belongs to the chain of `Level::Normal`, as
+    /// opposed to the chain of another level, which `is_element` can not
+    /// tell from the neighbours alone. Walks towards the newest element.
+    fn is_normal_element(&self, index: usize) -> bool {
+        if self.header.levels.is_none() {
+            return true;
+        }
+        let mut current = index;
+        for _ in 0..self.header.elements_count {
+            if current == self.header.first_element {
+                return true;
+            }
+            match self.read_links(current) {
+                Some(links) => current = links.prev,
+                None => return false,
+            }
+        }
+        false
+    }
+
+    /// the element reached from `start` after following the given link for
+    /// `steps` elements
+    fn follow_links(
+        &self,
+        start: usize,
+        steps: usize,
+        link: fn(&Links) -> usize,
+    ) -> Result<usize, Box<dyn Error>> {
+        let mut index = start;
+        for _ in 0..steps {
+            let bytes = self.store.read(index)?;
+            let links: Links = self.header.encoding.deserialize(&bytes)?;
+            index = link(&links);
+        }
+        Ok(index)
+    }
+
+    /// take an element out of the middle or either end of the chain, count
+    /// it as dequeued and save the header
+    fn remove_element(
+        &mut self,
+        index: usize,
+        links: &Links,
+        record: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        self.unlink(index, links, record)?;
+        self.counters_mut().dequeued += 1;
+        self.save_header()
+    }
+
+    /// connect the neighbours of an element and delete it, without saving
+    /// the header. The `next` pointer of the last element may be outdated,
+    /// so the ends are detected through the header instead.
+    fn unlink(&mut self, index: usize, links: &Links, record: &[u8]) -> Result<(), Box<dyn Error>> {
+        let removed = self.record_usage(record)?;
+        // only a gap in the middle invalidates positions
+        if index == self.header.last_element {
+            self.header.last_element = links.prev;
+            self.generations_mut().dequeued += 1;
+        } else {
+            self.generations_mut().removed += 1;
+            self.update_links(links.next, |older| older.prev = links.prev)?;
+        }
+        if index == self.header.first_element {
+            self.header.first_element = links.next;
+        } else {
+            self.update_links(links.prev, |newer| newer.next = links.next)?;
+        }
+        self.store.delete(index)?;
+        self.usage_mut().remove(removed);
+        self.header.counter_drift |= count_down(&mut self.header.elements_count);
+        if self.header.elements_count == 0 {
+            self.header.first_element = 0;
+            self.header.last_element = 0;
+        }
+        Ok(())
+    }
+
+    /// change the pointers of an element, keeping the bytes of its body as
+    /// they are, so even a body that no longer deserializes can be relinked
+    fn update_links(
+        &mut self,
+        index: usize,
+        change: impl FnOnce(&mut Links),
+    ) -> Result<(), Box<dyn Error>> {
+        let bytes = self.store.read(index)?;
+        let mut links: Links = self.header.encoding.deserialize(&bytes)?;
+        let body_start = self.header.encoding.serialize(&links)?.len();
+        change(&mut links);
+        let mut updated: Vec<u8> = self.header.encoding.serialize(&links)?;
+        updated.extend_from_slice(&bytes[body_start..]);
+        self.store.update(index, updated.as_slice())?;
+        // varint pointers may change the length of the record
+        let (before, after) = (bytes.len(), updated.len());
+        let (before, after) = (self.store.frames_for(before), self.store.frames_for(after));
+        let usage = self.usage_mut();
+        usage.remove(Usage::of_record(0, before));
+        usage.add(Usage::of_record(0, after));
+        Ok(())
+    }
+
+    fn check_counts(&mut self, repair: bool) -> Result<ChainCheck, Box<dyn Error>> {
+        self.synchronized(|raw| raw.check_counts_unsynchronized(repair, &mut |_, _| Ok(())))
+    }
+
+    /// check the chains of all levels, see `Queue::check_counts`
+    fn check_counts_unsynchronized(
+        &mut self,
+        repair: bool,
+        visit: &mut Visitor<'_>,
+    ) -> Result<ChainCheck, Box<dyn Error>> {
+        let mut check = self.check_chain(repair, visit)?;
+        if self.header.levels.is_some() {
+            for level in [Level::High, Level::Low] {
+                let level_check = self.at_level(level, |raw| raw.check_chain(repair, visit))?;
+                check = ChainCheck {
+                    stored_count: check.stored_count + level_check.stored_count,
+                    reachable_count: check.reachable_count + level_check.reachable_count,
+                    broken_links: check.broken_links || level_check.broken_links,
+                    repaired: check.repaired || level_check.repaired,
+                };
+            }
+        }
+        if check.repaired || (repair && self.header.counter_drift) {
+            // elements cut off from the chains no longer count, and the
+            // counts match the chains again
+            self.header.usage = Some(self.measure_usage()?);
+            self.header.counter_drift = false;
+            self.save_header()?;
+        }
+        Ok(check)
+    }
+
+    fn gc(&mut self) -> Result<GcReport, Box<dyn Error>> {
+        self.synchronized(|raw| {
+            let mut reachable = HashSet::from([0]);
+            let check = raw.check_counts_unsynchronized(false, &mut |index, _| {
+                reachable.insert(index);
+                Ok(())
+            })?;
+            if !check.is_consistent() {
+                return Err(WiredError::Inconsistent.into());
+            }
+            collect_garbage(&mut raw.store, &reachable)
+        })
+    }
+
+    /// compare the chain in the header with the elements, correcting the
+    /// header with `repair` without saving it
+    fn check_chain(
+        &mut self,
+        repair: bool,
+        visit: &mut Visitor<'_>,
+    ) -> Result<ChainCheck, Box<dyn Error>> {
+        let (first, last, count) = self.walk_chain(visit)?;
+        // the newest element must not refer to an even newer one
+        let dangling_prev = self.read_links(first).is_some_and(|links| links.prev != 0);
+        let check = ChainCheck {
+            stored_count: self.header.elements_count,
+            reachable_count: count,
+            broken_links: first != self.header.first_element
+                || last != self.header.last_element
+                || dangling_prev,
+            repaired: false,
+        };
+        if !repair || check.is_consistent() {
+            return Ok(check);
+        }
+        if dangling_prev {
+            self.update_links(first, |links| links.prev = 0)?;
+        }
+        self.header.first_element = first;
+        self.header.last_element = last;
+        self.header.elements_count = count;
+        self.generations_mut().removed += 1;
+        Ok(ChainCheck {
+            repaired: true,
+            ..check
+        })
+    }
+
+    /// the first and last element and the length of the intact chain,
+    /// starting from the enqueue end, or from the dequeue end if the former
+    /// holds no element. Every step checks the back pointer of the next
+    /// element, so an outdated pointer ends the chain instead of leading
+    /// astray. The visitor sees the record of every element in the chain.
+    fn walk_chain(&self, visit: &mut Visitor<'_>) -> Result<(usize, usize, usize), Box<dyn Error>> {
+        let (start, towards_last) = if self.read_checked(self.header.first_element)?.is_some() {
+            (self.header.first_element, true)
+        } else if self.read_checked(self.header.last_element)?.is_some() {
+            (self.header.last_element, false)
+        } else {
+            return Ok((0, 0, 0));
+        };
+        let mut visited = HashSet::from([start]);
+        let mut current = start;
+        while let Some((links, record)) = self.read_checked(current)? {
+            visit(current, &record)?;
+            let next = if towards_last { links.next } else { links.prev };
+            let linked_back = self.read_checked(next)?.is_some_and(|(following, _)| {
+                let back = if towards_last {
+                    following.prev
+                } else {
+                    following.next
+                };
+                back == current
+            });
+            if !linked_back || !visited.insert(next) {
+                break;
+            }
+            current = next;
+        }
+        if towards_last {
+            Ok((start, current, visited.len()))
+        } else {
+            Ok((current, start, visited.len()))
+        }
+    }
+
+    /// the links of the element at the index, `None` if the block holds no
+    /// element, e.g. because a pointer outlived a crash
+    fn read_links(&self, index: usize) -> Option<Links> {
+        self.read_record(index).map(|(links, _)| links)
+    }
+
+    /// like `read_record`, but a block whose frames are corrupted fails with
+    /// `WiredError::Corrupted` instead of reading as no element, so that a
+    /// walk over the chain does not cut it off at a damaged element
+    fn read_checked(&self, index: usize) -> Result<Option<ElementRecord>, Box<dyn Error>> {
+        if let Some(record) = self.read_record(index) {
+            return Ok(Some(record));
+        }
+        match self.store.read(index) {
+            Err(error) if matches!(error.downcast_ref(), Some(WiredError::Corrupted { .. })) => {
+                Err(error)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// the links of the element at the index along with its whole record
+    fn read_record(&self, index: usize) -> Option<ElementRecord> {
+        if index == 0 || !self.store.is_live(index) {
+            return None;
+        }
+        let bytes = self.store.read(index).ok()?;
+        let links = self.header.encoding.deserialize(&bytes).ok()?;
+        Some((links, bytes))
+    }
+
+    /// the index and ordinal of the element following the position, or of
+    /// the oldest element without one
+    fn following(&self, after: Option<Position>) -> Result<Option<(usize, u64)>, Box<dyn Error>> {
+        let generations = self.header.generations.unwrap_or_default();
+        let oldest = match self.header.elements_count {
+            0 => None,
+            _ => Some((self.header.last_element, generations.dequeued)),
+        };
+        let position = match after {
+            Some(position) => position,
+            None => return Ok(oldest),
+        };
+        if position.removed != generations.removed {
+            return Err(WiredError::PositionInvalidated.into());
+        }
+        if generations.dequeued > position.ordinal {
+            // dequeued, so every remaining element is newer
+            return Ok(oldest);
+        }
+        let bytes = self.store.read(position.index as usize)?;
+        let links: Links = self.header.encoding.deserialize(&bytes)?;
+        match links.prev {
+            0 => Ok(None),
+            newer => Ok(Some((newer, position.ordinal + 1))),
+        }
+    }
+
+    /// replace the serialized body of the element at the position, keeping
+    /// its links, and save the header. `false` if it got dequeued.
+    fn update_body(&mut self, position: Position, body: &[u8]) -> Result<bool, Box<dyn Error>> {
+        let generations = self.header.generations.unwrap_or_default();
+        if position.removed != generations.removed {
+            return Err(WiredError::PositionInvalidated.into());
+        }
+        if generations.dequeued > position.ordinal {
+            return Ok(false);
+        }
+        let index = position.index as usize;
+        let Some((links, record)) = self.read_record(index) else {
+            return Ok(false);
+        };
+        let bytes = self.join_element(&links, body)?;
+        let removed = self.record_usage(&record)?;
+        let added = self.record_usage(&bytes)?;
+        self.store.update(index, &bytes)?;
+        self.usage_mut().remove(removed);
+        self.usage_mut().add(added);
+        self.save_header()?;
+        Ok(true)
+    }
+
+    /// lock the file with the latest header and start a scan over the
+    /// elements of all levels, see `Queue::scan_borrowed`
+    fn scan_borrowed(&mut self) -> Result<BorrowedScan<'_>, Box<dyn Error>> {
+        self.store.lock()?;
+        if let Err(error) = self.refresh_header() {
+            self.store.unlock()?;
+            return Err(error);
+        }
+        Ok(BorrowedScan {
+            chains: Chains::of(&self.header),
+            raw: self,
+            finished: false,
+        })
+    }
+
+    /// the links and serialized body of the element at the index, borrowed
+    /// from the mapping if the element fits into a single frame
+    fn read_body(&self, index: usize) -> Result<ElementBody<'_>, Box<dyn Error>> {
+        let mut bytes = match self.store.read_borrowed(index)? {
+            Cow::Borrowed(bytes) => {
+                let (links, _, body) = self.split_element(bytes)?;
+                return Ok((links, body));
+            }
+            Cow::Owned(bytes) => bytes,
+        };
+        // the body ends the record, so keep the buffer without the links
+        let (links, _, body) = self.split_element(&bytes)?;
+        let start = match body {
+            Cow::Borrowed(body) => bytes.len() - body.len(),
+            Cow::Owned(body) => return Ok((links, Cow::Owned(body))),
+        };
+        bytes.drain(..start);
+        Ok((links, Cow::Owned(bytes)))
+    }
+}
+
+impl Relocate for RawQueue {
+    fn store_mut(&mut self) -> &mut BlockStorage {
+        &mut self.store
+    }
+
+    /// an element is referred to by its neighbours, or by the header at the
+    /// ends of the chain of its level
+    fn relocate(&mut self, from: usize, to: usize) -> Result<bool, Box<dyn Error>> {
+        let Some(links) = self.read_links(from) else {
+            return Ok(false);
+        };
+        let levels = self.header.levels.unwrap_or_default();
+        let chains = [
+            (self.header.first_element, self.header.last_element),
+            (levels.high.first_element, levels.high.last_element),
+            (levels.low.first_element, levels.low.last_element),
+        ];
+        let is_first = chains.iter().any(|(first, _)| *first == from);
+        let is_last = chains.iter().any(|(_, last)| *last == from);
+        let newer_linked = is_first
+            || self
+                .read_links(links.prev)
+                .is_some_and(|newer| newer.next == from);
+        let older_linked = is_last
+            || self
+                .read_links(links.next)
+                .is_some_and(|older| older.prev == from);
+        if !newer_linked || !older_linked {
+            return Ok(false);
+        }
+
+        if !is_first {
+            self.update_links(links.prev, |newer| newer.next = to)?;
+        }
+        if !is_last {
+            self.update_links(links.next, |older| older.prev = to)?;
+        }
+        let repoint = |index: &mut usize| {
+            if *index == from {
+                *index = to;
+            }
+        };
+        repoint(&mut self.header.first_element);
+        repoint(&mut self.header.last_element);
+        if let Some(levels) = &mut self.header.levels {
+            for chain in [&mut levels.high, &mut levels.low] {
+                repoint(&mut chain.first_element);
+                repoint(&mut chain.last_element);
+            }
+        }
+        // positions taken before refer to the old block
+        self.generations_mut().removed += 1;
+        self.save_header()?;
+        Ok(true)
+    }
+}
+
+impl<T> Iterator for Queue<T>
+where
+    T: Serialize,
+    for<'de> T: Deserialize<'de>,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.dequeue().unwrap_or(None)
+    }
+}
+
+/// A non-destructive iterator over the items of a [`Queue`](crate::Queue),
+/// created by `iter_resumable` or `iter_from`. Stops after the first error.
+pub struct ResumableIter<'a, T> {
+    queue: &'a mut Queue<T>,
+    after: Option<Position>,
+    finished: bool,
+}
+
+impl<'a, T> Iterator for ResumableIter<'a, T>
+where
+    T: Serialize,
+    for<'de> T: Deserialize<'de>,
+{
+    type Item = Result<(Position, T), Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let after = self.after;
+        match self.queue.synchronized(|queue| queue.read_after(after)) {
+            Ok(Some((position, item))) => {
+                self.after = Some(position);
+                Some(Ok((position, item)))
+            }
+            Ok(None) => None,
+            Err(error) => {
+                self.finished = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+/// A non-destructive iterator over the items of a [`Queue`](crate::Queue)
+/// as [`Borrowed`](crate::Borrowed) guards, created by `scan_borrowed`.
+/// Keeps the file locked until it is dropped and stops after the first error.
+pub struct BorrowedScan<'a> {
+    raw: &'a RawQueue,
+    chains: Chains,
+    finished: bool,
+}
+
+impl<'a> Iterator for BorrowedScan<'a> {
+    type Item = Result<Borrowed<'a>, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let Some(index) = self.chains.current() else {
+            self.finished = true;
+            return None;
+        };
+        let raw = self.raw;
+        match raw.read_body(index) {
+            Ok((links, body)) => {
+                self.chains.advance(links.prev);
+                Some(Ok(Borrowed::new(body, raw.header.encoding)))
+            }
+            Err(error) => {
+                self.finished = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+impl Drop for BorrowedScan<'_> {
+    fn drop(&mut self) {
+        let _ = self.raw.store.unlock();
+    }
+}
+
+/// A non-destructive iterator over the items of a [`Queue`](crate::Queue),
+/// created by `iter`, which reads the header only once. Stops after the
+/// first error.
+pub struct QueueIter<'a, T> {
+    queue: &'a Queue<T>,
+    // `None` until the header got read at the first step
+    chains: Option<Chains>,
+    finished: bool,
+}
+
+impl<T> QueueIter<'_, T>
+where
+    T: Serialize,
+    for<'de> T: Deserialize<'de>,
+{
+    /// the next item, reading the header only at the first step
+    fn step(&mut self) -> Result<Option<T>, Box<dyn Error>> {
+        let mut chains = match self.chains {
+            Some(chains) => chains,
+            None => Chains::of(&self.queue.raw.current_header()?),
+        };
+        let Some(index) = chains.current() else {
+            return Ok(None);
+        };
+        let bytes = self.queue.raw.store.read(index)?;
+        let element = self.queue.decode_element("iter", index, &bytes)?;
+        chains.advance(element.prev);
+        self.chains = Some(chains);
+        Ok(Some(element.body))
+    }
+}
+
+impl<T> Iterator for QueueIter<'_, T>
+where
+    T: Serialize,
+    for<'de> T: Deserialize<'de>,
+{
+    type Item = Result<T, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let result = self.step().transpose();
+        self.finished = !matches!(result, Some(Ok(_)));
+        result
+    }
+}
+
+/// the elements a scan has yet to visit, by level in dequeue order, for
+/// the iterations that read the header only once
+#[derive(Debug, Clone, Copy)]
+struct Chains([(usize, usize); 3]);
+
+impl Chains {
+    fn of(header: &Header) -> Self {
+        Self(Level::ALL.map(|level| header.chain_of(level)))
+    }
+
+    /// the index of the element to visit next, `None` once all are visited
+    fn current(&self) -> Option<usize> {
+        self.0
+            .iter()
+            .find(|(_, left)| *left > 0)
+            .map(|(index, _)| *index)
+    }
+
+    /// move on from the current element to the one enqueued after it
+    fn advance(&mut self, prev: usize) {
+        if let Some(chain) = self.0.iter_mut().find(|(_, left)| *left > 0) {
+            *chain = (prev, chain.1 - 1);
+        }
+    }
+}
+
+/// An opaque token for an item of a [`Queue`](crate::Queue), to continue
+/// iterating after it through `iter_from`. It can be serialized to survive
+/// a restart and stays valid as long as no item is removed from the middle
+/// of the queue.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    // block of the element
+    index: u64,
+    // the element is gone once this many elements have been dequeued
+    ordinal: u64,
+    // removals from the middle when the position was taken
+    removed: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Header {
+    first_element: usize,
+    last_element: usize,
+    elements_count: usize,
+    // `None` in files written before counters existed
+    counters: Option<QueueCounters>,
+    // `Fixed` in files written before the encoding was configurable
+    encoding: IntEncoding,
+    // `None` in files written before the usage was tracked
+    usage: Option<Usage>,
+    // `None` in files written before positions existed
+    generations: Option<Generations>,
+    // `None` in files created without compression, whose elements carry no flag
+    compress_above: Option<usize>,
+    // `None` in files written before levels existed, whose items are all normal
+    levels: Option<Levels>,
+    // set once a count would have dropped below zero, until a repair
+    counter_drift: bool,
+}
+
+impl Header {
+    /// the number of elements of all levels, regardless of which chain is
+    /// swapped in
+    fn total_count(&self) -> usize {
+        let levels = self.levels.unwrap_or_default();
+        self.elements_count + levels.high.elements_count + levels.low.elements_count
+    }
+
+    /// the number of elements of the level, while no chain is swapped in
+    fn count_of(&self, level: Level) -> usize {
+        self.chain_of(level).1
+    }
+
+    /// the dequeue end and number of elements of the level, while no chain
+    /// is swapped in
+    fn chain_of(&self, level: Level) -> (usize, usize) {
+        let levels = self.levels.unwrap_or_default();
+        match level {
+            Level::High => (levels.high.last_element, levels.high.elements_count),
+            Level::Normal => (self.last_element, self.elements_count),
+            Level::Low => (levels.low.last_element, levels.low.elements_count),
+        }
+    }
+
+    /// exchange the chain of the level with the one of the header itself,
+    /// which belongs to `Level::Normal` otherwise. Swapping twice restores it.
+    fn swap_chain(&mut self, level: Level) {
+        let levels = self.levels.get_or_insert_with(Levels::default);
+        let chain = match level {
+            Level::High => &mut levels.high,
+            Level::Normal => return,
+            Level::Low => &mut levels.low,
+        };
+        std::mem::swap(&mut self.first_element, &mut chain.first_element);
+        std::mem::swap(&mut self.last_element, &mut chain.last_element);
+        std::mem::swap(&mut self.elements_count, &mut chain.elements_count);
+        std::mem::swap(&mut self.generations, &mut chain.generations);
+    }
+}
+
+/// the chains of the levels besides `Level::Normal`, which uses the chain
+/// of the header itself
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+struct Levels {
+    high: Chain,
+    low: Chain,
+    // dequeues in a row that served another level while items of this
+    // level were waiting, by the rank of the level
+    passed_over: [u64; 3],
+}
+
+/// the ends and length of a chain of elements, like in the header
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+struct Chain {
+    first_element: usize,
+    last_element: usize,
+    elements_count: usize,
+    generations: Option<Generations>,
+}
+
+/// The priority of an item in a [`Queue`](crate::Queue), see `enqueue_with_level`.
+/// Items enqueued without a level are `Normal`.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub enum Level {
+    /// dequeued before all other items
+    High,
+    /// the level of `enqueue` and of all items in older files
+    #[default]
+    Normal,
+    /// dequeued after all other items
+    Low,
+}
+
+impl Level {
+    /// all levels, from the highest to the lowest
+    pub const ALL: [Level; 3] = [Level::High, Level::Normal, Level::Low];
+}
+
+/// how many elements left the queue at the dequeue end and elsewhere, which
+/// tells whether a `Position` still refers to the same element. Unlike the
+/// counters, these never get reset.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+struct Generations {
+    dequeued: u64,
+    removed: u64,
+}
+
+/// Lifetime counters of a [`Queue`](crate::Queue), useful for capacity planning.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueCounters {
+    /// the highest number of elements the queue ever held
+    pub max_depth: u64,
+    /// total number of enqueued elements
+    pub enqueued: u64,
+    /// total number of dequeued elements, including removed ones
+    pub dequeued: u64,
+}
+
+/// an item, or the error of an item that does not deserialize
+type LossyItem<T> = Result<T, Box<dyn Error>>;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Element<T> {
+    next: usize,
+    prev: usize,
+    body: T,
+}
+
+/// the pointers at the start of every `Element`, readable without knowing
+/// how to deserialize its body
+#[derive(Serialize, Deserialize, Debug)]
+struct Links {
+    next: usize,
+    prev: usize,
+}
+
+impl<T> Element<T> {
+    fn links(&self) -> Links {
+        Links {
+            next: self.next,
+            prev: self.prev,
+        }
+    }
+}
+
+/// sees the index and record of every element of a chain, see `walk_chain`
+type Visitor<'a> = dyn FnMut(usize, &[u8]) -> Result<(), Box<dyn Error>> + 'a;
+
+/// the links of an element with its serialized body, see `read_body`
+type ElementBody<'a> = (Links, Cow<'a, [u8]>);
+
+/// the links of an element with its whole record, see `read_record`
+type ElementRecord = (Links, Vec<u8>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn works() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<i32>::new(file).expect("could not create");
+        assert_eq!(queue.len(), 0);
+
+        queue.enqueue(1).expect("could not enqueue");
+        queue.enqueue(2).expect("could not enqueue");
+        assert_eq!(queue.len(), 2);
+
+        let data = queue.dequeue().expect("could not dequeue");
+        assert_eq!(data, Some(1));
+        assert_eq!(queue.len(), 1);
+
+        let data = queue.dequeue().expect("could not dequeue");
+        assert_eq!(data, Some(2));
+        assert_eq!(queue.len(), 0);
+
+        let data = queue.dequeue().expect("could not dequeue");
+        assert_eq!(data, None);
+    }
+
+    #[test]
+    fn iteration() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<i32>::new(file).expect("could not create");
+        queue.enqueue(1).expect("could not enqueue");
+        queue.enqueue(2).expect("could not enqueue");
+        assert_eq!(queue.len(), 2);
+
+        let vec: Vec<i32> = queue.collect();
+        assert_eq!(vec, vec![1, 2]);
+    }
+
+    #[test]
+    fn iter() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<i32>::new(file).expect("could not create");
+        assert_eq!(queue.iter().count(), 0);
+        for i in 0..50 {
+            let level = Level::ALL[i as usize % 3];
+            queue
+                .enqueue_with_level(i, level)
+                .expect("could not enqueue");
+        }
+
+        // one read of the header, then one read per element
+        let reads = queue.stats().reads;
+        let items = queue.iter().collect::<Result<Vec<i32>, _>>().unwrap();
+        assert_eq!(queue.stats().reads, reads + 1 + 50);
+        assert_eq!(items[..3], [0, 3, 6]);
+        assert_eq!(items, queue.snapshot_items().unwrap());
+        assert_eq!(queue.len(), 50);
+    }
+
+    #[test]
+    fn snapshot_items() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<i32>::new(file.try_clone().unwrap()).expect("could not create");
+        assert_eq!(queue.snapshot_items().expect("could not snapshot"), vec![]);
+        for i in 1..=5 {
+            queue.enqueue(i).expect("could not enqueue");
+        }
+
+        let items = queue.snapshot_items().expect("could not snapshot");
+        assert_eq!(items, vec![1, 2, 3, 4, 5]);
+        assert_eq!(queue.len(), 5);
+
+        let data = queue.dequeue().expect("could not dequeue");
+        assert_eq!(data, Some(1));
+        let items = queue.snapshot_items().expect("could not snapshot");
+        assert_eq!(items, vec![2, 3, 4, 5]);
+
+        // other handles see the same items
+        let mut other = Queue::<i32>::new(file).expect("could not open");
+        other.enqueue(6).expect("could not enqueue");
+        let items = queue.snapshot_items().expect("could not snapshot");
+        assert_eq!(items, vec![2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn reuse_after_drained() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<i32>::new(file).expect("could not create");
+        queue.enqueue(1).expect("could not enqueue");
+        queue.dequeue().expect("could not dequeue");
+        assert_eq!(queue.raw.header.first_element, 0);
+
+        queue.enqueue(2).expect("could not enqueue");
+        queue.enqueue(3).expect("could not enqueue");
+        let items = queue.snapshot_items().expect("could not snapshot");
+        assert_eq!(items, vec![2, 3]);
+    }
+
+    #[test]
+    fn counters() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<i32>::new(file.try_clone().unwrap()).expect("could not create");
+        for i in 0..5 {
+            queue.enqueue(i).expect("could not enqueue");
+        }
+        queue.dequeue().expect("could not dequeue");
+        queue.dequeue().expect("could not dequeue");
+        queue.enqueue(5).expect("could not enqueue");
+        let expected = QueueCounters {
+            max_depth: 5,
+            enqueued: 6,
+            dequeued: 2,
+        };
+        assert_eq!(queue.counters(), expected);
+
+        // works after reopen
+        let mut queue = Queue::<i32>::new(file).expect("could not open");
+        assert_eq!(queue.counters(), expected);
+
+        // new measurement window
+        queue.reset_counters().expect("could not reset");
+        queue.dequeue().expect("could not dequeue");
+        let expected = QueueCounters {
+            max_depth: 4,
+            enqueued: 0,
+            dequeued: 1,
+        };
+        assert_eq!(queue.counters(), expected);
+    }
+
+    #[test]
+    fn counters_of_old_files() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut store = BlockStorage::with_options(file.try_clone().unwrap(), &Options::default())
+            .expect("could not create");
+        store.create(&[0; 24]).unwrap();
+        let element = bincode::serialize(&Element {
+            next: 0,
+            prev: 0,
+            body: 7,
+        })
+        .unwrap();
+        let index = store.create(&element).unwrap();
+        let old_header = bincode::serialize(&(index, index, 1_usize)).unwrap();
+        store.update(0, &old_header).unwrap();
+        drop(store);
+
+        let mut queue = Queue::<i32>::new(file).expect("could not open");
+        let expected = QueueCounters {
+            max_depth: 1,
+            enqueued: 1,
+            dequeued: 0,
+        };
+        assert_eq!(queue.counters(), expected);
+        assert_eq!(queue.dequeue().expect("could not dequeue"), Some(7));
+    }
+
+    #[test]
+    fn remove_first_where() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<i32>::new(file.try_clone().unwrap()).expect("could not create");
+        let contents = || {
+            let mut bytes = vec![];
+            let mut file = file.try_clone().unwrap();
+            std::io::Seek::rewind(&mut file).unwrap();
+            std::io::Read::read_to_end(&mut file, &mut bytes).unwrap();
+            bytes
+        };
+
+        // no match writes nothing
+        assert_eq!(queue.remove_first_where(|_| true).unwrap(), None);
+        for i in 1..=5 {
+            queue.enqueue(i).expect("could not enqueue");
+        }
+        let before = contents();
+        assert_eq!(queue.remove_first_where(|&i| i > 10).unwrap(), None);
+        assert_eq!(contents(), before);
+
+        // stops at the first hit seen from the dequeue end
+        assert_eq!(queue.remove_first_where(|&i| i % 2 == 0).unwrap(), Some(2));
+        assert_eq!(queue.snapshot_items().unwrap(), vec![1, 3, 4, 5]);
+
+        // the tail, next to be dequeued
+        assert_eq!(queue.remove_first_where(|&i| i == 1).unwrap(), Some(1));
+        assert_eq!(queue.snapshot_items().unwrap(), vec![3, 4, 5]);
+
+        // the head, the most recently enqueued
+        assert_eq!(queue.remove_first_where(|&i| i == 5).unwrap(), Some(5));
+        assert_eq!(queue.snapshot_items().unwrap(), vec![3, 4]);
+        queue.enqueue(6).expect("could not enqueue");
+        assert_eq!(queue.snapshot_items().unwrap(), vec![3, 4, 6]);
+        assert_eq!(queue.dequeue().unwrap(), Some(3));
+        assert_eq!(queue.remove_first_where(|&i| i == 6).unwrap(), Some(6));
+
+        // the sole element
+        assert_eq!(queue.remove_first_where(|&i| i == 4).unwrap(), Some(4));
+        assert!(queue.is_empty());
+        assert_eq!(queue.dequeue().unwrap(), None);
+        queue.enqueue(7).expect("could not enqueue");
+        assert_eq!(queue.snapshot_items().unwrap(), vec![7]);
+        assert_eq!(queue.dequeue().unwrap(), Some(7));
+        assert_eq!(queue.counters().dequeued, 7);
+    }
+
+    #[test]
+    fn usage() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<String>::new(file.try_clone().unwrap()).expect("could not create");
+        assert_eq!((queue.payload_bytes(), queue.overhead_bytes()), (0, 0));
+
+        // every element holds two pointers in front of its body
+        let expected = |items: &[String]| {
+            let sizes = items
+                .iter()
+                .map(|item| bincode::serialized_size(item).unwrap() as usize);
+            let payload: usize = sizes.clone().sum();
+            let frames: usize = sizes.map(|size| (size + 16).div_ceil(992)).sum();
+            (payload, frames * 1024 - payload)
+        };
+        let mut items: Vec<String> = (0..6).map(|i| "x".repeat(i * 700)).collect();
+        for item in items.iter() {
+            queue.enqueue(item.clone()).unwrap();
+        }
+        assert_eq!(
+            (queue.payload_bytes(), queue.overhead_bytes()),
+            expected(&items)
+        );
+        queue.dequeue().unwrap();
+        queue.remove_at(2).unwrap();
+        queue.remove_first_where(|item| item.len() == 3500).unwrap();
+        items.remove(0);
+        items.remove(2);
+        items.retain(|item| item.len() != 3500);
+        assert_eq!(
+            (queue.payload_bytes(), queue.overhead_bytes()),
+            expected(&items)
+        );
+        assert_eq!(
+            queue.logical_size().unwrap(),
+            queue.payload_bytes() + 16 * items.len()
+        );
+
+        // persisted, and measured once for files that predate it
+        drop(queue);
+        let mut queue = Queue::<String>::new(file.try_clone().unwrap()).expect("could not open");
+        assert_eq!(
+            (queue.payload_bytes(), queue.overhead_bytes()),
+            expected(&items)
+        );
+        queue.raw.header.usage = None;
+        queue.raw.save_header().unwrap();
+        drop(queue);
+        let mut queue = Queue::<String>::new(file).expect("could not open");
+        assert_eq!(
+            (queue.payload_bytes(), queue.overhead_bytes()),
+            expected(&items)
+        );
+        while queue.dequeue().unwrap().is_some() {}
+        assert_eq!((queue.payload_bytes(), queue.overhead_bytes()), (0, 0));
+    }
+
+    #[test]
+    fn remove_at() {
+        let (_, mut queue) = queue_of(&[1, 2, 3, 4, 5]);
+        assert_eq!(queue.remove_at(2).unwrap(), Some(3));
+        assert_eq!(queue.snapshot_items().unwrap(), vec![1, 2, 4, 5]);
+        assert_eq!(queue.remove_at(0).unwrap(), Some(1));
+        assert_eq!(queue.snapshot_items().unwrap(), vec![2, 4, 5]);
+        assert_eq!(queue.remove_at(2).unwrap(), Some(5));
+        assert_eq!(queue.snapshot_items().unwrap(), vec![2, 4]);
+        assert_eq!(queue.remove_at(2).unwrap(), None);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.counters().dequeued, 3);
+        assert!(queue.check_counts(false).unwrap().is_consistent());
+
+        // both ends still work
+        queue.enqueue(6).unwrap();
+        assert_eq!(queue.collect::<Vec<_>>(), vec![2, 4, 6]);
+    }
+
+    #[test]
+    fn remove_index() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<i32>::new(file).expect("could not create");
+        let indices: Vec<usize> = (1..=4)
+            .map(|item| queue.enqueue_indexed(item).unwrap())
+            .collect();
+        assert_eq!(queue.remove_index(indices[2]).unwrap(), Some(3));
+        assert_eq!(queue.remove_index(indices[2]).unwrap(), None);
+        assert_eq!(queue.snapshot_items().unwrap(), vec![1, 2, 4]);
+        // neither the header nor a dequeued item count
+        assert_eq!(queue.remove_index(0).unwrap(), None);
+        assert_eq!(queue.dequeue().unwrap(), Some(1));
+        assert_eq!(queue.remove_index(indices[0]).unwrap(), None);
+        assert_eq!(queue.remove_index(indices[3]).unwrap(), Some(4));
+        assert_eq!(queue.remove_index(indices[1]).unwrap(), Some(2));
+        assert!(queue.is_empty());
+        assert_eq!(queue.counters().dequeued, 4);
+        assert!(queue.check_counts(false).unwrap().is_consistent());
+    }
+
+    #[test]
+    fn migrator() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut old_queue = Queue::<u32>::new(file.try_clone().unwrap()).expect("could not create");
+        for id in 1..=3 {
+            old_queue.enqueue(id).expect("could not enqueue");
+        }
+        drop(old_queue);
+
+        let item = |id, label: &str| (id, String::from(label));
+        let migrator: Migrator<(u32, String)> =
+            |bytes| Ok((bincode::deserialize(bytes)?, String::from("old")));
+        let queue = Queue::<(u32, String)>::new(file.try_clone().unwrap()).expect("could not open");
+        assert!(queue.snapshot_items().is_err());
+        let mut queue = queue.with_migrator(migrator);
+        queue.enqueue(item(4, "new")).unwrap();
+        assert_eq!(
+            queue.snapshot_items().unwrap(),
+            [
+                item(1, "old"),
+                item(2, "old"),
+                item(3, "old"),
+                item(4, "new")
+            ]
+        );
+        let converted = queue.dequeue_migrating(|id: u32| item(id, "converted"));
+        assert_eq!(converted.unwrap(), Some(item(1, "converted")));
+
+        // rewritten items decode without any migrator
+        assert_eq!(queue.rewrite_all().unwrap(), 2);
+        assert_eq!(queue.rewrite_all().unwrap(), 0);
+        drop(queue);
+        let queue = Queue::<(u32, String)>::new(file).expect("could not open");
+        assert_eq!(
+            queue.snapshot_items().unwrap(),
+            [item(2, "old"), item(3, "old"), item(4, "new")]
+        );
+        assert_eq!(queue.counters().dequeued, 1);
+    }
+
+    #[test]
+    fn drain_lossy() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<String>::new(file).expect("could not create");
+        let mut indices = vec![];
+        for item in ["a", "b", "c", "d"] {
+            indices.push(queue.enqueue_indexed(String::from(item)).unwrap());
+        }
+        // replace the body of "c" with a string that is no valid UTF-8
+        let bytes = queue.raw.store.read(indices[2]).unwrap();
+        let mut corrupt = bytes[..16].to_vec();
+        corrupt.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+        queue.raw.store.update(indices[2], &corrupt).unwrap();
+
+        let drained: Vec<_> = queue.drain_lossy().collect();
+        assert_eq!(drained.len(), 4);
+        assert_eq!(drained[0].as_ref().unwrap(), "a");
+        assert_eq!(drained[1].as_ref().unwrap(), "b");
+        assert!(drained[2].is_err());
+        assert_eq!(drained[3].as_ref().unwrap(), "d");
+        assert!(queue.is_empty());
+        assert_eq!((queue.payload_bytes(), queue.overhead_bytes()), (0, 0));
+        assert_eq!(queue.counters().dequeued, 4);
+    }
+
+    #[test]
+    fn dequeue_or_deadletter() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct OldJob {
+            id: u32,
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct NewJob {
+            id: u32,
+            name: String,
+        }
+
+        for encoding in [IntEncoding::Fixed, IntEncoding::Varint] {
+            let options = || Options::new().int_encoding(encoding);
+            let file = tempfile::tempfile().expect("could not create tempfile");
+            let mut old_queue = Queue::<OldJob>::with_options(file.try_clone().unwrap(), options())
+                .expect("could not create");
+            let mut queue =
+                Queue::<NewJob>::with_options(file, options()).expect("could not create");
+            let dead_letters_file = tempfile::tempfile().expect("could not create tempfile");
+            let mut dead_letters =
+                Queue::<Vec<u8>>::new(dead_letters_file).expect("could not create");
+
+            old_queue.enqueue(OldJob { id: 1 }).unwrap();
+            old_queue.enqueue(OldJob { id: 2 }).unwrap();
+            let job = |id| NewJob {
+                id,
+                name: format!("job {}", id),
+            };
+            queue.enqueue(job(3)).unwrap();
+            old_queue.enqueue(OldJob { id: 4 }).unwrap();
+            queue.enqueue(job(5)).unwrap();
+            assert!(queue.dequeue().is_err());
+
+            assert_eq!(
+                queue.dequeue_or_deadletter(&mut dead_letters).unwrap(),
+                Some(job(3))
+            );
+            assert_eq!(
+                queue.dequeue_or_deadletter(&mut dead_letters).unwrap(),
+                Some(job(5))
+            );
+            assert_eq!(
+                queue.dequeue_or_deadletter(&mut dead_letters).unwrap(),
+                None
+            );
+            assert!(queue.is_empty());
+            assert_eq!(queue.counters().dequeued, 5);
+
+            // the dead letters hold the untouched bodies, in their original order
+            let bodies = dead_letters.snapshot_items().unwrap();
+            let old_jobs: Vec<OldJob> = bodies
+                .iter()
+                .map(|bytes| encoding.deserialize(bytes).unwrap())
+                .collect();
+            assert_eq!(
+                old_jobs,
+                [OldJob { id: 1 }, OldJob { id: 2 }, OldJob { id: 4 }]
+            );
+            assert_eq!(bodies[0], encoding.serialize(&OldJob { id: 1 }).unwrap());
+
+            // the queue keeps working after skipping dead letters
+            queue.enqueue(job(6)).unwrap();
+            assert_eq!(queue.dequeue().unwrap(), Some(job(6)));
+        }
+    }
+
+    #[test]
+    fn resumable_iteration() {
+        let (file, mut queue) = queue_of(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        let scanned: Vec<(Position, i32)> = queue
+            .iter_resumable()
+            .take(5)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            scanned.iter().map(|(_, item)| *item).collect::<Vec<_>>(),
+            [0, 1, 2, 3, 4]
+        );
+        let (position, _) = scanned[4];
+        let items = |queue: &mut Queue<i32>, position| -> Vec<i32> {
+            let entries = queue.iter_from(position).unwrap();
+            entries.map(|entry| entry.unwrap().1).collect()
+        };
+
+        // earlier items were dequeued, the position is still there
+        for _ in 0..3 {
+            queue.dequeue().unwrap();
+        }
+        assert_eq!(items(&mut queue, position), [5, 6, 7, 8, 9]);
+
+        // positions survive a restart, and dequeued ones continue with the
+        // oldest remaining item
+        let token = bincode::serialize(&position).unwrap();
+        drop(queue);
+        let mut queue = Queue::<i32>::new(file).unwrap();
+        for _ in 0..4 {
+            queue.dequeue().unwrap();
+        }
+        queue.enqueue(10).unwrap();
+        let position = bincode::deserialize(&token).unwrap();
+        assert_eq!(items(&mut queue, position), [7, 8, 9, 10]);
+
+        // resuming at the end sees later items
+        let (last, _) = queue.iter_from(position).unwrap().last().unwrap().unwrap();
+        assert_eq!(items(&mut queue, last), []);
+        queue.enqueue(11).unwrap();
+        assert_eq!(items(&mut queue, last), [11]);
+
+        // a gap in the middle invalidates all positions
+        queue.remove_at(1).unwrap();
+        let error = queue.iter_from(position).err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<WiredError>(),
+            Some(&WiredError::PositionInvalidated)
+        );
+        assert_eq!(queue.iter_resumable().count(), 4);
+    }
+
+    #[test]
+    fn update_at() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<String>::new(file).unwrap();
+        for item in ["head", "a", "b", "middle", "c", "tail"] {
+            queue.enqueue(item.to_string()).unwrap();
+        }
+        let positions: Vec<Position> = queue
+            .iter_resumable()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        let payload_bytes = queue.payload_bytes();
+
+        // grow across several frames and shrink back, in every place
+        let large = "x".repeat(5000);
+        for index in [0, 3, 5] {
+            assert!(queue.update_at(&positions[index], &large).unwrap());
+        }
+        assert_eq!(queue.len(), 6);
+        assert_eq!(queue.payload_bytes(), payload_bytes + 3 * 5000 - 14);
+        assert!(queue.update_at(&positions[3], &"m".to_string()).unwrap());
+
+        // a dequeued item can not be updated anymore
+        assert_eq!(queue.dequeue().unwrap(), Some(large.clone()));
+        assert!(!queue.update_at(&positions[0], &large).unwrap());
+
+        // a removal from the middle invalidates the positions
+        assert_eq!(queue.remove_at(1).unwrap(), Some("b".to_string()));
+        let error = queue.update_at(&positions[1], &large).err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<WiredError>(),
+            Some(&WiredError::PositionInvalidated)
+        );
+
+        let items: Vec<String> = std::iter::from_fn(|| queue.dequeue().unwrap()).collect();
+        assert_eq!(items, ["a", "m", "c", &large]);
+        assert_eq!(queue.payload_bytes(), 0);
+    }
+
+    #[test]
+    fn try_reserve() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<String>::new(file.try_clone().unwrap()).unwrap();
+        for index in 0..10 {
+            queue.enqueue(format!("item {}", index)).unwrap();
+        }
+        let size = file.metadata().unwrap().len() as usize;
+        queue.raw.store.set_quota(2 * size);
+
+        // more than the disk holds fails before anything got written
+        let error = queue.try_reserve(4 * size).expect_err("should fail");
+        assert_eq!(error.downcast_ref(), Some(&WiredError::DiskFull));
+        let items: Vec<String> = queue.iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(items.len(), 10);
+        assert_eq!(items[9], "item 9");
+
+        // what fits gets allocated now, so the items need no resize later
+        queue.try_reserve(size / 2).unwrap();
+        let resizes = queue.stats().resizes;
+        queue.enqueue("x".repeat(size / 4)).unwrap();
+        assert_eq!(queue.stats().resizes, resizes);
+        assert_eq!(queue.len(), 11);
+    }
+
+    /// a queue of the given items, with the header of this handle already
+    /// read, so tests can tamper with it
+    fn queue_of(items: &[i32]) -> (File, Queue<i32>) {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<i32>::new(file.try_clone().unwrap()).expect("could not create");
+        for item in items {
+            queue.enqueue(*item).expect("could not enqueue");
+        }
+        queue.synchronized(|_| Ok(())).unwrap();
+        (file, queue)
+    }
+
+    #[test]
+    fn check_counts() {
+        let (_, mut queue) = queue_of(&[1, 2, 3]);
+        let check = queue.check_counts(true).unwrap();
+        assert!(check.is_consistent());
+        assert!(!check.repaired);
+
+        // outdated pointers of regular operations are fine
+        queue.enqueue(4).unwrap();
+        assert_eq!(queue.dequeue().unwrap(), Some(1));
+        assert_eq!(queue.remove_first_where(|&i| i == 3).unwrap(), Some(3));
+        queue.enqueue(1).unwrap();
+        queue.enqueue(3).unwrap();
+        assert_eq!(queue.remove_first_where(|&i| i == 3).unwrap(), Some(3));
+        assert!(queue.check_counts(false).unwrap().is_consistent());
+        assert_eq!(queue.snapshot_items().unwrap(), vec![2, 4, 1]);
+        assert_eq!(queue.dequeue().unwrap(), Some(2));
+        queue.enqueue(3).unwrap();
+
+        // a wrong count alone
+        queue.raw.header.elements_count = 5;
+        queue.raw.save_header().unwrap();
+        assert_eq!(queue.len(), 5);
+        let check = queue.check_counts(false).unwrap();
+        assert_eq!((check.stored_count, check.reachable_count), (5, 3));
+        assert!(!check.broken_links && !check.repaired);
+        assert_eq!(queue.len(), 5);
+        let check = queue.check_counts(true).unwrap();
+        assert!(check.repaired);
+        assert_eq!(queue.len(), 3);
+        assert!(queue.check_counts(false).unwrap().is_consistent());
+        assert_eq!(queue.snapshot_items().unwrap(), vec![4, 1, 3]);
+    }
+
+    #[test]
+    fn repair_deleted_last_element() {
+        // a dequeue deleted the element, but the header was not saved
+        let (_, mut queue) = queue_of(&[1, 2, 3, 4]);
+        queue
+            .raw
+            .store
+            .delete(queue.raw.header.last_element)
+            .unwrap();
+        let check = queue.check_counts(true).unwrap();
+        assert_eq!((check.stored_count, check.reachable_count), (4, 3));
+        assert!(check.broken_links && check.repaired);
+        assert_eq!(queue.snapshot_items().unwrap(), vec![2, 3, 4]);
+        queue.enqueue(5).unwrap();
+        assert_eq!(queue.by_ref().collect::<Vec<_>>(), vec![2, 3, 4, 5]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn gc() {
+        let (file, mut queue) = queue_of(&[1, 2, 3]);
+        queue.set_meta(b"meta").unwrap();
+        assert_eq!(queue.gc().unwrap(), GcReport::default());
+
+        // the element of an enqueue that failed to save the header
+        queue.raw.store.fail_after(1);
+        queue
+            .enqueue_with_level(4, Level::High)
+            .expect_err("should fail");
+        queue.raw.store.clear_fault();
+        let live_frames = queue.raw.store.live_frames();
+        let report = queue.gc().unwrap();
+        let bytes = queue.raw.store.frame_size();
+        assert_eq!(report, GcReport { blocks: 1, bytes });
+        assert_eq!(queue.raw.store.live_frames(), live_frames - 1);
+        assert_eq!(queue.gc().unwrap(), GcReport::default());
+        drop(queue);
+
+        let mut queue = Queue::<i32>::new(file).unwrap();
+        assert_eq!(queue.meta().unwrap().unwrap(), b"meta");
+        assert_eq!(queue.snapshot_items().unwrap(), vec![1, 2, 3]);
+
+        // elements cut off from a broken chain are no garbage yet
+        queue
+            .raw
+            .store
+            .delete(queue.raw.header.last_element)
+            .unwrap();
+        let live_frames = queue.raw.store.live_frames();
+        let error = queue.gc().expect_err("should fail");
+        assert_eq!(error.downcast_ref(), Some(&WiredError::Inconsistent));
+        assert_eq!(queue.raw.store.live_frames(), live_frames);
+        queue.check_counts(true).unwrap();
+        assert_eq!(queue.gc().unwrap(), GcReport::default());
+        assert_eq!(queue.snapshot_items().unwrap(), vec![2, 3]);
+    }
+
+    #[test]
+    fn repair_deleted_first_element() {
+        // an enqueue saved the header, but the element got lost
+        let (_, mut queue) = queue_of(&[1, 2, 3, 4]);
+        queue
+            .raw
+            .store
+            .delete(queue.raw.header.first_element)
+            .unwrap();
+        let check = queue.check_counts(true).unwrap();
+        assert_eq!((check.stored_count, check.reachable_count), (4, 3));
+        assert!(check.broken_links && check.repaired);
+        assert!(queue.check_counts(false).unwrap().is_consistent());
+        assert_eq!(queue.snapshot_items().unwrap(), vec![1, 2, 3]);
+
+        // draining and refilling still works, no stale pointer remains
+        assert_eq!(queue.by_ref().collect::<Vec<_>>(), vec![1, 2, 3]);
+        queue.enqueue(5).unwrap();
+        queue.enqueue(6).unwrap();
+        assert_eq!(queue.by_ref().collect::<Vec<_>>(), vec![5, 6]);
+    }
+
+    #[test]
+    fn repair_dangling_prev() {
+        // an enqueue linked the former first element, but the header was not saved
+        let (_, mut queue) = queue_of(&[1, 2]);
+        let first = queue.raw.header.first_element;
+        queue
+            .raw
+            .update_links(first, |links| links.prev = 4711)
+            .unwrap();
+        let check = queue.check_counts(true).unwrap();
+        assert_eq!((check.stored_count, check.reachable_count), (2, 2));
+        assert!(check.broken_links && check.repaired);
+        assert_eq!(queue.by_ref().collect::<Vec<_>>(), vec![1, 2]);
+        queue.enqueue(3).unwrap();
+        assert_eq!(queue.dequeue().unwrap(), Some(3));
+    }
+
+    #[test]
+    fn repair_on_open() {
+        let (file, mut queue) = queue_of(&[1, 2, 3]);
+        queue.raw.header.elements_count = 1;
+        queue.raw.save_header().unwrap();
+        drop(queue);
+
+        // skipped by default
+        let queue = Queue::<i32>::new(file.try_clone().unwrap()).expect("could not open");
+        assert_eq!(queue.len(), 1);
+        drop(queue);
+
+        let options = Options::new().repair_on_open(true);
+        let queue = Queue::<i32>::with_options(file, options).expect("could not open");
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.snapshot_items().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn open_checked() {
+        let (file, mut queue) = queue_of(&[1, 2, 3]);
+        queue.raw.header.elements_count = 1;
+        queue.raw.save_header().unwrap();
+        drop(queue);
+
+        let (mut queue, report) = Queue::<i32>::open_checked(file).expect("could not open");
+        assert_eq!(report.items_verified, 3);
+        assert!(report.bytes_scanned > 0);
+        assert!(report.chain.repaired);
+        assert_eq!(queue.by_ref().collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<String>::new(file.try_clone().unwrap()).unwrap();
+        let mut indices = vec![];
+        for item in ["a", "b", "c"] {
+            indices.push(queue.enqueue_indexed(String::from(item)).unwrap());
+        }
+        // a string that is no valid UTF-8
+        let bytes = queue.raw.store.read(indices[1]).unwrap();
+        let mut corrupt = bytes[..16].to_vec();
+        corrupt.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+        queue.raw.store.update(indices[1], &corrupt).unwrap();
+        drop(queue);
+
+        let error = Queue::<String>::open_checked(file).err().unwrap();
+        match error.downcast_ref::<WiredError>() {
+            Some(WiredError::Corrupted { index }) => assert_eq!(*index, indices[1]),
+            _ => panic!("unexpected error: {}", error),
+        }
+    }
+
+    #[test]
+    fn corrupted_body_size() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<String>::new(file.try_clone().unwrap()).unwrap();
+        let mut indices = vec![];
+        for item in ["a", "b", "c"] {
+            indices.push(queue.enqueue_indexed(String::from(item)).unwrap());
+        }
+        // the body size follows the position in the frame header
+        let position = queue.raw.store.index_to_position(indices[1]) as u64;
+        drop(queue);
+        let mut corrupt = file.try_clone().unwrap();
+        std::io::Seek::seek(&mut corrupt, std::io::SeekFrom::Start(position + 8)).unwrap();
+        corrupt.write_all(&5000_u64.to_le_bytes()).unwrap();
+
+        let corrupted = |error: Box<dyn Error>| match error.downcast_ref::<WiredError>() {
+            Some(WiredError::Corrupted { index }) => assert_eq!(*index, indices[1]),
+            _ => panic!("unexpected error: {}", error),
+        };
+        corrupted(
+            Queue::<String>::open_checked(file.try_clone().unwrap())
+                .err()
+                .unwrap(),
+        );
+        let mut queue = Queue::<String>::new(file).unwrap();
+        assert_eq!(queue.dequeue().unwrap(), Some(String::from("a")));
+        corrupted(queue.dequeue().unwrap_err());
+    }
+
+    #[test]
+    fn counter_drift() {
+        let (file, mut queue) = queue_of(&[1, 2, 3]);
+        queue.raw.header.counter_drift = true;
+        queue.raw.save_header().unwrap();
+        drop(queue);
+
+        let (mut queue, report) = Queue::<i32>::open_checked(file).expect("could not open");
+        assert!(report.counter_drift);
+        assert!(!report.chain.repaired);
+        assert!(!queue.counter_drift());
+        assert_eq!(queue.by_ref().collect::<Vec<_>>(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn deserialize_failed() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<String>::new(file).unwrap();
+        let mut indices = vec![];
+        for item in ["a", "b", "c"] {
+            indices.push(queue.enqueue_indexed(String::from(item)).unwrap());
+        }
+        // a string that is no valid UTF-8
+        let bytes = queue.raw.store.read(indices[1]).unwrap();
+        let mut corrupt = bytes[..16].to_vec();
+        corrupt.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+        queue.raw.store.update(indices[1], &corrupt).unwrap();
+
+        assert_eq!(queue.dequeue().unwrap(), Some(String::from("a")));
+        let error = queue.dequeue().err().unwrap();
+        match error.downcast_ref::<WiredError>() {
+            Some(WiredError::DeserializeFailed {
+                operation,
+                index,
+                position,
+                ..
+            }) => {
+                assert_eq!(*operation, "dequeue");
+                assert_eq!(*index, indices[1]);
+                assert_eq!(*position, queue.raw.store.index_to_position(indices[1]));
+            }
+            _ => panic!("unexpected error: {}", error),
+        }
+        assert!(error.to_string().contains(&format!("block {}", indices[1])));
+        assert_eq!(queue.len(), 2);
+
+        // removals from the middle name their own operation
+        let operation = |result: Result<Option<String>, Box<dyn Error>>| {
+            let error = result.err().unwrap();
+            match error.downcast_ref::<WiredError>() {
+                Some(WiredError::DeserializeFailed { operation, .. }) => *operation,
+                _ => panic!("unexpected error: {}", error),
+            }
+        };
+        assert_eq!(operation(queue.remove_at(0)), "remove_at");
+        assert_eq!(operation(queue.remove_index(indices[1])), "remove_index");
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn corrupted_length_prefix() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<Vec<u8>>::new(file).unwrap();
+        let index = queue.enqueue_indexed(vec![7; 100]).unwrap();
+        // the body claims to be an exabyte long, right after the links
+        queue
+            .raw
+            .store
+            .patch(index, 16, &(1_u64 << 60).to_le_bytes())
+            .unwrap();
+        let error = queue.dequeue().err().unwrap();
+        match error.downcast_ref::<WiredError>() {
+            Some(WiredError::DeserializeFailed { index: failed, .. }) => {
+                assert_eq!(*failed, index)
+            }
+            _ => panic!("unexpected error: {}", error),
+        }
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn compression() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let options = Options::new().compress_above(64);
+        let mut queue = Queue::<String>::with_options(file.try_clone().unwrap(), options).unwrap();
+        let large = "wired ".repeat(200);
+        let small = String::from("tiny");
+        let indices = [
+            queue.enqueue_indexed(large.clone()).unwrap(),
+            queue.enqueue_indexed(small.clone()).unwrap(),
+        ];
+        let flags: Vec<bool> = indices
+            .iter()
+            .map(|index| {
+                queue
+                    .raw
+                    .split_element(&queue.raw.store.read(*index).unwrap())
+                    .unwrap()
+                    .1
+            })
+            .collect();
+        assert_eq!(flags, [true, false]);
+        assert!(queue.raw.store.record_size(indices[0]).unwrap() < large.len());
+        drop(queue);
+
+        // the layout sticks to the file, whatever the options say
+        let mut queue = Queue::<String>::new(file).unwrap();
+        queue.enqueue(large.clone()).unwrap();
+        let items = vec![large.clone(), small, large];
+        assert_eq!(queue.snapshot_items().unwrap(), items);
+        assert_eq!(queue.by_ref().collect::<Vec<_>>(), items);
+    }
+
+    #[test]
+    fn scan_borrowed() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let options = Options::new().compress_above(2000);
+        let mut queue = Queue::<String>::with_options(file.try_clone().unwrap(), options).unwrap();
+        let multi_frame = "x".repeat(1500);
+        let compressed = "wired ".repeat(500);
+        queue.enqueue(String::from("normal")).unwrap();
+        queue.enqueue(multi_frame.clone()).unwrap();
+        queue.enqueue(compressed.clone()).unwrap();
+        queue
+            .enqueue_with_level(String::from("high"), Level::High)
+            .unwrap();
+        queue
+            .enqueue_with_level(String::from("low"), Level::Low)
+            .unwrap();
+
+        let guards = queue
+            .scan_borrowed()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let items: Vec<&str> = guards.iter().map(|guard| guard.value().unwrap()).collect();
+        assert_eq!(
+            items,
+            ["high", "normal", multi_frame.as_str(), &compressed, "low"]
+        );
+        let zero_copy: Vec<bool> = guards.iter().map(Borrowed::is_zero_copy).collect();
+        assert_eq!(zero_copy, [true, true, false, false, true]);
+        drop(guards);
+
+        // the lock is gone with the scan
+        let mut other = Queue::<String>::new(file).unwrap();
+        other.enqueue(String::from("other")).unwrap();
+        assert_eq!(queue.len(), 6);
+        assert_eq!(queue.dequeue().unwrap(), Some(String::from("high")));
+    }
+
+    #[test]
+    fn end_indices() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<i32>::new(file.try_clone().unwrap()).unwrap();
+        assert_eq!((queue.front_index(), queue.back_index()), (None, None));
+
+        let first = queue.enqueue_indexed(1).unwrap();
+        assert_eq!(
+            (queue.front_index(), queue.back_index()),
+            (Some(first), Some(first))
+        );
+        let second = queue.enqueue_indexed(2).unwrap();
+        let third = queue.enqueue_indexed(3).unwrap();
+        assert_eq!(
+            (queue.front_index(), queue.back_index()),
+            (Some(first), Some(third))
+        );
+
+        // other handles see the same ends
+        let mut other = Queue::<i32>::new(file).unwrap();
+        assert_eq!(other.dequeue().unwrap(), Some(1));
+        assert_eq!(
+            (queue.front_index(), queue.back_index()),
+            (Some(second), Some(third))
+        );
+
+        // the back is where `dequeue_back` removes
+        let fourth = queue.enqueue_indexed(4).unwrap();
+        assert_eq!(queue.back_index(), Some(fourth));
+        assert_eq!(queue.dequeue_back().unwrap(), Some(4));
+        assert_eq!(queue.back_index(), Some(third));
+        assert_eq!(queue.remove_index(third).unwrap(), Some(3));
+        assert_eq!(
+            (queue.front_index(), queue.back_index()),
+            (Some(second), Some(second))
+        );
+        assert_eq!(queue.dequeue().unwrap(), Some(2));
+        assert_eq!((queue.front_index(), queue.back_index()), (None, None));
+    }
+
+    #[test]
+    fn strict_levels() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<i32>::new(file).unwrap();
+        queue.enqueue(1).unwrap();
+        queue.enqueue_with_level(10, Level::Low).unwrap();
+        queue.enqueue_with_level(100, Level::High).unwrap();
+        queue.enqueue(2).unwrap();
+        queue.enqueue_with_level(200, Level::High).unwrap();
+        assert_eq!(queue.len(), 5);
+        assert_eq!(
+            queue.len_by_level(),
+            [(Level::High, 2), (Level::Normal, 2), (Level::Low, 1)]
+        );
+        assert_eq!(queue.counters().max_depth, 5);
+        assert_eq!(queue.snapshot_items().unwrap(), vec![100, 200, 1, 2, 10]);
+        assert_eq!(queue.check_counts(false).unwrap().reachable_count, 5);
+
+        // only items of the normal level have positions
+        let high = queue.raw.current_header().unwrap().levels.unwrap().high;
+        assert_eq!(queue.remove_index(high.first_element).unwrap(), None);
+        assert_eq!(queue.remove_at(0).unwrap(), Some(1));
+        queue.enqueue(1).unwrap();
+
+        assert_eq!(queue.dequeue().unwrap(), Some(100));
+        queue.enqueue_with_level(300, Level::High).unwrap();
+        assert_eq!(queue.by_ref().collect::<Vec<_>>(), vec![200, 300, 2, 1, 10]);
+        assert_eq!(queue.len(), 0);
+        assert_eq!(queue.payload_bytes(), 0);
+    }
+
+    #[test]
+    fn weighted_levels() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<i32>::new(file).unwrap().with_level_weight(2);
+        for item in 0..3 {
+            queue.enqueue_with_level(item, Level::Low).unwrap();
+            queue.enqueue(10 + item).unwrap();
+        }
+        for item in 0..6 {
+            queue.enqueue_with_level(100 + item, Level::High).unwrap();
+        }
+        // the low level waited through two dequeues when high ran empty
+        let order = queue.by_ref().collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            vec![100, 101, 10, 0, 102, 103, 11, 1, 104, 105, 2, 12]
+        );
+    }
+
+    #[test]
+    fn levels_persist() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<i32>::new(file.try_clone().unwrap()).unwrap();
+        queue.enqueue(1).unwrap();
+        queue.enqueue(2).unwrap();
+        // files without levels hold normal items only
+        assert_eq!(
+            queue.len_by_level(),
+            [(Level::High, 0), (Level::Normal, 2), (Level::Low, 0)]
+        );
+        queue.enqueue_with_level(10, Level::Low).unwrap();
+        queue.enqueue_with_level(100, Level::High).unwrap();
+        queue.enqueue_with_level(200, Level::High).unwrap();
+        drop(queue);
+
+        let mut queue = Queue::<i32>::new(file).unwrap();
+        assert_eq!(
+            queue.len_by_level(),
+            [(Level::High, 2), (Level::Normal, 2), (Level::Low, 1)]
+        );
+        assert!(queue.check_counts(false).unwrap().is_consistent());
+        assert_eq!(queue.rewrite_all().unwrap(), 0);
+        assert_eq!(queue.by_ref().collect::<Vec<_>>(), vec![100, 200, 1, 2, 10]);
     }
 }
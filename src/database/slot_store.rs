@@ -0,0 +1,378 @@
+use super::{count_down, decode_header};
+use crate::block_storage::{BlockStorage, Stats};
+use crate::options::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::marker::PhantomData;
+use std::path::Path;
+
+// slots of a new file unless given otherwise, fixed once it is created
+const DEFAULT_SLOTS: usize = 256;
+
+// the `next` pointer follows the `u64` id within every entry
+const NEXT_OFFSET: usize = 8;
+
+/// a Database of values stored by integer IDs of the application
+///
+/// Where a `KeyValue` stores every key in a block of its own and keeps an
+/// index of all keys in memory, a `SlotStore` maps the ID through a fixed
+/// hash to one of a fixed number of slots in its header. Every slot points
+/// to a chain of entries holding the ID and the value, so a lookup reads
+/// only the entries of one slot and nothing is kept in memory besides the
+/// slots. This is the leaner choice when the keys are integers anyway.
+///
+/// Choose about as many slots as IDs are expected via `with_slots` when
+/// creating the file, to keep the chains short.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// // create a new db
+/// # let file = tempfile::tempfile()?;
+/// let mut store = wired::SlotStore::<String>::new(file)?;
+///
+/// // store values by their IDs
+/// store.put(42, String::from("answer"))?;
+/// store.put(7, String::from("lucky"))?;
+///
+/// // read one back
+/// let value = store.get(42)?; // Some("answer")
+///
+/// // remove it
+/// store.remove(42)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct SlotStore<V> {
+    store: BlockStorage,
+    header: Header,
+    value_type: PhantomData<V>,
+}
+
+impl<V> SlotStore<V>
+where
+    V: Serialize + DeserializeOwned,
+{
+    pub fn new(file: File) -> Result<Self, Box<dyn Error>> {
+        Self::with_options(file, Options::default())
+    }
+
+    /// Create a new database or open an existing one, tuning how a new file
+    /// gets initialized. See [`Options`](crate::Options) for details.
+    pub fn with_options(file: File, options: Options) -> Result<Self, Box<dyn Error>> {
+        Self::with_slots(file, options, DEFAULT_SLOTS)
+    }
+
+    /// Create a new database with the given number of slots, or open an
+    /// existing one with the number of slots it was created with.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let options = wired::Options::default();
+    /// let store = wired::SlotStore::<String>::with_slots(file, options, 10_000)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_slots(file: File, options: Options, slots: usize) -> Result<Self, Box<dyn Error>> {
+        let store = BlockStorage::with_options(file, &options)?;
+        Self::from_store(store, slots)
+    }
+
+    /// Open the database at the given path, creating the file if needed.
+    ///
+    /// Prefer this over `new` when possible: knowing the path allows upgrades
+    /// of older file formats to replace the file atomically.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let store = BlockStorage::open(path.as_ref(), &Options::default())?;
+        Self::from_store(store, DEFAULT_SLOTS)
+    }
+
+    fn from_store(mut store: BlockStorage, slots: usize) -> Result<Self, Box<dyn Error>> {
+        store.register("SlotStore")?;
+        let header = if store.is_empty() {
+            let header = Header {
+                slots: vec![0; slots.max(1)],
+                len: 0,
+                counter_drift: false,
+            };
+            store.create(&bincode::serialize(&header)?)?;
+            header
+        } else {
+            decode_header(&store.read(0)?)?
+        };
+        Ok(Self {
+            store,
+            header,
+            value_type: PhantomData,
+        })
+    }
+
+    fn save_header(&mut self) -> Result<(), Box<dyn Error>> {
+        let bytes: Vec<u8> = bincode::serialize(&self.header)?;
+        self.store.update(0, bytes.as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.header.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// whether the number of entries in the header would have dropped
+    /// below zero, which means it drifted from the chains, e.g. after a
+    /// crash. Operations keep working with the count held at zero.
+    pub fn counter_drift(&self) -> bool {
+        self.header.counter_drift
+    }
+
+    /// the number of slots, fixed since the file got created
+    pub fn slots(&self) -> usize {
+        self.header.slots.len()
+    }
+
+    /// runtime statistics of this handle, like the number of file resizes
+    pub fn stats(&self) -> Stats {
+        self.store.stats()
+    }
+
+    /// hand all pending writes of this handle to the operating system, see
+    /// [`Queue::flush`](crate::Queue::flush)
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.store.flush()
+    }
+
+    /// return once all writes of this handle are durable, see
+    /// [`Queue::barrier`](crate::Queue::barrier)
+    pub fn barrier(&mut self) -> Result<(), Box<dyn Error>> {
+        self.store.sync()
+    }
+
+    /// the value stored for the ID, if any
+    ///
+    /// Note: this reads every entry in the chain of the slot up to the ID.
+    pub fn get(&self, id: u64) -> Result<Option<V>, Box<dyn Error>> {
+        match self.find(id)? {
+            Some(found) => {
+                let entry: Entry<V> = bincode::deserialize(&self.store.read(found.index)?)?;
+                Ok(Some(entry.value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains(&self, id: u64) -> Result<bool, Box<dyn Error>> {
+        Ok(self.find(id)?.is_some())
+    }
+
+    /// store the value for the ID, replacing the previous one
+    pub fn put(&mut self, id: u64, value: V) -> Result<(), Box<dyn Error>> {
+        if let Some(found) = self.find(id)? {
+            let entry = Entry {
+                id,
+                next: found.next,
+                value,
+            };
+            return self.store.update(found.index, &bincode::serialize(&entry)?);
+        }
+        // new entries go in front of the chain of their slot
+        let slot = slot_of(id, self.slots());
+        let entry = Entry {
+            id,
+            next: self.header.slots[slot],
+            value,
+        };
+        let index = self.store.create(&bincode::serialize(&entry)?)?;
+        self.header.slots[slot] = index;
+        self.header.len += 1;
+        self.save_header()
+    }
+
+    /// remove the value of the ID, if there is one
+    pub fn remove(&mut self, id: u64) -> Result<(), Box<dyn Error>> {
+        let found = match self.find(id)? {
+            Some(found) => found,
+            None => return Ok(()),
+        };
+        match found.previous {
+            Some(previous) => {
+                let next = bincode::serialize(&found.next)?;
+                self.store.patch(previous, NEXT_OFFSET, &next)?;
+            }
+            None => {
+                let slot = slot_of(id, self.slots());
+                self.header.slots[slot] = found.next;
+            }
+        }
+        self.header.counter_drift |= count_down(&mut self.header.len);
+        self.save_header()?;
+        self.store.delete(found.index)
+    }
+
+    /// walk the chain of the slot of the ID until its entry
+    fn find(&self, id: u64) -> Result<Option<Found>, Box<dyn Error>> {
+        let mut previous = None;
+        let mut index = self.header.slots[slot_of(id, self.slots())];
+        while index != 0 {
+            // the value is not needed to follow the chain
+            let (entry_id, next): (u64, usize) = bincode::deserialize(&self.store.read(index)?)?;
+            if entry_id == id {
+                return Ok(Some(Found {
+                    index,
+                    previous,
+                    next,
+                }));
+            }
+            previous = Some(index);
+            index = next;
+        }
+        Ok(None)
+    }
+}
+
+/// the slot of an ID. The result is persisted, so the hash must never
+/// change between versions. The finalizer of SplitMix64 spreads sequential
+/// IDs over all slots.
+fn slot_of(id: u64, slots: usize) -> usize {
+    let mut hash = id;
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
+    (hash % slots as u64) as usize
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Header {
+    // the first entry of every chain, `0` for an empty slot
+    slots: Vec<usize>,
+    len: usize,
+    // set once the number of entries would have dropped below zero
+    counter_drift: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry<V> {
+    id: u64,
+    next: usize,
+    value: V,
+}
+
+/// an entry within a chain, with its neighbours
+struct Found {
+    index: usize,
+    previous: Option<usize>,
+    next: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::fabricate_drift;
+
+    fn colliding_ids(slots: usize, count: usize) -> Vec<u64> {
+        let slot = slot_of(0, slots);
+        (0..)
+            .filter(|id| slot_of(*id, slots) == slot)
+            .take(count)
+            .collect()
+    }
+
+    #[test]
+    fn works() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut store = SlotStore::<String>::new(file.try_clone().unwrap()).unwrap();
+        assert_eq!(store.slots(), DEFAULT_SLOTS);
+        assert_eq!(store.get(1).unwrap(), None);
+
+        for id in [1, 2, 1000, u64::MAX] {
+            store.put(id, format!("value {}", id)).unwrap();
+        }
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.get(1000).unwrap(), Some("value 1000".to_string()));
+
+        // overwriting keeps the entry, even when the value grows
+        store.put(2, "x".repeat(3000)).unwrap();
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.get(2).unwrap(), Some("x".repeat(3000)));
+
+        store.remove(1).unwrap();
+        store.remove(1).unwrap();
+        assert_eq!(store.len(), 3);
+        assert!(!store.contains(1).unwrap());
+
+        // works after reopen
+        drop(store);
+        let store = SlotStore::<String>::new(file).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.get(1).unwrap(), None);
+        assert_eq!(store.get(2).unwrap(), Some("x".repeat(3000)));
+        let value = format!("value {}", u64::MAX);
+        assert_eq!(store.get(u64::MAX).unwrap(), Some(value));
+    }
+
+    #[test]
+    fn drifted_len() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut store = SlotStore::<String>::new(file.try_clone().unwrap()).unwrap();
+        for id in [1, 2, 3] {
+            store.put(id, id.to_string()).unwrap();
+        }
+        // a header that lost track of the entries
+        store.header.len = 0;
+        store.save_header().unwrap();
+        fabricate_drift();
+
+        store.remove(2).unwrap();
+        assert_eq!(store.len(), 0);
+        assert!(store.counter_drift());
+        assert_eq!(store.get(1).unwrap(), Some(String::from("1")));
+        assert_eq!(store.get(2).unwrap(), None);
+
+        drop(store);
+        let store = SlotStore::<String>::new(file).unwrap();
+        assert!(store.counter_drift());
+        assert_eq!(store.get(3).unwrap(), Some(String::from("3")));
+    }
+
+    #[test]
+    fn collisions() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let options = Options::default();
+        let mut store =
+            SlotStore::<u64>::with_slots(file.try_clone().unwrap(), options, 8).unwrap();
+        let ids = colliding_ids(8, 4);
+        for id in &ids {
+            store.put(*id, id * 10).unwrap();
+        }
+        for id in &ids {
+            assert_eq!(store.get(*id).unwrap(), Some(id * 10));
+        }
+        // a missing ID of the same slot walks the whole chain
+        let missing = colliding_ids(8, 5)[4];
+        assert_eq!(store.get(missing).unwrap(), None);
+
+        // unlink from the middle, the end and the front of the chain
+        store.remove(ids[1]).unwrap();
+        store.remove(ids[0]).unwrap();
+        store.remove(ids[3]).unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.get(ids[2]).unwrap(), Some(ids[2] * 10));
+        for id in [ids[0], ids[1], ids[3]] {
+            assert_eq!(store.get(id).unwrap(), None);
+        }
+
+        // the number of slots is fixed at creation
+        drop(store);
+        let options = Options::default();
+        let store = SlotStore::<u64>::with_slots(file, options, 100).unwrap();
+        assert_eq!(store.slots(), 8);
+        assert_eq!(store.get(ids[2]).unwrap(), Some(ids[2] * 10));
+    }
+}
@@ -1,8 +1,24 @@
-use crate::block_storage::BlockStorage;
+use super::{
+    collect_garbage, compact_step, count_down, decode_context, decode_header, decode_migrating,
+    element_bytes, join_element, split_element, ChainCheck, CompactBudget, CompactProgress,
+    ContentHash, GcReport, Migrator, RecordFraming, Relocate, SplitElement, Usage,
+};
+use crate::block_storage::{BlockStorage, Stats};
+use crate::encoding::IntEncoding;
+use crate::error::WiredError;
+use crate::options::Options;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
 use std::fs::File;
+use std::io::{BufRead, Write};
 use std::marker::PhantomData;
+#[cfg(unix)]
+use std::os::unix::io::OwnedFd;
+#[cfg(windows)]
+use std::os::windows::io::OwnedHandle;
+use std::path::Path;
+use std::time::SystemTime;
 
 /// a Last-In-First-Out Database
 ///
@@ -58,11 +74,18 @@ use std::marker::PhantomData;
 /// # Ok(())
 /// # }
 /// ```
-
 pub struct Stack<T> {
+    // everything that works on serialized items, see `RawStack`
+    raw: RawStack,
+    migrator: Option<Migrator<T>>,
+    data_type: PhantomData<T>,
+}
+
+/// The part of a `Stack` that works on serialized items only, compiled
+/// once instead of for every `T`, like `RawQueue` for a `Queue`.
+struct RawStack {
     store: BlockStorage,
     header: Header,
-    data_type: PhantomData<T>,
 }
 
 impl<T> Stack<T>
@@ -102,42 +125,357 @@ where
     /// # }
     /// ```
     pub fn new(file: File) -> Result<Self, Box<dyn Error>> {
-        let mut store = BlockStorage::new(file)?;
-        let header = Self::read_header(&mut store)?;
-        let data_type = PhantomData;
-        let mut stack = Self {
-            store,
-            header,
-            data_type,
-        };
-        stack.save_header()?;
-        Ok(stack)
+        Self::with_options(file, Options::default())
+    }
+
+    /// Open a database from a file descriptor opened elsewhere, taking
+    /// ownership of it, see [`Queue::from_fd`](crate::Queue::from_fd)
+    #[cfg(unix)]
+    pub fn from_fd(fd: impl Into<OwnedFd>) -> Result<Self, Box<dyn Error>> {
+        Self::new(File::from(fd.into()))
+    }
+
+    /// like `from_fd`, but for a handle of a file on Windows
+    #[cfg(windows)]
+    pub fn from_handle(handle: impl Into<OwnedHandle>) -> Result<Self, Box<dyn Error>> {
+        Self::new(File::from(handle.into()))
+    }
+
+    /// Open a database from a borrowed file through a duplicate of it, see
+    /// [`Queue::from_file`](crate::Queue::from_file)
+    pub fn from_file(file: &File) -> Result<Self, Box<dyn Error>> {
+        Self::new(file.try_clone()?)
+    }
+
+    /// Create a new database or open an existing one, tuning how a new file
+    /// gets initialized. See [`Options`](crate::Options) for details.
+    pub fn with_options(file: File, options: Options) -> Result<Self, Box<dyn Error>> {
+        let store = BlockStorage::with_options(file, &options)?;
+        Self::from_store(store, &options)
+    }
+
+    /// Create a new database in anonymous memory instead of a file, e.g. for
+    /// scratch data that should never hit the disk. The memory is reserved
+    /// for at least `size` bytes upfront but only committed once written
+    /// to, so it may be large. It grows like a file when needed and is
+    /// released once the database is dropped, along with all items.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut scratch = wired::Stack::<String>::anonymous(1 << 30)?;
+    /// scratch.push(String::from("intermediate result"))?;
+    /// let item = scratch.pop()?; // Some("intermediate result")
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn anonymous(size: usize) -> Result<Self, Box<dyn Error>> {
+        let options = Options::default();
+        let store = BlockStorage::anonymous(size, &options)?;
+        Self::from_store(store, &options)
+    }
+
+    /// Open the database at the given path, creating the file if needed.
+    ///
+    /// Prefer this over `new` when possible: knowing the path allows upgrades
+    /// of older file formats to replace the file atomically.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = wired::Stack::<String>::open("path/to/db.wired")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::open_with_options(path, Options::default())
+    }
+
+    /// Open the existing database at the given path, like `open`, but fail
+    /// with an `io::Error` of kind `NotFound` instead of creating a new
+    /// empty database if there is no file, e.g. because of a mistyped path.
+    pub fn open_existing<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let options = Options::default();
+        let store = BlockStorage::open_existing(path.as_ref(), &options)?;
+        Self::from_store(store, &options)
+    }
+
+    /// Open the database at the given path, tuning how a new file gets
+    /// initialized. See [`Options`](crate::Options) for details.
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        options: Options,
+    ) -> Result<Self, Box<dyn Error>> {
+        let store = BlockStorage::open(path.as_ref(), &options)?;
+        Self::from_store(store, &options)
+    }
+
+    /// Fill a new file with all items at once, much faster than pushing
+    /// them one by one. The items are written front to back without
+    /// consulting the free list, and the file is flushed once at the end
+    /// instead of after every item. The last item ends up on top, just like
+    /// with `push`.
+    ///
+    /// `size_hint` is the expected number of items. The file grows once to
+    /// hold that many items of the size of the first one. It is only an
+    /// estimate: too few grows the file as usual, too many leaves the
+    /// space for later items.
+    ///
+    /// Fails with `WiredError::NotEmpty` unless the file is new.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let items = (0..1_000_000).map(|id| format!("item {}", id));
+    /// let stack = wired::Stack::<String>::bulk_load(file, items, 1_000_000)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn bulk_load<I>(file: File, items: I, size_hint: usize) -> Result<Self, Box<dyn Error>>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        Self::bulk_load_with_options(file, items, size_hint, Options::default())
+    }
+
+    /// Fill a new file with all items at once like `bulk_load`, tuning how
+    /// the file gets initialized and the items get stored, e.g. with
+    /// `Options::compress_above`. See [`Options`](crate::Options) for details.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let items = (0..1_000_000).map(|id| format!("item {}", id));
+    /// let options = wired::Options::new().compress_above(256);
+    /// let stack = wired::Stack::<String>::bulk_load_with_options(file, items, 1_000_000, options)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn bulk_load_with_options<I>(
+        file: File,
+        items: I,
+        size_hint: usize,
+        options: Options,
+    ) -> Result<Self, Box<dyn Error>>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let mut store = BlockStorage::with_options(file, &options)?;
+        let encoding = options.int_encoding;
+        let mut bodies = items.into_iter().map(|item| encoding.serialize(&item));
+        store.register("Stack")?;
+        store.lock()?;
+        let result = RawStack::load(&mut store, &mut bodies, size_hint, &options);
+        store.unlock()?;
+        result?;
+        Self::from_store(store, &options)
+    }
+
+    fn from_store(store: BlockStorage, options: &Options) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            raw: RawStack::open(store, options)?,
+            migrator: None,
+            data_type: PhantomData,
+        })
     }
 
     pub fn len(&self) -> usize {
-        self.header.elements_count
+        self.raw.header.elements_count
     }
 
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 
-    fn read_header(store: &mut BlockStorage) -> Result<Header, Box<dyn Error>> {
-        let bytes = store.read(0)?;
-        if store.is_empty() {
-            let header = Header::default();
-            let bytes: Vec<u8> = bincode::serialize(&header)?;
-            store.create(bytes.as_slice())?;
-            Ok(header)
-        } else {
-            let header = bincode::deserialize_from(bytes.as_slice())?;
-            Ok(header)
-        }
+    /// whether the number of items in the header would have dropped below
+    /// zero, see [`Queue::counter_drift`](crate::Queue::counter_drift).
+    /// `check_counts(true)` corrects it and clears this flag.
+    pub fn counter_drift(&self) -> bool {
+        self.raw.header.counter_drift
     }
 
-    fn save_header(&mut self) -> Result<(), Box<dyn Error>> {
-        let bytes: Vec<u8> = bincode::serialize(&self.header)?;
-        self.store.update(0, bytes.as_slice())
+    /// runtime statistics of this handle, like the number of file resizes
+    pub fn stats(&self) -> Stats {
+        self.raw.store.stats()
+    }
+
+    /// the number of runs of adjacent free blocks by their length, to judge
+    /// whether the file is fragmented into many small holes and a compaction
+    /// would pay off
+    pub fn free_space_histogram(&self) -> Result<BTreeMap<usize, usize>, Box<dyn Error>> {
+        self.raw.store.free_space_histogram()
+    }
+
+    /// grow the file right away so items of the given total serialized size
+    /// fit, see [`Queue::try_reserve`](crate::Queue::try_reserve)
+    pub fn try_reserve(&mut self, additional_bytes: usize) -> Result<(), Box<dyn Error>> {
+        self.raw.store.reserve(additional_bytes)
+    }
+
+    /// hand all pending writes of this handle to the operating system, see
+    /// [`Queue::flush`](crate::Queue::flush)
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.raw.store.flush()
+    }
+
+    /// return once all writes of this handle are durable, see
+    /// [`Queue::barrier`](crate::Queue::barrier)
+    pub fn barrier(&mut self) -> Result<(), Box<dyn Error>> {
+        self.raw.store.sync()
+    }
+
+    /// when the database file was created, `None` for files written by
+    /// versions of this crate that did not record it yet
+    pub fn created_at(&self) -> Option<SystemTime> {
+        self.raw.store.created_at()
+    }
+
+    /// when the database was modified the last time, with a resolution of
+    /// seconds. Reads never change it.
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.raw.store.modified_at()
+    }
+
+    /// the metadata blob stored with the file by `set_meta`, `None` if
+    /// there is none
+    pub fn meta(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.raw.store.meta()
+    }
+
+    /// stamp the file with a blob of application metadata of up to 64KB,
+    /// replacing the previous one. It is stored apart from the items and
+    /// survives upgrades of the file format.
+    pub fn set_meta(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.raw.store.set_meta(bytes)
+    }
+
+    /// the total number of bytes of all stored items, without any framing
+    /// or free space. Compare it with the length of the file to see the
+    /// overhead of the storage layout.
+    ///
+    /// Note: this walks the headers of all frames in the file once.
+    pub fn logical_size(&self) -> Result<usize, Box<dyn Error>> {
+        // everything except the header block are elements
+        Ok(self.raw.store.live_bytes()? - self.raw.store.record_size(0)?)
+    }
+
+    /// lifetime counters of this stack, persisted across reopens
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut stack = wired::Stack::<String>::new(file)?;
+    /// stack.push(String::from("some item"))?;
+    /// stack.pop()?;
+    /// let counters = stack.counters(); // max_depth: 1, pushes: 1, pops: 1
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn counters(&self) -> StackCounters {
+        self.raw.header.counters.unwrap_or_default()
+    }
+
+    /// start a new measurement window: the maximum depth is set to the
+    /// current length and the totals start from zero again
+    pub fn reset_counters(&mut self) -> Result<(), Box<dyn Error>> {
+        self.raw.reset_counters()
+    }
+
+    /// the serialized bytes of all items, without their pointers or any
+    /// framing. Unlike `logical_size`, this needs no scan of the file.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut stack = wired::Stack::<u64>::new(file)?;
+    /// stack.push(42)?;
+    /// let payload = stack.payload_bytes(); // 8
+    /// let overhead = stack.overhead_bytes(); // 1016
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn payload_bytes(&self) -> usize {
+        self.raw.header.usage.unwrap_or_default().payload_bytes()
+    }
+
+    /// the bytes of all frames holding items that are not payload: the
+    /// pointers of the elements, frame headers and the unused rest of the
+    /// last frame of every element
+    pub fn overhead_bytes(&self) -> usize {
+        let usage = self.raw.header.usage.unwrap_or_default();
+        usage.overhead_bytes(self.raw.store.frame_size())
+    }
+
+    /// the bytes the item would take in the file once pushed, see
+    /// [`Queue::estimate_bytes`](crate::Queue::estimate_bytes)
+    pub fn estimate_bytes(&self, item: &T) -> Result<usize, Box<dyn Error>> {
+        let body_len = self.raw.header.encoding.serialized_size(item)?;
+        self.raw.element_bytes(body_len)
+    }
+
+    /// compare the number of elements stored in the header with the actual
+    /// chain of elements, which may disagree after an unclean shutdown.
+    /// With `repair`, the header gets corrected to match the chain. A
+    /// pointer to a deleted block cuts the stack off below the last element
+    /// still reachable from the top.
+    ///
+    /// Note: this is an `O(n)` operation that reads every element, but does
+    /// not deserialize any item. Use `Options::repair_on_open` to run it
+    /// whenever the file gets opened.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut stack = wired::Stack::<String>::new(file)?;
+    /// let check = stack.check_counts(true)?;
+    /// if !check.is_consistent() {
+    ///     println!("repaired: {} instead of {} items", check.reachable_count, check.stored_count);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn check_counts(&mut self, repair: bool) -> Result<ChainCheck, Box<dyn Error>> {
+        self.raw.check_counts(repair)
+    }
+
+    /// delete orphaned blocks that are not reachable from the header, see
+    /// [`Queue::gc`](crate::Queue::gc). Fails with
+    /// `WiredError::Inconsistent` without deleting anything if the chain
+    /// disagrees with the header, which `check_counts(true)` repairs.
+    pub fn gc(&mut self) -> Result<GcReport, Box<dyn Error>> {
+        self.raw.gc()
+    }
+
+    /// do a bounded part of a compaction, see
+    /// [`Queue::compact_step`](crate::Queue::compact_step)
+    ///
+    /// Note: an element is referred to by the one above it, which only a
+    /// walk down from the top finds, so every moved item costs an `O(n)`
+    /// scan of the pointers.
+    pub fn compact_step(
+        &mut self,
+        budget: CompactBudget,
+    ) -> Result<CompactProgress, Box<dyn Error>> {
+        compact_step(&mut self.raw, budget)
+    }
+
+    /// the number of bytes compacting with `compact_step` until it is done
+    /// would release, see
+    /// [`Queue::compaction_savings`](crate::Queue::compaction_savings)
+    pub fn compaction_savings(&self) -> Result<usize, Box<dyn Error>> {
+        self.raw.store.compaction_savings()
     }
 
     /// insert a new item at the end of the stack and persist to disk
@@ -154,19 +492,8 @@ where
     /// # }
     /// ```
     pub fn push(&mut self, data: T) -> Result<(), Box<dyn Error>> {
-        let mut element = Element {
-            body: data,
-            prev: 0,
-        };
-        if self.header.last_element != 0 {
-            element.prev = self.header.last_element;
-        }
-        let bytes: Vec<u8> = bincode::serialize(&element)?;
-        let index = self.store.create(bytes.as_slice())?;
-        self.header.last_element = index;
-        self.header.elements_count += 1;
-        self.save_header()?;
-        Ok(())
+        let body = self.raw.header.encoding.serialize(&data)?;
+        self.raw.push_body(&body)
     }
 
     /// remove the item at the and of the stack, persist to disk and return the item
@@ -185,17 +512,724 @@ where
     /// # }
     /// ```
     pub fn pop(&mut self) -> Result<Option<T>, Box<dyn Error>> {
-        if self.header.elements_count == 0 {
+        if self.raw.header.elements_count == 0 {
             return Ok(None);
         }
-        let index = self.header.last_element;
+        let index = self.raw.header.last_element;
+        let bytes = self.raw.store.read(index)?;
+        let element = self.decode_element("pop", index, &bytes)?;
+        self.raw.remove_top(element.prev, &bytes)?;
+        Ok(Some(element.body))
+    }
+
+    /// overwrite the item on top of the stack and return the one it
+    /// replaced, `None` without storing anything if the stack is empty.
+    ///
+    /// The element keeps its block, which grows or shrinks with the new
+    /// item, so nothing gets unlinked and pushed again, and the counters
+    /// of pushes and pops stay the same.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut stack = wired::Stack::<String>::new(file)?;
+    /// stack.push(String::from("draft"))?;
+    /// let previous = stack.replace_top(String::from("final"))?; // Some("draft")
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn replace_top(&mut self, data: T) -> Result<Option<T>, Box<dyn Error>> {
+        if self.raw.header.elements_count == 0 {
+            return Ok(None);
+        }
+        let index = self.raw.header.last_element;
+        let record = self.raw.store.read(index)?;
+        let element = self.decode_element("replace_top", index, &record)?;
+        let body = self.raw.header.encoding.serialize(&data)?;
+        self.raw.replace_body(index, &record, &body)?;
+        Ok(Some(element.body))
+    }
+
+    /// remove all items from the bottom up, in the order they were pushed,
+    /// and write their serialized bytes to the writer as they are,
+    /// delimited by the framing, without deserializing them. Returns the
+    /// number of records written. The header gets saved once at the end.
+    ///
+    /// Loading the records with `load_raw_from` pushes them in the same
+    /// order, which restores the stack. When writing fails, the records
+    /// written before are removed and all others stay.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// # let archive_file = tempfile::tempfile()?;
+    /// let mut stack = wired::Stack::<String>::new(file)?;
+    /// stack.push(String::from("old entry"))?;
+    /// let archive = std::io::BufWriter::new(archive_file);
+    /// let exported = stack.drain_raw_to(archive, wired::RecordFraming::LengthPrefixed)?; // 1
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn drain_raw_to<W: Write>(
+        &mut self,
+        mut writer: W,
+        framing: RecordFraming,
+    ) -> Result<u64, Box<dyn Error>> {
+        let mut exported = 0;
+        let result = self
+            .raw
+            .drain_bottom_to(&mut writer, framing, &mut exported);
+        if exported > 0 {
+            self.raw.save_header()?;
+        }
+        result?;
+        writer.flush()?;
+        Ok(exported)
+    }
+
+    /// push every record read from the reader, as written by
+    /// `drain_raw_to` with the same framing, without deserializing them.
+    /// Returns the number of records pushed. The header gets saved once at
+    /// the end, also when reading fails after some records.
+    ///
+    /// Nothing checks that the records decode as `T`, like for
+    /// [`KeyValue::set_raw`](crate::KeyValue::set_raw).
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// # let archive_file = tempfile::tempfile()?;
+    /// let mut stack = wired::Stack::<String>::new(file)?;
+    /// let archive = std::io::BufReader::new(archive_file);
+    /// let loaded = stack.load_raw_from(archive, wired::RecordFraming::LengthPrefixed)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn load_raw_from<R: BufRead>(
+        &mut self,
+        mut reader: R,
+        framing: RecordFraming,
+    ) -> Result<u64, Box<dyn Error>> {
+        let mut loaded = 0;
+        let result = self.raw.load_records(&mut reader, framing, &mut loaded);
+        if loaded > 0 {
+            self.raw.save_header()?;
+        }
+        result?;
+        Ok(loaded)
+    }
+
+    /// remove the item at the bottom of the stack, the one pushed first,
+    /// and return it
+    ///
+    /// Note: elements only link to the one below them, so this walks down
+    /// the whole stack, an `O(n)` operation.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut stack = wired::Stack::<String>::new(file)?;
+    /// stack.push(String::from("first"))?;
+    /// stack.push(String::from("second"))?;
+    /// let item = stack.pop_bottom()?; // Some("first")
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pop_bottom(&mut self) -> Result<Option<T>, Box<dyn Error>> {
+        let count = self.raw.header.elements_count;
+        if count == 0 {
+            return Ok(None);
+        }
+        // the bottom element and the one right above it, `0` for none
+        let mut bottom = self.raw.header.last_element;
+        let mut above = 0;
+        for _ in 1..count {
+            let bytes = self.raw.store.read(bottom)?;
+            let links: Links = self.raw.header.encoding.deserialize(&bytes)?;
+            above = bottom;
+            bottom = links.prev;
+        }
+        let bytes = self.raw.store.read(bottom)?;
+        let element = self.decode_element("pop_bottom", bottom, &bytes)?;
+        match above {
+            0 => self.raw.remove_top(element.prev, &bytes)?,
+            above => self.raw.remove_bottom(bottom, above, &bytes)?,
+        }
+        Ok(Some(element.body))
+    }
+
+    /// a hash of the logical contents of the stack, see
+    /// [`Queue::content_hash`](crate::Queue::content_hash). The items get
+    /// hashed in the order `pop` would return them, from the top down.
+    pub fn content_hash(&self) -> Result<u64, Box<dyn Error>> {
+        let raw = &self.raw;
+        let mut hash = ContentHash::new();
+        let mut index = raw.header.last_element;
+        for _ in 0..raw.header.elements_count {
+            let record = raw.store.read(index)?;
+            let (links, _, body) = raw.split_element(&record)?;
+            hash.record(&body);
+            index = links.prev;
+        }
+        Ok(hash.finish())
+    }
+
+    /// iterate over all items from the top without removing them, by
+    /// following the links between the elements, so a full scan reads
+    /// every element once and nothing else.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// let mut stack = wired::Stack::<String>::new(file)?;
+    /// stack.push(String::from("first"))?;
+    /// stack.push(String::from("second"))?;
+    /// for item in stack.iter() {
+    ///     let item = item?; // "second", then "first"
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn iter(&self) -> StackIter<'_, T> {
+        StackIter {
+            stack: self,
+            next: self.raw.header.last_element,
+            left: self.raw.header.elements_count,
+        }
+    }
+
+    /// remove all items and shrink the file back to its header, see
+    /// [`Queue::clear_and_shrink`](crate::Queue::clear_and_shrink). The items
+    /// count as popped.
+    pub fn clear_and_shrink(&mut self) -> Result<(), Box<dyn Error>> {
+        self.raw.clear_and_shrink()
+    }
+
+    /// decode items that no longer deserialize as `T`, e.g. after a field
+    /// was added to it, with the given migrator instead of failing. This
+    /// applies to every read of this handle, use
+    /// [`rewrite_all`](Self::rewrite_all) to upgrade the stored items for good.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let file = tempfile::tempfile()?;
+    /// // items were pushed as plain numbers before they got a label
+    /// let mut stack = wired::Stack::<(u32, String)>::new(file)?.with_migrator(|bytes| {
+    ///     let id: u32 = bincode::deserialize(bytes)?;
+    ///     Ok((id, String::from("unlabeled")))
+    /// });
+    /// let item = stack.pop()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_migrator(mut self, migrator: Migrator<T>) -> Self {
+        self.migrator = Some(migrator);
+        self
+    }
+
+    /// write every item back that only decodes through the migrator, as the
+    /// current type, so later reads need no migrator anymore. Items keep
+    /// their position within the stack. Returns the number of rewritten items.
+    ///
+    /// Note: this is an `O(n)` operation that reads every single item.
+    pub fn rewrite_all(&mut self) -> Result<usize, Box<dyn Error>> {
+        let mut rewritten = 0;
+        let mut index = self.raw.header.last_element;
+        for _ in 0..self.raw.header.elements_count {
+            let bytes = self.raw.store.read(index)?;
+            let (links, _, body) = self.raw.split_element(&bytes)?;
+            if self.raw.header.encoding.deserialize::<T>(&body).is_err() {
+                let element = self.decode_element("rewrite_all", index, &bytes)?;
+                let body = self.raw.header.encoding.serialize(&element.body)?;
+                let record = self.raw.join_element(&links, &body)?;
+                self.raw.store.update(index, &record)?;
+                rewritten += 1;
+            }
+            index = links.prev;
+        }
+        if rewritten > 0 {
+            self.raw.header.usage = Some(self.raw.measure_usage()?);
+            self.raw.save_header()?;
+        }
+        Ok(rewritten)
+    }
+
+    /// decode an element, using the migrator for a body that does not
+    /// deserialize as `T`
+    fn decode_element(
+        &self,
+        operation: &'static str,
+        index: usize,
+        bytes: &[u8],
+    ) -> Result<Element<T>, Box<dyn Error>> {
+        let (links, _, body) = self.raw.split_element(bytes)?;
+        let body = decode_migrating(self.raw.header.encoding, self.migrator, &body);
+        Ok(Element {
+            prev: links.prev,
+            body: decode_context(body, &self.raw.store, operation, index)?,
+        })
+    }
+}
+
+impl RawStack {
+    /// register the store as a stack and bring the header of an older file
+    /// up to date
+    fn open(mut store: BlockStorage, options: &Options) -> Result<Self, Box<dyn Error>> {
+        store.register("Stack")?;
+        let header = Self::read_header(&mut store, options.int_encoding, options.compress_above)?;
+        let mut raw = Self { store, header };
+        if raw.header.counters.is_none() {
+            // files written before counters existed start with the current state
+            let count = raw.header.elements_count as u64;
+            raw.header.counters = Some(StackCounters {
+                max_depth: count,
+                pushes: count,
+                pops: 0,
+            });
+            raw.save_header()?;
+        }
+        if raw.header.usage.is_none() {
+            raw.header.usage = Some(raw.measure_usage()?);
+            raw.save_header()?;
+        }
+        if options.repair_on_open {
+            raw.check_counts(true)?;
+        }
+        Ok(raw)
+    }
+
+    /// write the header and every serialized item with a bulk loader, see
+    /// `Stack::bulk_load`
+    fn load(
+        store: &mut BlockStorage,
+        bodies: &mut dyn Iterator<Item = Result<Vec<u8>, Box<dyn Error>>>,
+        size_hint: usize,
+        options: &Options,
+    ) -> Result<(), Box<dyn Error>> {
+        let (encoding, compress_above) = (options.int_encoding, options.compress_above);
+        let mut header = Header {
+            encoding,
+            compress_above,
+            ..Header::default()
+        };
+        let mut usage = Usage::default();
+        let mut loader = store.bulk_loader()?;
+        // the header gets its content once all elements are written
+        loader.append(&bincode::serialize(&header)?)?;
+        for body in bodies {
+            let links = Links {
+                prev: header.last_element,
+            };
+            let record = join_element(&links, &body?, encoding, compress_above)?;
+            if header.elements_count == 0 {
+                loader.reserve(1 + size_hint * loader.frames_for(record.len()))?;
+            }
+            header.last_element = loader.append(&record)?;
+            header.elements_count += 1;
+            let links_length = encoding.serialize(&links)?.len();
+            let frames = loader.frames_for(record.len());
+            usage.add(Usage::of_record(record.len() - links_length, frames));
+        }
+        loader.finish()?;
+        let count = header.elements_count as u64;
+        header.counters = Some(StackCounters {
+            max_depth: count,
+            pushes: count,
+            pops: 0,
+        });
+        header.usage = Some(usage);
+        store.update(0, &bincode::serialize(&header)?)
+    }
+
+    fn reset_counters(&mut self) -> Result<(), Box<dyn Error>> {
+        self.header.counters = Some(StackCounters {
+            max_depth: self.header.elements_count as u64,
+            ..StackCounters::default()
+        });
+        self.save_header()
+    }
+
+    fn counters_mut(&mut self) -> &mut StackCounters {
+        self.header
+            .counters
+            .get_or_insert_with(StackCounters::default)
+    }
+
+    fn usage_mut(&mut self) -> &mut Usage {
+        self.header.usage.get_or_insert_with(Usage::default)
+    }
+
+    /// the usage of a stored element, whose body follows its link
+    fn record_usage(&self, record: &[u8]) -> Result<Usage, Box<dyn Error>> {
+        let links: Links = self.header.encoding.deserialize(record)?;
+        let body_start = self.header.encoding.serialize(&links)?.len();
+        let frames = self.store.frames_for(record.len());
+        Ok(Usage::of_record(record.len() - body_start, frames))
+    }
+
+    /// add up the usage of all elements, for files that predate tracking it
+    fn measure_usage(&self) -> Result<Usage, Box<dyn Error>> {
+        let mut usage = Usage::default();
+        let mut index = self.header.last_element;
+        for _ in 0..self.header.elements_count {
+            let bytes = self.store.read(index)?;
+            usage.add(self.record_usage(&bytes)?);
+            index = self.header.encoding.deserialize::<Links>(&bytes)?.prev;
+        }
+        Ok(usage)
+    }
+
+    /// read the header, or create it with the given layout for a new file
+    fn read_header(
+        store: &mut BlockStorage,
+        encoding: IntEncoding,
+        compress_above: Option<usize>,
+    ) -> Result<Header, Box<dyn Error>> {
+        let bytes = store.read(0)?;
+        if store.is_empty() {
+            let header = Header {
+                encoding,
+                compress_above,
+                ..Header::default()
+            };
+            let bytes: Vec<u8> = bincode::serialize(&header)?;
+            store.create(bytes.as_slice())?;
+            Ok(header)
+        } else {
+            decode_header(bytes.as_slice())
+        }
+    }
+
+    fn save_header(&mut self) -> Result<(), Box<dyn Error>> {
+        let bytes: Vec<u8> = bincode::serialize(&self.header)?;
+        self.store.update(0, bytes.as_slice())
+    }
+
+    fn check_counts(&mut self, repair: bool) -> Result<ChainCheck, Box<dyn Error>> {
+        let (count, broken_at) = self.walk_chain();
+        let mut check = ChainCheck {
+            stored_count: self.header.elements_count,
+            reachable_count: count,
+            broken_links: broken_at.is_some(),
+            repaired: false,
+        };
+        if !repair {
+            return Ok(check);
+        }
+        if check.is_consistent() {
+            if self.header.counter_drift {
+                self.header.counter_drift = false;
+                self.save_header()?;
+            }
+            return Ok(check);
+        }
+        match broken_at {
+            Some(0) => self.header.last_element = 0,
+            Some(index) => self.update_prev(index, 0)?,
+            None => {}
+        }
+        self.header.elements_count = count;
+        self.header.counter_drift = false;
+        // elements cut off from the chain no longer count
+        self.header.usage = Some(self.measure_usage()?);
+        self.save_header()?;
+        check.repaired = true;
+        Ok(check)
+    }
+
+    fn gc(&mut self) -> Result<GcReport, Box<dyn Error>> {
+        let (count, broken_at) = self.walk_chain();
+        if broken_at.is_some() || count != self.header.elements_count {
+            return Err(WiredError::Inconsistent.into());
+        }
+        let mut reachable = HashSet::from([0]);
+        let mut current = self.header.last_element;
+        while current != 0 && reachable.insert(current) {
+            current = self.read_prev(current).unwrap_or(0);
+        }
+        collect_garbage(&mut self.store, &reachable)
+    }
+
+    /// the length of the intact chain from the top, and the element whose
+    /// `prev` pointer does not lead to another element, `0` for the top
+    fn walk_chain(&self) -> (usize, Option<usize>) {
+        let top = self.header.last_element;
+        if top == 0 {
+            return (0, None);
+        }
+        if self.read_prev(top).is_none() {
+            return (0, Some(0));
+        }
+        let mut visited = HashSet::from([top]);
+        let mut current = top;
+        while let Some(prev) = self.read_prev(current) {
+            if prev == 0 {
+                return (visited.len(), None);
+            }
+            if self.read_prev(prev).is_none() || !visited.insert(prev) {
+                break;
+            }
+            current = prev;
+        }
+        (visited.len(), Some(current))
+    }
+
+    /// the `prev` pointer of the element at the index, `None` if the block
+    /// holds no element, e.g. because a pointer outlived a crash
+    fn read_prev(&self, index: usize) -> Option<usize> {
+        if index == 0 || !self.store.is_live(index) {
+            return None;
+        }
+        let bytes = self.store.read(index).ok()?;
+        let links: Links = self.header.encoding.deserialize(&bytes).ok()?;
+        Some(links.prev)
+    }
+
+    /// change the `prev` pointer of an element, keeping its body as it is
+    fn update_prev(&mut self, index: usize, prev: usize) -> Result<(), Box<dyn Error>> {
         let bytes = self.store.read(index)?;
-        let element: Element<T> = bincode::deserialize_from(bytes.as_slice())?;
+        let links: Links = self.header.encoding.deserialize(&bytes)?;
+        let body_start = self.header.encoding.serialize(&links)?.len();
+        let mut updated: Vec<u8> = self.header.encoding.serialize(&Links { prev })?;
+        updated.extend_from_slice(&bytes[body_start..]);
+        self.store.update(index, updated.as_slice())?;
+        // varint pointers may change the length of the record
+        let (before, after) = (bytes.len(), updated.len());
+        let (before, after) = (self.store.frames_for(before), self.store.frames_for(after));
+        let usage = self.usage_mut();
+        usage.remove(Usage::of_record(0, before));
+        usage.add(Usage::of_record(0, after));
+        Ok(())
+    }
+
+    /// put a serialized item on top of the stack and save the header
+    fn push_body(&mut self, body: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.insert_top(body)?;
+        self.save_header()
+    }
+
+    /// link a serialized item on top of the stack, without saving the header
+    fn insert_top(&mut self, body: &[u8]) -> Result<(), Box<dyn Error>> {
+        let links = Links {
+            prev: self.header.last_element,
+        };
+        let bytes = self.join_element(&links, body)?;
+        let added = self.record_usage(&bytes)?;
+        let index = self.store.create(bytes.as_slice())?;
+        self.usage_mut().add(added);
+        self.header.last_element = index;
+        self.header.elements_count += 1;
+        let depth = self.header.elements_count as u64;
+        let counters = self.counters_mut();
+        counters.pushes += 1;
+        counters.max_depth = counters.max_depth.max(depth);
+        Ok(())
+    }
+
+    /// store another body in the block of an element, given its stored
+    /// bytes, and save the header
+    fn replace_body(
+        &mut self,
+        index: usize,
+        record: &[u8],
+        body: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let links: Links = self.header.encoding.deserialize(record)?;
+        let bytes = self.join_element(&links, body)?;
+        let removed = self.record_usage(record)?;
+        let added = self.record_usage(&bytes)?;
+        self.store.update(index, &bytes)?;
+        self.usage_mut().remove(removed);
+        self.usage_mut().add(added);
+        self.save_header()
+    }
+
+    /// write the bodies of all elements to the writer from the bottom up,
+    /// deleting each one once written, without saving the header
+    fn drain_bottom_to(
+        &mut self,
+        writer: &mut impl Write,
+        framing: RecordFraming,
+        exported: &mut u64,
+    ) -> Result<(), Box<dyn Error>> {
+        // all elements from the top down
+        let mut chain = Vec::with_capacity(self.header.elements_count);
+        let mut index = self.header.last_element;
+        for _ in 0..self.header.elements_count {
+            chain.push(index);
+            let links: Links = self.header.encoding.deserialize(&self.store.read(index)?)?;
+            index = links.prev;
+        }
+        let mut result = Ok(());
+        while let Some(&bottom) = chain.last() {
+            result = self.export_bottom(bottom, writer, framing);
+            if result.is_err() {
+                break;
+            }
+            chain.pop();
+            *exported += 1;
+        }
+        match chain.last() {
+            None => self.header.last_element = 0,
+            Some(&bottom) if *exported > 0 => self.update_prev(bottom, 0)?,
+            Some(_) => {}
+        }
+        result
+    }
+
+    /// write the body of the bottom element and delete it, leaving the
+    /// element above it pointing to it
+    fn export_bottom(
+        &mut self,
+        index: usize,
+        writer: &mut impl Write,
+        framing: RecordFraming,
+    ) -> Result<(), Box<dyn Error>> {
+        let record = self.store.read(index)?;
+        let (_, _, body) = self.split_element(&record)?;
+        framing.write_record(writer, &body)?;
+        let removed = self.record_usage(&record)?;
         self.store.delete(index)?;
-        self.header.last_element = element.prev;
-        self.header.elements_count -= 1;
-        self.save_header()?;
-        Ok(Some(element.body))
+        self.usage_mut().remove(removed);
+        self.header.counter_drift |= count_down(&mut self.header.elements_count);
+        self.counters_mut().pops += 1;
+        Ok(())
+    }
+
+    /// push every record of the reader as a body, without saving the header
+    fn load_records(
+        &mut self,
+        reader: &mut impl BufRead,
+        framing: RecordFraming,
+        loaded: &mut u64,
+    ) -> Result<(), Box<dyn Error>> {
+        while let Some(body) = framing.read_record(reader)? {
+            self.insert_top(&body)?;
+            *loaded += 1;
+        }
+        Ok(())
+    }
+
+    /// delete the element on top, given the element below it and its
+    /// stored bytes, and save the header
+    fn remove_top(&mut self, prev: usize, record: &[u8]) -> Result<(), Box<dyn Error>> {
+        let removed = self.record_usage(record)?;
+        self.store.delete(self.header.last_element)?;
+        self.usage_mut().remove(removed);
+        self.header.last_element = prev;
+        self.header.counter_drift |= count_down(&mut self.header.elements_count);
+        self.counters_mut().pops += 1;
+        self.save_header()
+    }
+
+    /// delete the element at the bottom, given the element right above it
+    /// and its stored bytes, and save the header
+    fn remove_bottom(
+        &mut self,
+        index: usize,
+        above: usize,
+        record: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let removed = self.record_usage(record)?;
+        self.update_prev(above, 0)?;
+        self.store.delete(index)?;
+        self.usage_mut().remove(removed);
+        self.header.counter_drift |= count_down(&mut self.header.elements_count);
+        self.counters_mut().pops += 1;
+        self.save_header()
+    }
+
+    fn clear_and_shrink(&mut self) -> Result<(), Box<dyn Error>> {
+        let count = self.header.elements_count as u64;
+        self.header.last_element = 0;
+        self.header.elements_count = 0;
+        self.counters_mut().pops += count;
+        self.header.usage = Some(Usage::default());
+        let bytes: Vec<u8> = bincode::serialize(&self.header)?;
+        self.store.clear_and_shrink(&bytes)
+    }
+
+    /// join the link and a serialized body in the layout of this file, see
+    /// `join_element`
+    fn join_element(&self, links: &Links, body: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        join_element(
+            links,
+            body,
+            self.header.encoding,
+            self.header.compress_above,
+        )
+    }
+
+    /// the bytes an element with a body of the given length takes once it
+    /// gets pushed, see `element_bytes`
+    fn element_bytes(&self, body_len: usize) -> Result<usize, Box<dyn Error>> {
+        let links = Links {
+            prev: self.header.last_element,
+        };
+        element_bytes(
+            &self.store,
+            &links,
+            body_len,
+            self.header.encoding,
+            self.header.compress_above,
+        )
+    }
+
+    /// the link, compression flag and serialized body of a stored element
+    fn split_element<'a>(
+        &self,
+        bytes: &'a [u8],
+    ) -> Result<SplitElement<'a, Links>, Box<dyn Error>> {
+        split_element(bytes, self.header.encoding, self.header.compress_above)
+    }
+}
+
+impl Relocate for RawStack {
+    fn store_mut(&mut self) -> &mut BlockStorage {
+        &mut self.store
+    }
+
+    /// an element is referred to by the element above it, or by the header
+    /// if it is the top
+    fn relocate(&mut self, from: usize, to: usize) -> Result<bool, Box<dyn Error>> {
+        if self.header.last_element == from {
+            self.header.last_element = to;
+            self.save_header()?;
+            return Ok(true);
+        }
+        let mut current = self.header.last_element;
+        for _ in 0..self.header.elements_count {
+            match self.read_prev(current) {
+                Some(prev) if prev == from => {
+                    // varint pointers may change the length of the record
+                    let before = self.store.record_size(current)?;
+                    self.update_prev(current, to)?;
+                    let after = self.store.record_size(current)?;
+                    let (before, after) =
+                        (self.store.frames_for(before), self.store.frames_for(after));
+                    let usage = self.usage_mut();
+                    usage.remove(Usage::of_record(0, before));
+                    usage.add(Usage::of_record(0, after));
+                    self.save_header()?;
+                    return Ok(true);
+                }
+                Some(prev) => current = prev,
+                None => break,
+            }
+        }
+        Ok(false)
     }
 }
 
@@ -211,10 +1245,71 @@ where
     }
 }
 
+/// A non-destructive iterator over the items of a [`Stack`](crate::Stack)
+/// from the top, created by `iter`. Stops after the first error.
+pub struct StackIter<'a, T> {
+    stack: &'a Stack<T>,
+    // the element to visit next and the number of elements left
+    next: usize,
+    left: usize,
+}
+
+impl<T> Iterator for StackIter<'_, T>
+where
+    T: Serialize,
+    for<'de> T: Deserialize<'de>,
+{
+    type Item = Result<T, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 {
+            return None;
+        }
+        let element = self
+            .stack
+            .raw
+            .store
+            .read(self.next)
+            .and_then(|bytes| self.stack.decode_element("iter", self.next, &bytes));
+        match element {
+            Ok(element) => {
+                self.next = element.prev;
+                self.left -= 1;
+                Some(Ok(element.body))
+            }
+            Err(error) => {
+                self.left = 0;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct Header {
     last_element: usize,
     elements_count: usize,
+    // `None` in files written before counters existed
+    counters: Option<StackCounters>,
+    // `Fixed` in files written before the encoding was configurable
+    encoding: IntEncoding,
+    // `None` in files written before the usage was tracked
+    usage: Option<Usage>,
+    // `None` in files created without compression, whose elements carry no flag
+    compress_above: Option<usize>,
+    // set once a count would have dropped below zero, until a repair
+    counter_drift: bool,
+}
+
+/// Lifetime counters of a [`Stack`](crate::Stack), useful for capacity planning.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StackCounters {
+    /// the highest number of elements the stack ever held
+    pub max_depth: u64,
+    /// total number of pushed elements
+    pub pushes: u64,
+    /// total number of popped elements
+    pub pops: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -223,6 +1318,13 @@ struct Element<T> {
     body: T,
 }
 
+/// the pointer at the start of every `Element`, readable without knowing
+/// how to deserialize its body
+#[derive(Serialize, Deserialize, Debug)]
+struct Links {
+    prev: usize,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -260,4 +1362,269 @@ mod tests {
         let vec: Vec<i32> = stack.collect();
         assert_eq!(vec, vec![2, 1]);
     }
+
+    #[test]
+    fn iter() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut stack = Stack::<i32>::new(file).expect("could not create");
+        assert_eq!(stack.iter().count(), 0);
+        for i in 0..50 {
+            stack.push(i).expect("could not push");
+        }
+
+        // the header stays in memory, so only the elements get read
+        let reads = stack.stats().reads;
+        let items = stack.iter().collect::<Result<Vec<i32>, _>>().unwrap();
+        assert_eq!(items, (0..50).rev().collect::<Vec<_>>());
+        assert_eq!(stack.stats().reads, reads + 50);
+        assert_eq!(stack.len(), 50);
+    }
+
+    #[test]
+    fn counters() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut stack = Stack::<i32>::new(file.try_clone().unwrap()).expect("could not create");
+        for i in 0..5 {
+            stack.push(i).expect("could not push");
+        }
+        stack.pop().expect("could not pop");
+        stack.pop().expect("could not pop");
+        stack.push(5).expect("could not push");
+        let expected = StackCounters {
+            max_depth: 5,
+            pushes: 6,
+            pops: 2,
+        };
+        assert_eq!(stack.counters(), expected);
+
+        // works after reopen
+        let mut stack = Stack::<i32>::new(file).expect("could not open");
+        assert_eq!(stack.counters(), expected);
+
+        // new measurement window
+        stack.reset_counters().expect("could not reset");
+        stack.pop().expect("could not pop");
+        let expected = StackCounters {
+            max_depth: 4,
+            pushes: 0,
+            pops: 1,
+        };
+        assert_eq!(stack.counters(), expected);
+    }
+
+    #[test]
+    fn counters_of_old_files() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut store = BlockStorage::with_options(file.try_clone().unwrap(), &Options::default())
+            .expect("could not create");
+        store.create(&[0; 16]).unwrap();
+        let element = bincode::serialize(&Element { prev: 0, body: 7 }).unwrap();
+        let index = store.create(&element).unwrap();
+        let old_header = bincode::serialize(&(index, 1_usize)).unwrap();
+        store.update(0, &old_header).unwrap();
+        drop(store);
+
+        let stack = Stack::<i32>::new(file).expect("could not open");
+        let expected = StackCounters {
+            max_depth: 1,
+            pushes: 1,
+            pops: 0,
+        };
+        assert_eq!(stack.counters(), expected);
+    }
+
+    fn stack_of(items: &[i32]) -> (File, Stack<i32>) {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut stack = Stack::<i32>::new(file.try_clone().unwrap()).expect("could not create");
+        for item in items {
+            stack.push(*item).expect("could not push");
+        }
+        (file, stack)
+    }
+
+    #[test]
+    fn check_counts() {
+        let (_, mut stack) = stack_of(&[]);
+        assert!(stack.check_counts(true).unwrap().is_consistent());
+        let (_, mut stack) = stack_of(&[1, 2, 3]);
+        stack.pop().unwrap();
+        stack.push(3).unwrap();
+        let check = stack.check_counts(true).unwrap();
+        assert!(check.is_consistent());
+        assert!(!check.repaired);
+
+        // a wrong count alone
+        stack.raw.header.elements_count = 1;
+        stack.raw.save_header().unwrap();
+        let check = stack.check_counts(false).unwrap();
+        assert_eq!((check.stored_count, check.reachable_count), (1, 3));
+        assert!(!check.broken_links && !check.repaired);
+        assert_eq!(stack.len(), 1);
+        assert!(stack.check_counts(true).unwrap().repaired);
+        assert_eq!(stack.len(), 3);
+        assert_eq!(stack.collect::<Vec<_>>(), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn repair_deleted_top() {
+        // a push saved the header, but the element got lost
+        let (_, mut stack) = stack_of(&[1, 2]);
+        stack
+            .raw
+            .store
+            .delete(stack.raw.header.last_element)
+            .unwrap();
+        let check = stack.check_counts(true).unwrap();
+        assert_eq!((check.stored_count, check.reachable_count), (2, 0));
+        assert!(check.broken_links && check.repaired);
+        assert!(stack.is_empty());
+        assert_eq!(stack.pop().unwrap(), None);
+        stack.push(3).unwrap();
+        assert_eq!(stack.collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn repair_deleted_element() {
+        // the chain got cut below the top two elements
+        let (_, mut stack) = stack_of(&[1, 2, 3, 4]);
+        let top = stack.raw.header.last_element;
+        let below = stack.raw.read_prev(top).unwrap();
+        let deleted = stack.raw.read_prev(below).unwrap();
+        stack.raw.store.delete(deleted).unwrap();
+        let check = stack.check_counts(true).unwrap();
+        assert_eq!((check.stored_count, check.reachable_count), (4, 2));
+        assert!(check.broken_links && check.repaired);
+        assert!(stack.check_counts(false).unwrap().is_consistent());
+        assert_eq!(stack.collect::<Vec<_>>(), vec![4, 3]);
+    }
+
+    #[test]
+    fn gc() {
+        let (file, mut stack) = stack_of(&[1, 2, 3]);
+
+        // the element of a push that failed to save the header
+        stack.raw.store.fail_after(1);
+        stack.push(4).expect_err("should fail");
+        drop(stack);
+        let mut stack = Stack::<i32>::new(file).unwrap();
+        let live_frames = stack.raw.store.live_frames();
+        assert_eq!(stack.gc().unwrap().blocks, 1);
+        assert_eq!(stack.raw.store.live_frames(), live_frames - 1);
+        assert_eq!(stack.gc().unwrap(), GcReport::default());
+        assert_eq!(stack.collect::<Vec<_>>(), vec![3, 2, 1]);
+
+        // a broken chain is left alone
+        let (_, mut stack) = stack_of(&[1, 2, 3]);
+        stack
+            .raw
+            .store
+            .delete(stack.raw.header.last_element)
+            .unwrap();
+        let error = stack.gc().expect_err("should fail");
+        assert_eq!(error.downcast_ref(), Some(&WiredError::Inconsistent));
+    }
+
+    #[test]
+    fn repair_on_open() {
+        let (file, mut stack) = stack_of(&[1, 2, 3]);
+        stack.raw.header.elements_count = 7;
+        stack.raw.save_header().unwrap();
+        drop(stack);
+
+        // skipped by default
+        let stack = Stack::<i32>::new(file.try_clone().unwrap()).expect("could not open");
+        assert_eq!(stack.len(), 7);
+        drop(stack);
+
+        let options = Options::new().repair_on_open(true);
+        let stack = Stack::<i32>::with_options(file, options).expect("could not open");
+        assert_eq!(stack.len(), 3);
+        assert_eq!(stack.collect::<Vec<_>>(), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn usage() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut stack = Stack::<String>::new(file.try_clone().unwrap()).expect("could not create");
+
+        // every element holds a pointer in front of its body
+        let expected = |items: &[String]| {
+            let sizes = items
+                .iter()
+                .map(|item| bincode::serialized_size(item).unwrap() as usize);
+            let payload: usize = sizes.clone().sum();
+            let frames: usize = sizes.map(|size| (size + 8).div_ceil(992)).sum();
+            (payload, frames * 1024 - payload)
+        };
+        let mut items: Vec<String> = (0..5).map(|i| "x".repeat(i * 900)).collect();
+        for item in items.iter() {
+            stack.push(item.clone()).unwrap();
+        }
+        stack.pop().unwrap();
+        items.pop();
+        assert_eq!(
+            (stack.payload_bytes(), stack.overhead_bytes()),
+            expected(&items)
+        );
+        assert_eq!(
+            stack.logical_size().unwrap(),
+            stack.payload_bytes() + 8 * items.len()
+        );
+
+        // persisted, and measured once for files that predate it
+        stack.raw.header.usage = None;
+        stack.raw.save_header().unwrap();
+        drop(stack);
+        let stack = Stack::<String>::new(file).expect("could not open");
+        assert_eq!(
+            (stack.payload_bytes(), stack.overhead_bytes()),
+            expected(&items)
+        );
+    }
+
+    #[test]
+    fn migrator() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut old_stack = Stack::<u32>::new(file.try_clone().unwrap()).expect("could not create");
+        for id in 1..=3 {
+            old_stack.push(id).expect("could not push");
+        }
+        drop(old_stack);
+
+        let item = |id, label: &str| (id, String::from(label));
+        let migrator: Migrator<(u32, String)> =
+            |bytes| Ok((bincode::deserialize(bytes)?, String::from("old")));
+        let mut stack =
+            Stack::<(u32, String)>::new(file.try_clone().unwrap()).expect("could not open");
+        assert!(stack.pop().is_err());
+        let mut stack = stack.with_migrator(migrator);
+        stack.push(item(4, "new")).unwrap();
+        assert_eq!(stack.pop().unwrap(), Some(item(4, "new")));
+        assert_eq!(stack.pop().unwrap(), Some(item(3, "old")));
+
+        // rewritten items decode without any migrator
+        assert_eq!(stack.rewrite_all().unwrap(), 2);
+        assert_eq!(stack.rewrite_all().unwrap(), 0);
+        drop(stack);
+        let stack = Stack::<(u32, String)>::new(file).expect("could not open");
+        assert_eq!(stack.collect::<Vec<_>>(), [item(2, "old"), item(1, "old")]);
+    }
+
+    #[test]
+    fn compression() {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let options = Options::new().compress_above(64);
+        let mut stack = Stack::<Vec<u8>>::with_options(file.try_clone().unwrap(), options).unwrap();
+        stack.push(vec![0; 4096]).unwrap();
+        stack.push(vec![1, 2, 3]).unwrap();
+        let bytes = stack.raw.store.read(stack.raw.header.last_element).unwrap();
+        assert!(!stack.raw.split_element(&bytes).unwrap().1);
+        assert!(stack.payload_bytes() < 4096);
+        drop(stack);
+
+        let mut stack = Stack::<Vec<u8>>::new(file).unwrap();
+        assert_eq!(stack.pop().unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(stack.pop().unwrap(), Some(vec![0; 4096]));
+        assert_eq!(stack.pop().unwrap(), None);
+    }
 }
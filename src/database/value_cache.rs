@@ -0,0 +1,99 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// A bounded cache of decoded values by the index of their block, which
+/// evicts the least recently used value once it holds `capacity` values.
+///
+/// Values get cloned through a function given on creation, so the types
+/// holding a cache need no `Clone` bound beyond the constructor enabling it.
+pub(crate) struct ValueCache<V> {
+    capacity: usize,
+    // the value of every cached block, with the tick of its last use
+    entries: HashMap<usize, (V, u64)>,
+    // the cached blocks by the tick of their last use, oldest first
+    recent: BTreeMap<u64, usize>,
+    tick: u64,
+    clone: fn(&V) -> V,
+}
+
+impl<V> ValueCache<V> {
+    pub(crate) fn new(capacity: usize, clone: fn(&V) -> V) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            recent: BTreeMap::new(),
+            tick: 0,
+            clone,
+        }
+    }
+
+    /// a copy of the cached value of the block, marking it as used
+    pub(crate) fn get(&mut self, index: usize) -> Option<V> {
+        let tick = self.next_tick();
+        let (value, used) = self.entries.get_mut(&index)?;
+        self.recent.remove(used);
+        self.recent.insert(tick, index);
+        *used = tick;
+        Some((self.clone)(value))
+    }
+
+    /// cache a copy of the value of the block, evicting the least recently
+    /// used value when full
+    pub(crate) fn insert(&mut self, index: usize, value: &V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.invalidate(index);
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.recent.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        let tick = self.next_tick();
+        self.entries.insert(index, ((self.clone)(value), tick));
+        self.recent.insert(tick, index);
+    }
+
+    /// forget the value of the block, once it got changed or deleted
+    pub(crate) fn invalidate(&mut self, index: usize) {
+        if let Some((_, used)) = self.entries.remove(&index) {
+            self.recent.remove(&used);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.recent.clear();
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ValueCache::new(2, String::clone);
+        cache.insert(1, &String::from("one"));
+        cache.insert(2, &String::from("two"));
+        // using 1 makes 2 the oldest
+        assert_eq!(cache.get(1), Some(String::from("one")));
+        cache.insert(3, &String::from("three"));
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(1), Some(String::from("one")));
+        assert_eq!(cache.get(3), Some(String::from("three")));
+
+        cache.invalidate(1);
+        assert_eq!(cache.get(1), None);
+        cache.clear();
+        assert_eq!(cache.get(3), None);
+
+        let mut disabled = ValueCache::new(0, String::clone);
+        disabled.insert(1, &String::from("one"));
+        assert_eq!(disabled.get(1), None);
+    }
+}
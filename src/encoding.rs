@@ -0,0 +1,102 @@
+use bincode::Options as _;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::Read;
+
+/// How integers within stored items are encoded.
+///
+/// The choice is made once when a database file is created and recorded in
+/// it, so reopening always uses the matching decoder.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IntEncoding {
+    /// every integer takes its full width, so records of the same type have
+    /// the same size and can be updated in place
+    #[default]
+    Fixed,
+    /// small numbers take fewer bytes, which makes files smaller
+    Varint,
+}
+
+impl IntEncoding {
+    pub(crate) fn serialize<T: Serialize + ?Sized>(
+        self,
+        value: &T,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let options = bincode::DefaultOptions::new().allow_trailing_bytes();
+        let bytes = match self {
+            IntEncoding::Fixed => options.with_fixint_encoding().serialize(value)?,
+            IntEncoding::Varint => options.with_varint_encoding().serialize(value)?,
+        };
+        Ok(bytes)
+    }
+
+    /// the length of `serialize`, without allocating the bytes
+    pub(crate) fn serialized_size<T: Serialize + ?Sized>(
+        self,
+        value: &T,
+    ) -> Result<usize, Box<dyn Error>> {
+        let options = bincode::DefaultOptions::new().allow_trailing_bytes();
+        let size = match self {
+            IntEncoding::Fixed => options.with_fixint_encoding().serialized_size(value)?,
+            IntEncoding::Varint => options.with_varint_encoding().serialized_size(value)?,
+        };
+        Ok(size as usize)
+    }
+
+    /// decode an item, which may borrow from the bytes, like a `&str`. A
+    /// length prefix claiming more than the bytes hold fails instead of
+    /// allocating what it claims.
+    pub(crate) fn deserialize<'a, T: Deserialize<'a>>(
+        self,
+        bytes: &'a [u8],
+    ) -> Result<T, Box<dyn Error>> {
+        let options = bincode::DefaultOptions::new()
+            .allow_trailing_bytes()
+            .with_limit(bytes.len() as u64);
+        let value = match self {
+            IntEncoding::Fixed => options.with_fixint_encoding().deserialize(bytes)?,
+            IntEncoding::Varint => options.with_varint_encoding().deserialize(bytes)?,
+        };
+        Ok(value)
+    }
+}
+
+/// decode in the format of `bincode::deserialize`, reading at most `limit`
+/// bytes, so a corrupted length prefix fails with a size limit error instead
+/// of allocating whatever it claims
+pub(crate) fn deserialize_bounded<T: DeserializeOwned>(
+    reader: impl Read,
+    limit: usize,
+) -> Result<T, Box<dyn Error>> {
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit as u64);
+    Ok(options.deserialize_from(reader)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_matches_plain_bincode() {
+        let value = (1_u64, -2_i32, String::from("three"), vec![4_u16; 5]);
+        let fixed = IntEncoding::Fixed.serialize(&value).unwrap();
+        assert_eq!(fixed, bincode::serialize(&value).unwrap());
+        let varint = IntEncoding::Varint.serialize(&value).unwrap();
+        assert!(varint.len() < fixed.len());
+        assert_eq!(
+            IntEncoding::Fixed.serialized_size(&value).unwrap(),
+            fixed.len()
+        );
+        assert_eq!(
+            IntEncoding::Varint.serialized_size(&value).unwrap(),
+            varint.len()
+        );
+        let decoded: (u64, i32, String, Vec<u16>) =
+            IntEncoding::Varint.deserialize(&varint).unwrap();
+        assert_eq!(decoded, value);
+    }
+}
@@ -0,0 +1,148 @@
+use std::error::Error;
+use std::fmt;
+
+/// Errors raised by wired itself, as opposed to errors bubbling up from the
+/// filesystem or from serialization.
+///
+/// All operations return a `Box<dyn Error>`, so use `downcast_ref` to check
+/// for a specific case:
+///
+/// ```rust,no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let file = tempfile::tempfile()?;
+/// match wired::Queue::<String>::new(file) {
+///     Err(error) => match error.downcast_ref::<wired::WiredError>() {
+///         Some(wired::WiredError::Truncated { .. }) => println!("file got truncated"),
+///         _ => println!("some other error: {}", error),
+///     },
+///     Ok(_queue) => println!("all good"),
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WiredError {
+    /// the file is shorter than its header claims, e.g. after an external truncation
+    Truncated { expected: usize, actual: usize },
+    /// the file was written with a format version this crate can not open
+    UnsupportedVersion { version: usize, supported: usize },
+    /// the file uses on-disk features this crate does not know, but which
+    /// are required to read it correctly
+    UnsupportedFeatures { flags: u32 },
+    /// an in-place write does not fit into the existing record
+    OutOfBounds,
+    /// a long-running operation was stopped by its `ProgressSink`
+    Cancelled,
+    /// the filesystem has no space left to grow the file, nothing was written
+    DiskFull,
+    /// the file refers to a position beyond the address space of this
+    /// target, e.g. a file larger than 4GB opened by a 32 bit build
+    PositionOverflow { position: u64 },
+    /// a new key would grow the in-memory key index of a `KeyValue` beyond
+    /// the configured `Options::max_index_bytes`
+    IndexFull { limit: usize },
+    /// the file is already open as another kind of container within this
+    /// process, which would overwrite its header
+    AlreadyOpen { as_kind: &'static str },
+    /// an item was removed from the middle of a `Queue` after a `Position`
+    /// was taken, so it is unknown where to continue
+    PositionInvalidated,
+    /// a metadata blob exceeds the limit of what a file stores along with
+    /// its container
+    MetaTooLarge { len: usize, limit: usize },
+    /// the element in the block at the index does not decode, found by a
+    /// scan like `Queue::open_checked`
+    Corrupted { index: usize },
+    /// a bulk load needs a freshly created file, but this one already holds
+    /// data
+    NotEmpty,
+    /// the header of a container disagrees with the records it refers to,
+    /// e.g. after an unclean shutdown, so repair it before a `gc`
+    Inconsistent,
+    /// a panic unwound through a modification of this handle, e.g. in a
+    /// closure or a `Hash` implementation, so its state in memory may
+    /// disagree with the file until `recover_in_memory_state`
+    Poisoned,
+    /// the record in the block at the index, which starts at the byte
+    /// position of the file, does not deserialize during the operation
+    DeserializeFailed {
+        operation: &'static str,
+        index: usize,
+        position: usize,
+        reason: String,
+    },
+    /// a record to write with `RecordFraming::NewlineDelimited` contains a
+    /// newline byte, so it could not be told apart from two records
+    NewlineInRecord,
+}
+
+impl fmt::Display for WiredError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WiredError::Truncated { expected, actual } => write!(
+                f,
+                "file is truncated: expected at least {} bytes, found {}",
+                expected, actual
+            ),
+            WiredError::UnsupportedVersion { version, supported } => write!(
+                f,
+                "unsupported format version {}, this crate supports up to version {}",
+                version, supported
+            ),
+            WiredError::UnsupportedFeatures { flags } => {
+                write!(f, "unsupported required format features {:#x}", flags)
+            }
+            WiredError::OutOfBounds => write!(f, "write exceeds the bounds of the record"),
+            WiredError::Cancelled => write!(f, "operation was cancelled"),
+            WiredError::DiskFull => write!(f, "no space left to grow the file"),
+            WiredError::PositionOverflow { position } => write!(
+                f,
+                "file position {} exceeds the address space of this target",
+                position
+            ),
+            WiredError::IndexFull { limit } => {
+                write!(f, "the key index reached its limit of {} bytes", limit)
+            }
+            WiredError::AlreadyOpen { as_kind } => {
+                write!(f, "the file is already open as a {}", as_kind)
+            }
+            WiredError::PositionInvalidated => {
+                write!(f, "the position was invalidated by a removal")
+            }
+            WiredError::MetaTooLarge { len, limit } => write!(
+                f,
+                "metadata of {} bytes exceeds the limit of {} bytes",
+                len, limit
+            ),
+            WiredError::Corrupted { index } => {
+                write!(f, "the element in block {} is corrupted", index)
+            }
+            WiredError::NotEmpty => write!(f, "the file is not empty"),
+            WiredError::Inconsistent => {
+                write!(f, "the container is inconsistent and needs a repair")
+            }
+            WiredError::Poisoned => {
+                write!(f, "a panic interrupted a modification of this handle")
+            }
+            WiredError::DeserializeFailed {
+                operation,
+                index,
+                position,
+                reason,
+            } => write!(
+                f,
+                "{} failed to deserialize the record in block {} at byte {}: {}",
+                operation, index, position, reason
+            ),
+            WiredError::NewlineInRecord => {
+                write!(
+                    f,
+                    "the record contains a newline and can not be newline delimited"
+                )
+            }
+        }
+    }
+}
+
+impl Error for WiredError {}
@@ -0,0 +1,94 @@
+//! On-disk format versions.
+//!
+//! Every database file records the version of the layout it was written
+//! with. A file written by an older version of this crate must always open
+//! with a newer one, which is enforced by keeping golden images of every
+//! format version in the test suite forever. Older files are upgraded by a
+//! chain of migrations when they are opened.
+
+/// version of the on-disk layout written by this crate
+pub const CURRENT_VERSION: usize = 3;
+
+/// every format version that can be opened, together with the first crate
+/// release that wrote it
+pub const COMPATIBILITY: &[(usize, &str)] = &[(1, "0.1.0"), (2, "0.6.0"), (3, "0.6.0")];
+
+/// flags in the lower half mark features a reader has to understand to open
+/// a file at all, flags in the upper half ones it may safely ignore. Only
+/// the position of a flag tells an older reader which kind it is.
+const REQUIRED_MASK: u32 = 0x0000_ffff;
+
+/// a part of the on-disk layout that a file may or may not use within one
+/// format version, recorded as a flag in the storage header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Feature {
+    flag: u32,
+}
+
+impl Feature {
+    /// a feature that changes how existing data is read, like checksums
+    pub const fn required(bit: u32) -> Self {
+        assert!(bit < 16);
+        Self { flag: 1 << bit }
+    }
+
+    /// a feature that older readers can ignore without misreading data
+    pub const fn optional(bit: u32) -> Self {
+        assert!(bit < 16);
+        Self {
+            flag: 1 << (bit + 16),
+        }
+    }
+
+    pub fn flag(&self) -> u32 {
+        self.flag
+    }
+
+    pub fn is_required(&self) -> bool {
+        self.flag & REQUIRED_MASK != 0
+    }
+}
+
+/// frames of several sizes within one file, see `Options::size_classes`
+pub const SIZE_CLASSES: Feature = Feature::required(0);
+
+/// every feature this crate knows. A new feature picks the next free bit of
+/// its kind and registers here, bits must never be reused.
+pub const FEATURES: &[Feature] = &[SIZE_CLASSES];
+
+/// the flags of every known feature
+pub fn known_flags() -> u32 {
+    FEATURES
+        .iter()
+        .fold(0, |flags, feature| flags | feature.flag())
+}
+
+/// the required flags among `flags` that are missing from `known`, a file
+/// with any of them can not be opened
+pub fn unsupported_flags(flags: u32, known: u32) -> u32 {
+    flags & REQUIRED_MASK & !known
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feature_flags() {
+        let checksums = Feature::required(0);
+        let hints = Feature::optional(0);
+        assert!(checksums.is_required());
+        assert!(!hints.is_required());
+        assert_ne!(checksums.flag(), hints.flag());
+
+        // unknown optional flags never matter, unknown required ones do
+        let unknown_required = Feature::required(15).flag();
+        let unknown_optional = Feature::optional(15).flag();
+        let known = checksums.flag() | hints.flag();
+        let flags = known | unknown_optional;
+        assert_eq!(unsupported_flags(flags, known), 0);
+        let flags = flags | unknown_required;
+        assert_eq!(unsupported_flags(flags, known), unknown_required);
+        assert_eq!(unsupported_flags(checksums.flag(), 0), checksums.flag());
+    }
+}
@@ -1,9 +1,32 @@
 mod block_storage;
+mod clock;
 mod database;
+mod encoding;
+mod error;
+pub mod format;
+mod options;
+mod progress;
 
-pub use database::key_value::KeyValue;
-pub use database::queue::Queue;
-pub use database::stack::Stack;
+pub use block_storage::{replay, ReplayReport, Stats};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use database::btree_key_value::{BTreeKeyValue, OrderedIter};
+pub use database::counters::Counters;
+pub use database::key_value::{
+    ExportReport, GroupSnapshot, KeySummary, KeyValue, KeyValueCounters,
+};
+pub use database::queue::{
+    BorrowedScan, Level, Position, Queue, QueueCounters, QueueIter, ResumableIter,
+};
+pub use database::slot_store::SlotStore;
+pub use database::stack::{Stack, StackCounters, StackIter};
+pub use database::{
+    Borrowed, ChainCheck, CompactBudget, CompactProgress, GcReport, Migrator, OpenReport,
+    RecordFraming,
+};
+pub use encoding::IntEncoding;
+pub use error::WiredError;
+pub use options::Options;
+pub use progress::ProgressSink;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,206 @@
+use crate::clock::{Clock, ManualClock, SystemClock};
+use crate::encoding::IntEncoding;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Tuning knobs for creating a new database file.
+///
+/// Most options only influence how a *new* (empty) file gets initialized.
+/// When an existing database is opened, they are silently ignored so that a
+/// file always keeps the layout it was created with.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let file = tempfile::tempfile()?;
+/// // reserve 64MB upfront and put every frame onto the free list
+/// let options = wired::Options::new().initial_bytes(64 << 20).preallocate(true);
+/// let queue = wired::Queue::<String>::with_options(file, options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct Options {
+    pub(crate) initial_bytes: Option<usize>,
+    pub(crate) preallocate: bool,
+    pub(crate) inline_values: usize,
+    pub(crate) max_index_bytes: Option<usize>,
+    pub(crate) disk_index: Option<usize>,
+    pub(crate) repair_on_open: bool,
+    pub(crate) compress_above: Option<usize>,
+    pub(crate) int_encoding: IntEncoding,
+    pub(crate) migration_progress: Option<Arc<ProgressCallback>>,
+    pub(crate) clock: Option<Arc<dyn Clock>>,
+    pub(crate) record_ops: Option<PathBuf>,
+    pub(crate) deterministic: bool,
+    pub(crate) size_classes: bool,
+    // clamp the frames of a truncated file instead of failing to open it,
+    // set by the `open_truncated_tolerant` constructors only
+    pub(crate) truncated_tolerant: bool,
+}
+
+/// receives the number of frames migrated so far and the total number of frames
+pub(crate) type ProgressCallback = dyn Fn(usize, usize) + Send + Sync;
+
+impl Options {
+    /// default options, equivalent to what `new` on the databases uses
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// set the length of a newly created file, so it gets mapped only once
+    /// instead of doubling repeatedly during an initial bulk load
+    pub fn initial_bytes(mut self, bytes: usize) -> Self {
+        self.initial_bytes = Some(bytes);
+        self
+    }
+
+    /// register every frame that fits into the initial file size onto the
+    /// free list, so the allocator never has to grow the file until all of
+    /// them are used up
+    pub fn preallocate(mut self, preallocate: bool) -> Self {
+        self.preallocate = preallocate;
+        self
+    }
+
+    /// `KeyValue` only: keep all entries directly within the header record
+    /// while their total serialized size stays below `max_bytes`. Larger
+    /// maps spill into the regular block layout. Disabled by default.
+    pub fn inline_values(mut self, max_bytes: usize) -> Self {
+        self.inline_values = max_bytes;
+        self
+    }
+
+    /// `KeyValue` only: refuse to insert new keys with
+    /// `WiredError::IndexFull` once the in-memory lookup of keys would grow
+    /// beyond an estimated `max_bytes`. Overwrites and removals keep working.
+    /// See `KeyValue::index_memory_bytes` for how the size is estimated.
+    /// Unlimited by default, and applies to existing files as well.
+    pub fn max_index_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_index_bytes = Some(max_bytes);
+        self
+    }
+
+    /// `KeyValue` only: keep the mapping of keys to their blocks in an
+    /// on-disk hash table with the given number of buckets instead of an
+    /// in-memory lookup, so memory stays bounded no matter how many keys
+    /// are stored. The trade-off is latency: every `get`, `set` and
+    /// `remove` reads a page of buckets, the bucket and the key block of
+    /// every key with a colliding hash from the file. Pick roughly as many
+    /// buckets as keys are expected, to keep the buckets short.
+    ///
+    /// The layout is fixed when the file gets created, so this is ignored
+    /// for existing files.
+    pub fn disk_index(mut self, buckets: usize) -> Self {
+        self.disk_index = Some(buckets);
+        self
+    }
+
+    /// `Queue` and `Stack` only: walk the chain of elements while opening
+    /// and repair the header if it disagrees, e.g. after an unclean
+    /// shutdown. This reads every element once, so it is disabled by
+    /// default. See `check_counts` on the databases to run it on demand.
+    pub fn repair_on_open(mut self, repair: bool) -> Self {
+        self.repair_on_open = repair;
+        self
+    }
+
+    /// `Queue` and `Stack` only: compress every item whose serialized size
+    /// exceeds `bytes`, as long as that makes it smaller. Small items stay
+    /// as they are, so they do not pay for the compression overhead. Every
+    /// item records whether it got compressed, which costs one byte each.
+    ///
+    /// The layout is fixed when the file gets created, so this is ignored
+    /// for existing files.
+    pub fn compress_above(mut self, bytes: usize) -> Self {
+        self.compress_above = Some(bytes);
+        self
+    }
+
+    /// how integers within stored items get encoded, `IntEncoding::Fixed` by
+    /// default. `Counters` always use the fixed encoding, since they update
+    /// their values in place.
+    pub fn int_encoding(mut self, encoding: IntEncoding) -> Self {
+        self.int_encoding = encoding;
+        self
+    }
+
+    /// get notified about the progress while an older file gets upgraded to
+    /// the current format version during opening
+    pub fn migration_progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(usize, usize) + Send + Sync + 'static,
+    {
+        self.migration_progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// the source of the current time, `SystemClock` by default. Tests set a
+    /// `ManualClock` to make anything that depends on time deterministic.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// append every write to the file to a log at the given path, which
+    /// `wired::replay` re-executes against a fresh file to reproduce the
+    /// workload. Each write costs one more append to the log, which keeps
+    /// growing until it gets removed.
+    pub fn record_ops<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.record_ops = Some(path.into());
+        self
+    }
+
+    /// make the same sequence of operations produce a byte-identical
+    /// file, e.g. for fixtures checked in with tests. Without a `clock`,
+    /// the timestamps of the file stay unset, and `KeyValue` persists its
+    /// lookup in the order of the value blocks instead of the order of its
+    /// hasher.
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// store records in frames of 256 bytes, 4KB and 64KB instead of frames
+    /// of 1KB only, so tiny records waste less space and huge ones need
+    /// fewer frames. Only applies when the file gets created, an existing
+    /// file keeps its layout, and versions without size classes refuse to
+    /// open such a file.
+    pub fn size_classes(mut self, size_classes: bool) -> Self {
+        self.size_classes = size_classes;
+        self
+    }
+
+    /// the configured clock, or the one of the operating system, which
+    /// stands still at zero in deterministic mode
+    pub(crate) fn clock_or_default(&self) -> Arc<dyn Clock> {
+        match &self.clock {
+            Some(clock) => clock.clone(),
+            None if self.deterministic => Arc::new(ManualClock::default()),
+            None => Arc::new(SystemClock),
+        }
+    }
+}
+
+impl fmt::Debug for Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Options")
+            .field("initial_bytes", &self.initial_bytes)
+            .field("preallocate", &self.preallocate)
+            .field("inline_values", &self.inline_values)
+            .field("max_index_bytes", &self.max_index_bytes)
+            .field("disk_index", &self.disk_index)
+            .field("repair_on_open", &self.repair_on_open)
+            .field("compress_above", &self.compress_above)
+            .field("int_encoding", &self.int_encoding)
+            .field("migration_progress", &self.migration_progress.is_some())
+            .field("clock", &self.clock.is_some())
+            .field("record_ops", &self.record_ops)
+            .field("deterministic", &self.deterministic)
+            .field("size_classes", &self.size_classes)
+            .field("truncated_tolerant", &self.truncated_tolerant)
+            .finish()
+    }
+}
@@ -0,0 +1,64 @@
+use crate::error::WiredError;
+use std::error::Error;
+use std::ops::ControlFlow;
+
+/// Receives progress reports from long-running operations and decides
+/// whether they should go on.
+///
+/// Operations call `report` at safe points, where stopping leaves the
+/// database valid. Returning `ControlFlow::Break` aborts the operation with
+/// [`WiredError::Cancelled`](crate::WiredError::Cancelled). Any closure with
+/// the same signature is a sink already.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::ops::ControlFlow;
+/// use std::time::{Duration, Instant};
+///
+/// # let file = tempfile::tempfile()?;
+/// let mut kv = wired::KeyValue::<String, String>::new(file)?;
+/// let deadline = Instant::now() + Duration::from_secs(5);
+/// let mut sink = |done: u64, total: Option<u64>| {
+///     println!("{} of {:?}", done, total);
+///     if Instant::now() < deadline {
+///         ControlFlow::Continue(())
+///     } else {
+///         ControlFlow::Break(())
+///     }
+/// };
+/// kv.rebuild_index(&mut sink)?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait ProgressSink {
+    /// `done` units of work are finished, out of `total` if that is known
+    fn report(&mut self, done: u64, total: Option<u64>) -> ControlFlow<()>;
+}
+
+impl<F> ProgressSink for F
+where
+    F: FnMut(u64, Option<u64>) -> ControlFlow<()>,
+{
+    fn report(&mut self, done: u64, total: Option<u64>) -> ControlFlow<()> {
+        self(done, total)
+    }
+}
+
+/// a sink that only listens, for callers without interest in progress
+pub(crate) fn ignore(_done: u64, _total: Option<u64>) -> ControlFlow<()> {
+    ControlFlow::Continue(())
+}
+
+/// report to the sink and turn a requested cancellation into an error
+pub(crate) fn checkpoint(
+    sink: &mut dyn ProgressSink,
+    done: usize,
+    total: usize,
+) -> Result<(), Box<dyn Error>> {
+    match sink.report(done as u64, Some(total as u64)) {
+        ControlFlow::Continue(()) => Ok(()),
+        ControlFlow::Break(()) => Err(WiredError::Cancelled.into()),
+    }
+}
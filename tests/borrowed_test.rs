@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use wired::{KeyValue, Queue};
+
+/// counts the allocations of the current thread, so tests running in
+/// parallel do not disturb each other
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// the number of allocations while running the operation
+fn allocations<R>(operation: impl FnOnce() -> R) -> (usize, R) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = operation();
+    (ALLOCATIONS.with(Cell::get) - before, result)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct User {
+    id: u64,
+    name: String,
+    email: String,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+struct UserView<'a> {
+    id: u64,
+    name: &'a str,
+    email: &'a str,
+}
+
+fn user(id: u64) -> User {
+    User {
+        id,
+        name: format!("user {}", id),
+        email: format!("user{}@example.com", id),
+    }
+}
+
+#[test]
+fn get_borrowed() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut kv = KeyValue::<u64, User>::new(file).unwrap();
+    kv.set(1, user(1)).unwrap();
+
+    let (count, view) = allocations(|| {
+        let guard = kv.get_borrowed(&1).unwrap().unwrap();
+        let view: UserView = guard.value().unwrap();
+        assert_eq!(view.name, "user 1");
+        view.id
+    });
+    assert_eq!(view, 1);
+    assert_eq!(count, 0);
+
+    // the owned read allocates the buffer and both strings
+    let (count, owned) = allocations(|| kv.get(&1).unwrap().unwrap());
+    assert_eq!(owned, user(1));
+    assert!(count >= 3);
+
+    // a value across several frames gets a single buffer
+    let mut large = user(2);
+    large.name = "x".repeat(5000);
+    kv.set(2, large.clone()).unwrap();
+    let guard = kv.get_borrowed(&2).unwrap().unwrap();
+    let (count, view) = allocations(|| guard.value::<UserView>().unwrap());
+    assert_eq!(count, 0);
+    assert_eq!(view.name, large.name);
+    assert!(!guard.is_zero_copy());
+}
+
+#[test]
+fn scan_borrowed() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut queue = Queue::<User>::new(file).unwrap();
+    for id in 0..100 {
+        queue.enqueue(user(id)).unwrap();
+    }
+
+    let mut scan = queue.scan_borrowed().unwrap();
+    let (count, ids) = allocations(|| {
+        let mut ids = 0;
+        for (id, guard) in scan.by_ref().enumerate() {
+            let guard = guard.unwrap();
+            let view: UserView = guard.value().unwrap();
+            assert_eq!(view.id, id as u64);
+            assert!(view.email.starts_with("user"));
+            ids += 1;
+        }
+        ids
+    });
+    drop(scan);
+    assert_eq!(ids, 100);
+    assert_eq!(count, 0);
+
+    // the owned snapshot allocates at least both strings per item
+    let (count, items) = allocations(|| queue.snapshot_items().unwrap());
+    assert_eq!(items.len(), 100);
+    assert!(count >= 200);
+}
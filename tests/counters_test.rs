@@ -0,0 +1,40 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use wired::Counters;
+
+#[test]
+fn works() {
+    // create new database containing counters
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let db = Counters::<String>::new(file.try_clone().unwrap()).unwrap();
+    let db = Arc::new(Mutex::new(db));
+
+    // increment some counters from several threads
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let db = Arc::clone(&db);
+            thread::spawn(move || {
+                for i in 0..50 {
+                    let mut db = db.lock().unwrap();
+                    db.incr(String::from("requests"), 1).unwrap();
+                    db.incr(format!("bucket {}", i % 3), 2).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // check totals
+    let db = db.lock().unwrap();
+    assert_eq!(db.len(), 4);
+    assert_eq!(db.get(&String::from("requests")).unwrap(), 200);
+    assert_eq!(db.get(&String::from("bucket 0")).unwrap(), 4 * 17 * 2);
+    assert_eq!(db.get(&String::from("bucket 1")).unwrap(), 4 * 17 * 2);
+    assert_eq!(db.get(&String::from("bucket 2")).unwrap(), 4 * 16 * 2);
+
+    // works after reopen
+    let db = Counters::<String>::new(file).unwrap();
+    assert_eq!(db.get(&String::from("requests")).unwrap(), 200);
+}
@@ -0,0 +1,355 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use wired::{format, KeyValue, Options, Queue, Stack};
+
+// Golden images of every format version ever released. They must never be
+// removed or regenerated: a file written by an older version of this crate
+// has to open with all later versions. When the format changes, bump
+// `format::CURRENT_VERSION`, run `cargo test -- --ignored` to write a new
+// image set and add it below.
+const GOLDEN: &[GoldenSet] = &[
+    GoldenSet {
+        version: 1,
+        queue: include_bytes!("golden/v1/queue.bin"),
+        stack: include_bytes!("golden/v1/stack.bin"),
+        key_value: include_bytes!("golden/v1/key_value.bin"),
+    },
+    GoldenSet {
+        version: 2,
+        queue: include_bytes!("golden/v2/queue.bin"),
+        stack: include_bytes!("golden/v2/stack.bin"),
+        key_value: include_bytes!("golden/v2/key_value.bin"),
+    },
+    GoldenSet {
+        version: 3,
+        queue: include_bytes!("golden/v3/queue.bin"),
+        stack: include_bytes!("golden/v3/stack.bin"),
+        key_value: include_bytes!("golden/v3/key_value.bin"),
+    },
+];
+
+// size of the storage header region in the current format version
+const HEADER_SIZE: usize = 1024;
+
+struct GoldenSet {
+    version: usize,
+    queue: &'static [u8],
+    stack: &'static [u8],
+    key_value: &'static [u8],
+}
+
+type Generator = fn(File);
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+struct Record {
+    id: u32,
+    name: String,
+    payload: Vec<u8>,
+}
+
+impl Record {
+    fn new(id: u32) -> Self {
+        // some records span multiple frames
+        let len = if id % 3 == 2 { 3000 } else { 10 };
+        let payload = (0..len).map(|i| (i as u32 + id) as u8).collect();
+        let name = format!("record {}", id);
+        Self { id, name, payload }
+    }
+}
+
+fn generate_queue(file: File) {
+    let mut db = Queue::<Record>::new(file).unwrap();
+    for id in 0..6 {
+        db.enqueue(Record::new(id)).unwrap();
+    }
+    db.dequeue().unwrap();
+    db.dequeue().unwrap();
+}
+
+fn generate_stack(file: File) {
+    let mut db = Stack::<Record>::new(file).unwrap();
+    for id in 0..6 {
+        db.push(Record::new(id)).unwrap();
+    }
+    db.pop().unwrap();
+    db.pop().unwrap();
+}
+
+fn generate_key_value(file: File) {
+    let mut db = KeyValue::<String, Record>::new(file).unwrap();
+    for id in 0..6 {
+        db.set(format!("key {}", id), Record::new(id)).unwrap();
+    }
+    db.remove(&"key 1".to_string()).unwrap();
+    db.set("key 3".to_string(), Record::new(5)).unwrap();
+}
+
+fn check_queue(file: File) {
+    let mut db = Queue::<Record>::new(file).unwrap();
+    assert_eq!(db.len(), 4);
+    for id in 2..6 {
+        assert_eq!(db.dequeue().unwrap(), Some(Record::new(id)));
+    }
+    assert_eq!(db.dequeue().unwrap(), None);
+}
+
+fn check_stack(file: File) {
+    let mut db = Stack::<Record>::new(file).unwrap();
+    assert_eq!(db.len(), 4);
+    for id in (0..4).rev() {
+        assert_eq!(db.pop().unwrap(), Some(Record::new(id)));
+    }
+    assert_eq!(db.pop().unwrap(), None);
+}
+
+fn check_key_value(file: File) {
+    let db = KeyValue::<String, Record>::new(file).unwrap();
+    assert_eq!(db.len(), 5);
+    let get = |id: u32| db.get(&format!("key {}", id)).unwrap();
+    assert_eq!(get(0), Some(Record::new(0)));
+    assert_eq!(get(1), None);
+    assert_eq!(get(2), Some(Record::new(2)));
+    assert_eq!(get(3), Some(Record::new(5)));
+    assert_eq!(get(4), Some(Record::new(4)));
+    assert_eq!(get(5), Some(Record::new(5)));
+}
+
+/// run a generator on a fresh file and return the image, trimmed to the
+/// frames actually in use so it does not depend on the page size
+fn generate_image(generator: Generator) -> Vec<u8> {
+    let mut file = tempfile::tempfile().unwrap();
+    generator(file.try_clone().unwrap());
+    let mut bytes = vec![];
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut bytes).unwrap();
+    let frame_count = u64::from_le_bytes(bytes[0..8].try_into().unwrap()) as usize;
+    bytes.truncate(HEADER_SIZE + frame_count * 1024);
+    bytes
+}
+
+fn load_image(bytes: &[u8]) -> File {
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(bytes).unwrap();
+    file
+}
+
+/// the bytes of a file after running the same operations in
+/// deterministic mode, including some that reuse freed frames
+fn deterministic_image() -> (Vec<u8>, Vec<u8>) {
+    let options = Options::new().deterministic(true);
+    let mut kv_file = tempfile::tempfile().unwrap();
+    let mut db =
+        KeyValue::<String, Record>::with_options(kv_file.try_clone().unwrap(), options.clone())
+            .unwrap();
+    for id in 0..200 {
+        db.set(format!("key {}", id), Record::new(id % 7)).unwrap();
+    }
+    for id in (0..200).step_by(3) {
+        db.remove(&format!("key {}", id)).unwrap();
+    }
+    db.compact_dedup().unwrap();
+    db.set("late".to_string(), Record::new(2)).unwrap();
+    drop(db);
+
+    let mut queue_file = tempfile::tempfile().unwrap();
+    let mut db = Queue::<Record>::with_options(queue_file.try_clone().unwrap(), options).unwrap();
+    for id in 0..20 {
+        db.enqueue(Record::new(id)).unwrap();
+    }
+    for _ in 0..7 {
+        db.dequeue().unwrap();
+    }
+    drop(db);
+
+    let read = |file: &mut File| {
+        let mut bytes = vec![];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut bytes).unwrap();
+        bytes
+    };
+    (read(&mut kv_file), read(&mut queue_file))
+}
+
+#[test]
+fn deterministic_files_are_identical() {
+    let (key_value, queue) = deterministic_image();
+    let (key_value_again, queue_again) = deterministic_image();
+    assert!(key_value == key_value_again, "key value files differ");
+    assert!(queue == queue_again, "queue files differ");
+    let db = Queue::<Record>::new(load_image(&queue)).unwrap();
+    // without a clock, the timestamps stay unset
+    assert_eq!(db.created_at(), None);
+    assert_eq!(db.last_modified(), None);
+}
+
+#[test]
+fn opens_all_golden_images() {
+    for golden in GOLDEN {
+        check_queue(load_image(golden.queue));
+        check_stack(load_image(golden.stack));
+        check_key_value(load_image(golden.key_value));
+    }
+}
+
+#[test]
+fn golden_images_are_complete() {
+    let versions: Vec<usize> = GOLDEN.iter().map(|golden| golden.version).collect();
+    let compatible: Vec<usize> = format::COMPATIBILITY.iter().map(|(v, _)| *v).collect();
+    assert_eq!(versions, compatible);
+}
+
+#[test]
+fn current_format_has_golden_images() {
+    // containers may append fields to their headers without a new format
+    // version, see `container_headers_of_older_versions`, so fresh images
+    // are checked logically instead of bytewise
+    let golden = GOLDEN.last().unwrap();
+    assert_eq!(golden.version, format::CURRENT_VERSION);
+    check_queue(load_image(&generate_image(generate_queue)));
+    check_stack(load_image(&generate_image(generate_stack)));
+    check_key_value(load_image(&generate_image(generate_key_value)));
+}
+
+#[test]
+fn container_headers_of_older_versions() {
+    // the body size in the first frame header, which holds the header of
+    // the container, right behind the storage header
+    let body_size = |image: &[u8], offset: usize| {
+        u64::from_le_bytes(image[offset + 8..offset + 16].try_into().unwrap())
+    };
+    // the key value header of version 1 ends before every field appended
+    // since, and the storage header of version 1 took 24 bytes
+    let fresh = generate_image(generate_key_value);
+    assert!(body_size(GOLDEN[0].key_value, 24) < body_size(&fresh, HEADER_SIZE));
+
+    let file = load_image(GOLDEN[0].key_value);
+    let mut db = KeyValue::<String, Record>::new(file.try_clone().unwrap()).unwrap();
+    db.set("key 6".to_string(), Record::new(6)).unwrap();
+    drop(db);
+    let db = KeyValue::<String, Record>::new(file).unwrap();
+    assert_eq!(db.len(), 6);
+    assert_eq!(db.get(&"key 0".to_string()).unwrap(), Some(Record::new(0)));
+    assert_eq!(db.get(&"key 6".to_string()).unwrap(), Some(Record::new(6)));
+}
+
+#[test]
+fn truncated_older_formats() {
+    let directory = tempfile::tempdir().unwrap();
+    let truncated = |error: Box<dyn std::error::Error>| match error.downcast_ref() {
+        Some(wired::WiredError::Truncated { expected, actual }) => (*expected, *actual),
+        _ => panic!("unexpected error: {}", error),
+    };
+    for golden in &GOLDEN[..2] {
+        let image = golden.queue;
+        // the storage header of versions 1 and 2, followed by whole frames
+        let header = image.len() % 1024;
+        let cut = |len: usize| {
+            let path = directory
+                .path()
+                .join(format!("v{}-{}.wired", golden.version, len));
+            std::fs::write(&path, &image[..len]).unwrap();
+            let error = Queue::<Record>::open(&path).err().unwrap();
+            (path, truncated(error))
+        };
+
+        // within the storage header, nothing can be salvaged
+        let (path, error) = cut(header - 4);
+        assert_eq!(error, (header, header - 4));
+        let tolerant = Queue::<Record>::open_truncated_tolerant(&path).err();
+        assert_eq!(truncated(tolerant.unwrap()), error);
+
+        // neither before the header of the queue in the first frame
+        let (path, error) = cut(header + 500);
+        assert_eq!(error, (image.len(), header + 500));
+        let tolerant = Queue::<Record>::open_truncated_tolerant(&path).err();
+        truncated(tolerant.unwrap());
+
+        // between frames and within a frame, the item in front survives,
+        // while the frame of the next one got lost
+        for len in [header + 7 * 1024, header + 7 * 1024 + 500] {
+            let (path, error) = cut(len);
+            assert_eq!(error, (image.len(), len));
+            let mut db = Queue::<Record>::open_truncated_tolerant(&path).unwrap();
+            assert_eq!(db.dequeue().unwrap(), Some(Record::new(2)));
+            assert!(db.dequeue().is_err());
+        }
+    }
+}
+
+#[test]
+fn timestamps_of_older_formats() {
+    // version 1 did not record any timestamps, only later writes set one
+    let mut db = Queue::<Record>::new(load_image(GOLDEN[0].queue)).unwrap();
+    assert_eq!(db.created_at(), None);
+    db.dequeue().unwrap();
+    assert_eq!(db.created_at(), None);
+    assert!(db.last_modified().is_some());
+
+    let db = Queue::<Record>::new(load_image(GOLDEN[1].queue)).unwrap();
+    assert!(db.created_at().is_some());
+    assert!(db.last_modified() >= db.created_at());
+}
+
+#[test]
+fn metadata_of_older_formats() {
+    // no format before the metadata blob had one, but every one can get it
+    for golden in GOLDEN {
+        let file = load_image(golden.stack);
+        let mut db = Stack::<Record>::new(file.try_clone().unwrap()).unwrap();
+        assert_eq!(db.meta().unwrap(), None);
+        db.set_meta(b"upgraded").unwrap();
+        drop(db);
+        let db = Stack::<Record>::new(file.try_clone().unwrap()).unwrap();
+        assert_eq!(db.meta().unwrap(), Some(b"upgraded".to_vec()));
+        drop(db);
+        check_stack(file);
+    }
+}
+
+#[test]
+fn rejects_newer_format() {
+    let mut bytes = GOLDEN.last().unwrap().queue.to_vec();
+    bytes[8..16].copy_from_slice(&(format::CURRENT_VERSION as u64 + 1).to_le_bytes());
+    let error = Queue::<Record>::new(load_image(&bytes)).err().unwrap();
+    match error.downcast_ref::<wired::WiredError>() {
+        Some(wired::WiredError::UnsupportedVersion { .. }) => {}
+        _ => panic!("unexpected error: {}", error),
+    }
+}
+
+#[test]
+fn feature_flags() {
+    // the flags follow the 2 byte version and 2 reserved bytes
+    let flags = |bits: u32| {
+        let mut bytes = GOLDEN.last().unwrap().queue.to_vec();
+        bytes[12..16].copy_from_slice(&bits.to_le_bytes());
+        load_image(&bytes)
+    };
+
+    // unknown optional features get ignored
+    check_queue(flags(1 << 31));
+
+    let error = Queue::<Record>::new(flags(1 << 15)).err().unwrap();
+    match error.downcast_ref::<wired::WiredError>() {
+        Some(wired::WiredError::UnsupportedFeatures { flags }) => assert_eq!(*flags, 1 << 15),
+        _ => panic!("unexpected error: {}", error),
+    }
+}
+
+#[test]
+#[ignore]
+fn write_golden_images() {
+    let dir = format!("tests/golden/v{}", format::CURRENT_VERSION);
+    std::fs::create_dir_all(&dir).unwrap();
+    let images: [(&str, Generator); 3] = [
+        ("queue", generate_queue),
+        ("stack", generate_stack),
+        ("key_value", generate_key_value),
+    ];
+    for (name, generator) in images.iter() {
+        let path = format!("{}/{}.bin", dir, name);
+        std::fs::write(path, generate_image(*generator)).unwrap();
+    }
+}
@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::hash::{Hash, Hasher};
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::time::{SystemTime, UNIX_EPOCH};
-use wired::KeyValue;
+use wired::{KeyValue, Options, WiredError};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Message {
@@ -45,8 +48,279 @@ fn works() {
     assert_eq!(db.len(), 3);
 
     // works after reopen
-    let mut db = KeyValue::<String, Message>::new(file).unwrap();
+    let db = KeyValue::<String, Message>::new(file).unwrap();
     let msg = db.get(&String::from("m4")).unwrap().unwrap();
     assert_eq!(msg.name, "msg 4");
     assert_eq!(db.len(), 3);
 }
+
+#[test]
+fn clear_and_shrink() {
+    for options in [Options::new(), Options::new().disk_index(64)] {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut db =
+            KeyValue::<u32, String>::with_options(file.try_clone().unwrap(), options).unwrap();
+        for key in 0..500 {
+            db.set(key, format!("value {}", key)).unwrap();
+        }
+        assert!(file.metadata().unwrap().len() > 500_000);
+
+        db.clear_and_shrink().unwrap();
+        assert!(db.is_empty());
+        assert_eq!(db.get(&1).unwrap(), None);
+        assert!(file.metadata().unwrap().len() <= 4096);
+
+        // inserting works again, and survives opening the file again
+        db.set(7, String::from("again")).unwrap();
+        drop(db);
+        let db = KeyValue::<u32, String>::new(file).unwrap();
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.get(&7).unwrap(), Some(String::from("again")));
+    }
+}
+
+#[test]
+fn with_cache() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut db = KeyValue::<u32, String>::with_cache(file, 2).unwrap();
+    db.set(1, String::from("one")).unwrap();
+
+    // only the first read of the value block touches the file
+    let reads = db.stats().reads;
+    for _ in 0..100 {
+        assert_eq!(db.get(&1).unwrap(), Some(String::from("one")));
+    }
+    assert_eq!(db.stats().reads, reads + 1);
+
+    // writes drop the cached value
+    db.set(1, String::from("uno")).unwrap();
+    assert_eq!(db.get(&1).unwrap(), Some(String::from("uno")));
+    db.with_value_mut(&1, |value| value.push('!')).unwrap();
+    assert_eq!(db.get(&1).unwrap(), Some(String::from("uno!")));
+    db.remove(&1).unwrap();
+    assert_eq!(db.get(&1).unwrap(), None);
+
+    // the least recently used value gets evicted once the cache is full
+    for key in 2..5 {
+        db.set(key, format!("value {}", key)).unwrap();
+        db.get(&key).unwrap();
+    }
+    let reads = db.stats().reads;
+    db.get(&4).unwrap();
+    db.get(&3).unwrap();
+    assert_eq!(db.stats().reads, reads);
+    db.get(&2).unwrap();
+    assert_eq!(db.stats().reads, reads + 1);
+}
+
+#[test]
+fn raw_values() {
+    for options in [Options::new(), Options::new().disk_index(64)] {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut db =
+            KeyValue::<u32, Vec<u8>>::with_options(file.try_clone().unwrap(), options).unwrap();
+
+        // an empty value is not a missing key
+        db.set_raw(1, &[]).unwrap();
+        db.set_raw(2, &[]).unwrap();
+        assert_eq!(db.get_raw(&1).unwrap(), Some(vec![]));
+        assert_eq!(db.get_raw(&3).unwrap(), None);
+        assert!(db.contains_key(&1));
+        assert_eq!(db.len(), 2);
+
+        // raw and typed accessors share the encoding of the database
+        db.set(3, vec![7, 8]).unwrap();
+        assert_eq!(
+            db.get_raw(&3).unwrap(),
+            Some(bincode::serialize(&vec![7_u8, 8]).unwrap())
+        );
+        db.set_raw(4, &bincode::serialize(&vec![9_u8]).unwrap())
+            .unwrap();
+        assert_eq!(db.get(&4).unwrap(), Some(vec![9]));
+
+        // a raw value that does not decode fails typed reads only
+        db.set_raw(5, &[1, 2]).unwrap();
+        assert!(db.get(&5).is_err());
+        assert_eq!(db.get_raw(&5).unwrap(), Some(vec![1, 2]));
+        assert!(db.get(&1).is_err());
+
+        // empty values survive a compaction and opening the file again
+        db.compact_dedup().unwrap();
+        drop(db);
+        let db = KeyValue::<u32, Vec<u8>>::new(file).unwrap();
+        assert_eq!(db.len(), 5);
+        assert_eq!(db.get_raw(&1).unwrap(), Some(vec![]));
+        assert_eq!(db.get_raw(&2).unwrap(), Some(vec![]));
+        assert_eq!(db.get_raw(&6).unwrap(), None);
+        assert_eq!(db.get(&3).unwrap(), Some(vec![7, 8]));
+    }
+}
+
+#[test]
+fn poisoned_by_panicking_closure() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut db = KeyValue::<u32, u64>::new(file.try_clone().unwrap()).unwrap();
+    db.set(1, 41).unwrap();
+
+    let panicked = catch_unwind(AssertUnwindSafe(|| {
+        db.with_value_mut(&1, |_| panic!("modify closure panics"))
+    }));
+    assert!(panicked.is_err());
+    let poisoned = Some(&WiredError::Poisoned);
+    assert_eq!(db.get(&1).unwrap_err().downcast_ref(), poisoned);
+    assert_eq!(db.set(2, 2).unwrap_err().downcast_ref(), poisoned);
+    assert_eq!(db.remove(&1).unwrap_err().downcast_ref(), poisoned);
+
+    db.recover_in_memory_state().unwrap();
+    assert_eq!(db.get(&1).unwrap(), Some(41));
+    db.with_value_mut(&1, |value| *value += 1).unwrap();
+    assert_eq!(db.get(&1).unwrap(), Some(42));
+    drop(db);
+    let db = KeyValue::<u32, u64>::new(file).unwrap();
+    assert_eq!(db.get(&1).unwrap(), Some(42));
+}
+
+thread_local! {
+    // hashes of a `PanickyKey` that still succeed, none when unset
+    static HASHES_LEFT: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// a key whose `Hash` panics after a given number of calls, to interrupt a
+/// modification at any step that touches the lookup
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct PanickyKey(u32);
+
+impl Hash for PanickyKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if let Some(left) = HASHES_LEFT.get() {
+            assert!(left > 0, "hash panics");
+            HASHES_LEFT.set(Some(left - 1));
+        }
+        self.0.hash(state);
+    }
+}
+
+#[test]
+fn poisoned_in_the_middle_of_a_modification() {
+    for hashes in 0..8 {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut db = KeyValue::<PanickyKey, String>::new(file.try_clone().unwrap()).unwrap();
+        db.set(PanickyKey(1), String::from("kept")).unwrap();
+        db.set(PanickyKey(2), String::from("old")).unwrap();
+
+        HASHES_LEFT.set(Some(hashes));
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            db.set(PanickyKey(2), String::from("new")).unwrap();
+            db.set(PanickyKey(3), String::from("added")).unwrap();
+            db.remove(&PanickyKey(1)).unwrap();
+        }));
+        HASHES_LEFT.set(None);
+        if result.is_ok() {
+            continue;
+        }
+        let error = db.get(&PanickyKey(1)).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&WiredError::Poisoned));
+
+        // after recovery, the handle agrees with what the file holds
+        db.recover_in_memory_state().unwrap();
+        let recovered: Vec<Option<String>> = (1..4)
+            .map(|key| db.get(&PanickyKey(key)).unwrap())
+            .collect();
+        assert_eq!(db.len(), recovered.iter().flatten().count());
+        drop(db);
+        let db = KeyValue::<PanickyKey, String>::open_rebuild_index(file).unwrap();
+        let stored: Vec<Option<String>> = (1..4)
+            .map(|key| db.get(&PanickyKey(key)).unwrap())
+            .collect();
+        assert_eq!(recovered, stored);
+        assert!(stored[1] == Some(String::from("old")) || stored[1] == Some(String::from("new")));
+    }
+}
+
+#[test]
+fn content_hash() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut kv = KeyValue::<String, String>::new(file).unwrap();
+    for key in 0..100 {
+        kv.set(format!("key {}", key), format!("value {}", key % 10))
+            .unwrap();
+    }
+    let hash = kv.content_hash().unwrap();
+    kv.compact_dedup().unwrap();
+    assert_eq!(kv.content_hash().unwrap(), hash);
+
+    // inserted in reverse, inline and with a disk index instead
+    for options in [
+        Options::new().inline_values(1 << 20),
+        Options::new().disk_index(16),
+    ] {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut other = KeyValue::<String, String>::with_options(file, options).unwrap();
+        for key in (0..100).rev() {
+            other
+                .set(format!("key {}", key), format!("value {}", key % 10))
+                .unwrap();
+        }
+        assert_eq!(other.content_hash().unwrap(), hash);
+    }
+
+    kv.set("key 7".to_string(), "value 8".to_string()).unwrap();
+    assert_ne!(kv.content_hash().unwrap(), hash);
+}
+
+#[test]
+fn export_filtered() {
+    let dir = tempfile::tempdir().expect("could not create tempdir");
+    let source = dir.path().join("source.wired");
+    let mut kv = KeyValue::<String, String>::open(&source).unwrap();
+    // enough entries for several batches, some of them spanning frames
+    for key in 0..3000 {
+        let value = match key % 3 {
+            0 => format!("shared value {}", key),
+            _ => format!("private marker {} {}", key, "x".repeat(key % 7 * 400)),
+        };
+        kv.set(format!("key {}", key), value).unwrap();
+    }
+    kv.remove(&String::from("key 3")).unwrap();
+    let hash = kv.content_hash().unwrap();
+
+    let target = dir.path().join("export.wired");
+    let report = kv
+        .export_filtered(&target, |_, value| value.starts_with("shared"))
+        .unwrap();
+    assert_eq!(report.exported, 999);
+    assert_eq!(report.skipped, 2000);
+    assert_eq!(report.file_bytes as u64, target.metadata().unwrap().len());
+    assert!(report.file_bytes < source.metadata().unwrap().len() as usize);
+    assert_eq!(kv.content_hash().unwrap(), hash);
+    assert_eq!(kv.len(), 2999);
+
+    // the copy opens on its own and holds exactly the accepted entries
+    drop(kv);
+    let export = KeyValue::<String, String>::open_existing(&target).unwrap();
+    assert_eq!(export.len(), 999);
+    assert_eq!(export.payload_bytes(), report.payload_bytes);
+    for key in 0..3000 {
+        let value = export.get(&format!("key {}", key)).unwrap();
+        match key {
+            3 => assert_eq!(value, None),
+            key if key % 3 == 0 => assert_eq!(value, Some(format!("shared value {}", key))),
+            _ => assert_eq!(value, None),
+        }
+    }
+    drop(export);
+    let bytes = std::fs::read(&target).unwrap();
+    assert!(!bytes.windows(7).any(|window| window == b"private"));
+
+    // an existing file is never overwritten
+    let kv = KeyValue::<String, String>::open(&source).unwrap();
+    let error = kv.export_filtered(&target, |_, _| true).unwrap_err();
+    let error = error.downcast::<std::io::Error>().unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+    assert_eq!(
+        KeyValue::<String, String>::open_existing(&target)
+            .unwrap()
+            .len(),
+        999
+    );
+}
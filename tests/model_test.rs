@@ -0,0 +1,256 @@
+//! Random operation sequences run against every container and against the
+//! std collection it behaves like, comparing both after every step. A
+//! failing sequence gets shrunk to a minimal one by proptest.
+
+use proptest::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use wired::{CompactBudget, KeyValue, Options, Queue, RecordFraming, Stack};
+
+/// a payload that prints as its recipe instead of its bytes, so shrunk
+/// counterexamples stay readable. Large ones span several frames.
+#[derive(Clone, Copy, PartialEq)]
+struct Value {
+    seed: u8,
+    len: usize,
+}
+
+impl Value {
+    fn bytes(&self) -> Vec<u8> {
+        (0..self.len)
+            .map(|i| self.seed.wrapping_add(i as u8))
+            .collect()
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Value({}, len {})", self.seed, self.len)
+    }
+}
+
+fn value() -> impl Strategy<Value = Value> {
+    let len = prop_oneof![4 => 0..100_usize, 1 => 900..3000_usize];
+    (any::<u8>(), len).prop_map(|(seed, len)| Value { seed, len })
+}
+
+/// drop the container and open the file again, checking what got persisted
+fn reopen<C>(container: C, file: &File, open: fn(File) -> C) -> C {
+    drop(container);
+    open(file.try_clone().unwrap())
+}
+
+#[derive(Debug, Clone)]
+enum QueueOp {
+    Enqueue(Value),
+    Dequeue,
+    DequeueBack,
+    Peek,
+    Reopen,
+}
+
+fn queue_op() -> impl Strategy<Value = QueueOp> {
+    prop_oneof![
+        4 => value().prop_map(QueueOp::Enqueue),
+        3 => Just(QueueOp::Dequeue),
+        1 => Just(QueueOp::DequeueBack),
+        1 => Just(QueueOp::Peek),
+        1 => Just(QueueOp::Reopen),
+    ]
+}
+
+fn open_queue(file: File) -> Queue<Vec<u8>> {
+    Queue::new(file).unwrap()
+}
+
+fn check_queue(ops: Vec<QueueOp>) -> Result<(), TestCaseError> {
+    let file = tempfile::tempfile().unwrap();
+    let mut queue = open_queue(file.try_clone().unwrap());
+    let mut model = VecDeque::new();
+    for op in ops {
+        match op {
+            QueueOp::Enqueue(value) => {
+                queue.enqueue(value.bytes()).unwrap();
+                model.push_back(value.bytes());
+            }
+            QueueOp::Dequeue => prop_assert_eq!(queue.dequeue().unwrap(), model.pop_front()),
+            QueueOp::DequeueBack => {
+                prop_assert_eq!(queue.dequeue_back().unwrap(), model.pop_back())
+            }
+            QueueOp::Peek => {
+                let front = queue.iter().next().transpose().unwrap();
+                prop_assert_eq!(front.as_ref(), model.front());
+            }
+            QueueOp::Reopen => queue = reopen(queue, &file, open_queue),
+        }
+        prop_assert_eq!(queue.len(), model.len());
+        let items: Vec<Vec<u8>> = queue.iter().collect::<Result<_, _>>().unwrap();
+        prop_assert!(items.iter().eq(model.iter()));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+enum StackOp {
+    Push(Value),
+    Pop,
+    PopBottom,
+    Reopen,
+}
+
+fn stack_op() -> impl Strategy<Value = StackOp> {
+    prop_oneof![
+        4 => value().prop_map(StackOp::Push),
+        3 => Just(StackOp::Pop),
+        1 => Just(StackOp::PopBottom),
+        1 => Just(StackOp::Reopen),
+    ]
+}
+
+fn open_stack(file: File) -> Stack<Vec<u8>> {
+    Stack::new(file).unwrap()
+}
+
+fn check_stack(ops: Vec<StackOp>) -> Result<(), TestCaseError> {
+    let file = tempfile::tempfile().unwrap();
+    let mut stack = open_stack(file.try_clone().unwrap());
+    let mut model = Vec::new();
+    for op in ops {
+        match op {
+            StackOp::Push(value) => {
+                stack.push(value.bytes()).unwrap();
+                model.push(value.bytes());
+            }
+            StackOp::Pop => prop_assert_eq!(stack.pop().unwrap(), model.pop()),
+            StackOp::PopBottom => {
+                let bottom = (!model.is_empty()).then(|| model.remove(0));
+                prop_assert_eq!(stack.pop_bottom().unwrap(), bottom)
+            }
+            StackOp::Reopen => stack = reopen(stack, &file, open_stack),
+        }
+        prop_assert_eq!(stack.len(), model.len());
+        let items: Vec<Vec<u8>> = stack.iter().collect::<Result<_, _>>().unwrap();
+        prop_assert!(items.iter().eq(model.iter().rev()));
+    }
+    Ok(())
+}
+
+// few keys, so sets overwrite and removes hit existing keys often
+#[derive(Debug, Clone)]
+enum KeyValueOp {
+    Set(u8, Value),
+    Get(u8),
+    Remove(u8),
+    Reopen,
+}
+
+fn key_value_op() -> impl Strategy<Value = KeyValueOp> {
+    let key = 0..16_u8;
+    prop_oneof![
+        4 => (key.clone(), value()).prop_map(|(key, value)| KeyValueOp::Set(key, value)),
+        2 => key.clone().prop_map(KeyValueOp::Get),
+        2 => key.prop_map(KeyValueOp::Remove),
+        1 => Just(KeyValueOp::Reopen),
+    ]
+}
+
+fn open_key_value(file: File) -> KeyValue<u8, Vec<u8>> {
+    KeyValue::new(file).unwrap()
+}
+
+fn check_key_value(ops: Vec<KeyValueOp>) -> Result<(), TestCaseError> {
+    let file = tempfile::tempfile().unwrap();
+    let mut kv = open_key_value(file.try_clone().unwrap());
+    let mut model = HashMap::new();
+    for op in ops {
+        match op {
+            KeyValueOp::Set(key, value) => {
+                kv.set(key, value.bytes()).unwrap();
+                model.insert(key, value.bytes());
+            }
+            KeyValueOp::Get(key) => {
+                prop_assert_eq!(kv.get(&key).unwrap(), model.get(&key).cloned())
+            }
+            KeyValueOp::Remove(key) => {
+                kv.remove(&key).unwrap();
+                model.remove(&key);
+            }
+            KeyValueOp::Reopen => kv = reopen(kv, &file, open_key_value),
+        }
+        prop_assert_eq!(kv.len(), model.len());
+        for (key, value) in &model {
+            prop_assert_eq!(kv.get(key).unwrap(), Some(value.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// run the operations with `Options::record_ops`, compacting at the end,
+/// then replay the log into a fresh file that has to hold the same items
+fn check_queue_replay(ops: Vec<QueueOp>) -> Result<(), TestCaseError> {
+    let log = tempfile::NamedTempFile::new().unwrap();
+    let options = Options::new().record_ops(log.path());
+    let open = |file: File| Queue::<Vec<u8>>::with_options(file, options.clone()).unwrap();
+    let file = tempfile::tempfile().unwrap();
+    let mut queue = open(file.try_clone().unwrap());
+    for op in ops {
+        match op {
+            QueueOp::Enqueue(value) => queue.enqueue(value.bytes()).unwrap(),
+            QueueOp::Dequeue => {
+                queue.dequeue().unwrap();
+            }
+            QueueOp::DequeueBack => {
+                queue.dequeue_back().unwrap();
+            }
+            // reading leaves no trace in the log
+            QueueOp::Peek => {}
+            QueueOp::Reopen => {
+                drop(queue);
+                queue = open(file.try_clone().unwrap());
+            }
+        }
+    }
+    while !queue.compact_step(CompactBudget::default()).unwrap().done {}
+    drop(queue);
+
+    let replayed = tempfile::tempfile().unwrap();
+    let report = wired::replay(log.path(), replayed.try_clone().unwrap()).unwrap();
+    prop_assert_eq!(report.diverged_at, None);
+    prop_assert!(report.operations > 0);
+
+    let export = |file: File| {
+        let mut queue = Queue::<Vec<u8>>::new(file).unwrap();
+        let mut bytes = vec![];
+        queue
+            .drain_raw_to(&mut bytes, RecordFraming::LengthPrefixed)
+            .unwrap();
+        bytes
+    };
+    prop_assert_eq!(export(replayed), export(file));
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn queue_behaves_like_vec_deque(ops in prop::collection::vec(queue_op(), 0..60)) {
+        check_queue(ops)?;
+    }
+
+    #[test]
+    fn stack_behaves_like_vec(ops in prop::collection::vec(stack_op(), 0..60)) {
+        check_stack(ops)?;
+    }
+
+    #[test]
+    fn key_value_behaves_like_hash_map(ops in prop::collection::vec(key_value_op(), 0..60)) {
+        check_key_value(ops)?;
+    }
+
+    #[test]
+    fn recorded_queue_replays_identically(ops in prop::collection::vec(queue_op(), 0..60)) {
+        check_queue_replay(ops)?;
+    }
+}
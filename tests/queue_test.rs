@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::time::{SystemTime, UNIX_EPOCH};
-use wired::Queue;
+use wired::{
+    CompactBudget, CompactProgress, IntEncoding, Level, Options, Queue, RecordFraming, WiredError,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Message {
@@ -49,3 +52,815 @@ fn works() {
     assert_eq!(db.len(), 0);
     assert!(db.is_empty());
 }
+
+#[test]
+fn preallocated_bulk_load() {
+    // an unconfigured file grows repeatedly while loading
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut db = Queue::<Message>::new(file).unwrap();
+    for i in 0..300 {
+        db.enqueue(Message::new(&format!("msg {}", i))).unwrap();
+    }
+    assert!(db.stats().resizes > 1);
+
+    // a preallocated file never needs to be remapped
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let options = Options::new().initial_bytes(4 << 20).preallocate(true);
+    let mut db = Queue::<Message>::with_options(file.try_clone().unwrap(), options).unwrap();
+    for i in 0..300 {
+        db.enqueue(Message::new(&format!("msg {}", i))).unwrap();
+    }
+    assert_eq!(db.stats().resizes, 0);
+    assert_eq!(file.metadata().unwrap().len(), 4 << 20);
+
+    // works after reopen
+    let mut db = Queue::<Message>::new(file).unwrap();
+    assert_eq!(db.len(), 300);
+    assert_eq!(db.dequeue().unwrap().unwrap().name, "msg 0".to_string());
+}
+
+#[test]
+fn producer_and_consumer() {
+    let directory = tempfile::tempdir().expect("could not create tempdir");
+    let path = directory.path().join("queue.wired");
+    let mut producer = Queue::<String>::open(&path).unwrap();
+    let mut consumer = Queue::<String>::open(&path).unwrap();
+
+    // both handles see each other's changes
+    producer.enqueue(String::from("first")).unwrap();
+    assert_eq!(consumer.len(), 1);
+    assert_eq!(consumer.dequeue().unwrap(), Some(String::from("first")));
+    assert!(producer.is_empty());
+
+    // exchange enough items to grow the file while both are active
+    let count = 200;
+    let handle = std::thread::spawn(move || {
+        for i in 0..count {
+            producer
+                .enqueue(format!("item {} {}", i, "x".repeat(500)))
+                .unwrap();
+        }
+    });
+    let mut received = vec![];
+    while received.len() < count {
+        match consumer.dequeue().unwrap() {
+            Some(item) => received.push(item),
+            None => std::thread::yield_now(),
+        }
+    }
+    handle.join().unwrap();
+    for (i, item) in received.iter().enumerate() {
+        assert!(item.starts_with(&format!("item {} ", i)));
+    }
+    assert!(consumer.is_empty());
+    assert_eq!(consumer.counters().enqueued, count as u64 + 1);
+}
+
+#[test]
+fn snapshot_of_other_handles() {
+    let directory = tempfile::tempdir().expect("could not create tempdir");
+    let path = directory.path().join("queue.wired");
+    let mut producer = Queue::<String>::open(&path).unwrap();
+    let reader = Queue::<String>::open(&path).unwrap();
+
+    // grow the file behind the mapping of the reader
+    let items: Vec<String> = (0..100)
+        .map(|i| format!("item {} {}", i, "x".repeat(500)))
+        .collect();
+    for item in items.iter() {
+        producer.enqueue(item.clone()).unwrap();
+    }
+    assert!(producer.stats().resizes > reader.stats().resizes);
+    assert_eq!(reader.snapshot_items().unwrap(), items);
+    assert_eq!(reader.len(), 100);
+}
+
+#[test]
+fn open_existing() {
+    let directory = tempfile::tempdir().expect("could not create tempdir");
+    let path = directory.path().join("queue.wired");
+
+    // a missing file is an error instead of a new database
+    let error = Queue::<String>::open_existing(&path).err().unwrap();
+    let error = error.downcast_ref::<std::io::Error>().unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    assert!(!path.exists());
+
+    let mut queue = Queue::<String>::open(&path).unwrap();
+    queue.enqueue(String::from("first")).unwrap();
+    drop(queue);
+    let mut queue = Queue::<String>::open_existing(&path).unwrap();
+    assert_eq!(queue.dequeue().unwrap(), Some(String::from("first")));
+}
+
+#[test]
+fn flush() {
+    let directory = tempfile::tempdir().expect("could not create tempdir");
+    let path = directory.path().join("queue.wired");
+    let mut queue = Queue::<String>::open(&path).unwrap();
+    queue.flush().unwrap();
+
+    queue.enqueue(String::from("first")).unwrap();
+    let flushes = queue.stats().flushes;
+    queue.flush().unwrap();
+    assert_eq!(queue.stats().flushes, flushes + 1);
+
+    // a second handle sees the items while the first is still open
+    let mut reopened = Queue::<String>::open_existing(&path).unwrap();
+    assert_eq!(reopened.dequeue().unwrap(), Some(String::from("first")));
+    reopened.flush().unwrap();
+    drop(reopened);
+    assert!(queue.is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn from_fd() {
+    let directory = tempfile::tempdir().expect("could not create tempdir");
+    let path = directory.path().join("queue.wired");
+    let open = || {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .unwrap()
+    };
+
+    // grow the file through a descriptor that never had a path attached
+    let fd: std::os::unix::io::OwnedFd = open().into();
+    let mut queue = Queue::<String>::from_fd(fd).unwrap();
+    for i in 0..100 {
+        queue
+            .enqueue(format!("item {} {}", i, "x".repeat(500)))
+            .unwrap();
+    }
+    assert!(queue.stats().resizes > 0);
+    drop(queue);
+
+    // a borrowed file stays usable for the lender
+    let file = open();
+    let mut queue = Queue::<String>::from_file(&file).unwrap();
+    assert_eq!(queue.len(), 100);
+    assert!(queue.dequeue().unwrap().unwrap().starts_with("item 0 "));
+    drop(queue);
+    assert_eq!(
+        file.metadata().unwrap().len(),
+        path.metadata().unwrap().len()
+    );
+}
+
+#[test]
+fn visible_to_other_processes() {
+    let directory = tempfile::tempdir().expect("could not create tempdir");
+    let path = directory.path().join("queue.wired");
+    let mut producer = Queue::<String>::open(&path).unwrap();
+
+    // enough items to grow the file before the barrier
+    let count = 100;
+    for i in 0..count {
+        producer.enqueue(other_process_item(i)).unwrap();
+    }
+    producer.barrier().unwrap();
+    assert!(producer.stats().resizes > 0);
+    assert!(producer.stats().syncs > 0);
+
+    // run `other_process_consumer` of this test binary in a new process
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "other_process_consumer", "--nocapture"])
+        .env("WIRED_CONSUMER_PATH", &path)
+        .env("WIRED_CONSUMER_COUNT", count.to_string())
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 passed"));
+
+    // the consumer saw everything, and its reply is visible here
+    assert_eq!(producer.dequeue().unwrap(), Some(String::from("reply")));
+    assert!(producer.is_empty());
+}
+
+/// the consumer of `visible_to_other_processes`, which does nothing unless
+/// started by it
+#[test]
+fn other_process_consumer() {
+    let Ok(path) = std::env::var("WIRED_CONSUMER_PATH") else {
+        return;
+    };
+    let count: usize = std::env::var("WIRED_CONSUMER_COUNT")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let mut consumer = Queue::<String>::open(path).unwrap();
+    for i in 0..count {
+        assert_eq!(consumer.dequeue().unwrap(), Some(other_process_item(i)));
+    }
+    assert!(consumer.is_empty());
+    consumer.enqueue(String::from("reply")).unwrap();
+    consumer.barrier().unwrap();
+}
+
+fn other_process_item(i: usize) -> String {
+    format!("item {} {}", i, "x".repeat(500))
+}
+
+#[test]
+fn append_queue() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut queue = Queue::<String>::new(file).unwrap();
+    queue.enqueue(String::from("a1")).unwrap();
+    queue.enqueue(String::from("a2")).unwrap();
+
+    let options = Options::new().int_encoding(IntEncoding::Varint);
+    let other_file = tempfile::tempfile().expect("could not create tempfile");
+    let mut other = Queue::<String>::with_options(other_file, options).unwrap();
+    for item in ["b1", "b2", "b3"] {
+        other.enqueue(String::from(item)).unwrap();
+    }
+
+    queue.append_queue(&mut other).unwrap();
+    assert!(other.is_empty());
+    assert_eq!(other.counters().dequeued, 3);
+    assert_eq!(queue.counters().enqueued, 5);
+    assert_eq!(
+        queue.snapshot_items().unwrap(),
+        ["a1", "a2", "b1", "b2", "b3"]
+    );
+
+    // both stay usable, and appending an empty queue changes nothing
+    other.enqueue(String::from("c1")).unwrap();
+    queue.append_queue(&mut other).unwrap();
+    queue.append_queue(&mut other).unwrap();
+    let items = queue.by_ref().collect::<Vec<_>>();
+    assert_eq!(items, ["a1", "a2", "b1", "b2", "b3", "c1"]);
+    assert_eq!(other.dequeue().unwrap(), None);
+}
+
+#[test]
+fn clear_and_shrink() {
+    let directory = tempfile::tempdir().expect("could not create tempdir");
+    let path = directory.path().join("queue.wired");
+    let mut queue = Queue::<String>::open(&path).unwrap();
+    queue.set_meta(b"schema v1").unwrap();
+    for i in 0..1_000 {
+        queue.enqueue(format!("item {}", i)).unwrap();
+    }
+    queue
+        .enqueue_with_level(String::from("urgent"), Level::High)
+        .unwrap();
+    assert!(std::fs::metadata(&path).unwrap().len() > 1_000_000);
+
+    // only the header region, the queue header and the metadata remain
+    queue.clear_and_shrink().unwrap();
+    assert!(queue.is_empty());
+    assert_eq!(queue.counters().dequeued, 1_001);
+    assert!(std::fs::metadata(&path).unwrap().len() <= 4096);
+    assert_eq!(queue.meta().unwrap().unwrap(), b"schema v1");
+
+    // the empty queue works as usual, also after opening it again
+    queue.enqueue(String::from("again")).unwrap();
+    drop(queue);
+    let mut queue = Queue::<String>::open_existing(&path).unwrap();
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue.dequeue().unwrap(), Some(String::from("again")));
+    assert_eq!(queue.meta().unwrap().unwrap(), b"schema v1");
+}
+
+#[test]
+fn compact_step() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut queue = Queue::<u32>::new(file.try_clone().unwrap()).unwrap();
+    let mut expected: VecDeque<u32> = (0..600).collect();
+    for item in expected.iter() {
+        queue.enqueue(*item).unwrap();
+    }
+    queue.set_meta(b"schema v1").unwrap();
+    queue.enqueue_with_level(1000, Level::High).unwrap();
+
+    // the oldest items at the front of the file are gone
+    assert_eq!(queue.dequeue().unwrap(), Some(1000));
+    for _ in 0..500 {
+        assert_eq!(queue.dequeue().unwrap(), expected.pop_front());
+    }
+    queue.enqueue_with_level(2000, Level::Low).unwrap();
+    let size = file.metadata().unwrap().len();
+
+    // compact in small steps with other operations in between
+    let mut relocated = 0;
+    for step in 0.. {
+        assert!(step < 100, "compaction does not finish");
+        let progress = queue.compact_step(CompactBudget { blocks: 10 }).unwrap();
+        relocated += progress.relocated;
+        if progress.done {
+            assert!(progress.released_bytes > 0);
+            break;
+        }
+        assert_eq!(progress.relocated, 10);
+        let item = 600 + step;
+        queue.enqueue(item).unwrap();
+        expected.push_back(item);
+        assert_eq!(queue.dequeue().unwrap(), expected.pop_front());
+    }
+    assert!(relocated >= 100);
+    assert!(file.metadata().unwrap().len() < size / 4);
+    assert!(queue.check_counts(false).unwrap().is_consistent());
+
+    // everything survived, also for another handle
+    drop(queue);
+    let mut queue = Queue::<u32>::new(file).unwrap();
+    assert_eq!(queue.meta().unwrap().unwrap(), b"schema v1");
+    assert_eq!(queue.len(), expected.len() + 1);
+    assert_eq!(
+        queue.compact_step(CompactBudget::default()).unwrap(),
+        CompactProgress {
+            relocated: 0,
+            released_bytes: 0,
+            done: true,
+        }
+    );
+    expected.push_back(2000);
+    assert_eq!(queue.by_ref().collect::<VecDeque<_>>(), expected);
+}
+
+#[test]
+fn int_encodings() {
+    let items: Vec<Vec<u32>> = (0..10).map(|i| vec![i; 2000]).collect();
+    let mut sizes = vec![];
+    for (encoding, other) in [
+        (IntEncoding::Fixed, IntEncoding::Varint),
+        (IntEncoding::Varint, IntEncoding::Fixed),
+    ] {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let options = Options::new().int_encoding(encoding);
+        let mut db = Queue::<Vec<u32>>::with_options(file.try_clone().unwrap(), options).unwrap();
+        for item in items.iter() {
+            db.enqueue(item.clone()).unwrap();
+        }
+        drop(db);
+        sizes.push(file.metadata().unwrap().len());
+
+        // the encoding of the file wins over the options when reopening
+        let options = Options::new().int_encoding(other);
+        let db = Queue::<Vec<u32>>::with_options(file, options).unwrap();
+        assert_eq!(db.collect::<Vec<_>>(), items);
+    }
+    assert!(sizes[1] < sizes[0]);
+}
+
+#[test]
+fn refuses_other_container_kinds() {
+    let directory = tempfile::tempdir().expect("could not create tempdir");
+    let path = directory.path().join("queue.wired");
+    let mut queue = Queue::<String>::open(&path).unwrap();
+    queue.enqueue(String::from("first")).unwrap();
+
+    let error = wired::KeyValue::<String, String>::open(&path)
+        .err()
+        .unwrap();
+    match error.downcast_ref::<wired::WiredError>() {
+        Some(wired::WiredError::AlreadyOpen { as_kind }) => assert_eq!(*as_kind, "Queue"),
+        _ => panic!("unexpected error: {}", error),
+    }
+    assert_eq!(queue.dequeue().unwrap(), Some(String::from("first")));
+
+    // the file is free again once the queue is gone
+    drop(queue);
+    std::fs::File::create(&path).expect("could not truncate");
+    assert!(wired::KeyValue::<String, String>::open(&path).is_ok());
+}
+
+#[test]
+fn metadata() {
+    let directory = tempfile::tempdir().expect("could not create tempdir");
+    let path = directory.path().join("queue.wired");
+    let mut queue = Queue::<String>::open(&path).unwrap();
+    assert_eq!(queue.meta().unwrap(), None);
+    queue.enqueue(String::from("first")).unwrap();
+    let size = queue.logical_size().unwrap();
+    queue.set_meta(b"schema 1").unwrap();
+    assert_eq!(queue.logical_size().unwrap(), size);
+    drop(queue);
+
+    let mut queue = Queue::<String>::open(&path).unwrap();
+    assert_eq!(queue.meta().unwrap(), Some(b"schema 1".to_vec()));
+
+    // overwriting spans several frames, then shrinks back into one
+    let large = vec![7_u8; 5000];
+    queue.set_meta(&large).unwrap();
+    assert_eq!(queue.meta().unwrap(), Some(large));
+    queue.set_meta(b"v2").unwrap();
+    drop(queue);
+
+    let mut queue = Queue::<String>::open(&path).unwrap();
+    assert_eq!(queue.meta().unwrap(), Some(b"v2".to_vec()));
+    let error = queue.set_meta(&vec![0; 64 * 1024 + 1]).err().unwrap();
+    match error.downcast_ref::<wired::WiredError>() {
+        Some(wired::WiredError::MetaTooLarge { len, limit }) => {
+            assert_eq!((*len, *limit), (64 * 1024 + 1, 64 * 1024))
+        }
+        _ => panic!("unexpected error: {}", error),
+    }
+    assert_eq!(queue.meta().unwrap(), Some(b"v2".to_vec()));
+    assert_eq!(queue.dequeue().unwrap(), Some(String::from("first")));
+}
+
+#[test]
+fn bulk_load() {
+    let items: Vec<String> = (0..500)
+        .map(|i| match i % 100 {
+            // some items span several frames
+            0 => "large ".repeat(500),
+            _ => format!("item {}", i),
+        })
+        .collect();
+
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut enqueued = Queue::<String>::new(file).unwrap();
+    for item in items.iter() {
+        enqueued.enqueue(item.clone()).unwrap();
+    }
+
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let loaded = Queue::<String>::bulk_load(file.try_clone().unwrap(), items.clone(), 500).unwrap();
+    assert_eq!(loaded.len(), 500);
+    assert_eq!(loaded.payload_bytes(), enqueued.payload_bytes());
+    assert_eq!(loaded.overhead_bytes(), enqueued.overhead_bytes());
+    assert_eq!(loaded.counters(), enqueued.counters());
+    assert!(enqueued.stats().resizes > 5);
+    assert!(loaded.stats().resizes <= 1);
+    assert!(enqueued.stats().flushes > 1000);
+    assert!(loaded.stats().flushes < 10);
+    drop(loaded);
+
+    // a loaded file is like any other one
+    let mut loaded = Queue::<String>::new(file.try_clone().unwrap()).unwrap();
+    loaded.enqueue(String::from("after")).unwrap();
+    enqueued.enqueue(String::from("after")).unwrap();
+    assert_eq!(
+        loaded.snapshot_items().unwrap(),
+        enqueued.snapshot_items().unwrap()
+    );
+    assert_eq!(loaded.dequeue().unwrap(), Some("large ".repeat(500)));
+    assert_eq!(loaded.dequeue().unwrap(), Some(String::from("item 1")));
+    drop(loaded);
+
+    let error = Queue::<String>::bulk_load(file, vec![String::from("more")], 1)
+        .err()
+        .unwrap();
+    assert_eq!(
+        error.downcast_ref::<wired::WiredError>(),
+        Some(&wired::WiredError::NotEmpty)
+    );
+}
+
+#[test]
+fn bulk_load_with_options() {
+    let items: Vec<String> = (0..100).map(|i| format!("item {}", i).repeat(50)).collect();
+    let options = || Options::new().compress_above(64);
+
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut enqueued = Queue::<String>::with_options(file, options()).unwrap();
+    for item in items.iter() {
+        enqueued.enqueue(item.clone()).unwrap();
+    }
+
+    // the items get compressed like enqueued ones
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let loaded = Queue::<String>::bulk_load_with_options(
+        file.try_clone().unwrap(),
+        items.clone(),
+        100,
+        options(),
+    )
+    .unwrap();
+    assert_eq!(loaded.payload_bytes(), enqueued.payload_bytes());
+    assert!(loaded.payload_bytes() < items.iter().map(String::len).sum());
+    drop(loaded);
+
+    let loaded = Queue::<String>::new(file).unwrap();
+    assert_eq!(loaded.snapshot_items().unwrap(), items);
+}
+
+#[test]
+fn dequeue_back() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut db = Queue::<u32>::new(file.try_clone().unwrap()).unwrap();
+    assert_eq!(db.dequeue_back().unwrap(), None);
+
+    // a single item is both ends
+    db.enqueue(1).unwrap();
+    assert_eq!(db.dequeue_back().unwrap(), Some(1));
+    assert_eq!(db.dequeue().unwrap(), None);
+
+    // two items leave one behind, usable from both ends
+    db.enqueue(2).unwrap();
+    db.enqueue(3).unwrap();
+    assert_eq!(db.dequeue_back().unwrap(), Some(3));
+    db.enqueue(4).unwrap();
+    assert_eq!(db.dequeue().unwrap(), Some(2));
+    assert_eq!(db.dequeue_back().unwrap(), Some(4));
+    assert!(db.is_empty());
+
+    // interleaved with both ends, surviving a reopen
+    for item in 5..15 {
+        db.enqueue(item).unwrap();
+    }
+    assert_eq!(db.dequeue().unwrap(), Some(5));
+    assert_eq!(db.dequeue_back().unwrap(), Some(14));
+    assert_eq!(db.dequeue_back().unwrap(), Some(13));
+    drop(db);
+    let mut db = Queue::<u32>::new(file).unwrap();
+    assert_eq!(db.len(), 7);
+    assert!(db.check_counts(false).unwrap().is_consistent());
+    db.enqueue(15).unwrap();
+    assert_eq!(db.dequeue_back().unwrap(), Some(15));
+    assert_eq!(db.dequeue().unwrap(), Some(6));
+    let rest: Vec<u32> = std::iter::from_fn(|| db.dequeue().unwrap()).collect();
+    assert_eq!(rest, [7, 8, 9, 10, 11, 12]);
+
+    // with levels, the newest item of the lowest level goes first
+    db.enqueue_with_level(1, Level::High).unwrap();
+    db.enqueue_with_level(2, Level::Low).unwrap();
+    db.enqueue_with_level(3, Level::Low).unwrap();
+    db.enqueue(4).unwrap();
+    assert_eq!(db.dequeue_back().unwrap(), Some(3));
+    assert_eq!(db.dequeue_back().unwrap(), Some(2));
+    assert_eq!(db.dequeue_back().unwrap(), Some(4));
+    assert_eq!(db.dequeue_back().unwrap(), Some(1));
+    assert_eq!(db.dequeue_back().unwrap(), None);
+}
+
+#[test]
+fn trim_front() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut db = Queue::<u32>::new(file.try_clone().unwrap()).unwrap();
+    assert_eq!(db.trim_front(3).unwrap(), 0);
+    for item in 0..10 {
+        db.enqueue(item).unwrap();
+    }
+    assert_eq!(db.trim_front(3).unwrap(), 3);
+    assert_eq!(db.len(), 7);
+    assert_eq!(db.counters().dequeued, 3);
+    drop(db);
+    let mut db = Queue::<u32>::new(file).unwrap();
+    assert!(db.check_counts(false).unwrap().is_consistent());
+    let rest: Vec<u32> = std::iter::from_fn(|| db.dequeue().unwrap()).collect();
+    assert_eq!(rest, [3, 4, 5, 6, 7, 8, 9]);
+
+    // asking for more removes all there is, the highest level first
+    db.enqueue_with_level(1, Level::Low).unwrap();
+    db.enqueue_with_level(2, Level::High).unwrap();
+    db.enqueue(3).unwrap();
+    db.enqueue(4).unwrap();
+    assert_eq!(db.trim_front(2).unwrap(), 2);
+    assert_eq!(db.dequeue().unwrap(), Some(4));
+    assert_eq!(db.trim_front(5).unwrap(), 1);
+    assert!(db.is_empty());
+    assert_eq!(db.payload_bytes(), 0);
+}
+
+#[test]
+fn estimate_bytes() {
+    let encodings = [IntEncoding::Fixed, IntEncoding::Varint];
+    for options in encodings.map(|encoding| Options::new().int_encoding(encoding)) {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<Vec<u8>>::with_options(file, options.compress_above(100)).unwrap();
+        // single frames, the edge of a frame and chains of several frames
+        for len in [0, 10, 975, 976, 977, 990, 3000, 10_000] {
+            let estimate = queue.estimate_bytes(&vec![1; len]).unwrap();
+            let before = queue.payload_bytes() + queue.overhead_bytes();
+            // random bytes do not compress
+            let mut state = len as u64 + 1;
+            let item = (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect();
+            queue.enqueue(item).unwrap();
+            let after = queue.payload_bytes() + queue.overhead_bytes();
+            assert_eq!(after - before, estimate, "item of {} bytes", len);
+        }
+    }
+}
+
+#[test]
+fn drain_raw_to() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let options = Options::new().compress_above(100);
+    let mut queue = Queue::<String>::with_options(file, options).unwrap();
+    let items: Vec<String> = (0..20).map(|i| "item ".repeat(i * 10)).collect();
+    for item in &items[..19] {
+        queue.enqueue(item.clone()).unwrap();
+    }
+    queue
+        .enqueue_with_level(items[19].clone(), Level::High)
+        .unwrap();
+
+    let mut archive = vec![];
+    let exported = queue
+        .drain_raw_to(&mut archive, RecordFraming::LengthPrefixed)
+        .unwrap();
+    assert_eq!(exported, 20);
+    assert!(queue.is_empty());
+    assert_eq!(queue.payload_bytes(), 0);
+    assert_eq!(queue.counters().dequeued, 20);
+
+    // the high item comes first, as it would be dequeued
+    let mut expected = items.clone();
+    expected.rotate_right(1);
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut copy = Queue::<String>::new(file.try_clone().unwrap()).unwrap();
+    let loaded = copy
+        .load_raw_from(archive.as_slice(), RecordFraming::LengthPrefixed)
+        .unwrap();
+    assert_eq!(loaded, 20);
+    drop(copy);
+    let mut copy = Queue::<String>::new(file).unwrap();
+    let copied: Vec<String> = std::iter::from_fn(|| copy.dequeue().unwrap()).collect();
+    assert_eq!(copied, expected);
+}
+
+#[test]
+fn drain_raw_newline_delimited() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut queue = Queue::<u32>::new(file).unwrap();
+    for item in [1, 2, 3] {
+        queue.enqueue(item).unwrap();
+    }
+    let mut archive = vec![];
+    let framing = RecordFraming::NewlineDelimited;
+    assert_eq!(queue.drain_raw_to(&mut archive, framing).unwrap(), 3);
+    assert_eq!(archive.iter().filter(|&&byte| byte == b'\n').count(), 3);
+
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut copy = Queue::<u32>::new(file).unwrap();
+    assert_eq!(copy.load_raw_from(archive.as_slice(), framing).unwrap(), 3);
+    let copied: Vec<u32> = copy.iter().collect::<Result<_, _>>().unwrap();
+    assert_eq!(copied, [1, 2, 3]);
+
+    // a record holding a newline stays, after the ones before got written
+    copy.enqueue(10).unwrap();
+    let mut archive = vec![];
+    let error = copy.drain_raw_to(&mut archive, framing).err().unwrap();
+    assert_eq!(error.downcast_ref(), Some(&WiredError::NewlineInRecord));
+    assert_eq!(archive.iter().filter(|&&byte| byte == b'\n').count(), 3);
+    assert_eq!(copy.len(), 1);
+    assert_eq!(copy.dequeue().unwrap(), Some(10));
+}
+
+#[test]
+fn compaction_savings() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut queue = Queue::<u32>::new(file.try_clone().unwrap()).unwrap();
+    // a fresh file already holds room to grow
+    let savings = queue.compaction_savings().unwrap();
+    let progress = queue.compact_step(CompactBudget::default()).unwrap();
+    assert_eq!(progress.released_bytes, savings);
+    assert_eq!(queue.compaction_savings().unwrap(), 0);
+
+    for item in 0..600 {
+        queue.enqueue(item).unwrap();
+    }
+    for _ in 0..500 {
+        queue.dequeue().unwrap();
+    }
+    let savings = queue.compaction_savings().unwrap();
+    assert!(savings > 0);
+
+    let size = file.metadata().unwrap().len() as usize;
+    while !queue.compact_step(CompactBudget::default()).unwrap().done {}
+    let compacted = file.metadata().unwrap().len() as usize;
+    assert_eq!(size - compacted, savings);
+    assert_eq!(queue.compaction_savings().unwrap(), 0);
+}
+
+#[test]
+fn content_hash() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut queue = Queue::<String>::new(file).unwrap();
+    for item in 0..300 {
+        queue.enqueue(format!("item {}", item)).unwrap();
+    }
+    for _ in 0..200 {
+        queue.dequeue().unwrap();
+    }
+    let hash = queue.content_hash().unwrap();
+    while !queue.compact_step(CompactBudget::default()).unwrap().done {}
+    assert_eq!(queue.content_hash().unwrap(), hash);
+
+    // the same items in a differently laid out file
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let options = Options::new().compress_above(4);
+    let mut fresh = Queue::<String>::with_options(file, options).unwrap();
+    for item in 200..300 {
+        fresh.enqueue(format!("item {}", item)).unwrap();
+    }
+    assert_eq!(fresh.content_hash().unwrap(), hash);
+
+    // a single changed item
+    fresh.dequeue_back().unwrap();
+    fresh.enqueue("item 300".to_string()).unwrap();
+    assert_ne!(fresh.content_hash().unwrap(), hash);
+
+    let mut archive = vec![];
+    queue
+        .drain_raw_to(&mut archive, RecordFraming::LengthPrefixed)
+        .unwrap();
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut copy = Queue::<String>::new(file).unwrap();
+    copy.load_raw_from(archive.as_slice(), RecordFraming::LengthPrefixed)
+        .unwrap();
+    assert_eq!(copy.content_hash().unwrap(), hash);
+}
+
+#[test]
+fn size_classes() {
+    // mostly tiny items with a few huge ones in between
+    let items: Vec<Vec<u8>> = (0..400_usize)
+        .map(|i| match i % 50 {
+            7 => vec![i as u8; 100_000],
+            _ => vec![i as u8; 20],
+        })
+        .collect();
+    let fill = |options: Options| {
+        let file = tempfile::tempfile().expect("could not create tempfile");
+        let mut queue = Queue::<Vec<u8>>::with_options(file.try_clone().unwrap(), options).unwrap();
+        for item in &items {
+            queue.enqueue(item.clone()).unwrap();
+        }
+        for _ in 0..100 {
+            queue.dequeue().unwrap();
+        }
+        while !queue.compact_step(CompactBudget::default()).unwrap().done {}
+        let size = file.metadata().unwrap().len();
+        (file, size)
+    };
+    let (_, single) = fill(Options::new());
+    let (file, classes) = fill(Options::new().size_classes(true));
+    assert!(classes < single, "{} vs {} bytes", classes, single);
+
+    let mut queue = Queue::<Vec<u8>>::new(file).unwrap();
+    assert_eq!(queue.len(), 300);
+    for item in &items[100..] {
+        assert_eq!(queue.dequeue().unwrap().as_ref(), Some(item));
+    }
+}
+
+#[test]
+fn open_truncated_tolerant() {
+    let directory = tempfile::tempdir().expect("could not create tempdir");
+    let path = directory.path().join("db.wired");
+    let mut queue = Queue::<String>::open(&path).unwrap();
+    for i in 0..20 {
+        queue.enqueue(format!("item {}", i)).unwrap();
+    }
+    drop(queue);
+    let bytes = std::fs::read(&path).unwrap();
+    // the header region, the header of the queue and one frame per item
+    let expected = 1024 + 21 * 1024;
+    assert!(bytes.len() > expected);
+
+    let truncated = |error: Box<dyn std::error::Error>| match error.downcast_ref() {
+        Some(WiredError::Truncated { expected, actual }) => (*expected, *actual),
+        _ => panic!("unexpected error: {}", error),
+    };
+    let cut = |len: usize| {
+        let path = directory.path().join(format!("cut-{}.wired", len));
+        std::fs::write(&path, &bytes[..len]).unwrap();
+        let error = Queue::<String>::open(&path).err().expect("should fail");
+        (path, truncated(error))
+    };
+
+    // within the header of the file, nothing can be salvaged
+    let (path, error) = cut(40);
+    assert!(error.0 > 40 && error.0 < 1024);
+    assert_eq!(error.1, 40);
+    let tolerant = Queue::<String>::open_truncated_tolerant(&path).err();
+    assert_eq!(truncated(tolerant.expect("should fail")), error);
+
+    // neither before the header of the queue in the first frame
+    let (path, error) = cut(500);
+    assert_eq!(error, (expected, 500));
+    let tolerant = Queue::<String>::open_truncated_tolerant(&path).err();
+    assert_eq!(truncated(tolerant.expect("should fail")).1, 500);
+
+    // between frames and within a frame, the items in front survive
+    for len in [7 * 1024, 7 * 1024 + 500] {
+        let (path, error) = cut(len);
+        assert_eq!(error, (expected, len));
+        let mut queue = Queue::<String>::open_truncated_tolerant(&path).unwrap();
+        for i in 0..5 {
+            assert_eq!(queue.dequeue().unwrap(), Some(format!("item {}", i)));
+        }
+        let (_, actual) = truncated(queue.dequeue().expect_err("should fail"));
+        assert_eq!(actual, len);
+    }
+}
@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+use wired::{Options, SlotStore};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct User {
+    name: String,
+    age: u8,
+}
+
+#[test]
+fn works() {
+    // a single slot chains every entry
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut db =
+        SlotStore::<User>::with_slots(file.try_clone().unwrap(), Options::default(), 1).unwrap();
+    for id in 0..10 {
+        let name = format!("user {}", id);
+        db.put(
+            id * 1000,
+            User {
+                name,
+                age: id as u8,
+            },
+        )
+        .unwrap();
+    }
+    db.remove(5000).unwrap();
+    assert_eq!(db.len(), 9);
+
+    // works after reopen
+    drop(db);
+    let db = SlotStore::<User>::new(file).unwrap();
+    assert_eq!(db.slots(), 1);
+    assert_eq!(db.get(5000).unwrap(), None);
+    let user = db.get(9000).unwrap().unwrap();
+    assert_eq!(user.name, "user 9");
+    assert_eq!(user.age, 9);
+}
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
-use wired::Stack;
+use wired::{CompactBudget, RecordFraming, Stack, WiredError};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Message {
@@ -46,3 +46,263 @@ fn works() {
     assert_eq!(db.len(), 0);
     assert!(db.is_empty());
 }
+
+#[test]
+fn open_by_path() {
+    let directory = tempfile::tempdir().expect("could not create tempdir");
+    let path = directory.path().join("stack.wired");
+
+    let mut db = Stack::<Message>::open(&path).unwrap();
+    db.push(Message::new("msg 1")).unwrap();
+    drop(db);
+
+    let mut db = Stack::<Message>::open(&path).unwrap();
+    assert_eq!(db.pop().unwrap().unwrap().name, "msg 1".to_string());
+    assert!(db.is_empty());
+}
+
+#[test]
+fn bulk_load() {
+    let items: Vec<Vec<u32>> = (0..300).map(|i| vec![i; (i % 7) as usize * 100]).collect();
+
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut pushed = Stack::<Vec<u32>>::new(file).unwrap();
+    for item in items.iter() {
+        pushed.push(item.clone()).unwrap();
+    }
+
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut loaded = Stack::<Vec<u32>>::bulk_load(file, items.clone(), 300).unwrap();
+    assert_eq!(loaded.len(), 300);
+    assert_eq!(loaded.payload_bytes(), pushed.payload_bytes());
+    assert_eq!(loaded.counters(), pushed.counters());
+    assert!(loaded.stats().resizes < pushed.stats().resizes);
+    assert!(loaded.stats().flushes < 10);
+    assert_eq!(
+        loaded.by_ref().collect::<Vec<_>>(),
+        pushed.by_ref().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn compact_step() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut stack = Stack::<u32>::new(file.try_clone().unwrap()).unwrap();
+
+    // a large metadata blob that gets replaced leaves a hole at the front
+    stack.set_meta(&[0; 60 * 1024]).unwrap();
+    let mut expected: Vec<u32> = (0..200).collect();
+    for item in expected.iter() {
+        stack.push(*item).unwrap();
+    }
+    stack.set_meta(b"small").unwrap();
+    let size = file.metadata().unwrap().len();
+
+    let mut relocated = 0;
+    for step in 0.. {
+        assert!(step < 100, "compaction does not finish");
+        let progress = stack.compact_step(CompactBudget { blocks: 8 }).unwrap();
+        relocated += progress.relocated;
+        if progress.done {
+            break;
+        }
+        stack.push(1000 + step).unwrap();
+        expected.push(1000 + step);
+        if step % 2 == 0 {
+            assert_eq!(stack.pop().unwrap(), expected.pop());
+        }
+    }
+    assert!(relocated >= 50);
+    assert!(file.metadata().unwrap().len() < size);
+    assert!(stack.check_counts(false).unwrap().is_consistent());
+
+    drop(stack);
+    let stack = Stack::<u32>::new(file).unwrap();
+    expected.reverse();
+    assert_eq!(stack.collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn pop_bottom() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut db = Stack::<u32>::new(file.try_clone().unwrap()).unwrap();
+    assert_eq!(db.pop_bottom().unwrap(), None);
+
+    // a single item is both ends
+    db.push(1).unwrap();
+    assert_eq!(db.pop_bottom().unwrap(), Some(1));
+    assert_eq!(db.pop().unwrap(), None);
+
+    // two items leave one behind, usable from both ends
+    db.push(2).unwrap();
+    db.push(3).unwrap();
+    assert_eq!(db.pop_bottom().unwrap(), Some(2));
+    db.push(4).unwrap();
+    assert_eq!(db.pop().unwrap(), Some(4));
+    assert_eq!(db.pop_bottom().unwrap(), Some(3));
+    assert!(db.is_empty());
+
+    // interleaved with both ends, surviving a reopen
+    for item in 5..15 {
+        db.push(item).unwrap();
+    }
+    assert_eq!(db.pop().unwrap(), Some(14));
+    assert_eq!(db.pop_bottom().unwrap(), Some(5));
+    assert_eq!(db.pop_bottom().unwrap(), Some(6));
+    drop(db);
+    let mut db = Stack::<u32>::new(file).unwrap();
+    assert_eq!(db.len(), 7);
+    assert!(db.check_counts(false).unwrap().is_consistent());
+    assert_eq!(db.counters().pops, 7);
+    db.push(15).unwrap();
+    assert_eq!(db.pop_bottom().unwrap(), Some(7));
+    let rest: Vec<u32> = std::iter::from_fn(|| db.pop().unwrap()).collect();
+    assert_eq!(rest, [15, 13, 12, 11, 10, 9, 8]);
+    assert_eq!(db.payload_bytes(), 0);
+}
+
+#[test]
+fn estimate_bytes() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut stack = Stack::<Vec<u8>>::new(file).unwrap();
+    for len in [0, 100, 980, 990, 5000] {
+        let item = vec![1; len];
+        let estimate = stack.estimate_bytes(&item).unwrap();
+        let before = stack.payload_bytes() + stack.overhead_bytes();
+        stack.push(item).unwrap();
+        assert_eq!(
+            stack.payload_bytes() + stack.overhead_bytes() - before,
+            estimate
+        );
+    }
+}
+
+#[test]
+fn drain_raw_to() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut stack = Stack::<String>::new(file).unwrap();
+    let items: Vec<String> = (0..20).map(|i| "item ".repeat(i * 50)).collect();
+    for item in &items {
+        stack.push(item.clone()).unwrap();
+    }
+    let mut archive = vec![];
+    let exported = stack
+        .drain_raw_to(&mut archive, RecordFraming::LengthPrefixed)
+        .unwrap();
+    assert_eq!(exported, 20);
+    assert!(stack.is_empty());
+    assert_eq!(stack.payload_bytes(), 0);
+
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut copy = Stack::<String>::new(file.try_clone().unwrap()).unwrap();
+    let loaded = copy
+        .load_raw_from(archive.as_slice(), RecordFraming::LengthPrefixed)
+        .unwrap();
+    assert_eq!(loaded, 20);
+    drop(copy);
+    let mut copy = Stack::<String>::new(file).unwrap();
+    let copied: Vec<String> = std::iter::from_fn(|| copy.pop().unwrap()).collect();
+    assert!(copied.iter().eq(items.iter().rev()));
+
+    // the bottom items written before a failure are gone, the rest stays.
+    // The length prefix of a string of ten bytes is a newline.
+    for item in ["a", "bb", "0123456789", "ccc"] {
+        copy.push(String::from(item)).unwrap();
+    }
+    let mut archive = vec![];
+    let error = copy
+        .drain_raw_to(&mut archive, RecordFraming::NewlineDelimited)
+        .err()
+        .unwrap();
+    assert_eq!(error.downcast_ref(), Some(&WiredError::NewlineInRecord));
+    assert_eq!(copy.len(), 2);
+    let rest: Vec<String> = copy.iter().collect::<Result<_, _>>().unwrap();
+    assert_eq!(rest, ["ccc", "0123456789"]);
+    assert!(copy.check_counts(false).unwrap().is_consistent());
+}
+
+#[test]
+fn replace_top() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut stack = Stack::<String>::new(file.try_clone().unwrap()).unwrap();
+    assert_eq!(stack.replace_top(String::from("nothing")).unwrap(), None);
+    assert!(stack.is_empty());
+
+    stack.push(String::from("base")).unwrap();
+    stack.push(String::from("draft")).unwrap();
+    let replaced = stack.replace_top(String::from("final")).unwrap();
+    assert_eq!(replaced, Some(String::from("draft")));
+    // a larger item spans several frames
+    let large = "x".repeat(5000);
+    assert_eq!(
+        stack.replace_top(large.clone()).unwrap(),
+        Some(String::from("final"))
+    );
+    assert_eq!(stack.len(), 2);
+    let payload = stack.payload_bytes();
+    drop(stack);
+
+    let mut stack = Stack::<String>::new(file).unwrap();
+    assert_eq!(stack.payload_bytes(), payload);
+    assert_eq!(stack.pop().unwrap(), Some(large));
+    assert_eq!(stack.pop().unwrap(), Some(String::from("base")));
+    assert!(stack.check_counts(false).unwrap().is_consistent());
+}
+
+#[test]
+fn content_hash() {
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut stack = Stack::<String>::new(file).unwrap();
+    for item in 0..300 {
+        stack.push(format!("item {}", item)).unwrap();
+    }
+    for _ in 0..200 {
+        stack.pop().unwrap();
+    }
+    let hash = stack.content_hash().unwrap();
+    while !stack.compact_step(CompactBudget::default()).unwrap().done {}
+    assert_eq!(stack.content_hash().unwrap(), hash);
+
+    stack.replace_top("changed".to_string()).unwrap();
+    assert_ne!(stack.content_hash().unwrap(), hash);
+
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let mut empty = Stack::<String>::new(file).unwrap();
+    assert_ne!(empty.content_hash().unwrap(), hash);
+    empty.push(String::new()).unwrap();
+    let file = tempfile::tempfile().expect("could not create tempfile");
+    let other = Stack::<String>::new(file).unwrap();
+    // an empty item still counts
+    assert_ne!(empty.content_hash().unwrap(), other.content_hash().unwrap());
+}
+
+#[test]
+fn anonymous() {
+    let mut stack = Stack::<Vec<u8>>::anonymous(0).unwrap();
+    let mut expected = vec![];
+    for item in 0..300 {
+        let bytes = vec![item as u8; item * 37];
+        stack.push(bytes.clone()).unwrap();
+        expected.push(bytes);
+    }
+    for _ in 0..100 {
+        assert_eq!(stack.pop().unwrap(), expected.pop());
+    }
+    let progress = stack.compact_step(CompactBudget { blocks: 1000 }).unwrap();
+    assert!(progress.done);
+    stack.barrier().unwrap();
+    assert!(stack.stats().resizes > 0);
+    assert_eq!(stack.stats().syncs, 0);
+    assert_eq!(stack.len(), 200);
+    expected.reverse();
+    assert_eq!(
+        stack.iter().collect::<Result<Vec<_>, _>>().unwrap(),
+        expected
+    );
+
+    // a large reservation only commits the pages written to
+    let mut stack = Stack::<String>::anonymous(1 << 30).unwrap();
+    stack.push(String::from("scratch")).unwrap();
+    assert_eq!(stack.pop().unwrap(), Some(String::from("scratch")));
+    assert_eq!(stack.stats().resizes, 0);
+}